@@ -0,0 +1,177 @@
+use pulldown_cmark::{CowStr, Event, Tag};
+
+/// The admonition types which get their own style, anything else is shown
+/// with the generic admonition style.
+const KNOWN_TYPES: &[&str] = &["note", "tip", "important", "warning", "caution"];
+
+/// Turns GitHub-style alerts (a blockquote starting with `[!NOTE]`, etc.)
+/// into admonition boxes.
+///
+/// Whether a blockquote is an alert is only known once its first line has
+/// been seen, so its events are buffered until then.
+pub(crate) struct EventAdmonitions<'a> {
+    enabled: bool,
+    /// The element admonitions are wrapped in.
+    element: &'static str,
+    /// Events of a blockquote which might still turn out to be an alert.
+    pending: Option<Vec<Event<'a>>>,
+    /// Whether each currently open blockquote is an admonition.
+    open: Vec<bool>,
+}
+
+impl<'a> EventAdmonitions<'a> {
+    /// `element` is what admonitions get wrapped in, `aside` is only valid
+    /// in EPUB 3 so older books should use a `div`.
+    pub(crate) fn new(enabled: bool, element: &'static str) -> Self {
+        EventAdmonitions {
+            enabled,
+            element,
+            pending: None,
+            open: Vec::new(),
+        }
+    }
+
+    pub(crate) fn convert(&mut self, event: Event<'a>) -> Vec<Event<'a>> {
+        if !self.enabled {
+            return vec![event];
+        }
+
+        let mut pending = match self.pending.take() {
+            Some(pending) => pending,
+            None => {
+                return match event {
+                    Event::Start(Tag::BlockQuote) => {
+                        self.pending = Some(vec![event]);
+                        Vec::new()
+                    }
+                    Event::End(Tag::BlockQuote) => match self.open.pop() {
+                        Some(true) => vec![Event::Html(CowStr::from(format!("</{}>\n", self.element)))],
+                        _ => vec![event],
+                    },
+                    _ => vec![event],
+                };
+            }
+        };
+
+        let in_paragraph = pending.len() > 1;
+        match event {
+            Event::Start(Tag::Paragraph) if !in_paragraph => {
+                pending.push(event);
+                self.pending = Some(pending);
+                Vec::new()
+            }
+            Event::Text(_) if in_paragraph => {
+                pending.push(event);
+                self.pending = Some(pending);
+                Vec::new()
+            }
+            Event::SoftBreak | Event::HardBreak | Event::End(Tag::Paragraph) if in_paragraph => {
+                let marker: String = pending
+                    .iter()
+                    .filter_map(|event| match *event {
+                        Event::Text(ref text) => Some(text.as_ref()),
+                        _ => None,
+                    })
+                    .collect();
+
+                match alert_type(&marker) {
+                    Some(kind) => {
+                        self.open.push(true);
+                        let mut events = vec![Event::Html(CowStr::from(self.opening_tag(&kind)))];
+                        // the rest of the first paragraph becomes the body
+                        if !matches!(event, Event::End(Tag::Paragraph)) {
+                            events.push(Event::Start(Tag::Paragraph));
+                        }
+                        events
+                    }
+                    None => self.not_an_alert(pending, event),
+                }
+            }
+            _ => self.not_an_alert(pending, event),
+        }
+    }
+
+    /// Give back the buffered events of a plain blockquote, followed by
+    /// whatever comes next.
+    fn not_an_alert(&mut self, mut pending: Vec<Event<'a>>, event: Event<'a>) -> Vec<Event<'a>> {
+        self.open.push(false);
+        pending.extend(self.convert(event));
+        pending
+    }
+
+    fn opening_tag(&self, kind: &str) -> String {
+        let title = match kind.chars().next() {
+            Some(first) => first.to_uppercase().chain(kind.chars().skip(1)).collect(),
+            None => String::new(),
+        };
+        let class = if KNOWN_TYPES.contains(&kind) {
+            format!("admonition {}", kind)
+        } else {
+            String::from("admonition")
+        };
+
+        format!(
+            "<{} class=\"{}\">\n<p class=\"admonition-title\">{}</p>\n",
+            self.element, class, title
+        )
+    }
+}
+
+/// The type of a `[!TYPE]` alert marker, in lowercase.
+fn alert_type(marker: &str) -> Option<String> {
+    let kind = marker.trim().strip_prefix("[!")?.strip_suffix(']')?;
+
+    if !kind.is_empty() && kind.chars().all(|c| c.is_ascii_alphanumeric()) {
+        Some(kind.to_ascii_lowercase())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Generator;
+    use pulldown_cmark::html;
+
+    fn render(input: &str) -> String {
+        let mut body = String::new();
+        let mut converter = EventAdmonitions::new(true, "aside");
+        let events = Generator::new_cmark_parser(input).flat_map(|event| converter.convert(event));
+        html::push_html(&mut body, events);
+        body
+    }
+
+    #[test]
+    fn alerts_become_admonitions() {
+        let body = render("> [!NOTE]\n> Remember this.\n\n> [!Tip]\n\n> Just a quote.\n");
+
+        assert_eq!(
+            body,
+            "<aside class=\"admonition note\">\n<p class=\"admonition-title\">Note</p>\n<p>Remember this.</p>\n</aside>\n\
+             <aside class=\"admonition tip\">\n<p class=\"admonition-title\">Tip</p>\n</aside>\n\
+             <blockquote>\n<p>Just a quote.</p>\n</blockquote>\n"
+        );
+    }
+
+    #[test]
+    fn unknown_types_use_the_generic_style() {
+        let body = render("> [!DANGER]\n> Careful!\n");
+
+        assert!(body.starts_with("<aside class=\"admonition\">\n<p class=\"admonition-title\">Danger</p>"), "{}", body);
+    }
+
+    #[test]
+    fn alerts_inside_quotes() {
+        let input = "> > [!NOTE]\n> > Inner\n>\n> outer\n";
+        let mut expected = String::new();
+        html::push_html(&mut expected, Generator::new_cmark_parser(input));
+        let expected = expected.replacen(
+            "<blockquote>\n<p>[!NOTE]\nInner</p>\n</blockquote>",
+            "<aside class=\"admonition note\">\n<p class=\"admonition-title\">Note</p>\n<p>Inner</p>\n</aside>",
+            1,
+        );
+
+        assert_eq!(render(input), expected);
+    }
+}
@@ -0,0 +1,186 @@
+use crate::footnotes::id_prefix;
+use crate::package::escape_xml;
+use pulldown_cmark::{CowStr, Event, Options, Parser};
+use std::path::Path;
+
+/// A term to index, where it was marked in a chapter.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct IndexEntry {
+    pub(crate) term: String,
+    /// Where the marker is, e.g. `chapter_1.html#index-chapter-1-2`.
+    pub(crate) href: String,
+    /// The title of the chapter it's in, which links to it are labelled
+    /// with.
+    pub(crate) chapter: String,
+}
+
+/// Replaces `<!-- index: term -->` markers with an empty element for the
+/// book's index to link to, remembering the terms.
+pub(crate) struct EventIndexMarkers {
+    enabled: bool,
+    prefix: String,
+    terms: Vec<(String, String)>,
+}
+
+impl EventIndexMarkers {
+    pub(crate) fn new(enabled: bool, chapter_path: &Path) -> Self {
+        EventIndexMarkers {
+            enabled,
+            prefix: id_prefix(chapter_path),
+            terms: Vec::new(),
+        }
+    }
+
+    pub(crate) fn convert<'a>(&mut self, event: Event<'a>) -> Event<'a> {
+        let html = match event {
+            Event::Html(ref html) if self.enabled => html,
+            _ => return event,
+        };
+        let term = match marker_term(html) {
+            Some(term) => term.to_string(),
+            None => return event,
+        };
+
+        let id = format!("index-{}-{}", self.prefix, self.terms.len() + 1);
+        // a marker on a line of its own is an HTML block
+        let element = if html.ends_with('\n') {
+            format!("<div class=\"index-marker\" id=\"{}\"></div>\n", id)
+        } else {
+            format!("<span class=\"index-marker\" id=\"{}\"></span>", id)
+        };
+        self.terms.push((term, id));
+        Event::Html(CowStr::from(element))
+    }
+}
+
+/// The term of an `<!-- index: term -->` marker.
+fn marker_term(html: &str) -> Option<&str> {
+    let term = html
+        .trim()
+        .strip_prefix("<!--")?
+        .strip_suffix("-->")?
+        .trim()
+        .strip_prefix("index:")?
+        .trim();
+
+    (!term.is_empty()).then_some(term)
+}
+
+/// The terms marked in a chapter's markdown, parsed with `options`, with the
+/// ids of the elements the markers are turned into.
+///
+/// Chapters are rendered in parallel and may come from the cache, so the
+/// terms are found beforehand rather than while rendering.
+pub(crate) fn terms(markdown: &str, chapter_path: &Path, options: Options) -> Vec<(String, String)> {
+    let mut markers = EventIndexMarkers::new(true, chapter_path);
+    for event in Parser::new_ext(markdown, options) {
+        markers.convert(event);
+    }
+
+    markers.terms
+}
+
+/// The body of the index page: every term in alphabetical order, ignoring
+/// case, under a heading for its first letter, with a link to each chapter
+/// it's marked in. Terms differing only in case are listed once.
+pub(crate) fn render(title: &str, entries: &[IndexEntry]) -> String {
+    let mut sorted: Vec<&IndexEntry> = entries.iter().collect();
+    sorted.sort_by_cached_key(|entry| entry.term.to_lowercase());
+
+    let mut terms: Vec<(&str, Vec<&IndexEntry>)> = Vec::new();
+    for entry in sorted {
+        match terms.last_mut() {
+            Some((term, links)) if term.to_lowercase() == entry.term.to_lowercase() => {
+                // one link per chapter is enough to find the term
+                if !links.iter().any(|link| link.chapter == entry.chapter) {
+                    links.push(entry);
+                }
+            }
+            _ => terms.push((&entry.term, vec![entry])),
+        }
+    }
+
+    let mut body = format!("<h1>{}</h1>\n", escape_xml(title));
+    let mut letter = None;
+    for (term, links) in terms {
+        let first = term
+            .chars()
+            .next()
+            .filter(|c| c.is_alphabetic())
+            .map_or_else(|| String::from("#"), |c| c.to_uppercase().collect());
+        if letter.as_ref() != Some(&first) {
+            if letter.is_some() {
+                body.push_str("</ul>\n");
+            }
+            body.push_str(&format!("<h2 class=\"index-letter\">{}</h2>\n<ul class=\"index\">\n", escape_xml(&first)));
+            letter = Some(first);
+        }
+
+        let links: Vec<String> = links
+            .iter()
+            .map(|link| format!("<a href=\"{}\">{}</a>", escape_xml(&link.href), escape_xml(&link.chapter)))
+            .collect();
+        body.push_str(&format!("<li>{}, {}</li>\n", escape_xml(term), links.join(", ")));
+    }
+    if letter.is_some() {
+        body.push_str("</ul>\n");
+    }
+
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Generator;
+    use pulldown_cmark::html;
+
+    #[test]
+    fn markers_become_anchors() {
+        let markdown = "<!-- index: Ownership -->\n# Ownership\n\nSee the borrow checker<!-- index: borrow checker -->.\n\n<!-- not a marker -->\n";
+        let mut markers = EventIndexMarkers::new(true, Path::new("guide/ownership.md"));
+        let events = Generator::new_cmark_parser(markdown).map(|event| markers.convert(event));
+        let mut rendered = String::new();
+        html::push_html(&mut rendered, events);
+
+        assert_eq!(
+            rendered,
+            "<div class=\"index-marker\" id=\"index-guide-ownership-1\"></div>\n<h1>Ownership</h1>\n\
+             <p>See the borrow checker<span class=\"index-marker\" id=\"index-guide-ownership-2\"></span>.</p>\n\
+             <!-- not a marker -->\n"
+        );
+        let options = crate::config::MarkdownOption::options(crate::config::MarkdownOption::DEFAULT);
+        assert_eq!(terms(markdown, Path::new("guide/ownership.md"), options), markers.terms);
+    }
+
+    #[test]
+    fn terms_are_grouped_by_letter() {
+        let entry = |term: &str, href: &str, chapter: &str| IndexEntry {
+            term: term.to_string(),
+            href: href.to_string(),
+            chapter: chapter.to_string(),
+        };
+        let entries = vec![
+            entry("borrowing", "b.html#index-b-1", "Borrowing"),
+            entry("Arc", "a.html#index-a-1", "Arc"),
+            entry("Box", "a.html#index-a-2", "Arc"),
+            entry("Borrowing", "b.html#index-b-2", "Borrowing"),
+            entry("borrowing", "c.html#index-c-1", "Cells"),
+            entry("&str", "c.html#index-c-2", "Cells"),
+        ];
+
+        let rendered = render("Index", &entries);
+
+        assert_eq!(
+            rendered,
+            "<h1>Index</h1>\n\
+             <h2 class=\"index-letter\">#</h2>\n<ul class=\"index\">\n\
+             <li>&amp;str, <a href=\"c.html#index-c-2\">Cells</a></li>\n</ul>\n\
+             <h2 class=\"index-letter\">A</h2>\n<ul class=\"index\">\n\
+             <li>Arc, <a href=\"a.html#index-a-1\">Arc</a></li>\n</ul>\n\
+             <h2 class=\"index-letter\">B</h2>\n<ul class=\"index\">\n\
+             <li>borrowing, <a href=\"b.html#index-b-1\">Borrowing</a>, <a href=\"c.html#index-c-1\">Cells</a></li>\n\
+             <li>Box, <a href=\"a.html#index-a-2\">Arc</a></li>\n</ul>\n"
+        );
+    }
+}
@@ -0,0 +1,114 @@
+use mdbook::book::Chapter;
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The file recording which fingerprint the cached chapters were rendered
+/// with.
+const FINGERPRINT_FILE: &str = "fingerprint";
+/// The extension rendered chapters are cached with.
+const ENTRY_EXTENSION: &str = "xhtml";
+
+/// Rendered chapters from previous builds, keyed by a hash of each chapter.
+///
+/// Everything which affects every chapter (the crate version, stylesheet,
+/// template and config) goes into a fingerprint, and the whole cache is
+/// thrown away whenever that changes.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct RenderCache {
+    dir: PathBuf,
+}
+
+impl RenderCache {
+    /// Open the cache in `dir`, clearing it out if it was filled using a
+    /// different fingerprint.
+    pub(crate) fn open(dir: &Path, fingerprint: u64) -> io::Result<RenderCache> {
+        fs::create_dir_all(dir)?;
+
+        let fingerprint = format!("{:016x}", fingerprint);
+        let fingerprint_file = dir.join(FINGERPRINT_FILE);
+        let previous = fs::read_to_string(&fingerprint_file).unwrap_or_default();
+
+        if previous.trim() != fingerprint {
+            debug!("Clearing the render cache in {}", dir.display());
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.extension().is_some_and(|ext| ext == ENTRY_EXTENSION) {
+                    fs::remove_file(path)?;
+                }
+            }
+            fs::write(&fingerprint_file, fingerprint)?;
+        }
+
+        Ok(RenderCache { dir: dir.to_path_buf() })
+    }
+
+    /// The key a chapter's rendered XHTML is cached under. `context` is
+    /// anything else particular to the chapter which changes how it's
+    /// rendered.
+    pub(crate) fn key<C: Hash>(ch: &Chapter, context: &C) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        context.hash(&mut hasher);
+        // the section number is part of the title and decides the level
+        ch.to_string().hash(&mut hasher);
+        ch.content.hash(&mut hasher);
+        ch.path.hash(&mut hasher);
+        ch.parent_names.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Look up the XHTML a chapter was rendered to last time.
+    pub(crate) fn get(&self, key: u64) -> Option<String> {
+        fs::read_to_string(self.entry(key)).ok()
+    }
+
+    /// Remember a chapter's rendered XHTML. A cache which can't be written to
+    /// only makes the next build slower, so failures are just logged.
+    pub(crate) fn put(&self, key: u64, rendered: &str) {
+        let entry = self.entry(key);
+        if let Err(e) = fs::write(&entry, rendered) {
+            warn!("Unable to cache a rendered chapter in {}: {}", entry.display(), e);
+        }
+    }
+
+    fn entry(&self, key: u64) -> PathBuf {
+        self.dir.join(format!("{:016x}", key)).with_extension(ENTRY_EXTENSION)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn entries_survive_reopening_with_the_same_fingerprint() {
+        let dir = TempDir::new("mdbook-epub-cache").unwrap();
+        let ch = Chapter::new("Intro", String::from("# Intro"), "intro.md", Vec::new());
+        let key = RenderCache::key(&ch, &());
+
+        let cache = RenderCache::open(dir.path(), 1).unwrap();
+        assert_eq!(cache.get(key), None);
+        cache.put(key, "<h1>Intro</h1>");
+
+        let cache = RenderCache::open(dir.path(), 1).unwrap();
+        assert_eq!(cache.get(key).as_deref(), Some("<h1>Intro</h1>"));
+
+        let cache = RenderCache::open(dir.path(), 2).unwrap();
+        assert_eq!(cache.get(key), None);
+    }
+
+    #[test]
+    fn keys_change_with_the_content() {
+        let ch = Chapter::new("Intro", String::from("# Intro"), "intro.md", Vec::new());
+        let edited = Chapter::new("Intro", String::from("# Introduction"), "intro.md", Vec::new());
+        let moved = Chapter::new("Intro", String::from("# Intro"), "start.md", Vec::new());
+
+        assert_eq!(RenderCache::key(&ch, &()), RenderCache::key(&ch.clone(), &()));
+        assert_ne!(RenderCache::key(&ch, &()), RenderCache::key(&edited, &()));
+        assert_ne!(RenderCache::key(&ch, &()), RenderCache::key(&moved, &()));
+        assert_ne!(RenderCache::key(&ch, &()), RenderCache::key(&ch, &"next"));
+    }
+}
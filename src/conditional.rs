@@ -0,0 +1,123 @@
+use std::ops::Range;
+
+use pulldown_cmark::{Event, Parser};
+
+/// The kinds of block a chapter can mark out for the EPUB.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Block {
+    /// Kept in the EPUB, for content other formats leave out.
+    Only,
+    /// Left out of the EPUB.
+    Skip,
+}
+
+/// A comment opening or closing a block.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Marker {
+    Open(Block),
+    Close(Block),
+}
+
+impl Marker {
+    fn parse(html: &str) -> Option<Marker> {
+        let comment = html.trim().strip_prefix("<!--")?.strip_suffix("-->")?.trim();
+        match comment {
+            "epub:only" => Some(Marker::Open(Block::Only)),
+            "/epub:only" => Some(Marker::Close(Block::Only)),
+            "epub:skip" => Some(Marker::Open(Block::Skip)),
+            "/epub:skip" => Some(Marker::Close(Block::Skip)),
+            _ => None,
+        }
+    }
+}
+
+/// Tailor a chapter's markdown to the EPUB: what's between
+/// `<!-- epub:skip -->` and `<!-- /epub:skip -->` is taken out, and the
+/// `<!-- epub:only -->` and `<!-- /epub:only -->` comments around what only
+/// the EPUB should show are removed, leaving what's between them. Returns
+/// `None` for a chapter without any of these comments.
+///
+/// Blocks can be nested, and anything inside a skipped block is skipped,
+/// `epub:only` blocks included. Comments in code are left alone, along with
+/// closing comments which don't close the innermost open block. A block which
+/// is never closed runs to the end of the chapter.
+pub(crate) fn tailor(markdown: &str) -> Option<String> {
+    let markers: Vec<(Marker, Range<usize>)> = Parser::new(markdown)
+        .into_offset_iter()
+        .filter_map(|(event, range)| match event {
+            Event::Html(html) => Marker::parse(&html).map(|marker| (marker, range)),
+            _ => None,
+        })
+        .collect();
+    if markers.is_empty() {
+        return None;
+    }
+
+    let mut out = String::with_capacity(markdown.len());
+    let mut open: Vec<Block> = Vec::new();
+    let mut last = 0;
+    for (marker, range) in markers {
+        let skipping = open.contains(&Block::Skip);
+        match marker {
+            Marker::Open(block) => open.push(block),
+            Marker::Close(block) if open.last() == Some(&block) => {
+                open.pop();
+            }
+            Marker::Close(_) => {
+                warn!("Ignoring {}, which doesn't close the block it's in", markdown[range.clone()].trim());
+                continue;
+            }
+        }
+
+        if !skipping {
+            out.push_str(&markdown[last..range.start]);
+            // a comment on a line of its own still separates what's around it
+            if markdown[range.clone()].ends_with('\n') {
+                out.push('\n');
+            }
+        }
+        last = range.end;
+    }
+
+    if open.is_empty() {
+        out.push_str(&markdown[last..]);
+    } else {
+        warn!("A block marked with <!-- epub:only --> or <!-- epub:skip --> isn't closed");
+        if !open.contains(&Block::Skip) {
+            out.push_str(&markdown[last..]);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skipped_blocks_are_taken_out() {
+        let markdown = "Before.\n<!-- epub:skip -->\n[Play online](https://example.com)\n<!-- /epub:skip -->\nAfter.\n";
+        assert_eq!(tailor(markdown).unwrap(), "Before.\n\nAfter.\n");
+        assert_eq!(tailor("Read <!-- epub:skip -->online<!-- /epub:skip -->here.").unwrap(), "Read here.");
+    }
+
+    #[test]
+    fn only_blocks_are_kept() {
+        let markdown = "<!-- epub:only -->\nThis is the *EPUB* edition.\n<!-- /epub:only -->\n";
+        assert_eq!(tailor(markdown).unwrap(), "\nThis is the *EPUB* edition.\n\n");
+    }
+
+    #[test]
+    fn skipping_wins_when_nested() {
+        let markdown = "A\n\n<!-- epub:skip -->\nB\n<!-- epub:only -->\nC\n<!-- /epub:only -->\n<!-- /epub:skip -->\n\n\
+                        <!-- epub:only -->\nD\n<!-- epub:skip -->\nE\n<!-- /epub:skip -->\n<!-- /epub:only -->\n";
+        assert_eq!(tailor(markdown).unwrap(), "A\n\n\n\n\nD\n\n\n");
+    }
+
+    #[test]
+    fn comments_in_code_and_stray_ones_are_left_alone() {
+        assert_eq!(tailor("`<!-- epub:skip -->`\n\n```\n<!-- epub:skip -->\n```\n"), None);
+        assert_eq!(tailor("A\n<!-- /epub:skip -->\nB\n").unwrap(), "A\n<!-- /epub:skip -->\nB\n");
+        assert_eq!(tailor("A\n<!-- epub:skip -->\nB\n").unwrap(), "A\n\n");
+    }
+}
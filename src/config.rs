@@ -1,8 +1,13 @@
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt::{self, Display, Formatter};
+use std::path::{Path, PathBuf};
 use super::Error;
 use mdbook::renderer::RenderContext;
+use pulldown_cmark::Options;
 
 pub const DEFAULT_TEMPLATE: &str = include_str!("index.hbs");
+pub const DEFAULT_TITLE_PAGE_TEMPLATE: &str = include_str!("title_page.hbs");
 
 /// The configuration struct used to tweak how an EPUB document is generated.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -12,6 +17,10 @@ pub struct Config {
     pub additional_css: Vec<PathBuf>,
     /// Should we use the default stylesheet (default: true)?
     pub use_default_css: bool,
+    /// CSS declarations keyed by selector and then property, added after
+    /// every other stylesheet so they override them, e.g. `code` ↦
+    /// `font-family` ↦ `monospace`.
+    pub css_overrides: BTreeMap<String, BTreeMap<String, String>>,
     /// The template file to use when rendering individual chapters (relative
     /// to the book root).
     pub index_template: Option<PathBuf>,
@@ -19,14 +28,238 @@ pub struct Config {
     pub cover_image: Option<PathBuf>,
     /// Additional assets to include in the ebook, such as typefaces.
     pub additional_resources: Vec<PathBuf>,
+    /// Package each chapter's markdown source next to it, for readers to
+    /// extract.
+    pub embed_source: bool,
     /// Don't render section labels.
     pub no_section_label: bool,
+    /// A handlebars template for numbered chapters' titles in the
+    /// navigation, with `{{number}}` and `{{name}}`.
+    pub chapter_title_template: Option<String>,
     /// Use "smart quotes" instead of the usual `"` character.
     pub curly_quotes: bool,
+    /// Turn `--` into an en dash, `---` into an em dash and `...` into an
+    /// ellipsis. Unlike the parser's `SmartPunctuation` markdown option,
+    /// quotes are left to `curly_quotes`.
+    pub smart_punctuation: bool,
     /// Remove HTML comments to avoid warnings about comments containing double hyphens.
     pub remove_html_comments: bool,
     /// Enable Rust codeblock filter
     pub enable_rust_codeblock_filter: bool,
+    /// The book's publisher.
+    pub publisher: Option<String>,
+    /// A unique identifier for the book (e.g. `urn:isbn:...`), used instead of
+    /// a randomly generated UUID.
+    pub identifier: Option<String>,
+    /// What kind of identifier `identifier` is. Without one, `identifier` is
+    /// used exactly as written.
+    pub identifier_scheme: Option<IdentifierScheme>,
+    /// The publication date, either as an RFC 3339 timestamp or a plain
+    /// `YYYY-MM-DD` date.
+    pub pub_date: Option<String>,
+    /// Keywords describing the book, each emitted as a `dc:subject`.
+    pub subjects: Vec<String>,
+    /// The version of the EPUB specification to target (default: "2.0").
+    pub version: EpubVersion,
+    /// Add a contents page linking to every chapter at the front of the book
+    /// (default: true).
+    pub generate_toc: bool,
+    /// Syntax highlight fenced code blocks when generating the book.
+    pub highlight: bool,
+    /// Force a page break before every top-level chapter.
+    pub page_break_on_chapter: bool,
+    /// Force a page break before every sub-chapter.
+    pub page_break_on_section: bool,
+    /// Font files to embed in the book, with a matching `@font-face` rule
+    /// added to the stylesheet for each.
+    pub embed_fonts: Vec<PathBuf>,
+    /// Leave draft chapters (those without a source file) out of the book
+    /// instead of adding a placeholder page for them (default: true).
+    pub skip_drafts: bool,
+    /// Download images referenced by `http(s)://` URLs and package them in
+    /// the book (default: false, for reproducible builds).
+    pub download_remote_images: bool,
+    /// How many seconds to wait for a remote image before giving up.
+    pub download_timeout: u64,
+    /// Render `$...$` and `$$...$$` math as MathML (EPUB 3 only).
+    pub math: bool,
+    /// Add a title page showing the book's title, authors and description
+    /// (default: true).
+    pub title_page: bool,
+    /// The template file to use when rendering the title page (relative to
+    /// the book root).
+    pub title_page_template: Option<PathBuf>,
+    /// How many threads to render chapters with (default: 0, one per CPU).
+    pub render_threads: usize,
+    /// Check the generated book for references to missing files and for
+    /// files nothing refers to.
+    pub validate: bool,
+    /// The name of the generated file, relative to the output directory
+    /// (default: the book's title with an `.epub` extension).
+    pub output_filename: Option<String>,
+    /// How hard to compress the files in the archive (default: "deflate").
+    pub compression: Compression,
+    /// People other than the book's authors who worked on it, such as
+    /// editors and translators.
+    pub contributors: Vec<Contributor>,
+    /// Render GitHub-style alerts (`> [!NOTE]`) as styled admonitions.
+    pub admonitions: bool,
+    /// Fail instead of warning when a chapter links to a chapter which isn't
+    /// part of the book.
+    pub fail_on_broken_links: bool,
+    /// A markdown file to render as the book's copyright page.
+    pub copyright_page: Option<PathBuf>,
+    /// Where the copyright page goes (default: "front", right after the
+    /// title page).
+    pub copyright_position: PagePosition,
+    /// List the copyright page in the navigation (default: true).
+    pub copyright_in_toc: bool,
+    /// Where footnote definitions are placed (default: "inline").
+    pub footnotes: FootnotePlacement,
+    /// The series the book belongs to, shown by Calibre and other readers.
+    pub series: Option<String>,
+    /// The book's position in its series (default: 1 when `series` is set).
+    #[serde(deserialize_with = "deserialize_series_index")]
+    pub series_index: Option<f64>,
+    /// Add a cover page showing the cover image (default: true). SVG covers
+    /// always get one, because readers can't show them by themselves.
+    pub cover_page: bool,
+    /// Text to show over the cover image on the cover page.
+    pub cover_title: Option<String>,
+    /// The cover image's width in pixels. With `cover_height`, the cover page
+    /// becomes a fixed-layout page of that size.
+    pub cover_width: Option<u32>,
+    /// The cover image's height in pixels.
+    pub cover_height: Option<u32>,
+    /// Make an SVG cover showing the title and authors when the book has no
+    /// cover image.
+    pub generate_text_cover: bool,
+    /// The background colour of the generated text cover.
+    pub text_cover_background: String,
+    /// The colour of the generated text cover's text.
+    pub text_cover_foreground: String,
+    /// A directory to cache rendered chapters in (relative to the book
+    /// root), so unchanged chapters aren't rendered again on the next build.
+    pub cache_dir: Option<PathBuf>,
+    /// How task list items (`- [x] done`) are marked (default: "checkbox").
+    pub task_list_style: TaskListStyle,
+    /// The colour scheme of the default stylesheet (default: "default").
+    pub css_theme: CssTheme,
+    /// Render ```` ```mermaid ```` code blocks as SVG diagrams with the
+    /// mermaid CLI.
+    pub mermaid: bool,
+    /// Only package chapters whose source path matches one of these glob
+    /// patterns (default: every chapter).
+    pub include_chapters: Vec<String>,
+    /// Leave out chapters whose source path matches one of these glob
+    /// patterns, along with their sub-chapters.
+    pub exclude_chapters: Vec<String>,
+    /// Put the whole book in one XHTML file, with each chapter in its own
+    /// section.
+    pub single_file: bool,
+    /// Files used by the templates, such as a logo, relative to the
+    /// `index_template`'s directory (or the book root without one). Each is
+    /// given to templates as the path to it, named after the file.
+    pub template_assets: Vec<PathBuf>,
+    /// Convert WebP and AVIF images to PNG for EPUB 2, whose readers can't
+    /// be expected to show them.
+    pub transcode_unsupported_images: bool,
+    /// Extra package metadata, such as `ibooks:specified-fonts`, keyed by
+    /// the property name. `title`, `author`, `lang` and `description`
+    /// replace the book's own.
+    #[serde(deserialize_with = "deserialize_metadata")]
+    pub metadata: BTreeMap<String, String>,
+    /// Remove scripts, event handlers and `javascript:` URLs from the raw
+    /// HTML in chapters.
+    pub sanitize_html: bool,
+    /// Give each of `SUMMARY.md`'s part titles a page of its own, rather
+    /// than just a heading in the navigation.
+    pub emit_part_pages: bool,
+    /// Turn images with alt text which are alone in their paragraph into
+    /// figures numbered across the whole book, captioned with the alt text.
+    pub number_figures: bool,
+    /// Log every image packaged (and every one which isn't) at the info
+    /// level, rather than only when debugging.
+    pub log_assets: bool,
+    /// Which way the book's text reads.
+    pub direction: Direction,
+    /// Package images with the same contents once, pointing every chapter
+    /// at the first copy.
+    pub dedup_assets: bool,
+    /// What to do about images, audio and video the chapters use which
+    /// don't exist.
+    pub missing_asset_policy: MissingAssetPolicy,
+    /// Add an index page at the end of the book listing the terms marked
+    /// with `<!-- index: term -->`.
+    pub generate_index: bool,
+    /// The markdown extensions chapters are parsed with.
+    pub markdown_options: Vec<MarkdownOption>,
+    /// Accessibility metadata for stores which require it. None is emitted
+    /// without an `[output.epub.accessibility]` table.
+    pub accessibility: Option<Accessibility>,
+    /// Extra stylesheets for particular chapters, keyed by the chapter's
+    /// source path. A chapter's `stylesheet` front matter takes precedence.
+    pub chapter_stylesheets: BTreeMap<String, PathBuf>,
+    /// The source paths of chapters to keep out of the navigation and the
+    /// contents page, along with their sub-chapters. They're still in the
+    /// book.
+    pub toc_hidden: Vec<String>,
+    /// Paths of chapters to read first, in this order, ahead of the rest of
+    /// the book. The navigation keeps the book's order.
+    pub spine_order: Vec<String>,
+    /// Render the book's `description` as a page at the front of the book,
+    /// as well as putting it in the metadata.
+    pub description_as_page: bool,
+    /// The title of the `description_as_page` page (default: "About this
+    /// book").
+    pub description_page_title: String,
+    /// Wrap each table in a `<div class="table-wrapper">` which scrolls
+    /// sideways, so wide tables fit narrow screens.
+    pub responsive_tables: bool,
+    /// Turn `[[Page]]` and `[[Page|Label]]` into links to the chapter with
+    /// that title or file name.
+    pub wikilinks: bool,
+    /// How many levels to move every chapter's headings down, or up when
+    /// it's negative, so `1` makes `#` an `<h2>`. Chapters can set their own
+    /// with a `heading_offset` key in their front matter.
+    pub heading_offset: i32,
+    /// Find the chapters and assets and check the links, then print what
+    /// would go into the book instead of writing it.
+    pub dry_run: bool,
+    /// Log a summary of the book's size and what takes it up once it's
+    /// generated.
+    pub report: bool,
+    /// Write the summary to this file as well (relative to the book root).
+    pub report_path: Option<PathBuf>,
+    /// Hand-written XHTML pages added to the book as they are.
+    pub raw_pages: Vec<RawPage>,
+    /// Collapse the whitespace in chapters and strip the comments from the
+    /// stylesheet, to make the book smaller.
+    pub minify: bool,
+    /// Fail instead of warning when an image in a chapter has no alt text.
+    pub fail_on_missing_alt: bool,
+    /// The alt text for images which don't have any.
+    pub default_alt: Option<String>,
+    /// The book's copyright and license statement.
+    pub rights: Option<String>,
+    /// Where the book's license can be read, for EPUB 3.
+    pub license_url: Option<String>,
+    /// How many levels of parts and chapters go in the navigation and the
+    /// contents page. Deeper chapters are still in the book.
+    pub toc_depth: Option<u32>,
+    /// Move every chapter and asset to the top of the book, for readers
+    /// which get lost in subdirectories.
+    pub flatten_paths: bool,
+    /// Which directories the chapters, images and stylesheets are packaged
+    /// in.
+    pub directory_layout: DirectoryLayout,
+    /// Splice in the files named by `{{#include ...}}` directives which
+    /// `mdbook` hasn't already.
+    pub resolve_includes: bool,
+    /// Name `mdbook-epub` and its version as the book's generator.
+    pub emit_generator_metadata: bool,
+    /// Write each of the book's parts out as a book of its own.
+    pub split_by_part: bool,
 }
 
 impl Config {
@@ -37,10 +270,18 @@ impl Config {
             Some(table) => {
                 let mut cfg: Config = table.clone().try_into()?;
 
-                // make sure we update the `index_template` to make it relative
-                // to the book root
-                if let Some(template_file) = cfg.index_template.take() {
-                    cfg.index_template = Some(ctx.root.join(template_file));
+                cfg.resolve_paths(&ctx.root);
+
+                if let Some(ref date) = cfg.pub_date {
+                    validate_pub_date(date)?;
+                }
+                cfg.book_identifier()?;
+                cfg.cover_dimensions()?;
+                if cfg.smart_punctuation && cfg.markdown_options.contains(&MarkdownOption::SmartPunctuation) {
+                    warn!(
+                        "`smart-punctuation` has nothing left to do, as `markdown-options` includes \"smart-punctuation\", \
+                         which converts dashes and ellipses, and quotes as well"
+                    );
                 }
 
                 Ok(cfg)
@@ -49,6 +290,60 @@ impl Config {
         }
     }
 
+    /// Make sure the templates, cache directory and report are relative to
+    /// the book root, canonicalized when they exist so it's clear which files are used.
+    pub(crate) fn resolve_paths(&mut self, root: &Path) {
+        let resolve = |path: PathBuf| {
+            let path = root.join(path);
+            path.canonicalize().unwrap_or(path)
+        };
+
+        self.index_template = self.index_template.take().map(resolve);
+        self.title_page_template = self.title_page_template.take().map(resolve);
+        self.cache_dir = self.cache_dir.take().map(resolve);
+        self.report_path = self.report_path.take().map(resolve);
+    }
+
+    /// The width and height of the fixed-layout cover page, if it is one.
+    pub(crate) fn cover_dimensions(&self) -> Result<Option<(u32, u32)>, Error> {
+        match (self.cover_width, self.cover_height) {
+            (None, None) => Ok(None),
+            (Some(width), Some(height)) if width > 0 && height > 0 => Ok(Some((width, height))),
+            _ => Err(Error::InvalidCoverDimensions),
+        }
+    }
+
+    /// The book's `dc:identifier`, written out the way its
+    /// `identifier_scheme` expects, or `None` to keep the random UUID.
+    pub(crate) fn book_identifier(&self) -> Result<Option<String>, Error> {
+        let scheme = match self.identifier_scheme {
+            Some(scheme) => scheme,
+            None => return Ok(self.identifier.clone()),
+        };
+        let identifier = match self.identifier {
+            Some(ref identifier) => identifier.trim(),
+            None if scheme == IdentifierScheme::Uuid => return Ok(None),
+            None => return Err(Error::MissingIdentifier(scheme.to_string())),
+        };
+
+        let identifier = match scheme {
+            IdentifierScheme::Uuid => with_prefix("urn:uuid:", identifier),
+            IdentifierScheme::Url => identifier.to_string(),
+            IdentifierScheme::Isbn => {
+                let isbn = normalize_isbn(strip_prefix_ignore_case(identifier, "urn:isbn:"))
+                    .ok_or_else(|| Error::InvalidIsbn(identifier.to_string()))?;
+                format!("urn:isbn:{}", isbn)
+            }
+            IdentifierScheme::Doi => with_prefix("urn:doi:", identifier),
+        };
+        Ok(Some(identifier))
+    }
+
+    /// The `markdown_options` as parser options.
+    pub(crate) fn cmark_options(&self) -> Options {
+        MarkdownOption::options(&self.markdown_options)
+    }
+
     pub fn template(&self) -> Result<String, Error> {
         match self.index_template {
             Some(ref filename) => {
@@ -60,6 +355,121 @@ impl Config {
             None => Ok(DEFAULT_TEMPLATE.to_string()),
         }
     }
+
+    pub fn title_page_template(&self) -> Result<String, Error> {
+        match self.title_page_template {
+            Some(ref filename) => {
+                let buffer = std::fs::read_to_string(filename)
+                    .map_err(|_| Error::OpenTemplate(filename.clone()))?;
+
+                Ok(buffer)
+            }
+            None => Ok(DEFAULT_TITLE_PAGE_TEMPLATE.to_string()),
+        }
+    }
+}
+
+/// Make sure a publication date is something e-readers will understand.
+fn validate_pub_date(date: &str) -> Result<(), Error> {
+    let valid = chrono::DateTime::parse_from_rfc3339(date).is_ok()
+        || chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d").is_ok();
+
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::InvalidPubDate(date.to_string()))
+    }
+}
+
+fn strip_prefix_ignore_case<'t>(text: &'t str, prefix: &str) -> &'t str {
+    match text.get(..prefix.len()) {
+        Some(start) if start.eq_ignore_ascii_case(prefix) => &text[prefix.len()..],
+        _ => text,
+    }
+}
+
+fn with_prefix(prefix: &str, text: &str) -> String {
+    format!("{}{}", prefix, strip_prefix_ignore_case(text, prefix))
+}
+
+/// The digits of an ISBN-10 or ISBN-13, without hyphens or spaces, if its
+/// check digit is right.
+pub(crate) fn normalize_isbn(isbn: &str) -> Option<String> {
+    let isbn: String = isbn
+        .chars()
+        .filter(|c| *c != '-' && *c != ' ')
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    let digit = |c: char| c.to_digit(10);
+
+    let valid = match isbn.len() {
+        10 => {
+            // only the check digit can be an X, for 10
+            let (body, check) = isbn.split_at(9);
+            let check = match check {
+                "X" => Some(10),
+                check => check.chars().next().and_then(digit),
+            };
+            let body: Option<Vec<u32>> = body.chars().map(digit).collect();
+            match (body, check) {
+                (Some(body), Some(check)) => {
+                    let sum: u32 = body.iter().enumerate().map(|(i, d)| (10 - i as u32) * d).sum();
+                    (sum + check).is_multiple_of(11)
+                }
+                _ => false,
+            }
+        }
+        13 => match isbn.chars().map(digit).collect::<Option<Vec<u32>>>() {
+            Some(digits) => {
+                let sum: u32 = digits.iter().enumerate().map(|(i, d)| if i % 2 == 0 { *d } else { 3 * d }).sum();
+                sum.is_multiple_of(10)
+            }
+            None => false,
+        },
+        _ => false,
+    };
+
+    valid.then_some(isbn)
+}
+
+/// Accept `series-index` as either a number or a numeric string.
+fn deserialize_series_index<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let index = match <TextOrNumber as serde::Deserialize>::deserialize(deserializer)? {
+        TextOrNumber::Number(number) => number,
+        TextOrNumber::Text(text) => text.trim().parse().map_err(|_| {
+            serde::de::Error::custom(format!("invalid series index \"{}\", expected a number", text))
+        })?,
+    };
+
+    if index.is_finite() {
+        Ok(Some(index))
+    } else {
+        Err(serde::de::Error::custom(format!("invalid series index {}, expected a finite number", index)))
+    }
+}
+
+/// Metadata values are written out as text, whatever type they're given as
+/// in `book.toml`.
+fn deserialize_metadata<'de, D>(deserializer: D) -> Result<BTreeMap<String, String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let table = <BTreeMap<String, toml::Value> as serde::Deserialize>::deserialize(deserializer)?;
+
+    table
+        .into_iter()
+        .map(|(key, value)| match value {
+            toml::Value::String(text) => Ok((key, text)),
+            toml::Value::Array(_) | toml::Value::Table(_) => Err(serde::de::Error::custom(format!(
+                "invalid metadata value for \"{}\", expected a string, number or boolean",
+                key
+            ))),
+            other => Ok((key, other.to_string())),
+        })
+        .collect()
 }
 
 impl Default for Config {
@@ -70,10 +480,620 @@ impl Default for Config {
             index_template: None,
             cover_image: None,
             additional_resources: Vec::new(),
+            embed_source: false,
             no_section_label: false,
+            chapter_title_template: None,
             curly_quotes: false,
+            smart_punctuation: false,
             remove_html_comments: false,
             enable_rust_codeblock_filter: false,
+            publisher: None,
+            identifier: None,
+            identifier_scheme: None,
+            pub_date: None,
+            subjects: Vec::new(),
+            version: EpubVersion::V2,
+            generate_toc: true,
+            highlight: false,
+            page_break_on_chapter: false,
+            page_break_on_section: false,
+            embed_fonts: Vec::new(),
+            skip_drafts: true,
+            download_remote_images: false,
+            download_timeout: 30,
+            math: false,
+            title_page: true,
+            title_page_template: None,
+            render_threads: 0,
+            validate: false,
+            output_filename: None,
+            compression: Compression::default(),
+            contributors: Vec::new(),
+            admonitions: false,
+            fail_on_broken_links: false,
+            copyright_page: None,
+            copyright_position: PagePosition::default(),
+            copyright_in_toc: true,
+            footnotes: FootnotePlacement::default(),
+            series: None,
+            series_index: None,
+            cover_page: true,
+            cover_title: None,
+            cover_width: None,
+            cover_height: None,
+            generate_text_cover: false,
+            text_cover_background: String::from("#2b303b"),
+            text_cover_foreground: String::from("#ffffff"),
+            cache_dir: None,
+            task_list_style: TaskListStyle::default(),
+            css_theme: CssTheme::default(),
+            mermaid: false,
+            include_chapters: Vec::new(),
+            exclude_chapters: Vec::new(),
+            single_file: false,
+            template_assets: Vec::new(),
+            transcode_unsupported_images: false,
+            metadata: BTreeMap::new(),
+            sanitize_html: true,
+            emit_part_pages: false,
+            number_figures: false,
+            log_assets: false,
+            direction: Direction::default(),
+            dedup_assets: true,
+            missing_asset_policy: MissingAssetPolicy::default(),
+            generate_index: false,
+            markdown_options: MarkdownOption::DEFAULT.to_vec(),
+            accessibility: None,
+            chapter_stylesheets: BTreeMap::new(),
+            css_overrides: BTreeMap::new(),
+            toc_hidden: Vec::new(),
+            spine_order: Vec::new(),
+            responsive_tables: false,
+            wikilinks: false,
+            heading_offset: 0,
+            dry_run: false,
+            description_as_page: false,
+            description_page_title: String::from("About this book"),
+            report: false,
+            report_path: None,
+            raw_pages: Vec::new(),
+            minify: false,
+            fail_on_missing_alt: false,
+            default_alt: None,
+            rights: None,
+            license_url: None,
+            toc_depth: None,
+            flatten_paths: false,
+            directory_layout: DirectoryLayout::default(),
+            resolve_includes: false,
+            emit_generator_metadata: true,
+            split_by_part: false,
+        }
+    }
+}
+
+/// Someone who contributed to the book.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Contributor {
+    pub name: String,
+    /// Their [MARC relator code][marc], e.g. `edt` for an editor or `trl` for
+    /// a translator (default: "aut").
+    ///
+    /// [marc]: https://www.loc.gov/marc/relators/relaterm.html
+    #[serde(default = "default_role")]
+    pub role: String,
+}
+
+fn default_role() -> String {
+    String::from("aut")
+}
+
+/// How a book can be read, as [schema.org accessibility properties][a11y].
+/// Anything left empty is worked out from the book.
+///
+/// [a11y]: https://www.w3.org/2021/a11y-discov-vocab/latest/
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Accessibility {
+    /// `schema:accessMode`s, e.g. `textual` or `visual` (default: `textual`,
+    /// and `visual` when the book has images).
+    pub access_modes: Vec<String>,
+    /// `schema:accessModeSufficient`s, each a comma-separated list of modes
+    /// which are enough to read the whole book (default: `textual` for
+    /// text-only books, otherwise `textual,visual`).
+    pub access_mode_sufficient: Vec<String>,
+    /// `schema:accessibilityFeature`s (default: `readingOrder` and
+    /// `tableOfContents`).
+    pub features: Vec<String>,
+    /// A `schema:accessibilitySummary` in plain words.
+    pub summary: Option<String>,
+}
+
+/// An XHTML page which is added to the book without any processing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RawPage {
+    /// The page's file, which is packaged at this path.
+    pub path: PathBuf,
+    /// Whether it goes before or after the chapters (default: "front").
+    #[serde(default)]
+    pub position: PagePosition,
+    /// The page's entry in the navigation. Pages without a title are left
+    /// out of it.
+    #[serde(default)]
+    pub title: Option<String>,
+}
+
+/// Where an extra page is placed in the book.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PagePosition {
+    /// With the front matter, before the first chapter.
+    #[default]
+    Front,
+    /// After the last chapter.
+    Back,
+}
+
+/// Where a chapter's footnotes are placed.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FootnotePlacement {
+    /// Wherever they were written in the chapter.
+    #[default]
+    Inline,
+    /// Together, as a numbered list at the end of the chapter.
+    ChapterEnd,
+}
+
+/// How the items of a task list are marked.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TaskListStyle {
+    /// A disabled `<input type="checkbox">`.
+    #[default]
+    Checkbox,
+    /// A ☐ or ☑ character.
+    Unicode,
+}
+
+/// The colour schemes the default stylesheet comes in.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CssTheme {
+    /// Dark text on a white page.
+    #[default]
+    Default,
+    /// Light text on a black page, for OLED screens.
+    Dark,
+    /// Brown text on a cream page, which darkens in a reader's night mode.
+    Sepia,
+}
+
+/// What happens when a chapter uses an image, audio or video file which
+/// doesn't exist.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MissingAssetPolicy {
+    /// Stop the build.
+    #[default]
+    Fail,
+    /// Warn about the file and leave it out.
+    Warn,
+    /// Warn, and point images at a blank placeholder instead.
+    Placeholder,
+}
+
+/// How the files in the book are arranged into directories.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DirectoryLayout {
+    /// Every file where its source is in the book, e.g. `guide/setup.html`.
+    #[default]
+    Flat,
+    /// Chapters under `text/`, images under `images/`, stylesheets under
+    /// `styles/` and fonts under `fonts/`, as some tools expect.
+    Conventional,
+}
+
+/// A markdown extension, on top of CommonMark.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MarkdownOption {
+    Tables,
+    Footnotes,
+    Strikethrough,
+    TaskLists,
+    /// Curly quotes, dashes from `--` and `---`, and ellipses from `...`.
+    SmartPunctuation,
+    /// Ids and classes for headings, e.g. `# Title {#id .class}`.
+    HeadingAttributes,
+}
+
+impl MarkdownOption {
+    /// The extensions chapters are parsed with unless `markdown_options`
+    /// says otherwise.
+    pub const DEFAULT: &'static [MarkdownOption] = &[
+        MarkdownOption::Tables,
+        MarkdownOption::Footnotes,
+        MarkdownOption::Strikethrough,
+        MarkdownOption::TaskLists,
+    ];
+
+    pub(crate) fn options(enabled: &[MarkdownOption]) -> Options {
+        enabled.iter().fold(Options::empty(), |options, option| {
+            options
+                | match option {
+                    MarkdownOption::Tables => Options::ENABLE_TABLES,
+                    MarkdownOption::Footnotes => Options::ENABLE_FOOTNOTES,
+                    MarkdownOption::Strikethrough => Options::ENABLE_STRIKETHROUGH,
+                    MarkdownOption::TaskLists => Options::ENABLE_TASKLISTS,
+                    MarkdownOption::SmartPunctuation => Options::ENABLE_SMART_PUNCTUATION,
+                    MarkdownOption::HeadingAttributes => Options::ENABLE_HEADING_ATTRIBUTES,
+                }
+        })
+    }
+}
+
+/// What kind of identifier a book's `identifier` is.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IdentifierScheme {
+    /// A UUID, which is generated when no `identifier` is given.
+    Uuid,
+    /// The book's canonical URL.
+    Url,
+    /// An ISBN-10 or ISBN-13.
+    Isbn,
+    /// A Digital Object Identifier, e.g. `10.1000/182`.
+    Doi,
+}
+
+impl IdentifierScheme {
+    /// The EPUB 2 `opf:scheme` of an identifier.
+    pub(crate) fn opf_scheme(self) -> &'static str {
+        match self {
+            IdentifierScheme::Uuid => "UUID",
+            IdentifierScheme::Url => "URL",
+            IdentifierScheme::Isbn => "ISBN",
+            IdentifierScheme::Doi => "DOI",
+        }
+    }
+}
+
+impl Display for IdentifierScheme {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(match self {
+            IdentifierScheme::Uuid => "uuid",
+            IdentifierScheme::Url => "url",
+            IdentifierScheme::Isbn => "isbn",
+            IdentifierScheme::Doi => "doi",
+        })
+    }
+}
+
+/// Which way a book's text reads.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Direction {
+    /// Left to right.
+    Ltr,
+    /// Right to left, e.g. for Arabic or Hebrew.
+    Rtl,
+    /// Right to left for languages written that way, otherwise left to
+    /// right.
+    #[default]
+    Auto,
+}
+
+/// The language codes of scripts written right to left.
+const RTL_LANGUAGES: &[&str] = &["ar", "he", "iw", "fa", "ur", "yi", "ps", "sd", "ug", "dv", "ckb"];
+
+impl Direction {
+    /// Work out which way a book in `lang` (e.g. `ar` or `he-IL`) reads,
+    /// which is never `Auto`.
+    pub fn resolve(self, lang: &str) -> Direction {
+        match self {
+            Direction::Auto => {
+                let primary = lang.split(['-', '_']).next().unwrap_or("").to_ascii_lowercase();
+                if RTL_LANGUAGES.contains(&primary.as_str()) {
+                    Direction::Rtl
+                } else {
+                    Direction::Ltr
+                }
+            }
+            direction => direction,
+        }
+    }
+
+    /// The value of a `dir` attribute for this direction.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Direction::Ltr => "ltr",
+            Direction::Rtl => "rtl",
+            Direction::Auto => "auto",
+        }
+    }
+}
+
+/// How the files inside the EPUB archive are compressed.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Compression {
+    /// Don't compress anything, which suits books that are mostly images
+    /// in already-compressed formats like JPEG.
+    Stored,
+    /// Deflate, favouring speed over size.
+    DeflateFast,
+    /// Deflate with the usual balance of speed and size.
+    #[default]
+    Deflate,
+    /// Deflate, favouring size over speed.
+    DeflateBest,
+}
+
+/// The versions of the EPUB specification we know how to generate.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "TextOrNumber", into = "String")]
+pub enum EpubVersion {
+    /// EPUB 2.0.1, for older e-readers.
+    V2,
+    /// EPUB 3.0.1.
+    V3,
+}
+
+impl EpubVersion {
+    const ACCEPTED: &'static str = r#""2.0" or "3.0""#;
+}
+
+impl Display for EpubVersion {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            EpubVersion::V2 => write!(f, "2.0"),
+            EpubVersion::V3 => write!(f, "3.0"),
+        }
+    }
+}
+
+impl From<EpubVersion> for String {
+    fn from(version: EpubVersion) -> String {
+        version.to_string()
+    }
+}
+
+impl From<EpubVersion> for epub_builder::EpubVersion {
+    fn from(version: EpubVersion) -> epub_builder::EpubVersion {
+        match version {
+            EpubVersion::V2 => epub_builder::EpubVersion::V20,
+            EpubVersion::V3 => epub_builder::EpubVersion::V30,
+        }
+    }
+}
+
+/// A raw value which people may write either as a string or as a bare TOML
+/// number, such as `version` or `series-index`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TextOrNumber {
+    Text(String),
+    Number(f64),
+}
+
+impl TryFrom<TextOrNumber> for EpubVersion {
+    type Error = String;
+
+    fn try_from(value: TextOrNumber) -> Result<EpubVersion, String> {
+        let text = match value {
+            TextOrNumber::Text(text) => text,
+            TextOrNumber::Number(number) => format!("{:.1}", number),
+        };
+
+        match text.trim() {
+            "2" | "2.0" => Ok(EpubVersion::V2),
+            "3" | "3.0" => Ok(EpubVersion::V3),
+            other => Err(format!(
+                "unsupported EPUB version \"{}\", expected {}",
+                other,
+                EpubVersion::ACCEPTED
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mdbook::book::Book;
+    use mdbook::config::Config as MdConfig;
+
+    fn render_context(config: MdConfig) -> RenderContext {
+        RenderContext::new("/book", Book::new(), config, "/book/out")
+    }
+
+    #[test]
+    fn paths_are_resolved_against_the_root() {
+        let root = std::env::current_dir().unwrap().join("tests").join("dummy");
+        let mut cfg = Config {
+            index_template: Some(PathBuf::from("./src/../book.toml")),
+            cache_dir: Some(PathBuf::from("cache")),
+            ..Config::default()
+        };
+
+        cfg.resolve_paths(&root);
+
+        assert_eq!(cfg.index_template, Some(root.join("book.toml").canonicalize().unwrap()));
+        assert_eq!(cfg.title_page_template, None);
+        // the cache directory doesn't exist until the book is first built
+        assert_eq!(cfg.cache_dir, Some(root.join("cache")));
+    }
+
+    #[test]
+    fn pub_date_accepts_dates_and_timestamps() {
+        for date in &["2023-02-08", "2023-02-08T10:20:30Z", "2023-02-08T10:20:30+09:00"] {
+            let mut md = MdConfig::default();
+            md.set("output.epub.pub-date", *date).unwrap();
+
+            let cfg = Config::from_render_context(&render_context(md)).unwrap();
+
+            assert_eq!(cfg.pub_date.as_deref(), Some(*date));
         }
     }
+
+    #[test]
+    fn isbn_check_digits_are_validated() {
+        assert_eq!(normalize_isbn("978-0-306-40615-7").as_deref(), Some("9780306406157"));
+        assert_eq!(normalize_isbn("0 306 40615 2").as_deref(), Some("0306406152"));
+        assert_eq!(normalize_isbn("080442957x").as_deref(), Some("080442957X"));
+        assert_eq!(normalize_isbn("978-0-306-40615-8"), None);
+        assert_eq!(normalize_isbn("0-306-40615-3"), None);
+        assert_eq!(normalize_isbn("X306406152"), None);
+        assert_eq!(normalize_isbn("97803064061"), None);
+    }
+
+    #[test]
+    fn identifiers_are_written_for_their_scheme() {
+        let identifier = |scheme: &str, identifier: Option<&str>| {
+            let mut md = MdConfig::default();
+            md.set("output.epub.identifier-scheme", scheme).unwrap();
+            if let Some(identifier) = identifier {
+                md.set("output.epub.identifier", identifier).unwrap();
+            }
+            Config::from_render_context(&render_context(md)).and_then(|cfg| cfg.book_identifier())
+        };
+
+        assert_eq!(identifier("uuid", None).unwrap(), None);
+        assert_eq!(
+            identifier("uuid", Some("6ba7b810-9dad-11d1-80b4-00c04fd430c8")).unwrap().as_deref(),
+            Some("urn:uuid:6ba7b810-9dad-11d1-80b4-00c04fd430c8")
+        );
+        assert_eq!(
+            identifier("url", Some("https://example.com/book")).unwrap().as_deref(),
+            Some("https://example.com/book")
+        );
+        assert_eq!(
+            identifier("isbn", Some("urn:isbn:978-0-306-40615-7")).unwrap().as_deref(),
+            Some("urn:isbn:9780306406157")
+        );
+        assert_eq!(identifier("doi", Some("10.1000/182")).unwrap().as_deref(), Some("urn:doi:10.1000/182"));
+        assert!(matches!(identifier("isbn", Some("978-0-306-40615-8")), Err(Error::InvalidIsbn(_))));
+        assert!(matches!(identifier("doi", None), Err(Error::MissingIdentifier(_))));
+    }
+
+    #[test]
+    fn markdown_options_are_parsed() {
+        let cfg = Config::from_render_context(&render_context(MdConfig::default())).unwrap();
+        assert_eq!(
+            cfg.cmark_options(),
+            Options::ENABLE_TABLES | Options::ENABLE_FOOTNOTES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS
+        );
+
+        let mut md = MdConfig::default();
+        md.set("output.epub.markdown-options", vec!["tables", "heading-attributes"]).unwrap();
+        let cfg = Config::from_render_context(&render_context(md)).unwrap();
+        assert_eq!(cfg.cmark_options(), Options::ENABLE_TABLES | Options::ENABLE_HEADING_ATTRIBUTES);
+
+        let mut md = MdConfig::default();
+        md.set("output.epub.markdown-options", vec!["tables", "wiki-links"]).unwrap();
+        let err = Config::from_render_context(&render_context(md)).unwrap_err();
+        assert!(err.to_string().contains("wiki-links"), "{}", err);
+    }
+
+    #[test]
+    fn accessibility_table_is_optional() {
+        let cfg = Config::from_render_context(&render_context(MdConfig::default())).unwrap();
+        assert_eq!(cfg.accessibility, None);
+
+        let mut md = MdConfig::default();
+        md.set("output.epub.accessibility.features", vec!["alternativeText"]).unwrap();
+        let cfg = Config::from_render_context(&render_context(md)).unwrap();
+        assert_eq!(
+            cfg.accessibility,
+            Some(Accessibility {
+                features: vec![String::from("alternativeText")],
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn version_accepts_strings_and_numbers() {
+        let mut md = MdConfig::default();
+        md.set("output.epub.version", "3.0").unwrap();
+        let cfg = Config::from_render_context(&render_context(md)).unwrap();
+        assert_eq!(cfg.version, EpubVersion::V3);
+
+        let mut md = MdConfig::default();
+        md.set("output.epub.version", 2.0).unwrap();
+        let cfg = Config::from_render_context(&render_context(md)).unwrap();
+        assert_eq!(cfg.version, EpubVersion::V2);
+    }
+
+    #[test]
+    fn unknown_version_lists_the_accepted_values() {
+        let mut md = MdConfig::default();
+        md.set("output.epub.version", "4.0").unwrap();
+
+        let err = Config::from_render_context(&render_context(md)).unwrap_err();
+
+        assert!(err.to_string().contains(r#""2.0" or "3.0""#), "{}", err);
+    }
+
+    #[test]
+    fn invalid_pub_date_is_an_error() {
+        let mut md = MdConfig::default();
+        md.set("output.epub.pub-date", "08/02/2023").unwrap();
+
+        let err = Config::from_render_context(&render_context(md)).unwrap_err();
+
+        assert!(err.to_string().contains("08/02/2023"), "{}", err);
+    }
+
+    #[test]
+    fn series_index_accepts_numbers_and_numeric_strings() {
+        for (index, expected) in &[("2.5", 2.5), (" 3 ", 3.0)] {
+            let mut md = MdConfig::default();
+            md.set("output.epub.series-index", *index).unwrap();
+            let cfg = Config::from_render_context(&render_context(md)).unwrap();
+            assert_eq!(cfg.series_index, Some(*expected));
+        }
+
+        let mut md = MdConfig::default();
+        md.set("output.epub.series-index", 4).unwrap();
+        let cfg = Config::from_render_context(&render_context(md)).unwrap();
+        assert_eq!(cfg.series_index, Some(4.0));
+    }
+
+    #[test]
+    fn invalid_series_index_is_an_error() {
+        let mut md = MdConfig::default();
+        md.set("output.epub.series-index", "first").unwrap();
+
+        let err = Config::from_render_context(&render_context(md)).unwrap_err();
+
+        assert!(err.to_string().contains("invalid series index \"first\""), "{}", err);
+    }
+
+    #[test]
+    fn metadata_values_become_text() {
+        let mut md = MdConfig::default();
+        md.set("output.epub.metadata.ibooks:specified-fonts", true).unwrap();
+        md.set("output.epub.metadata.ibooks:version", 2).unwrap();
+        md.set("output.epub.metadata.title", "Another Title").unwrap();
+
+        let cfg = Config::from_render_context(&render_context(md)).unwrap();
+
+        assert_eq!(cfg.metadata.get("ibooks:specified-fonts").map(String::as_str), Some("true"));
+        assert_eq!(cfg.metadata.get("ibooks:version").map(String::as_str), Some("2"));
+        assert_eq!(cfg.metadata.get("title").map(String::as_str), Some("Another Title"));
+    }
+
+    #[test]
+    fn automatic_direction_follows_the_language() {
+        assert_eq!(Direction::Auto.resolve("ar"), Direction::Rtl);
+        assert_eq!(Direction::Auto.resolve("he-IL"), Direction::Rtl);
+        assert_eq!(Direction::Auto.resolve("FA_ir"), Direction::Rtl);
+        assert_eq!(Direction::Auto.resolve("en"), Direction::Ltr);
+        assert_eq!(Direction::Auto.resolve(""), Direction::Ltr);
+        assert_eq!(Direction::Rtl.resolve("en"), Direction::Rtl);
+        assert_eq!(Direction::Ltr.resolve("ar"), Direction::Ltr);
+    }
 }
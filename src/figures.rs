@@ -0,0 +1,174 @@
+use crate::package::escape_xml;
+use pulldown_cmark::{CowStr, Event, Options, Parser, Tag};
+
+/// Turns images which are alone in their paragraph and have alt text into
+/// numbered figures, captioned with the alt text.
+///
+/// Whether a paragraph holds nothing but an image is only known once it
+/// ends, so its events are held back until then.
+pub(crate) struct EventFigures<'a> {
+    enabled: bool,
+    /// The element figures are wrapped in, and the one for their caption.
+    elements: (&'static str, &'static str),
+    /// The number the next figure gets.
+    next_number: usize,
+    /// Events of a paragraph which might still turn out to be a figure.
+    pending: Option<Vec<Event<'a>>>,
+}
+
+impl<'a> EventFigures<'a> {
+    /// `elements` are what a figure and its caption are written as, since
+    /// `figure` and `figcaption` are only valid in EPUB 3.
+    pub(crate) fn new(enabled: bool, elements: (&'static str, &'static str), first_number: usize) -> Self {
+        EventFigures {
+            enabled,
+            elements,
+            next_number: first_number,
+            pending: None,
+        }
+    }
+
+    pub(crate) fn convert(&mut self, event: Event<'a>) -> Vec<Event<'a>> {
+        if !self.enabled {
+            return vec![event];
+        }
+
+        let mut pending = match self.pending.take() {
+            Some(pending) => pending,
+            None => {
+                if let Event::Start(Tag::Paragraph) = event {
+                    self.pending = Some(vec![event]);
+                    return Vec::new();
+                }
+                return vec![event];
+            }
+        };
+
+        let still_possible = match event {
+            Event::End(Tag::Paragraph) => {
+                if let Some(figure) = self.figure(&pending) {
+                    return vec![Event::Html(CowStr::from(figure))];
+                }
+                pending.push(event);
+                return pending;
+            }
+            // the paragraph has to start with the image...
+            _ if pending.len() == 1 => matches!(event, Event::Start(Tag::Image(..))),
+            // ...and end with it
+            _ => !matches!(pending.last(), Some(Event::End(Tag::Image(..)))),
+        };
+
+        pending.push(event);
+        if still_possible {
+            self.pending = Some(pending);
+            Vec::new()
+        } else {
+            pending
+        }
+    }
+
+    /// The figure for a paragraph's events, if they're just an image with
+    /// alt text.
+    fn figure(&mut self, paragraph: &[Event]) -> Option<String> {
+        let (dest, title) = match paragraph.get(1) {
+            Some(Event::Start(Tag::Image(_, dest, title))) => (dest, title),
+            _ => return None,
+        };
+        if !matches!(paragraph.last(), Some(Event::End(Tag::Image(..)))) {
+            return None;
+        }
+
+        let alt: String = paragraph[2..paragraph.len() - 1]
+            .iter()
+            .filter_map(|event| match *event {
+                Event::Text(ref text) | Event::Code(ref text) => Some(text.as_ref()),
+                _ => None,
+            })
+            .collect();
+        if alt.trim().is_empty() {
+            return None;
+        }
+
+        let number = self.next_number;
+        self.next_number += 1;
+
+        let title = if title.is_empty() {
+            String::new()
+        } else {
+            format!(" title=\"{}\"", escape_xml(title))
+        };
+        let (figure, caption) = self.elements;
+        Some(format!(
+            "<{0} class=\"figure\" id=\"figure-{2}\">\n<img src=\"{3}\" alt=\"{4}\"{5}/>\n\
+             <{1} class=\"figcaption\">Figure {2}. {4}</{1}>\n</{0}>\n",
+            figure,
+            caption,
+            number,
+            escape_xml(dest),
+            escape_xml(alt.trim()),
+            title
+        ))
+    }
+}
+
+/// How many figures some markdown has, when parsed with `options`.
+pub(crate) fn count(markdown: &str, options: Options) -> usize {
+    let mut figures = EventFigures::new(true, ("figure", "figcaption"), 0);
+    for event in Parser::new_ext(markdown, options) {
+        figures.convert(event);
+    }
+
+    figures.next_number
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pulldown_cmark::{html, Parser};
+
+    fn render(markdown: &str, first_number: usize) -> String {
+        let mut figures = EventFigures::new(true, ("figure", "figcaption"), first_number);
+        let events = Parser::new(markdown).flat_map(|event| figures.convert(event));
+        let mut rendered = String::new();
+        html::push_html(&mut rendered, events);
+        rendered
+    }
+
+    #[test]
+    fn lone_images_become_numbered_figures() {
+        let rendered = render("![A crab](crab.png \"Ferris\")\n\n![The `Box` type](box.svg)\n", 3);
+
+        assert_eq!(
+            rendered,
+            "<figure class=\"figure\" id=\"figure-3\">\n<img src=\"crab.png\" alt=\"A crab\" title=\"Ferris\"/>\n\
+             <figcaption class=\"figcaption\">Figure 3. A crab</figcaption>\n</figure>\n\
+             <figure class=\"figure\" id=\"figure-4\">\n<img src=\"box.svg\" alt=\"The Box type\"/>\n\
+             <figcaption class=\"figcaption\">Figure 4. The Box type</figcaption>\n</figure>\n"
+        );
+    }
+
+    #[test]
+    fn other_images_are_left_alone() {
+        let markdown = "Inline ![crab](crab.png) image\n\n![crab](crab.png) then text\n\n![](no-alt.png)\n\nplain\n";
+
+        let rendered = render(markdown, 1);
+
+        let mut expected = String::new();
+        html::push_html(&mut expected, Parser::new(markdown));
+        assert_eq!(rendered, expected);
+        assert_eq!(count(markdown, Options::empty()), 0);
+    }
+
+    #[test]
+    fn figures_are_counted() {
+        assert_eq!(count("# Title\n\n![One](1.png)\n\ntext\n\n![Two](2.png)\n", Options::empty()), 2);
+    }
+
+    #[test]
+    fn figures_are_counted_with_the_chapters_options() {
+        // a footnote holding an image is only a footnote with footnotes on
+        let markdown = "[^1]: ![One](1.png)\n";
+        assert_eq!(count(markdown, Options::ENABLE_FOOTNOTES), 1);
+        assert_eq!(count(markdown, Options::empty()), 0);
+    }
+}
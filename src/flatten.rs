@@ -0,0 +1,338 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use crate::config::DirectoryLayout;
+use crate::generator::package_path;
+use crate::resources;
+use crate::validate;
+
+/// The directory every content file of the book lives under.
+const CONTENT_DIR: &str = "OEBPS/";
+/// The files `epub-builder` writes for the package itself, which stay at the
+/// top of `OEBPS` whatever the layout.
+const PACKAGE_FILES: &[&str] = &["OEBPS/content.opf", "OEBPS/toc.ncx", "OEBPS/nav.xhtml"];
+/// The attributes holding references in XHTML, SVG and package documents.
+const REFERENCE_ATTRIBUTES: &[&str] = &["href", "src", "xlink:href", "poster"];
+
+/// Where the files of the book go, keyed by their current name: moved to
+/// the top of `OEBPS` first with `flatten`, then into the directories of the
+/// `layout`. Files which don't move are left out.
+pub(crate) fn new_names(names: &[String], flatten: bool, layout: DirectoryLayout) -> HashMap<String, String> {
+    let mut renamed = if flatten { flat_names(names) } else { HashMap::new() };
+    if layout == DirectoryLayout::Conventional {
+        let current: Vec<String> = names
+            .iter()
+            .map(|name| renamed.get(name).unwrap_or(name).clone())
+            .collect();
+        let conventional = conventional_names(&current);
+        for (name, current) in names.iter().zip(&current) {
+            if let Some(moved) = conventional.get(current) {
+                renamed.insert(name.clone(), moved.clone());
+            }
+        }
+    }
+    renamed
+}
+
+/// Where the files in subdirectories of `OEBPS` go once they're all moved to
+/// the top of it, keyed by their current name. Their directories become part
+/// of the name (`guide/intro.html` is `guide_intro.html`), with a number
+/// added when that's already taken. Files which don't move are left out.
+fn flat_names(names: &[String]) -> HashMap<String, String> {
+    let mut taken: HashSet<String> = names
+        .iter()
+        .filter(|name| !is_nested(name))
+        .cloned()
+        .collect();
+    let mut renamed = HashMap::new();
+
+    for name in names.iter().filter(|name| is_nested(name)) {
+        let flat = format!("{}{}", CONTENT_DIR, name[CONTENT_DIR.len()..].replace('/', "_"));
+        let candidate = untaken(flat, &mut taken);
+        renamed.insert(name.clone(), candidate);
+    }
+
+    renamed
+}
+
+/// Where the files of `OEBPS` go in the conventional layout, keyed by their
+/// current name: each moves into the directory for its kind of file, keeping
+/// the directories it was in unless it's already under that one
+/// (`guide/intro.html` is `text/guide/intro.html`, but `images/logo.png`
+/// stays put). Files which don't move are left out.
+fn conventional_names(names: &[String]) -> HashMap<String, String> {
+    let moving = |name: &&String| -> bool {
+        match (name.strip_prefix(CONTENT_DIR), layout_dir(name)) {
+            (Some(rest), Some(dir)) => !PACKAGE_FILES.contains(&name.as_str()) && !rest.starts_with(&format!("{}/", dir)),
+            _ => false,
+        }
+    };
+    let mut taken: HashSet<String> = names.iter().filter(|name| !moving(name)).cloned().collect();
+    let mut renamed = HashMap::new();
+
+    for name in names.iter().filter(moving) {
+        let dir = layout_dir(name).unwrap_or_default();
+        let candidate = untaken(format!("{}{}/{}", CONTENT_DIR, dir, &name[CONTENT_DIR.len()..]), &mut taken);
+        renamed.insert(name.clone(), candidate);
+    }
+
+    renamed
+}
+
+/// The directory a file goes in in the conventional layout, from its
+/// mimetype. Anything else, such as audio, stays where it is.
+fn layout_dir(name: &str) -> Option<&'static str> {
+    let mimetype = resources::mimetype(Path::new(name));
+    match (mimetype.type_().as_str(), mimetype.essence_str()) {
+        (_, "text/html") | (_, "application/xhtml+xml") => Some("text"),
+        ("image", _) => Some("images"),
+        (_, "text/css") => Some("styles"),
+        ("font", _) => Some("fonts"),
+        _ => None,
+    }
+}
+
+/// `name`, or with a number added before its extension when that's already
+/// `taken`, which it then is.
+pub(crate) fn untaken(name: String, taken: &mut HashSet<String>) -> String {
+    let file_start = name.rfind('/').map_or(0, |ix| ix + 1);
+    let (stem, extension) = match name[file_start..].rfind('.') {
+        Some(ix) if ix > 0 => name.split_at(file_start + ix),
+        _ => (name.as_str(), ""),
+    };
+
+    let mut candidate = name.clone();
+    let mut count = 2;
+    while taken.contains(&candidate) {
+        candidate = format!("{}-{}{}", stem, count, extension);
+        count += 1;
+    }
+    taken.insert(candidate.clone());
+    candidate
+}
+
+/// Does the file need to move to the top of `OEBPS`?
+fn is_nested(name: &str) -> bool {
+    name.strip_prefix(CONTENT_DIR).is_some_and(|rest| rest.contains('/'))
+}
+
+/// Does this file refer to others by their paths?
+pub(crate) fn has_references(name: &str) -> bool {
+    let extension = Path::new(name).extension().and_then(|ext| ext.to_str()).unwrap_or("");
+    name.starts_with(CONTENT_DIR) && ["html", "xhtml", "opf", "ncx", "css", "svg"].contains(&extension)
+}
+
+/// Point the references in the file called `name` at where their targets
+/// have been `renamed` to, from wherever the file itself has ended up.
+/// References which still lead to the same place are left as they were.
+pub(crate) fn rewrite_references(content: &str, name: &str, renamed: &HashMap<String, String>) -> String {
+    let dir = Path::new(name).parent().unwrap_or_else(|| Path::new(""));
+    let new_name = renamed.get(name).map_or(name, String::as_str);
+    let new_dir = Path::new(new_name).parent().unwrap_or_else(|| Path::new(""));
+
+    let rewrite = |link: &str| -> Option<String> {
+        if !validate::is_internal(link) {
+            return None;
+        }
+        let split = link.find(['#', '?']).unwrap_or(link.len());
+        let (path, suffix) = link.split_at(split);
+        if path.is_empty() {
+            return None;
+        }
+
+        let target = package_path(&validate::normalize(&dir.join(validate::percent_decode(path))));
+        let new_target = renamed.get(&target).cloned();
+        if new_target.is_none() && new_dir == dir {
+            return None;
+        }
+        let new_target = new_target.unwrap_or(target);
+        let relative = relative_path(new_dir, Path::new(&new_target));
+        Some(format!("{}{}", validate::percent_encode(&relative), suffix))
+    };
+
+    if name.ends_with(".css") {
+        return rewrite_css_urls(content, rewrite);
+    }
+    resources::rewrite_attribute_values(content, |_, attribute, value| match attribute {
+        "srcset" => Some(resources::rewrite_srcset(value, |link| rewrite(link))),
+        attribute if REFERENCE_ATTRIBUTES.contains(&attribute) => rewrite(value),
+        _ => None,
+    })
+}
+
+/// Rewrite the `url()`s in a stylesheet, quoted or not.
+pub(crate) fn rewrite_css_urls<F>(css: &str, mut rewrite: F) -> String
+where
+    F: FnMut(&str) -> Option<String>,
+{
+    let mut out = String::with_capacity(css.len());
+    let mut rest = css;
+
+    while let Some(ix) = rest.find("url(") {
+        let value = &rest[ix + 4..];
+        let end = match value.find(')') {
+            Some(end) => end,
+            None => break,
+        };
+        let raw = &value[..end];
+        let link = raw.trim().trim_matches(|c| c == '"' || c == '\'');
+
+        out.push_str(&rest[..ix + 4]);
+        match rewrite(link) {
+            Some(rewritten) => out.push_str(&raw.replacen(link, &rewritten, 1)),
+            None => out.push_str(raw),
+        }
+        rest = &value[end..];
+    }
+
+    out.push_str(rest);
+    out
+}
+
+/// The relative path from a file in `dir` to `target`, both within the
+/// archive.
+pub(crate) fn relative_path(dir: &Path, target: &Path) -> String {
+    let common = dir
+        .components()
+        .zip(target.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut relative = PathBuf::new();
+    for _ in common..dir.components().count() {
+        relative.push("..");
+    }
+    relative.extend(target.components().skip(common));
+    package_path(&relative)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn nested_files_are_renamed_uniquely() {
+        let renamed = flat_names(&names(&[
+            "mimetype",
+            "META-INF/container.xml",
+            "OEBPS/content.opf",
+            "OEBPS/guide_intro.html",
+            "OEBPS/guide/intro.html",
+            "OEBPS/guide/images/logo.png",
+            "OEBPS/guide/images/logo.png.bak",
+        ]));
+
+        let mut renamed: Vec<_> = renamed.iter().map(|(from, to)| (from.as_str(), to.as_str())).collect();
+        renamed.sort();
+        assert_eq!(
+            renamed,
+            vec![
+                ("OEBPS/guide/images/logo.png", "OEBPS/guide_images_logo.png"),
+                ("OEBPS/guide/images/logo.png.bak", "OEBPS/guide_images_logo.png.bak"),
+                ("OEBPS/guide/intro.html", "OEBPS/guide_intro-2.html"),
+            ]
+        );
+    }
+
+    #[test]
+    fn references_follow_the_files() {
+        let renamed = flat_names(&names(&[
+            "OEBPS/stylesheet.css",
+            "OEBPS/intro.html",
+            "OEBPS/guide/setup.html",
+            "OEBPS/guide/images/logo.png",
+        ]));
+
+        let chapter = "<link rel=\"stylesheet\" href=\"../stylesheet.css\" />\n\
+                       <a href=\"../intro.html#top\">Intro</a> <a href='#here'>Here</a> \
+                       <a href=\"https://example.com/a/b.html\">Site</a>\n\
+                       <img src=\"images/logo.png\" alt=\"\"/> <svg><image xlink:href=\"images/logo.png\"/></svg>\n";
+        assert_eq!(
+            rewrite_references(chapter, "OEBPS/guide/setup.html", &renamed),
+            "<link rel=\"stylesheet\" href=\"stylesheet.css\" />\n\
+             <a href=\"intro.html#top\">Intro</a> <a href='#here'>Here</a> \
+             <a href=\"https://example.com/a/b.html\">Site</a>\n\
+             <img src=\"guide_images_logo.png\" alt=\"\"/> <svg><image xlink:href=\"guide_images_logo.png\"/></svg>\n"
+        );
+
+        let opf = "<item id=\"setup\" href=\"guide/setup.html\"/>\n<item id=\"intro\" href=\"intro.html\"/>";
+        assert_eq!(
+            rewrite_references(opf, "OEBPS/content.opf", &renamed),
+            "<item id=\"setup\" href=\"guide_setup.html\"/>\n<item id=\"intro\" href=\"intro.html\"/>"
+        );
+
+        let css = "@font-face { src: url(\"guide/images/logo.png\"); }\nbody { background: url(none.png); }";
+        assert_eq!(
+            rewrite_references(css, "OEBPS/stylesheet.css", &renamed),
+            "@font-face { src: url(\"guide_images_logo.png\"); }\nbody { background: url(none.png); }"
+        );
+    }
+
+    #[test]
+    fn escaped_names_and_srcsets_are_followed() {
+        let renamed = flat_names(&names(&[
+            "OEBPS/guide/a.html",
+            "OEBPS/guide/café menu.png",
+            "OEBPS/guide/50%.png",
+            "OEBPS/guide/big.png",
+        ]));
+
+        let chapter = "<img src=\"caf%C3%A9%20menu.png\" srcset=\"50%25.png 1x, big.png 2x\" alt=\"\"/>";
+        assert_eq!(
+            rewrite_references(chapter, "OEBPS/guide/a.html", &renamed),
+            "<img src=\"guide_café%20menu.png\" srcset=\"guide_50%25.png 1x, guide_big.png 2x\" alt=\"\"/>"
+        );
+    }
+
+    #[test]
+    fn conventional_layout_sorts_files_by_kind() {
+        let renamed = new_names(
+            &names(&[
+                "mimetype",
+                "OEBPS/content.opf",
+                "OEBPS/nav.xhtml",
+                "OEBPS/toc.ncx",
+                "OEBPS/stylesheet.css",
+                "OEBPS/guide/setup.html",
+                "OEBPS/images/logo.png",
+                "OEBPS/logo.png",
+                "OEBPS/fonts/serif.woff2",
+                "OEBPS/clip.mp3",
+                "OEBPS/photo.avif",
+                "OEBPS/serif.ttf",
+                "OEBPS/sans.otf",
+                "OEBPS/sans.woff",
+            ]),
+            false,
+            DirectoryLayout::Conventional,
+        );
+
+        let mut renamed: Vec<_> = renamed.iter().map(|(from, to)| (from.as_str(), to.as_str())).collect();
+        renamed.sort();
+        assert_eq!(
+            renamed,
+            vec![
+                ("OEBPS/guide/setup.html", "OEBPS/text/guide/setup.html"),
+                ("OEBPS/logo.png", "OEBPS/images/logo-2.png"),
+                ("OEBPS/photo.avif", "OEBPS/images/photo.avif"),
+                ("OEBPS/sans.otf", "OEBPS/fonts/sans.otf"),
+                ("OEBPS/sans.woff", "OEBPS/fonts/sans.woff"),
+                ("OEBPS/serif.ttf", "OEBPS/fonts/serif.ttf"),
+                ("OEBPS/stylesheet.css", "OEBPS/styles/stylesheet.css"),
+            ]
+        );
+
+        let flattened = new_names(&names(&["OEBPS/guide/setup.html"]), true, DirectoryLayout::Conventional);
+        assert_eq!(flattened["OEBPS/guide/setup.html"], "OEBPS/text/guide_setup.html");
+    }
+
+    #[test]
+    fn relative_paths() {
+        assert_eq!(relative_path(Path::new("OEBPS"), Path::new("OEBPS/a.html")), "a.html");
+        assert_eq!(relative_path(Path::new("OEBPS/a/b"), Path::new("OEBPS/c.png")), "../../c.png");
+    }
+}
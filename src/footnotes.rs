@@ -0,0 +1,209 @@
+use pulldown_cmark::{html, CowStr, Event, Tag};
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::config::FootnotePlacement;
+use crate::package::escape_xml;
+
+/// Numbers a chapter's footnotes and gives them ids which are unique across
+/// the whole book, optionally moving the footnote definitions to the end of
+/// the chapter.
+pub(crate) struct EventFootnotes<'a> {
+    placement: FootnotePlacement,
+    /// Put in front of every id, so footnotes in different chapters never
+    /// share one.
+    prefix: String,
+    numbers: HashMap<String, usize>,
+    /// How often each footnote has been referenced so far.
+    references: HashMap<usize, usize>,
+    /// The footnote currently being collected for the end of the chapter.
+    current: Option<(usize, Vec<Event<'a>>)>,
+    collected: Vec<(usize, Vec<Event<'a>>)>,
+}
+
+/// Something to put in front of the ids given to a chapter's elements, so
+/// they're unique across the book even when every chapter is in one file.
+pub(crate) fn id_prefix(chapter_path: &Path) -> String {
+    chapter_path
+        .with_extension("")
+        .to_string_lossy()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect()
+}
+
+impl<'a> EventFootnotes<'a> {
+    pub(crate) fn new(placement: FootnotePlacement, chapter_path: &Path) -> Self {
+        // XHTML ids have to start with a letter, which chapters like
+        // `1-intro.md` don't
+        let prefix = match id_prefix(chapter_path) {
+            prefix if prefix.starts_with(|c: char| c.is_ascii_alphabetic()) => prefix,
+            prefix => format!("ch-{}", prefix),
+        };
+
+        EventFootnotes {
+            placement,
+            prefix,
+            numbers: HashMap::new(),
+            references: HashMap::new(),
+            current: None,
+            collected: Vec::new(),
+        }
+    }
+
+    pub(crate) fn convert(&mut self, event: Event<'a>) -> Vec<Event<'a>> {
+        match event {
+            Event::FootnoteReference(ref name) => {
+                let number = self.number(name);
+                let count = self.references.entry(number).or_insert(0);
+                *count += 1;
+                let count = *count;
+                // only the first reference is linked back to
+                let id = if count == 1 {
+                    self.reference_id(number)
+                } else {
+                    format!("{}-{}", self.reference_id(number), count)
+                };
+
+                let html = format!(
+                    "<sup class=\"footnote-reference\" id=\"{}\"><a href=\"#{}\">{}</a></sup>",
+                    id,
+                    self.definition_id(number),
+                    number
+                );
+                self.emit(Event::Html(CowStr::from(html)))
+            }
+            Event::Start(Tag::FootnoteDefinition(ref name)) => {
+                let number = self.number(name);
+
+                match self.placement {
+                    FootnotePlacement::ChapterEnd => {
+                        self.current = Some((number, Vec::new()));
+                        Vec::new()
+                    }
+                    FootnotePlacement::Inline => {
+                        let html = format!(
+                            "<div class=\"footnote-definition\" id=\"{}\"><sup class=\"footnote-definition-label\"><a href=\"#{}\">{}</a></sup>\n",
+                            self.definition_id(number),
+                            self.reference_id(number),
+                            number
+                        );
+                        vec![Event::Html(CowStr::from(html))]
+                    }
+                }
+            }
+            Event::End(Tag::FootnoteDefinition(_)) => match self.current.take() {
+                Some(footnote) => {
+                    self.collected.push(footnote);
+                    Vec::new()
+                }
+                None => vec![Event::Html(CowStr::from("</div>\n"))],
+            },
+            _ => self.emit(event),
+        }
+    }
+
+    /// The footnotes collected for the end of the chapter, as a numbered
+    /// list with links back to where they were referenced.
+    pub(crate) fn finish(&mut self) -> Option<String> {
+        if self.collected.is_empty() {
+            return None;
+        }
+
+        let mut footnotes = std::mem::take(&mut self.collected);
+        footnotes.sort_by_key(|&(number, _)| number);
+
+        let mut out = String::from("<div class=\"footnotes\">\n<hr />\n<ol>\n");
+        for (number, events) in footnotes {
+            let mut content = String::new();
+            html::push_html(&mut content, events.into_iter());
+            let back_link = format!(" <a class=\"footnote-back\" href=\"#{}\">↩</a>", self.reference_id(number));
+
+            // keep the back-link inside the note's last paragraph
+            match content.trim_end().strip_suffix("</p>") {
+                Some(start) => content = format!("{}{}</p>\n", start, back_link),
+                None => content.push_str(&back_link),
+            }
+
+            out.push_str(&format!("<li id=\"{}\">{}</li>\n", self.definition_id(number), content));
+        }
+        out.push_str("</ol>\n</div>\n");
+
+        Some(out)
+    }
+
+    /// Pass an event on, unless it belongs to a footnote being collected.
+    fn emit(&mut self, event: Event<'a>) -> Vec<Event<'a>> {
+        match self.current {
+            Some((_, ref mut events)) => {
+                events.push(event);
+                Vec::new()
+            }
+            None => vec![event],
+        }
+    }
+
+    /// Footnotes are numbered in the order they're first mentioned.
+    fn number(&mut self, name: &str) -> usize {
+        let next = self.numbers.len() + 1;
+        *self.numbers.entry(name.to_string()).or_insert(next)
+    }
+
+    fn definition_id(&self, number: usize) -> String {
+        escape_xml(&format!("{}-fn-{}", self.prefix, number))
+    }
+
+    fn reference_id(&self, number: usize) -> String {
+        escape_xml(&format!("{}-fnref-{}", self.prefix, number))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Generator;
+
+    const INPUT: &str = "Some text[^note] and more[^other].\n\n[^other]: The other note.\n\nMiddle.\n\n[^note]: A note.\n\nAgain[^note].\n";
+
+    fn render(placement: FootnotePlacement) -> String {
+        let mut body = String::new();
+        let mut footnotes = EventFootnotes::new(placement, Path::new("part/chapter.md"));
+        let events = Generator::new_cmark_parser(INPUT).flat_map(|event| footnotes.convert(event));
+        html::push_html(&mut body, events);
+        body.extend(footnotes.finish());
+        body
+    }
+
+    #[test]
+    fn footnotes_at_the_end_of_the_chapter() {
+        let body = render(FootnotePlacement::ChapterEnd);
+
+        let should_be = concat!(
+            "<p>Some text<sup class=\"footnote-reference\" id=\"part-chapter-fnref-1\"><a href=\"#part-chapter-fn-1\">1</a></sup>",
+            " and more<sup class=\"footnote-reference\" id=\"part-chapter-fnref-2\"><a href=\"#part-chapter-fn-2\">2</a></sup>.</p>\n",
+            "<p>Middle.</p>\n",
+            "<p>Again<sup class=\"footnote-reference\" id=\"part-chapter-fnref-1-2\"><a href=\"#part-chapter-fn-1\">1</a></sup>.</p>\n",
+            "<div class=\"footnotes\">\n<hr />\n<ol>\n",
+            "<li id=\"part-chapter-fn-1\"><p>A note. <a class=\"footnote-back\" href=\"#part-chapter-fnref-1\">↩</a></p>\n</li>\n",
+            "<li id=\"part-chapter-fn-2\"><p>The other note. <a class=\"footnote-back\" href=\"#part-chapter-fnref-2\">↩</a></p>\n</li>\n",
+            "</ol>\n</div>\n",
+        );
+        assert_eq!(body, should_be);
+    }
+
+    #[test]
+    fn ids_start_with_a_letter() {
+        let footnotes = EventFootnotes::new(FootnotePlacement::Inline, Path::new("3-ownership.md"));
+
+        assert_eq!(footnotes.definition_id(1), "ch-3-ownership-fn-1");
+        assert_eq!(footnotes.reference_id(1), "ch-3-ownership-fnref-1");
+    }
+
+    #[test]
+    fn inline_footnotes_stay_put() {
+        let body = render(FootnotePlacement::Inline);
+
+        assert!(body.contains("<p>Middle.</p>\n<div class=\"footnote-definition\" id=\"part-chapter-fn-1\">"), "{}", body);
+        assert!(!body.contains("class=\"footnotes\""), "{}", body);
+    }
+}
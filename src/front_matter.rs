@@ -0,0 +1,107 @@
+use serde_json::{Map, Value};
+
+/// The formats a front-matter block can be written in.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Format {
+    /// YAML, fenced by `---` lines.
+    Yaml,
+    /// TOML, fenced by `+++` lines.
+    Toml,
+}
+
+impl Format {
+    fn fence(self) -> &'static str {
+        match self {
+            Format::Yaml => "---",
+            Format::Toml => "+++",
+        }
+    }
+
+    fn parse(self, block: &str) -> Result<Value, String> {
+        match self {
+            Format::Yaml => serde_yaml::from_str(block).map_err(|e| e.to_string()),
+            Format::Toml => toml::from_str(block).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// Split a front-matter block (YAML fenced by `---` or TOML fenced by `+++`)
+/// off the top of a chapter's content, returning the parsed keys along with
+/// the rest of the content.
+///
+/// Content without a block, or whose block isn't a valid mapping, is
+/// returned untouched.
+pub(crate) fn split(content: &str) -> (Map<String, Value>, &str) {
+    [Format::Yaml, Format::Toml]
+        .iter()
+        .find_map(|&format| try_split(content, format))
+        .unwrap_or_else(|| (Map::new(), content))
+}
+
+fn try_split(content: &str, format: Format) -> Option<(Map<String, Value>, &str)> {
+    let fence = format.fence();
+    let body = content.strip_prefix(fence)?;
+    let body = body
+        .strip_prefix('\n')
+        .or_else(|| body.strip_prefix("\r\n"))?;
+
+    let mut offset = 0;
+    for line in body.split_inclusive('\n') {
+        if line.trim_end() == fence {
+            let block = &body[..offset];
+            let rest = &body[offset + line.len()..];
+
+            return match format.parse(block) {
+                Ok(Value::Object(values)) => Some((values, rest)),
+                Ok(Value::Null) => Some((Map::new(), rest)),
+                Ok(_) => None,
+                Err(e) => {
+                    warn!("Ignoring invalid front matter: {}", e);
+                    None
+                }
+            };
+        }
+        offset += line.len();
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn yaml_front_matter_is_split_off() {
+        let (values, rest) = split("---\nclass: code-heavy\n---\n# Chapter 1\n");
+
+        assert_eq!(values["class"], "code-heavy");
+        assert_eq!(rest, "# Chapter 1\n");
+    }
+
+    #[test]
+    fn toml_front_matter_is_split_off() {
+        let (values, rest) = split("+++\ntitle = \"Intro\"\nauthor = \"Jane\"\n+++\n# Chapter 1\n");
+
+        assert_eq!(values["title"], "Intro");
+        assert_eq!(values["author"], "Jane");
+        assert_eq!(rest, "# Chapter 1\n");
+    }
+
+    #[test]
+    fn content_without_front_matter_is_untouched() {
+        let inputs = [
+            "# Chapter 1\n\n---\n\nclass: x\n---\n",
+            "---\nnot closed\n",
+            "---\n- a list\n---\n",
+            "+++\nnot = valid = toml\n+++\n",
+            "+++ not a fence\n+++\n",
+        ];
+
+        for input in inputs {
+            let (values, rest) = split(input);
+            assert!(values.is_empty(), "{}", input);
+            assert_eq!(rest, input);
+        }
+    }
+}
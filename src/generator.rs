@@ -1,49 +1,325 @@
-use std::{iter,
+use std::{borrow::Cow,
+          collections::{hash_map::DefaultHasher, BTreeMap, HashMap, HashSet},
+          hash::{Hash, Hasher},
           io::{Read, Write},
           fmt::{self, Debug, Formatter},
           fs::File,
-          path::PathBuf
+          ops::Range,
+          path::{Component, Path, PathBuf},
+          time::Duration,
 };
 
 use mdbook::renderer::RenderContext;
-use mdbook::book::{BookItem, Chapter};
-use epub_builder::{EpubBuilder, EpubContent, ZipLibrary};
-use pulldown_cmark::{CodeBlockKind, html, Parser, Options, Event, CowStr, Tag};
+use mdbook::book::{Book, BookItem, Chapter};
+use mdbook::config::{BookConfig, Config as MdConfig};
+use epub_builder::{EpubBuilder, EpubContent, ReferenceType, TocElement, ZipLibrary};
+use pulldown_cmark::{CodeBlockKind, html, HeadingLevel, Options, Parser, Event, CowStr, Tag};
 use super::Error;
 use handlebars::{Handlebars, RenderError};
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
 
-use crate::config::Config;
+use crate::admonition::EventAdmonitions;
+use crate::book_index::{self, EventIndexMarkers, IndexEntry};
+use crate::cache::RenderCache;
+use crate::conditional;
+use crate::config::{
+    Accessibility, Config, CssTheme, Direction, EpubVersion, IdentifierScheme, MarkdownOption, MissingAssetPolicy,
+    PagePosition,
+};
+use crate::figures::{self, EventFigures};
+use crate::flatten;
+use crate::footnotes::EventFootnotes;
+use crate::front_matter;
+use crate::highlight::Highlighter;
+use crate::includes;
+use crate::math::EventMathConverter;
+use crate::mermaid::{self, EventMermaid};
+use crate::minify;
+use crate::package::{self, PackagePatch};
+use crate::progress::{NoProgress, ProgressReporter};
+use crate::report::Report;
 use crate::resources::{self, Asset};
-use crate::DEFAULT_CSS;
+use crate::sanitize::EventSanitizer;
+use crate::task_list::EventTaskList;
+use crate::validate;
+use crate::wikilinks::{self, EventWikilinks};
+use crate::{DARK_CSS, DEFAULT_CSS, SEPIA_CSS};
+
+/// Where the cover page is written.
+const COVER_PAGE_PATH: &str = "cover.html";
+/// The coordinate system used when an SVG cover doesn't say what its own is,
+/// in the same proportions as a typical e-reader screen.
+const DEFAULT_COVER_VIEW_BOX: &str = "0 0 600 800";
+/// Where the `generate_text_cover` cover image is packaged.
+const TEXT_COVER_PATH: &str = "cover.svg";
+/// The chapter path used when rendering the copyright page, which is
+/// written next to it as `copyright.html`.
+const COPYRIGHT_PAGE_SOURCE: &str = "copyright.md";
+/// The copyright page's title in the navigation.
+const COPYRIGHT_PAGE_TITLE: &str = "Copyright";
+/// The inline style which starts an element on a new page.
+const PAGE_BREAK_STYLE: &str = "page-break-before: always; break-before: page;";
+/// Where the whole book is written with `single_file`.
+const SINGLE_FILE_PATH: &str = "book.html";
+/// Keys of the `metadata` table which replace what `epub-builder` would
+/// write, rather than being added as `meta` elements.
+const BUILT_IN_METADATA: &[&str] = &["title", "author", "lang", "description"];
+/// The chapter the `description_as_page` page is rendered as, which it's
+/// packaged beside as `.html`, with a number added when a chapter of the
+/// book is already packaged there.
+const DESCRIPTION_PAGE_SOURCE: &str = "about_this_book.md";
+/// Where the `template_assets` are packaged.
+const TEMPLATE_ASSETS_DIR: &str = "template";
+/// Where the files the `additional_css` stylesheets refer to are packaged.
+const STYLESHEET_ASSETS_DIR: &str = "css";
+/// Where the `generate_index` page is written, beside any chapter which
+/// would be packaged as `index.html`.
+const INDEX_PAGE_PATH: &str = "book_index.html";
+/// The heading of the `generate_index` page.
+const INDEX_PAGE_TITLE: &str = "Index";
+/// Where the generated title page is written.
+const TITLE_PAGE_PATH: &str = "title_page.html";
+/// Where the generated table of contents page is written.
+const TOC_PAGE_PATH: &str = "toc.html";
+/// The heading used for the generated table of contents page.
+const TOC_PAGE_TITLE: &str = "Table of Contents";
+
+/// Something which gets a final say over each chapter's XHTML, e.g. to
+/// restructure tables for a particular reader.
+///
+/// Post-processors run once a chapter has been completely rendered, after
+/// links have been rewritten, quotes converted and code highlighted, and
+/// the result has been put through the `index` template. They're given the
+/// whole XHTML document and run in the order they were added.
+///
+/// Chapters are rendered in parallel, hence the `Send + Sync` bound.
+/// Closures taking the chapter and its XHTML work too.
+pub trait ChapterPostProcessor: Send + Sync {
+    fn process(&self, chapter: &Chapter, html: String) -> String;
+}
+
+impl<F> ChapterPostProcessor for F
+where
+    F: Fn(&Chapter, String) -> String + Send + Sync,
+{
+    fn process(&self, chapter: &Chapter, html: String) -> String {
+        self(chapter, html)
+    }
+}
 
 /// The actual EPUB book renderer.
+///
+/// Only a handful of `RenderContext` fields are used while generating:
+///
+/// - `root`, the book's root directory which relative paths are resolved
+///   against,
+/// - `book`, the chapters to render,
+/// - `config.book`, for the title, authors, description, language and the
+///   `src` directory, and
+/// - `destination`, where downloaded remote images are cached.
+///
+/// The `output.epub` table of `config` is only read by [`Generator::new()`].
+/// Use [`Generator::from_book()`] to generate a book without a
+/// `RenderContext`.
 pub struct Generator<'a> {
-    ctx: &'a RenderContext,
+    ctx: Cow<'a, RenderContext>,
+    /// The book being rendered, kept apart from `ctx` so chapters can be
+    /// borrowed while the generator is updated.
+    book: &'a Book,
     builder: EpubBuilder<ZipLibrary>,
     config: Config,
     hbs: Handlebars<'a>,
+    /// Renders `chapter_title_template`, which is plain text rather than
+    /// HTML.
+    titles: Handlebars<'a>,
+    highlighter: Option<Highlighter>,
+    /// Manifest `properties` to add for content files, e.g. `mathml`.
+    manifest_properties: Vec<(String, String)>,
+    post_processors: Vec<Box<dyn ChapterPostProcessor + 'a>>,
+    progress: Box<dyn ProgressReporter + 'a>,
+    /// Where the chapters left out by `include_chapters`/`exclude_chapters`
+    /// would have been packaged.
+    excluded: HashSet<String>,
+    /// Where the chapters kept out of the navigation by `toc_hidden` are
+    /// packaged.
+    toc_hidden: HashSet<String>,
+    /// The titles of the chapters before and after each chapter in the
+    /// book, keyed by where the chapter is packaged.
+    neighbours: HashMap<String, Neighbours>,
+    /// The number of the first figure in each chapter, keyed by where the
+    /// chapter is packaged.
+    first_figures: HashMap<String, usize>,
+    /// Where the first copy of each image packaged more than once is, or
+    /// the placeholder for an image which doesn't exist, keyed by where the
+    /// image would have been.
+    duplicate_assets: BTreeMap<String, String>,
+    /// The terms marked for the index, in book order.
+    index_entries: Vec<IndexEntry>,
+    /// Where the chapters' own stylesheets which have been added so far are
+    /// packaged.
+    packaged_stylesheets: HashSet<String>,
+    /// The contents of the chapters which differ from their markdown, with
+    /// the files of `resolve_includes` directives spliced in and what's
+    /// marked for other formats taken out, keyed by their source paths.
+    included: HashMap<String, String>,
+    /// The chapters `[[Page]]` links can lead to, with `wikilinks`.
+    wikilinks: BTreeMap<String, PathBuf>,
+    /// The ids of the chapters' sections with `single_file`, keyed by where
+    /// the chapters would otherwise have been packaged.
+    anchors: BTreeMap<String, String>,
+    /// The images `transcode_unsupported_images` converted to PNG, keyed by
+    /// where they'd have been packaged, with where the PNG is packaged and
+    /// where it's read from.
+    transcoded: BTreeMap<String, (String, PathBuf)>,
+    /// The `cover.*` image found in the source directory, relative to it,
+    /// when no `cover_image` is configured.
+    found_cover: Option<PathBuf>,
+    /// The `mermaid` diagrams rendered so far.
+    diagrams: mermaid::Diagrams,
+}
+
+/// The titles of the chapters either side of a chapter, for templates.
+#[derive(Debug, Default, Clone, PartialEq, Hash)]
+struct Neighbours {
+    prev_title: Option<String>,
+    next_title: Option<String>,
 }
 
 impl<'a> Generator<'a> {
+    /// Create a generator for an `mdbook` render, reading the `output.epub`
+    /// table of the book's config.
     pub fn new(ctx: &'a RenderContext) -> Result<Generator<'a>, Error> {
-        let builder = EpubBuilder::new(ZipLibrary::new()?)?;
         let config = Config::from_render_context(ctx)?;
+        Generator::with_config(Cow::Borrowed(ctx), &ctx.book, config)
+    }
+
+    /// Create a generator without going through `mdbook`'s renderer
+    /// machinery.
+    ///
+    /// Relative paths in `config` and the book's `src` directory are resolved
+    /// against `root`. The book's metadata can be set with
+    /// [`Generator::with_book_config()`], and remote images are cached under
+    /// `<root>/book` unless [`Generator::with_destination()`] says otherwise.
+    pub fn from_book(book: &'a Book, mut config: Config, root: &Path) -> Result<Generator<'a>, Error> {
+        config.resolve_paths(root);
+        // the chapters are read from `book`, so the context doesn't need a copy
+        let ctx = RenderContext::new(root, Book::new(), MdConfig::default(), root.join("book"));
+        Generator::with_config(Cow::Owned(ctx), book, config)
+    }
+
+    /// Use this title, authors, language, etc. for the book.
+    pub fn with_book_config(mut self, book_config: BookConfig) -> Self {
+        let moved = book_config.src != self.ctx.config.book.src;
+        self.ctx.to_mut().config.book = book_config;
+        if moved {
+            self.found_cover = self.find_cover();
+        }
+        self
+    }
+
+    /// Run every rendered chapter through `post_processor` before it's added
+    /// to the book.
+    pub fn with_post_processor<P: ChapterPostProcessor + 'a>(mut self, post_processor: P) -> Self {
+        self.post_processors.push(Box::new(post_processor));
+        self
+    }
+
+    /// Tell `progress` about each chapter and asset as the book is built.
+    pub fn with_progress_reporter<P: ProgressReporter + 'a>(mut self, progress: P) -> Self {
+        self.progress = Box::new(progress);
+        self
+    }
+
+    /// Where to keep files created while generating, such as downloaded images.
+    pub fn with_destination<P: Into<PathBuf>>(mut self, destination: P) -> Self {
+        self.ctx.to_mut().destination = destination.into();
+        self
+    }
+
+    /// The options the book is generated with, after the defaults are filled
+    /// in and the template and cache paths resolved against the book root.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    fn with_config(ctx: Cow<'a, RenderContext>, book: &'a Book, config: Config) -> Result<Generator<'a>, Error> {
+        let mut builder = EpubBuilder::new(ZipLibrary::new()?)?;
+        builder.epub_version(config.version.into());
 
         let mut hbs = Handlebars::new();
         hbs.register_template_string("index", config.template()?)
             .map_err(|_| Error::TemplateParse)?;
+        hbs.register_template_string("title_page", config.title_page_template()?)
+            .map_err(|_| Error::TemplateParse)?;
+
+        let mut titles = Handlebars::new();
+        titles.register_escape_fn(handlebars::no_escape);
+        if let Some(ref template) = config.chapter_title_template {
+            titles
+                .register_template_string("chapter_title", template)
+                .map_err(|_| Error::TemplateParse)?;
+        }
+
+        if config.css_theme != CssTheme::Default && !config.use_default_css {
+            warn!("`css-theme` only changes the default stylesheet, which `use-default-css = false` leaves out");
+        }
+
+        if config.math && config.version == EpubVersion::V2 {
+            warn!("MathML is only supported by EPUB 3, consider setting `version = \"3.0\"`");
+        }
+
+        let highlighter = if config.highlight {
+            Some(Highlighter::new())
+        } else {
+            None
+        };
+
+        let excluded = excluded_chapters(book, &config)?;
+        let toc_hidden = toc_hidden_chapters(book, &config.toc_hidden);
+        let mut included = if config.resolve_includes {
+            resolve_includes(book, &ctx.root.join(&ctx.config.book.src))?
+        } else {
+            HashMap::new()
+        };
+        tailor_chapters(book, &mut included);
 
-        Ok(Generator {
+        let mut generator = Generator {
             builder,
             ctx,
+            book,
             config,
             hbs,
-        })
+            titles,
+            highlighter,
+            manifest_properties: Vec::new(),
+            post_processors: Vec::new(),
+            progress: Box::new(NoProgress),
+            excluded,
+            toc_hidden,
+            wikilinks: BTreeMap::new(),
+            neighbours: HashMap::new(),
+            first_figures: HashMap::new(),
+            duplicate_assets: BTreeMap::new(),
+            index_entries: Vec::new(),
+            packaged_stylesheets: HashSet::new(),
+            included,
+            anchors: BTreeMap::new(),
+            transcoded: BTreeMap::new(),
+            found_cover: None,
+            diagrams: mermaid::Diagrams::default(),
+        };
+        generator.found_cover = generator.find_cover();
+        if generator.config.wikilinks {
+            generator.wikilinks = wikilinks::targets(book, |ch| generator.chapter_output_path(ch).is_some());
+        }
+        if generator.config.single_file {
+            generator.anchors = single_file_anchors(&generator.chapter_hrefs());
+        }
+        Ok(generator)
     }
 
     fn populate_metadata(&mut self) -> Result<(), Error> {
-        self.builder.metadata("generator", "mdbook-epub")?;
-
         if let Some(title) = self.ctx.config.book.title.clone() {
             self.builder.metadata("title", title)?;
         } else {
@@ -51,273 +327,3454 @@ impl<'a> Generator<'a> {
         }
 
         if let Some(desc) = self.ctx.config.book.description.clone() {
-            self.builder.metadata("description", desc)?;
+            // epub-builder writes the description verbatim too
+            self.builder.metadata("description", package::escape_xml(&desc))?;
         }
 
-        if !self.ctx.config.book.authors.is_empty() {
-            self.builder
-                .metadata("author", self.ctx.config.book.authors.join(", "))?;
+        // only the first author goes through epub-builder, which can't give
+        // several creators distinct ids, the rest are added by `package_patch()`
+        if let Some(author) = self.ctx.config.book.authors.first() {
+            self.builder.metadata("author", author.clone())?;
         }
 
-        self.builder
-            .metadata("generator", env!("CARGO_PKG_NAME"))?;
+        if self.config.emit_generator_metadata {
+            let generator = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+            self.builder.metadata("generator", generator)?;
+        }
 
-        if let Some(lang) = self.ctx.config.book.language.clone() {
-            self.builder.metadata("lang", lang)?;
-        } else {
-            self.builder.metadata("lang", "en")?;
+        let lang = self.language().to_string();
+        self.builder.metadata("lang", lang)?;
+
+        for subject in unique_subjects(&self.config.subjects) {
+            // epub-builder writes subjects into the OPF verbatim
+            self.builder.metadata("subject", package::escape_xml(subject))?;
+        }
+
+        if let Some(ref rights) = self.config.rights {
+            // epub-builder calls `dc:rights` the license, and also writes it verbatim
+            self.builder.metadata("license", package::escape_xml(rights))?;
+        }
+
+        // the rest of the `metadata` table is added by `package_patch()`
+        for (key, value) in self.config.metadata.iter() {
+            if !BUILT_IN_METADATA.contains(&key.as_str()) {
+                continue;
+            }
+            warn!("`metadata.{}` replaces the book's own {}", key, key);
+            if key == "author" || key == "description" {
+                // these accumulate, so clear out the book's first
+                self.builder.metadata(key, "")?;
+            }
+            self.builder.metadata(key, package::escape_xml(value))?;
         }
 
         Ok(())
     }
 
-    pub fn generate<W: Write>(mut self, writer: W) -> Result<(), Error> {
+    /// Collect the package metadata `epub-builder` can't emit by itself.
+    fn package_patch(&self) -> Result<PackagePatch, Error> {
+        let identifier = self.config.book_identifier()?;
+        let mut patch = PackagePatch {
+            identifier_scheme: match self.config.version {
+                EpubVersion::V2 => self.config.identifier_scheme.map(|scheme| scheme.opf_scheme().to_string()),
+                EpubVersion::V3 => None,
+            },
+            identifier,
+            date: self.config.pub_date.clone(),
+            // EPUB 3 requires a last-modified time, to the second and in UTC
+            modified: (self.config.version == EpubVersion::V3)
+                .then(|| chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string()),
+            properties: self.manifest_properties.clone(),
+            page_progression_direction: self.page_progression_direction(),
+            // epub-builder names itself in the navigation unless told otherwise
+            strip_generator: !self.config.emit_generator_metadata,
+            // only books with sections or parts need the NCX's depth fixing
+            ncx_depth: self.book.iter().any(|item| match *item {
+                BookItem::Chapter(ref ch) => chapter_level(ch) > 0,
+                BookItem::PartTitle(_) => true,
+                BookItem::Separator => false,
+            }),
+            ..Default::default()
+        };
+
+        if !self.config.spine_order.is_empty() {
+            if self.config.single_file {
+                warn!("`spine-order` has no effect with `single-file`, which puts every chapter on one page");
+            } else {
+                patch.spine_order = self.reading_order();
+            }
+        }
+
+        // the cover page is in the guide already, but readers which only
+        // look in the guide still need pointing at a cover without one, and
+        // epub-builder leaves untitled pages out of the landmarks
+        match self.cover_page_image() {
+            Some(_) if self.config.version == EpubVersion::V3 => patch.landmarks.push(format!(
+                "<li><a epub:type=\"cover\" href=\"{}\">Cover</a></li>",
+                COVER_PAGE_PATH
+            )),
+            Some(_) => {}
+            None => {
+                if let Some(path) = self.cover_image() {
+                    patch.guide.push(cover_reference(&package_path(&path)));
+                }
+            }
+        }
+
+        // just the cover is laid out at a fixed size, the rest of the book
+        // still reflows
+        if self.cover_page_image().is_some()
+            && self.config.version == EpubVersion::V3
+            && self.config.cover_dimensions()?.is_some()
+        {
+            patch
+                .spine_properties
+                .push((String::from(COVER_PAGE_PATH), String::from("rendition:layout-pre-paginated")));
+        }
+
+        if let Some(ref publisher) = self.config.publisher {
+            patch.metadata.push(format!("<dc:publisher>{}</dc:publisher>", package::escape_xml(publisher)));
+        }
+
+        // an `author` in the `metadata` table replaces all of the book's
+        let book_authors = if self.config.metadata.contains_key("author") {
+            &[][..]
+        } else {
+            &self.ctx.config.book.authors[..]
+        };
+        let authors = book_authors.iter().skip(1).map(|name| (name.as_str(), "aut"));
+        let contributors = self
+            .config
+            .contributors
+            .iter()
+            .map(|contributor| (contributor.name.as_str(), contributor.role.as_str()));
+        // epub-builder's author is "epub-creator-1"
+        for (i, (name, role)) in authors.chain(contributors).enumerate() {
+            patch
+                .metadata
+                .extend(creator_metadata(self.config.version, i + 2, name, role));
+        }
+
+        if let Some(ref series) = self.config.series {
+            let index = self.config.series_index.unwrap_or(1.0);
+            patch.metadata.extend(series_metadata(self.config.version, series, index));
+        }
+
+        if let Some(ref url) = self.config.license_url {
+            match self.config.version {
+                EpubVersion::V2 => warn!("`license-url` is only used by EPUB 3 books"),
+                EpubVersion::V3 => patch.metadata.push(license_link(url)),
+            }
+        }
+
+        if let Some(ref accessibility) = self.config.accessibility {
+            patch
+                .metadata
+                .extend(accessibility_metadata(self.config.version, accessibility, self.has_images()?));
+        }
+
+        for (key, value) in self.config.metadata.iter() {
+            if !BUILT_IN_METADATA.contains(&key.as_str()) {
+                patch.metadata.push(custom_metadata(self.config.version, key, value));
+            }
+        }
+
+        if self.config.version == EpubVersion::V3 {
+            if let Some(refinement) = self.config.identifier_scheme.and_then(|scheme| {
+                identifier_type(scheme, patch.identifier.as_deref().unwrap_or_default())
+            }) {
+                patch.metadata.push(refinement);
+            }
+        }
+
+        Ok(patch)
+    }
+
+    /// Where the chapters are packaged, in the order they're read: the ones
+    /// `spine_order` lists first, then the rest in the book's order.
+    fn reading_order(&self) -> Vec<String> {
+        let natural: Vec<String> = self
+            .book
+            .iter()
+            .filter_map(|item| match *item {
+                BookItem::Chapter(ref ch) => self.chapter_output_path(ch),
+                _ => None,
+            })
+            .collect();
+
+        let mut order = Vec::new();
+        for path in &self.config.spine_order {
+            let href = chapter_href(&validate::normalize(Path::new(path)));
+            if !natural.contains(&href) {
+                warn!("`spine-order` lists {}, which isn't a chapter of the book", path);
+            } else if !order.contains(&href) {
+                order.push(href);
+            }
+        }
+        let rest: Vec<String> = natural.into_iter().filter(|href| !order.contains(href)).collect();
+        order.extend(rest);
+        order
+    }
+
+    /// How many levels of parts and chapters the navigation goes down.
+    fn toc_depth(&self) -> u32 {
+        self.config.toc_depth.map_or(u32::MAX, |depth| depth.max(1))
+    }
+
+    /// Which way the book reads, worked out from its language unless
+    /// `direction` says.
+    fn direction(&self) -> Direction {
+        self.config.direction.resolve(self.language())
+    }
+
+    /// The spine's `page-progression-direction`, which EPUB 2 has no room
+    /// for. Readers assume left to right, so that's only spelled out when
+    /// it's been asked for explicitly.
+    fn page_progression_direction(&self) -> Option<String> {
+        let direction = self.direction();
+        let wanted = direction == Direction::Rtl || self.config.direction == Direction::Ltr;
+
+        match self.config.version {
+            EpubVersion::V3 if wanted => Some(direction.as_str().to_string()),
+            _ => None,
+        }
+    }
+
+    /// Does the book have a cover, or do its chapters have any images?
+    fn has_images(&self) -> Result<bool, Error> {
+        let book = self.packaged_book();
+
+        Ok(self.config.cover_image.is_some()
+            || find_cover_image(&self.src_dir()).is_some()
+            || !self.local_assets()?.is_empty()
+            || !resources::find_remote(&book).is_empty()
+            || (self.config.mermaid && !mermaid::find(&book).is_empty()))
+    }
+
+    /// The directory chapters and their assets are read from.
+    fn src_dir(&self) -> PathBuf {
+        self.ctx.root.join(&self.ctx.config.book.src)
+    }
+
+    /// The book's language, falling back to English when `book.language` is
+    /// missing or empty.
+    fn language(&self) -> &str {
+        self.ctx.config.book.language.as_deref()
+            .filter(|lang| !lang.trim().is_empty())
+            .unwrap_or("en")
+    }
+
+    /// The language a chapter is written in: its `lang` front matter, or else
+    /// the book's.
+    fn chapter_language<'c>(&'c self, front_matter: &'c serde_json::Map<String, serde_json::Value>) -> &'c str {
+        front_matter
+            .get("lang")
+            .and_then(|lang| lang.as_str())
+            .filter(|lang| !lang.trim().is_empty())
+            .unwrap_or_else(|| self.language())
+    }
+
+    /// How far to move a chapter's headings: its `heading_offset` front
+    /// matter, or else the book's.
+    fn heading_offset(&self, front_matter: &serde_json::Map<String, serde_json::Value>) -> i32 {
+        match front_matter.get("heading_offset").and_then(|offset| offset.as_i64()) {
+            Some(offset) => offset.clamp(i32::MIN as i64, i32::MAX as i64) as i32,
+            None => self.config.heading_offset,
+        }
+    }
+
+    /// Write the book to `writer`, or with `dry_run` what would go into it.
+    pub fn generate<W: Write>(mut self, mut writer: W) -> Result<(), Error> {
+        if self.config.dry_run {
+            writer.write_all(self.plan()?.as_bytes())?;
+            return Ok(());
+        }
         info!("Generating the EPUB book");
 
         self.populate_metadata()?;
+        self.transcode_images()?;
+        self.generate_cover_page()?;
+        self.generate_title_page()?;
+        self.generate_description_page()?;
+        if self.config.copyright_position == PagePosition::Front {
+            self.generate_copyright_page()?;
+        }
+        self.add_raw_pages(PagePosition::Front)?;
+        self.generate_toc_page()?;
         self.generate_chapters()?;
+        if self.config.copyright_position == PagePosition::Back {
+            self.generate_copyright_page()?;
+        }
+        self.add_raw_pages(PagePosition::Back)?;
+        self.generate_index_page()?;
 
         self.add_cover_image()?;
         self.embed_stylesheets()?;
         self.additional_assets()?;
         self.additional_resources()?;
-        self.builder.generate(writer)?;
+        self.embed_sources()?;
+        self.template_assets()?;
+        self.embed_fonts()?;
+
+        let mut epub = Vec::new();
+        self.builder.generate(&mut epub)?;
+
+        let patch = self.package_patch()?;
+        let (compression, flatten, layout) =
+            (self.config.compression, self.config.flatten_paths, self.config.directory_layout);
+        if self.config.validate || self.wants_report() {
+            let mut patched = Vec::new();
+            package::write(epub, &mut patched, &patch, compression, flatten, layout)?;
+            if self.config.validate {
+                debug!("Validating the generated book");
+                validate::validate(&patched)?;
+            }
+            if self.wants_report() {
+                self.report(&patched)?;
+            }
+            writer.write_all(&patched)?;
+        } else {
+            package::write(epub, writer, &patch, compression, flatten, layout)?;
+        }
         info!("Generating the EPUB book - DONE !");
         Ok(())
     }
 
+    fn wants_report(&self) -> bool {
+        self.config.report || self.config.report_path.is_some()
+    }
+
+    /// Log a summary of what went into the generated book, and write it to
+    /// the `report_path` if there is one.
+    fn report(&self, epub: &[u8]) -> Result<(), Error> {
+        let title = self.ctx.config.book.title.as_deref().unwrap_or("the book");
+        let report = Report::new(title, epub, self.neighbours.len())?.to_string();
+        info!("{}", report.trim_end());
+
+        if let Some(ref path) = self.config.report_path {
+            debug!("Writing the report to {}", path.display());
+            std::fs::write(path, report)?;
+        }
+        Ok(())
+    }
+
+    /// Add a chapter and those nested under it to the `plan`'s list of
+    /// `chapters` and its `toc`, `offset` levels down, the way
+    /// `generate_chapters` packages them and `add_chapter` lists them.
+    fn plan_chapter<'c>(&self, ch: &'c Chapter, offset: i32, chapters: &mut Vec<(String, &'c str)>, toc: &mut String) {
+        if let Some(path) = self.chapter_output_path(ch) {
+            chapters.push((path, ch.name.as_str()));
+            let level = chapter_level(ch) + offset;
+            if (level as u32) < self.toc_depth() && !self.is_toc_hidden(ch) {
+                toc.push_str(&format!("{}{}\n", "  ".repeat(level as usize + 1), self.chapter_title(ch)));
+            }
+        }
+
+        for sub_item in &ch.sub_items {
+            if let BookItem::Chapter(ref sub_ch) = *sub_item {
+                self.plan_chapter(sub_ch, offset, chapters, toc);
+            }
+        }
+    }
+
+    /// What would go into the book, as `dry_run` writes it: the chapters in
+    /// the order they're read, the contents, and the assets they use. Links
+    /// and alt text are checked as they would be for a real build, but no
+    /// chapter is rendered.
+    pub fn plan(&self) -> Result<String, Error> {
+        self.check_links()?;
+        self.check_alt_text()?;
+
+        let title = self.ctx.config.book.title.as_deref().unwrap_or("the book");
+        let mut chapters = Vec::new();
+        let mut toc = String::new();
+        for item in top_level_items(&self.book.sections) {
+            match item {
+                TopLevelItem::Item(BookItem::Chapter(ref ch)) => self.plan_chapter(ch, 0, &mut chapters, &mut toc),
+                TopLevelItem::Item(_) => {}
+                TopLevelItem::Part(part) => {
+                    // parts without any chapters to package aren't in the book
+                    let start = chapters.len();
+                    let mut part_toc = String::new();
+                    for item in part.items {
+                        if let BookItem::Chapter(ref ch) = *item {
+                            self.plan_chapter(ch, 1, &mut chapters, &mut part_toc);
+                        }
+                    }
+                    if chapters.len() > start {
+                        toc.push_str(&format!("  {}\n{}", part.title, part_toc));
+                    }
+                }
+            }
+        }
+        if !self.config.spine_order.is_empty() && !self.config.single_file {
+            let order = self.reading_order();
+            chapters.sort_by_key(|(path, _)| order.iter().position(|href| href == path));
+        }
+        let mut spine = String::new();
+        for (number, (path, name)) in chapters.iter().enumerate() {
+            spine.push_str(&format!("  {}. {} ({})\n", number + 1, path, name));
+        }
+
+        let local_assets = self.local_assets()?;
+        let duplicates = if self.config.dedup_assets {
+            resources::duplicates(&local_assets)?
+        } else {
+            HashMap::new()
+        };
+        let mut assets = String::new();
+        for asset in &local_assets {
+            let name = package_path(&asset.filename);
+            match duplicates.get(&asset.filename) {
+                Some(first) => {
+                    assets.push_str(&format!("  {} (the same as {}, not packaged)\n", name, package_path(first)))
+                }
+                None => assets.push_str(&format!("  {} ({})\n", name, asset.mimetype)),
+            }
+        }
+        for url in resources::find_remote(&self.packaged_book()) {
+            if self.config.download_remote_images {
+                let name = package_path(&resources::remote_filename(&url));
+                assets.push_str(&format!("  {} (downloaded as {})\n", url, name));
+            } else {
+                assets.push_str(&format!("  {} (remote, not packaged)\n", url));
+            }
+        }
+
+        Ok(format!(
+            "Dry run of {}, nothing is written\nChapters, in reading order:\n{}Table of contents:\n{}Assets:\n{}",
+            title, spine, toc, assets
+        ))
+    }
+
     fn generate_chapters(&mut self) -> Result<(), Error> {
+        self.check_links()?;
+        self.check_alt_text()?;
         debug!("Rendering Chapters");
 
-        for item in &self.ctx.book.sections {
-            if let BookItem::Chapter(ref ch) = *item {
-                trace!("Adding chapter \"{}\"", ch);
-                self.add_chapter(ch)?;
+        let mut chapters = Vec::new();
+        let mut parts = Vec::new();
+        for item in top_level_items(&self.book.sections) {
+            match item {
+                TopLevelItem::Item(BookItem::Chapter(ref ch)) => self.collect_chapters(ch, &mut chapters),
+                TopLevelItem::Item(_) => {}
+                TopLevelItem::Part(part) => {
+                    let start = chapters.len();
+                    for item in part.items {
+                        if let BookItem::Chapter(ref ch) = *item {
+                            self.collect_chapters(ch, &mut chapters);
+                        }
+                    }
+                    if chapters.len() == start {
+                        debug!("Skipping part {:?}, which has no chapters", part.title);
+                        continue;
+                    }
+                    parts.push(PartChapters {
+                        part,
+                        chapters: start..chapters.len(),
+                    });
+                }
+            }
+        }
+
+        self.neighbours = chapters
+            .iter()
+            .enumerate()
+            .map(|(i, (_, path))| {
+                let title = |ix: Option<usize>| ix.and_then(|ix| chapters.get(ix)).map(|(ch, _)| ch.name.clone());
+                let neighbours = Neighbours {
+                    prev_title: title(i.checked_sub(1)),
+                    next_title: title(Some(i + 1)),
+                };
+                (path.clone(), neighbours)
+            })
+            .collect();
+
+        if self.config.number_figures {
+            // chapters are rendered in parallel, so they need to know where
+            // their numbering starts beforehand
+            let mut next_figure = 1;
+            for (ch, path) in chapters.iter() {
+                self.first_figures.insert(path.clone(), next_figure);
+                next_figure += figures::count(front_matter::split(self.chapter_content(ch)).1, self.config.cmark_options());
+            }
+        }
+
+        if self.config.generate_index {
+            for (ch, path) in chapters.iter() {
+                let file = if self.config.single_file { SINGLE_FILE_PATH } else { path.as_str() };
+                let content = front_matter::split(self.chapter_content(ch)).1;
+                let terms = book_index::terms(content, &source_path(ch), self.config.cmark_options());
+                self.index_entries.extend(terms.into_iter().map(|(term, id)| IndexEntry {
+                    term,
+                    href: format!("{}#{}", file, id),
+                    chapter: ch.name.clone(),
+                }));
             }
         }
 
+        if self.config.dedup_assets {
+            // chapters are pointed at the first copy as they're rendered
+            let assets = self.local_assets()?;
+            self.duplicate_assets = resources::duplicates(&assets)?
+                .iter()
+                .map(|(duplicate, first)| (package_path(duplicate), package_path(first)))
+                .collect();
+        }
+        if self.config.missing_asset_policy == MissingAssetPolicy::Placeholder {
+            for (asset, _) in resources::find_missing(&self.packaged_book(), &self.src_dir())? {
+                if asset.mimetype.type_() == mime_guess::mime::IMAGE {
+                    self.duplicate_assets
+                        .insert(package_path(&asset.filename), String::from(resources::PLACEHOLDER_IMAGE_PATH));
+                }
+            }
+        }
+        // converted images are packaged as PNGs, so links to them and their
+        // copies go to those
+        for target in self.duplicate_assets.values_mut() {
+            if let Some((png, _)) = self.transcoded.get(target) {
+                *target = png.clone();
+            }
+        }
+        for (name, (png, _)) in &self.transcoded {
+            self.duplicate_assets.entry(name.clone()).or_insert_with(|| png.clone());
+        }
+
+        let cache = match self.config.cache_dir {
+            Some(ref dir) => Some(RenderCache::open(dir, self.cache_fingerprint()?)?),
+            None => None,
+        };
+
+        // rendering is independent for each chapter, but the builder isn't
+        // thread-safe so the results are added in book order afterwards
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(self.config.render_threads)
+            .build()?;
+        let anchors = if self.config.single_file { Some(&self.anchors) } else { None };
+        let rendered = pool.install(|| {
+            chapters
+                .par_iter()
+                .enumerate()
+                .map(|(index, &(ch, _))| {
+                    self.progress.chapter_started(&ch.name, index, chapters.len());
+                    self.render_chapter_cached(ch, anchors, cache.as_ref())
+                        .map(|html| self.post_process(ch, html))
+                        .map(|html| self.minify(html))
+                })
+                .collect::<Result<Vec<String>, RenderError>>()
+        })?;
+
+        if self.config.single_file {
+            if chapters.iter().any(|(ch, _)| self.chapter_stylesheet(ch).is_some()) {
+                warn!("Chapters' own stylesheets aren't used with `single-file`");
+            }
+            return self.add_single_file(chapters, rendered, &parts);
+        }
+
+        for (i, ((ch, path), rendered)) in chapters.into_iter().zip(rendered).enumerate() {
+            let part = parts.iter().find(|part| part.chapters.contains(&i));
+            let level = chapter_level(ch) + part.map_or(0, |_| 1);
+            let mut heading = None;
+            if let Some(part) = part.filter(|part| part.chapters.start == i) {
+                if self.config.emit_part_pages {
+                    self.add_part_page(&part.part)?;
+                } else {
+                    heading = Some(part.part.title);
+                }
+            }
+            self.add_chapter_stylesheet(ch)?;
+            self.add_chapter(ch, path, rendered, level, heading)?;
+        }
+
         Ok(())
     }
 
-    fn add_chapter(&mut self, ch: &Chapter) -> Result<(), Error> {
-        let rendered = self.render_chapter(ch)?;
+    /// Put every chapter into one file, with an entry in the navigation for
+    /// each chapter's section.
+    fn add_single_file(
+        &mut self,
+        chapters: Vec<(&Chapter, String)>,
+        sections: Vec<String>,
+        parts: &[PartChapters],
+    ) -> Result<(), Error> {
+        debug!("Combining {} chapters into {}", chapters.len(), SINGLE_FILE_PATH);
 
-        let content_path = ch.path.as_ref()
-            .ok_or_else(|| Error::ContentFileNotFound(format!("Content file was not found for Chapter {}", ch.name)))?;
-        trace!("add a chapter {:?} by a path = {:?}", &ch.name, content_path);
-        let path = content_path.with_extension("html").display().to_string();
-        let mut content = EpubContent::new(path, rendered.as_bytes()).title(format!("{}", ch));
+        let title = self
+            .ctx
+            .config
+            .book
+            .title
+            .clone()
+            .or_else(|| chapters.first().map(|(ch, _)| ch.name.clone()))
+            .unwrap_or_default();
+        let mut body = String::new();
+        let mut toc = Vec::new();
+        for (i, ((ch, path), section)) in chapters.into_iter().zip(sections).enumerate() {
+            let href = format!("{}#{}", SINGLE_FILE_PATH, self.section_anchor(&path));
+            let part = parts.iter().find(|part| part.chapters.contains(&i));
 
-        let level = ch.number.as_ref().map(|n| n.len() as i32 - 1).unwrap_or(0);
-        content = content.level(level);
+            if let Some(part) = part.filter(|part| part.chapters.start == i).map(|part| &part.part) {
+                let part_href = if self.config.emit_part_pages {
+                    let anchor = part_anchor(part.number);
+                    body.push_str(&format!(
+                        "<div class=\"part\" id=\"{}\" style=\"{}\">\n{}</div>\n",
+                        anchor,
+                        PAGE_BREAK_STYLE,
+                        part_heading(part.title)
+                    ));
+                    format!("{}#{}", SINGLE_FILE_PATH, anchor)
+                } else {
+                    href.clone()
+                };
+                toc.push(TocElement::new(part_href, part.title).level(1));
+            }
+
+            body.push_str(&section);
+            // the sections are a level below the book's own entry
+            let level = chapter_level(ch) + 1 + part.map_or(0, |_| 1);
+            if (level as u32) <= self.toc_depth() && !self.is_toc_hidden(ch) {
+                toc.push(TocElement::new(href, self.chapter_title(ch)).level(level));
+            }
+        }
+
+        let ctx = json!({
+            "title": title,
+            "body": body,
+            "stylesheet": "stylesheet.css",
+            "lang": self.language(),
+        });
+        let rendered = self.render_template("index", "", ctx)?;
+        self.record_properties(SINGLE_FILE_PATH, &rendered);
 
+        let mut content = EpubContent::new(SINGLE_FILE_PATH, rendered.as_bytes())
+            .title(title)
+            .level(0);
+        for element in toc {
+            content.toc.add(element);
+        }
         self.builder.add_content(content)?;
 
-        // second pass to actually add the sub-chapters
+        Ok(())
+    }
+
+    /// Look for links to chapters which aren't part of the book, warning
+    /// about them or failing when `fail_on_broken_links` is set.
+    fn check_links(&self) -> Result<(), Error> {
+        let chapters: Vec<&Chapter> = self
+            .book
+            .iter()
+            .filter_map(|item| match *item {
+                BookItem::Chapter(ref ch) => Some(ch),
+                _ => None,
+            })
+            .collect();
+        let packaged: HashSet<PathBuf> = chapters
+            .iter()
+            .filter_map(|ch| self.chapter_output_path(ch))
+            .map(PathBuf::from)
+            .collect();
+
+        let mut broken = Vec::new();
+        for ch in chapters {
+            let source = match ch.path {
+                Some(ref path) => path,
+                None => continue,
+            };
+            let dir = source.parent().unwrap_or_else(|| Path::new(""));
+            let (_, content) = front_matter::split(self.chapter_content(ch));
+
+            for (dest, target) in chapter_links(content, self.config.cmark_options()) {
+                if escapes_book(dir, &dest) {
+                    warn!("{} links to {}, which is outside the book", source.display(), dest);
+                    broken.push(format!("{} links to {}", source.display(), dest));
+                    continue;
+                }
+                if !packaged.contains(&validate::normalize(&dir.join(target))) {
+                    warn!("{} links to {}, which isn't a chapter of the book", source.display(), dest);
+                    broken.push(format!("{} links to {}", source.display(), dest));
+                }
+            }
+        }
+
+        if self.config.fail_on_broken_links && !broken.is_empty() {
+            return Err(Error::BrokenLinks(broken.join(", ")));
+        }
+
+        Ok(())
+    }
+
+    /// Look for images without alt text, warning about them or failing when
+    /// `fail_on_missing_alt` is set.
+    fn check_alt_text(&self) -> Result<(), Error> {
+        let mut missing = Vec::new();
+        for item in self.book.iter() {
+            // chapters which aren't packaged can't let readers down
+            let (ch, source) = match *item {
+                BookItem::Chapter(ref ch) => match ch.path {
+                    Some(ref path) if self.chapter_output_path(ch).is_some() => (ch, path),
+                    _ => continue,
+                },
+                _ => continue,
+            };
+            let (_, content) = front_matter::split(self.chapter_content(ch));
+
+            for src in images_missing_alt(content, self.config.cmark_options()) {
+                warn!("The image {} in {} has no alt text", src, source.display());
+                missing.push(format!("{} in {}", src, source.display()));
+            }
+        }
+
+        if self.config.fail_on_missing_alt && !missing.is_empty() {
+            return Err(Error::MissingAltText(missing.join(", ")));
+        }
+
+        Ok(())
+    }
+
+    /// Render a chapter, reusing the XHTML from the last build when it hasn't
+    /// changed. Post-processors aren't cached, they run every time.
+    fn render_chapter_cached(
+        &self,
+        ch: &Chapter,
+        single_file: Option<&BTreeMap<String, String>>,
+        cache: Option<&RenderCache>,
+    ) -> Result<String, RenderError> {
+        let render = |ch: &Chapter| match single_file {
+            Some(anchors) => Ok(self.render_chapter_section(ch, anchors)),
+            None => self.render_chapter(ch),
+        };
+        let cache = match cache {
+            Some(cache) => cache,
+            None => return render(ch),
+        };
+
+        // the template is also told about the chapters either side, figures
+        // are numbered from the previous chapters' and with `single_file`
+        // links go to the sections of the chapters in the book
+        let key = RenderCache::key(
+            ch,
+            &(self.chapter_neighbours(ch), self.first_figure(ch), self.chapter_content(ch), single_file),
+        );
+        if let Some(rendered) = cache.get(key) {
+            trace!("Using the cached rendering of {:?}", ch.name);
+            return Ok(rendered);
+        }
+
+        let rendered = render(ch)?;
+        cache.put(key, &rendered);
+        Ok(rendered)
+    }
+
+    /// A hash of everything besides the chapter itself which affects how a
+    /// chapter is rendered.
+    fn cache_fingerprint(&self) -> Result<u64, Error> {
+        let mut hasher = DefaultHasher::new();
+        env!("CARGO_PKG_VERSION").hash(&mut hasher);
+        DEFAULT_CSS.hash(&mut hasher);
+        self.config.template()?.hash(&mut hasher);
+        serde_json::to_string(&self.config)
+            .map_err(|e| Error::EpubDocCreate(e.to_string()))?
+            .hash(&mut hasher);
+        self.language().hash(&mut hasher);
+        self.duplicate_assets.hash(&mut hasher);
+        self.wikilinks.hash(&mut hasher);
+        Ok(hasher.finish())
+    }
+
+    /// Minify a rendered chapter, if `minify` is on.
+    fn minify(&self, html: String) -> String {
+        if self.config.minify {
+            minify::xhtml(&html)
+        } else {
+            html
+        }
+    }
+
+    fn post_process(&self, ch: &Chapter, html: String) -> String {
+        self.post_processors
+            .iter()
+            .fold(html, |html, post_processor| post_processor.process(ch, html))
+    }
+
+    /// Collect every chapter which gets packaged, along with where it's
+    /// packaged, in the order they appear in the book.
+    fn collect_chapters(&self, ch: &'a Chapter, chapters: &mut Vec<(&'a Chapter, String)>) {
+        match self.chapter_output_path(ch) {
+            Some(path) => chapters.push((ch, path)),
+            None => debug!("Skipping draft chapter {:?}", ch.name),
+        }
+
         for sub_item in &ch.sub_items {
             if let BookItem::Chapter(ref sub_ch) = *sub_item {
-                trace!("add sub-item = {:?}", sub_ch.name);
-                self.add_chapter(sub_ch)?;
+                self.collect_chapters(sub_ch, chapters);
             }
         }
+    }
+
+    /// Render the title page which opens the book.
+    fn generate_title_page(&mut self) -> Result<(), Error> {
+        if !self.config.title_page {
+            return Ok(());
+        }
+        debug!("Rendering the title page");
+
+        let book = &self.ctx.config.book;
+        let ctx = json!({
+            "title": book.title.clone().unwrap_or_default(),
+            "authors": book.authors.join(", "),
+            "description": book.description.clone().unwrap_or_default(),
+            "stylesheet": "stylesheet.css",
+            "lang": self.language(),
+        });
+        let rendered = self.render_template("title_page", "", ctx)?;
+
+        let content = EpubContent::new(TITLE_PAGE_PATH, rendered.as_bytes())
+            .reftype(ReferenceType::TitlePage);
+        self.builder.add_content(content)?;
 
         Ok(())
     }
 
-    pub fn new_cmark_parser(text: &str) -> Parser<'_, '_> {
-        let mut opts = Options::empty();
-        opts.insert(Options::ENABLE_TABLES);
-        opts.insert(Options::ENABLE_FOOTNOTES);
-        opts.insert(Options::ENABLE_STRIKETHROUGH);
-        opts.insert(Options::ENABLE_TASKLISTS);
-        Parser::new_ext(text, opts)
+    /// Render the configured copyright page like any other chapter.
+    fn generate_copyright_page(&mut self) -> Result<(), Error> {
+        let path = match self.config.copyright_page {
+            Some(ref path) => path.clone(),
+            None => return Ok(()),
+        };
+        debug!("Rendering the copyright page from {}", path.display());
+
+        let full_path = self
+            .resolve_path(&path)
+            .ok_or_else(|| Error::PageNotFound(path.clone()))?;
+        let content = std::fs::read_to_string(&full_path).map_err(|_| Error::PageNotFound(full_path.clone()))?;
+
+        let ch = Chapter::new(COPYRIGHT_PAGE_TITLE, content, COPYRIGHT_PAGE_SOURCE, Vec::new());
+        let rendered = self.render_chapter(&ch)?;
+        self.add_chapter_stylesheet(&ch)?;
+
+        let href = chapter_href(Path::new(COPYRIGHT_PAGE_SOURCE));
+        self.record_properties(&href, &rendered);
+        let mut content = EpubContent::new(href, rendered.as_bytes()).reftype(ReferenceType::Copyright);
+        // pages without a title are left out of the navigation
+        if self.config.copyright_in_toc {
+            content = content.title(COPYRIGHT_PAGE_TITLE);
+        }
+        self.builder.add_content(content)?;
+
+        Ok(())
     }
 
-    /// Render the chapter into its fully formed HTML representation.
-    fn render_chapter(&self, ch: &Chapter) -> Result<String, RenderError> {
-        let mut body = String::new();
-        let p = Generator::new_cmark_parser(&ch.content);
-        let mut converter = EventQuoteConverter::new(self.config.curly_quotes);
-        let mut comment_remover = EventHtmlConverter::new(self.config.remove_html_comments);
-        let mut rust_filter = RustCodeBlockFilter::new(self.config.enable_rust_codeblock_filter);
-        let events = p
-            .map(|event| converter.convert(event))
-            .map(|event| comment_remover.convert(event))
-            .map(|event| rust_filter.convert(event));
+    /// Render the book's description, which can be markdown, as a page of its
+    /// own with `description_as_page`.
+    fn generate_description_page(&mut self) -> Result<(), Error> {
+        let description = match self.ctx.config.book.description {
+            Some(ref description) if self.config.description_as_page && !description.trim().is_empty() => description,
+            _ => return Ok(()),
+        };
+        let mut taken: HashSet<String> = self.chapter_hrefs().into_iter().collect();
+        let path = flatten::untaken(chapter_href(Path::new(DESCRIPTION_PAGE_SOURCE)), &mut taken);
+        let source = Path::new(&path).with_extension("md");
+        debug!("Rendering the description as {}", source.display());
 
-        html::push_html(&mut body, events);
+        let title = self.config.description_page_title.clone();
+        let content = format!("# {}\n\n{}\n", title, description);
+        let ch = Chapter::new(&title, content, source, Vec::new());
+        let rendered = self.render_chapter(&ch)?;
+
+        self.record_properties(&path, &rendered);
+        let content = EpubContent::new(path, rendered.as_bytes()).title(title);
+        self.builder.add_content(content)?;
 
-        let css_path = ch.path.as_ref()
-            .ok_or_else(|| RenderError::new(format!("No CSS found by a path =  = {:?}", ch.path)))?;
+        Ok(())
+    }
 
-        let stylesheet_path = css_path
-            .parent()
-            .expect("All chapters have a parent")
-            .components()
-            .map(|_| "..")
-            .chain(iter::once("stylesheet.css"))
-            .collect::<Vec<_>>()
-            .join("/");
+    /// Add the `raw_pages` which go at `position`, checking each is
+    /// well-formed first.
+    fn add_raw_pages(&mut self, position: PagePosition) -> Result<(), Error> {
+        let pages: Vec<_> = self
+            .config
+            .raw_pages
+            .iter()
+            .filter(|page| page.position == position)
+            .cloned()
+            .collect();
 
-        let ctx = json!({ "title": ch.name, "body": body, "stylesheet": stylesheet_path });
+        for page in pages {
+            debug!("Adding the raw page {}", page.path.display());
+            let full_path = self
+                .resolve_path(&page.path)
+                .ok_or_else(|| Error::PageNotFound(page.path.clone()))?;
+            let content = std::fs::read_to_string(&full_path).map_err(|_| Error::PageNotFound(full_path.clone()))?;
+            validate::well_formed(&content).map_err(|e| Error::MalformedPage(full_path.clone(), e))?;
 
-        self.hbs.render("index", &ctx)
+            let href = package_path(&validate::normalize(&page.path));
+            self.record_properties(&href, &content);
+            let mut content = EpubContent::new(href, content.as_bytes());
+            // pages without a title are left out of the navigation
+            if let Some(title) = page.title {
+                content = content.title(title);
+            }
+            self.builder.add_content(content)?;
+        }
+
+        Ok(())
+    }
+
+    /// Render the index of the terms marked in the chapters.
+    fn generate_index_page(&mut self) -> Result<(), Error> {
+        if !self.config.generate_index {
+            return Ok(());
+        }
+        if self.index_entries.is_empty() {
+            warn!("`generate-index` is set, but no chapter marks any terms with `<!-- index: term -->`");
+            return Ok(());
+        }
+        debug!("Rendering the index of {} terms", self.index_entries.len());
+
+        let ctx = json!({
+            "title": INDEX_PAGE_TITLE,
+            "body": book_index::render(INDEX_PAGE_TITLE, &self.index_entries),
+            "stylesheet": "stylesheet.css",
+            "lang": self.language(),
+        });
+        let rendered = self.render_template("index", "", ctx)?;
+
+        let content = EpubContent::new(INDEX_PAGE_PATH, rendered.as_bytes())
+            .title(INDEX_PAGE_TITLE)
+            .reftype(ReferenceType::Index);
+        self.builder.add_content(content)?;
+
+        Ok(())
+    }
+
+    /// Render an inline contents page which links to every chapter.
+    fn generate_toc_page(&mut self) -> Result<(), Error> {
+        if !self.config.generate_toc {
+            return Ok(());
+        }
+        debug!("Rendering the table of contents page");
+
+        let mut body = format!("<h1>{}</h1>\n", TOC_PAGE_TITLE);
+        self.render_toc_list(&self.book.sections, self.toc_depth(), &mut body);
+
+        let ctx = json!({
+            "title": TOC_PAGE_TITLE,
+            "body": body,
+            "stylesheet": "stylesheet.css",
+            "lang": self.language(),
+        });
+        let rendered = self.render_template("index", "", ctx)?;
+
+        let content = EpubContent::new(TOC_PAGE_PATH, rendered.as_bytes())
+            .title(TOC_PAGE_TITLE)
+            .level(0);
+        self.builder.add_content(content)?;
+
+        Ok(())
+    }
+
+    /// Write the chapters in `items` as a nested `<ol>`, `depth` levels deep.
+    fn render_toc_list(&self, items: &[BookItem], depth: u32, out: &mut String) {
+        if depth == 0 {
+            return;
+        }
+        let mut list = String::new();
+        self.render_toc_items(items, depth, &mut list);
+        if list.is_empty() {
+            return;
+        }
+
+        out.push_str("<ol class=\"toc\">\n");
+        out.push_str(&list);
+        out.push_str("</ol>\n");
+    }
+
+    /// Write a `<li>` for each of the chapters and parts in `items`.
+    fn render_toc_items(&self, items: &[BookItem], depth: u32, out: &mut String) {
+        for item in top_level_items(items) {
+            let ch = match item {
+                TopLevelItem::Item(BookItem::Chapter(ref ch)) => ch,
+                TopLevelItem::Item(_) => continue,
+                TopLevelItem::Part(part) => {
+                    self.render_toc_part(&part, depth, out);
+                    continue;
+                }
+            };
+            if self.is_excluded(ch) {
+                // sub-chapters which were included take the chapter's place
+                self.render_toc_items(&ch.sub_items, depth, out);
+                continue;
+            }
+            if self.is_toc_hidden(ch) {
+                continue;
+            }
+
+            let title = package::escape_xml(&self.chapter_title(ch));
+            match self.chapter_link(ch) {
+                Some(href) => {
+                    let href = package::escape_xml(&href);
+                    out.push_str(&format!("<li><a href=\"{}\">{}</a>", href, title));
+                }
+                None => out.push_str(&format!("<li>{}", title)),
+            }
+            out.push('\n');
+            self.render_toc_list(&ch.sub_items, depth - 1, out);
+            out.push_str("</li>\n");
+        }
+    }
+
+    /// Write a `<li>` for a part, with its chapters nested inside. Parts
+    /// whose chapters have all been left out are left out too.
+    fn render_toc_part(&self, part: &Part, depth: u32, out: &mut String) {
+        let mut list = String::new();
+        self.render_toc_list(part.items, depth - 1, &mut list);
+        let packaged = part.items.iter().any(|item| match *item {
+            BookItem::Chapter(ref ch) => self.is_packaged(ch),
+            _ => false,
+        });
+        // when the chapters are too deep to list, the part still is
+        if list.is_empty() && !(depth == 1 && packaged) {
+            return;
+        }
+
+        let title = package::escape_xml(part.title);
+        match self.part_link(part).filter(|_| packaged) {
+            Some(href) => {
+                let href = package::escape_xml(&href);
+                out.push_str(&format!("<li class=\"part\"><a href=\"{}\">{}</a>\n", href, title));
+            }
+            None => out.push_str(&format!("<li class=\"part\">{}\n", title)),
+        }
+        out.push_str(&list);
+        out.push_str("</li>\n");
+    }
+
+    /// Whether a chapter, or any chapter under it, ends up in the book.
+    fn is_packaged(&self, ch: &Chapter) -> bool {
+        self.chapter_output_path(ch).is_some()
+            || ch.sub_items.iter().any(|item| match *item {
+                BookItem::Chapter(ref sub_ch) => self.is_packaged(sub_ch),
+                _ => false,
+            })
+    }
+
+    /// Where a chapter is packaged, or `None` for draft chapters which are
+    /// being skipped and chapters which have been excluded.
+    fn chapter_output_path(&self, ch: &Chapter) -> Option<String> {
+        let path = match ch.path {
+            Some(ref path) => chapter_href(path),
+            None if self.config.skip_drafts => return None,
+            None => draft_href(ch),
+        };
+
+        if self.excluded.contains(&path) {
+            None
+        } else {
+            Some(path)
+        }
+    }
+
+    /// Where each of the book's chapters is packaged, in reading order.
+    fn chapter_hrefs(&self) -> Vec<String> {
+        self.book
+            .iter()
+            .filter_map(|item| match *item {
+                BookItem::Chapter(ref ch) => self.chapter_output_path(ch),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn first_figure(&self, ch: &Chapter) -> usize {
+        self.chapter_output_path(ch)
+            .and_then(|path| self.first_figures.get(&path).copied())
+            .unwrap_or(1)
+    }
+
+    fn chapter_neighbours(&self, ch: &Chapter) -> Neighbours {
+        self.chapter_output_path(ch)
+            .and_then(|path| self.neighbours.get(&path).cloned())
+            .unwrap_or_default()
+    }
+
+    /// Where links to a chapter from outside its own file should go.
+    fn chapter_link(&self, ch: &Chapter) -> Option<String> {
+        let path = self.chapter_output_path(ch)?;
+
+        if self.config.single_file {
+            Some(format!("{}#{}", SINGLE_FILE_PATH, self.section_anchor(&path)))
+        } else {
+            Some(path)
+        }
+    }
+
+    /// The id of the section a chapter packaged at `href` gets with
+    /// `single_file`.
+    fn section_anchor(&self, href: &str) -> String {
+        self.anchors.get(href).cloned().unwrap_or_else(|| chapter_anchor(href))
+    }
+
+    fn is_excluded(&self, ch: &Chapter) -> bool {
+        let path = match ch.path {
+            Some(ref path) => chapter_href(path),
+            None => draft_href(ch),
+        };
+        self.excluded.contains(&path)
+    }
+
+    /// Is the chapter kept out of the navigation by `toc_hidden`?
+    fn is_toc_hidden(&self, ch: &Chapter) -> bool {
+        ch.path.as_ref().is_some_and(|path| self.toc_hidden.contains(&chapter_href(path)))
+    }
+
+    /// The markdown of a chapter, with any files it includes spliced in.
+    fn chapter_content<'c>(&'c self, ch: &'c Chapter) -> &'c str {
+        let included = ch.path.as_ref().and_then(|path| self.included.get(&package_path(path)));
+        included.map_or(&ch.content, |content| content)
+    }
+
+    /// The book with only the chapters which get packaged, for finding the
+    /// assets they use, and their includes spliced in.
+    fn packaged_book(&self) -> Cow<'a, Book> {
+        if self.excluded.is_empty() && self.included.is_empty() {
+            return Cow::Borrowed(self.book);
+        }
+
+        let mut chapters = Vec::new();
+        for item in &self.book.sections {
+            if let BookItem::Chapter(ref ch) = *item {
+                self.collect_chapters(ch, &mut chapters);
+            }
+        }
+
+        let mut book = Book::new();
+        for (ch, _) in chapters {
+            let mut ch = ch.clone();
+            ch.content = self.chapter_content(&ch).to_string();
+            ch.sub_items.clear();
+            book.push_item(ch);
+        }
+        Cow::Owned(book)
+    }
+
+    /// The title used for a chapter in the navigation.
+    fn chapter_title(&self, ch: &Chapter) -> String {
+        let number = match ch.number {
+            Some(ref number) if !self.config.no_section_label => number,
+            _ => return ch.name.clone(),
+        };
+        if self.config.chapter_title_template.is_none() {
+            return format!("{}", ch);
+        }
+
+        let number = number.iter().map(|n| n.to_string()).collect::<Vec<_>>().join(".");
+        let data = json!({ "number": number, "name": ch.name });
+        match self.titles.render("chapter_title", &data) {
+            Ok(title) => title.trim().to_string(),
+            Err(e) => {
+                warn!("Unable to render the title of {}: {}", ch.name, e);
+                format!("{}", ch)
+            }
+        }
+    }
+
+    /// Add a chapter at `level` in the navigation. The first chapter of a
+    /// part without a page of its own also carries the part's `heading`.
+    fn add_chapter(
+        &mut self,
+        ch: &Chapter,
+        path: String,
+        rendered: String,
+        level: i32,
+        heading: Option<&str>,
+    ) -> Result<(), Error> {
+        trace!("add a chapter {:?} by a path = {:?}", &ch.name, path);
+        self.record_properties(&path, &rendered);
+        let title = self.chapter_title(ch);
+        // chapters nested deeper than `toc_depth` are left out of the navigation
+        let in_toc = (level as u32) < self.toc_depth() && !self.is_toc_hidden(ch);
+        let nav_title = if in_toc { title.clone() } else { String::new() };
+        let mut content = EpubContent::new(path.clone(), rendered.as_bytes()).title(nav_title);
+
+        content = content.level(level);
+        if let Some(heading) = heading {
+            content.toc = TocElement::new(path.clone(), heading).level(level - 1);
+            if in_toc {
+                content.toc = content.toc.child(TocElement::new(path, title).level(level));
+            }
+        }
+
+        self.builder.add_content(content)?;
+
+        Ok(())
+    }
+
+    /// Add the page dividing a part of the book from the rest.
+    fn add_part_page(&mut self, part: &Part) -> Result<(), Error> {
+        let path = part_href(part.number);
+        debug!("Rendering part {:?} as {}", part.title, path);
+
+        let ctx = json!({
+            "title": part.title,
+            "body": part_heading(part.title),
+            "stylesheet": "stylesheet.css",
+            "lang": self.language(),
+            "class": "part",
+        });
+        let rendered = self.render_template("index", "", ctx)?;
+
+        let content = EpubContent::new(path, rendered.as_bytes()).title(part.title).level(0);
+        self.builder.add_content(content)?;
+
+        Ok(())
+    }
+
+    /// Where the navigation should take readers for a part, if anywhere.
+    fn part_link(&self, part: &Part) -> Option<String> {
+        if !self.config.emit_part_pages {
+            None
+        } else if self.config.single_file {
+            Some(format!("{}#{}", SINGLE_FILE_PATH, part_anchor(part.number)))
+        } else {
+            Some(part_href(part.number))
+        }
+    }
+
+    /// EPUB 3 content documents must declare any MathML or SVG they use.
+    fn record_properties(&mut self, path: &str, rendered: &str) {
+        if self.config.version == EpubVersion::V3 {
+            if rendered.contains("<math") {
+                self.manifest_properties.push((path.to_string(), String::from("mathml")));
+            }
+            if rendered.contains("<svg") {
+                self.manifest_properties.push((path.to_string(), String::from("svg")));
+            }
+        }
+    }
+
+    /// A parser with the default `markdown_options`.
+    pub fn new_cmark_parser(text: &str) -> Parser<'_, '_> {
+        Parser::new_ext(text, MarkdownOption::options(MarkdownOption::DEFAULT))
+    }
+
+    /// A parser for chapters, with the configured `markdown_options`.
+    fn cmark_parser<'t>(&self, text: &'t str) -> Parser<'t, 't> {
+        Parser::new_ext(text, self.config.cmark_options())
+    }
+
+    /// Render the chapter into its fully formed HTML representation.
+    fn render_chapter(&self, ch: &Chapter) -> Result<String, RenderError> {
+        let chapter_path = source_path(ch);
+        let root = root_prefix(&chapter_path);
+
+        let (front_matter, content) = front_matter::split(self.chapter_content(ch));
+        let class = front_matter.get("class").and_then(|class| class.as_str());
+
+        let heading_offset = self.heading_offset(&front_matter);
+        let mut body = self.render_body(ch, content, &chapter_path, heading_offset, None);
+
+        let is_top_level = chapter_level(ch) == 0;
+        if (is_top_level && self.config.page_break_on_chapter)
+            || (!is_top_level && self.config.page_break_on_section)
+        {
+            body = format!("<div class=\"page-break\" style=\"{}\">\n{}</div>\n", PAGE_BREAK_STYLE, body);
+        }
+
+        let stylesheet_path = format!("{}stylesheet.css", root);
+        let chapter_stylesheet = self
+            .chapter_stylesheet(ch)
+            .map(|path| format!("{}{}", root, stylesheet_href(&path)));
+        let neighbours = self.chapter_neighbours(ch);
+
+        let ctx = json!({
+            "title": ch.name,
+            "body": body,
+            "stylesheet": stylesheet_path,
+            "chapter_stylesheet": chapter_stylesheet,
+            "lang": self.chapter_language(&front_matter),
+            "class": class,
+            "front_matter": front_matter,
+            "chapter_number": ch.number.as_ref().map(|number| number.to_string().trim_end_matches('.').to_string()),
+            "chapter_path": package_path(&chapter_path),
+            "prev_title": neighbours.prev_title,
+            "next_title": neighbours.next_title,
+        });
+
+        self.render_template("index", &root, ctx)
+    }
+
+    /// The chapter's own stylesheet, from its `stylesheet` front matter or
+    /// `chapter_stylesheets`, as configured.
+    fn chapter_stylesheet(&self, ch: &Chapter) -> Option<PathBuf> {
+        let (front_matter, _) = front_matter::split(self.chapter_content(ch));
+        if let Some(stylesheet) = front_matter.get("stylesheet").and_then(|stylesheet| stylesheet.as_str()) {
+            return Some(PathBuf::from(stylesheet));
+        }
+
+        let chapter = package_path(&source_path(ch));
+        self.config
+            .chapter_stylesheets
+            .iter()
+            .find(|(path, _)| package_path(&validate::normalize(Path::new(path))) == chapter)
+            .map(|(_, stylesheet)| stylesheet.clone())
+    }
+
+    /// Package the chapter's own stylesheet, unless another chapter already
+    /// has.
+    fn add_chapter_stylesheet(&mut self, ch: &Chapter) -> Result<(), Error> {
+        let path = match self.chapter_stylesheet(ch) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let href = stylesheet_href(&path);
+        if !self.packaged_stylesheets.insert(href.clone()) {
+            return Ok(());
+        }
+
+        debug!("Embedding {}'s stylesheet {}", ch.name, path.display());
+        let full_path = self.resolve_path(&path).ok_or_else(|| Error::CssOpen(path.clone()))?;
+        let content = File::open(&full_path).map_err(|_| Error::CssOpen(full_path.clone()))?;
+        self.builder.add_resource(&href, content, "text/css")?;
+
+        Ok(())
+    }
+
+    /// Render one of the templates, adding the paths of the `template_assets`
+    /// relative to the page, which is `root` away from the top of the book.
+    fn render_template(&self, name: &str, root: &str, mut ctx: serde_json::Value) -> Result<String, RenderError> {
+        if let Some(ctx) = ctx.as_object_mut() {
+            if self.direction() == Direction::Rtl {
+                ctx.entry("dir").or_insert_with(|| json!("rtl"));
+            }
+            let mut given = HashSet::new();
+            for path in &self.config.template_assets {
+                let (key, href) = template_asset(path);
+                // assets sharing a name were warned about as they were packaged
+                if !given.insert(key.clone()) {
+                    continue;
+                }
+                if ctx.contains_key(&key) {
+                    warn!("The template asset {} is hidden by the template's own `{}`", path.display(), key);
+                    continue;
+                }
+                ctx.insert(key, json!(format!("{}{}", root, href)));
+            }
+        }
+
+        self.hbs.render(name, &ctx)
+    }
+
+    /// Render the chapter as a section of the single file `single_file` puts
+    /// the whole book in, starting on a new page. `anchors` are the sections'
+    /// ids keyed by where every chapter would otherwise have been packaged,
+    /// so links to chapters can be pointed at their sections.
+    fn render_chapter_section(&self, ch: &Chapter, anchors: &BTreeMap<String, String>) -> String {
+        let chapter_path = source_path(ch);
+
+        let (front_matter, content) = front_matter::split(self.chapter_content(ch));
+        let class = match front_matter.get("class").and_then(|class| class.as_str()) {
+            Some(class) => format!("chapter {}", class),
+            None => String::from("chapter"),
+        };
+        // the single file is in the book's language, so only others are marked
+        let lang = match self.chapter_language(&front_matter) {
+            lang if lang == self.language() => String::new(),
+            lang => format!(" xml:lang=\"{0}\" lang=\"{0}\"", package::escape_xml(lang)),
+        };
+
+        format!(
+            "<div class=\"{}\" id=\"{}\"{} style=\"{}\">\n{}</div>\n",
+            package::escape_xml(&class),
+            self.section_anchor(&chapter_href(&chapter_path)),
+            lang,
+            PAGE_BREAK_STYLE,
+            self.render_body(ch, content, &chapter_path, self.heading_offset(&front_matter), Some(anchors))
+        )
+    }
+
+    /// Render a chapter's markdown to XHTML. In `single_file` mode, links and
+    /// images are rewritten to work from the single file, and headings' ids
+    /// start with the chapter's section id so they're unique in it.
+    fn render_body(
+        &self,
+        ch: &Chapter,
+        content: &str,
+        chapter_path: &Path,
+        heading_offset: i32,
+        single_file: Option<&BTreeMap<String, String>>,
+    ) -> String {
+        let root = match single_file {
+            Some(_) => String::new(),
+            None => root_prefix(chapter_path),
+        };
+        let chapter_dir = chapter_path.parent().unwrap_or_else(|| Path::new(""));
+        // images in the single file have already been made relative to the root
+        let image_dir = match single_file {
+            Some(_) => Path::new(""),
+            None => chapter_dir,
+        };
+
+        let mut body = String::new();
+        let p = self.cmark_parser(content);
+        let mut converter = EventQuoteConverter::new(self.config.curly_quotes, self.config.smart_punctuation);
+        let mut comment_remover = EventHtmlConverter::new(self.config.remove_html_comments);
+        let mut rust_filter = RustCodeBlockFilter::new(self.config.enable_rust_codeblock_filter);
+        let mut code_highlighter = EventCodeHighlighter::new(self.highlighter.as_ref());
+        let mut math = EventMathConverter::new(self.config.math);
+        let section = single_file.map(|_| self.section_anchor(&chapter_href(chapter_path)));
+        let mut heading_ids = EventHeadingIds::new(section.as_deref());
+        let admonition_element = match self.config.version {
+            EpubVersion::V2 => "div",
+            EpubVersion::V3 => "aside",
+        };
+        let mut admonitions = EventAdmonitions::new(self.config.admonitions, admonition_element);
+        let mut footnotes = EventFootnotes::new(self.config.footnotes, chapter_path);
+        let mut task_list = EventTaskList::new(self.config.task_list_style);
+        let mut sanitizer = EventSanitizer::new(self.config.sanitize_html);
+        let mut index_markers = EventIndexMarkers::new(self.config.generate_index, chapter_path);
+        let figure_elements = match self.config.version {
+            EpubVersion::V2 => ("div", "p"),
+            EpubVersion::V3 => ("figure", "figcaption"),
+        };
+        let mut figures = EventFigures::new(self.config.number_figures, figure_elements, self.first_figure(ch));
+        let mut default_alt = EventDefaultAlt::new(self.config.default_alt.as_deref());
+        let cache_dir = self.ctx.destination.join("cache");
+        let mut mermaid = EventMermaid::new(self.config.mermaid, |source: &str| {
+            self.diagrams
+                .render(source, &cache_dir)
+                .map(|_| format!("{}{}", root, package_path(&mermaid::diagram_filename(source))))
+        });
+        let download_remote_images = self.config.download_remote_images;
+        let responsive_tables = self.config.responsive_tables;
+        let mut wikilinks = EventWikilinks::new(self.config.wikilinks, &self.wikilinks, chapter_path);
+        let events = p
+            .flat_map(|event| sanitizer.convert(event))
+            .map(|event| index_markers.convert(event))
+            .flat_map(|event| wikilinks.convert(event))
+            .map(|event| convert_md_links(event, chapter_dir))
+            .map(|event| match single_file {
+                Some(anchors) => rebase_for_single_file(event, chapter_path, anchors),
+                None => event,
+            })
+            .map(|event| {
+                if download_remote_images {
+                    localize_remote_images(event, &root)
+                } else {
+                    event
+                }
+            })
+            .map(|event| localize_root_relative_images(event, &root))
+            .map(|event| point_at_first_copies(event, image_dir, &root, &self.duplicate_assets))
+            .flat_map(|event| mermaid.convert(event))
+            .flat_map(|event| math.convert(event))
+            .flat_map(|event| admonitions.convert(event))
+            .flat_map(|event| task_list.convert(event))
+            .flat_map(|event| wrap_tables(event, responsive_tables))
+            .map(|event| offset_heading(event, heading_offset))
+            .map(|event| converter.convert(event))
+            .flat_map(|event| figures.convert(event))
+            .flat_map(|event| default_alt.convert(event))
+            .map(|event| comment_remover.convert(event))
+            .map(|event| rust_filter.convert(event))
+            .flat_map(|event| heading_ids.convert(event))
+            .flat_map(|event| footnotes.convert(event))
+            .filter_map(|event| code_highlighter.convert(event));
+
+        html::push_html(&mut body, events);
+        body.extend(footnotes.finish());
+
+        if ch.path.is_none() && body.is_empty() {
+            // a placeholder page for a draft chapter
+            body = format!("<h1>{}</h1>\n", package::escape_xml(&ch.name));
+        }
+
+        body
+    }
+
+    /// Generate the stylesheet and add it to the document.
+    fn embed_stylesheets(&mut self) -> Result<(), Error> {
+        debug!("Embedding stylesheets");
+
+        let stylesheet = self
+            .generate_stylesheet()?;
+        self.builder.stylesheet(stylesheet.as_slice())?;
+
+        Ok(())
+    }
+
+    fn additional_assets(&mut self) -> Result<(), Error> {
+        debug!("Embedding additional assets");
+
+        let book = self.packaged_book();
+        for asset in self.local_assets()? {
+            let name = package_path(&asset.filename);
+            let png = self.transcoded.get(&name).map(|(png, _)| png);
+            if let Some(first) = self.duplicate_assets.get(&name).filter(|&first| Some(first) != png) {
+                log!(self.asset_log_level(), "Not packaging {}, which is the same as {}", name, first);
+                continue;
+            }
+            self.load_asset(&asset)?;
+        }
+        self.missing_assets()?;
+
+        if self.config.download_remote_images {
+            let cache_dir = self.ctx.destination.join("cache");
+            let timeout = Duration::from_secs(self.config.download_timeout);
+
+            for url in resources::find_remote(&book) {
+                let asset = resources::download(&url, &cache_dir, timeout)?;
+                log!(self.asset_log_level(), "Downloaded {} to {}", url, asset.location_on_disk.display());
+                self.load_asset(&asset)?;
+            }
+        } else {
+            for url in resources::find_remote(&book) {
+                log!(
+                    self.asset_log_level(),
+                    "Not packaging remote image {}, `download-remote-images` is off",
+                    url
+                );
+            }
+        }
+
+        for link in resources::find_protocol_relative(&book) {
+            log!(self.asset_log_level(), "Not packaging image {}, which has no scheme", link);
+        }
+
+        if self.config.mermaid {
+            let cache_dir = self.ctx.destination.join("cache");
+
+            // diagrams which couldn't be rendered were left as code blocks
+            for source in mermaid::find(&book) {
+                if let Some(location) = self.diagrams.render(&source, &cache_dir) {
+                    let filename = mermaid::diagram_filename(&source);
+                    log!(
+                        self.asset_log_level(),
+                        "Packaging mermaid diagram {} as {} (image/svg+xml)",
+                        location.display(),
+                        package_path(&filename)
+                    );
+                    let content = File::open(&location).map_err(|_| Error::AssetOpen)?;
+                    self.builder.add_resource(&filename, content, "image/svg+xml")?;
+                    self.progress.asset_embedded(&package_path(&filename));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Warn about the files the chapters use which don't exist, packaging
+    /// the placeholder for images when they're pointed at it.
+    fn missing_assets(&mut self) -> Result<(), Error> {
+        if self.config.missing_asset_policy == MissingAssetPolicy::Fail {
+            return Ok(());
+        }
+
+        let mut placeholder = false;
+        for (asset, chapter) in resources::find_missing(&self.packaged_book(), &self.src_dir())? {
+            let name = package_path(&asset.filename);
+            let location = asset.location_on_disk.display();
+            if self.duplicate_assets.get(&name).map(String::as_str) == Some(resources::PLACEHOLDER_IMAGE_PATH) {
+                warn!("Unable to find {} (used by {}), a blank image is used instead", location, chapter.display());
+                placeholder = true;
+            } else {
+                warn!("Unable to find {} (used by {}), so it's left out", location, chapter.display());
+            }
+        }
+
+        if placeholder {
+            self.builder
+                .add_resource(resources::PLACEHOLDER_IMAGE_PATH, resources::PLACEHOLDER_IMAGE, "image/png")?;
+            self.progress.asset_embedded(resources::PLACEHOLDER_IMAGE_PATH);
+        }
+        Ok(())
+    }
+
+    /// The images, audio and video in the chapters' source directory which
+    /// they use.
+    fn local_assets(&self) -> Result<Vec<Asset>, Error> {
+        resources::find(&self.packaged_book(), &self.src_dir(), self.config.missing_asset_policy)
+    }
+
+    fn additional_resources(&mut self) -> Result<(), Error> {
+        debug!("Embedding additional resources");
+
+        for path in self.config.additional_resources.iter() {
+            debug!("Embedding resource: {:?}", path);
+
+            // paths without a file name (e.g. ending in `..`) resolve to a
+            // directory and are reported as missing too
+            let full_path = self
+                .resolve_path(path)
+                .ok_or_else(|| Error::ResourceNotFound(path.clone()))?;
+            debug!("Found resource by a path = {:?}", full_path);
+            let mt = resources::mimetype(&full_path);
+
+            let content = File::open(&full_path).map_err(|e| Error::ResourceOpen(full_path.clone(), e))?;
+            debug!("Adding resource: {:?} / {:?} ", path, mt.to_string());
+            self.builder.add_resource(path, content, mt.to_string())?;
+        }
+
+        Ok(())
+    }
+
+    /// With `embed_source`, package each chapter's markdown alongside its
+    /// XHTML, as a resource which isn't linked from the navigation or the
+    /// spine. Chapters which weren't read from a file get the markdown mdbook
+    /// gave us.
+    fn embed_sources(&mut self) -> Result<(), Error> {
+        if !self.config.embed_source {
+            return Ok(());
+        }
+        debug!("Embedding the chapters' sources");
+
+        let book = self.book;
+        let mut embedded = HashSet::new();
+        for item in book.iter() {
+            let (ch, path) = match *item {
+                BookItem::Chapter(ref ch) => match ch.path {
+                    Some(ref path) if self.chapter_output_path(ch).is_some() => (ch, path),
+                    _ => continue,
+                },
+                _ => continue,
+            };
+            let href = package_path(path);
+            if !embedded.insert(href.clone()) {
+                continue;
+            }
+
+            let source = std::fs::read(self.src_dir().join(path)).unwrap_or_else(|_| ch.content.clone().into_bytes());
+            log!(self.asset_log_level(), "Embedding the source of {}: {}", ch.name, href);
+            self.builder
+                .add_resource(&href, source.as_slice(), resources::SOURCE_MEDIA_TYPE)?;
+        }
+
+        Ok(())
+    }
+
+    fn template_assets(&mut self) -> Result<(), Error> {
+        debug!("Embedding template assets");
+
+        // relative paths start from the template's directory
+        let template_dir = match self.config.index_template {
+            Some(ref template) => template.parent().map(Path::to_path_buf).unwrap_or_default(),
+            None => self.ctx.root.clone(),
+        };
+
+        let mut packaged: HashMap<String, &Path> = HashMap::new();
+        let mut names: HashMap<String, &Path> = HashMap::new();
+        for path in self.config.template_assets.iter() {
+            let full_path = template_dir.join(path);
+            if !full_path.is_file() {
+                return Err(Error::TemplateAssetNotFound(full_path));
+            }
+            let mt = resources::mimetype(&full_path);
+
+            let (key, href) = template_asset(path);
+            if let Some(first) = packaged.get(&href) {
+                warn!(
+                    "The template assets {} and {} would both be packaged as {}, only the first is",
+                    first.display(),
+                    path.display(),
+                    href
+                );
+                continue;
+            }
+            packaged.insert(href.clone(), path);
+            if let Some(first) = names.get(&key) {
+                warn!(
+                    "The template assets {} and {} are both called `{}`, templates are only given the first",
+                    first.display(),
+                    path.display(),
+                    key
+                );
+            }
+            names.entry(key).or_insert(path);
+
+            let content = File::open(&full_path).map_err(|_| Error::TemplateAssetNotFound(full_path.clone()))?;
+            debug!("Adding template asset: {:?} as {}", full_path, href);
+            self.builder.add_resource(href, content, mt.to_string())?;
+        }
+
+        Ok(())
+    }
+
+    fn embed_fonts(&mut self) -> Result<(), Error> {
+        debug!("Embedding fonts");
+
+        for (path, href) in self.config.embed_fonts.iter().zip(font_hrefs(&self.config.embed_fonts)) {
+            let full_path = self
+                .resolve_path(path)
+                .ok_or_else(|| Error::FontNotFound(path.clone()))?;
+            let mt = font_mimetype(&full_path);
+
+            let content = File::open(&full_path).map_err(|_| Error::FontNotFound(full_path.clone()))?;
+            debug!("Adding font: {:?} / {:?} ", full_path, mt);
+            self.builder.add_resource(href, content, mt)?;
+        }
+
+        Ok(())
+    }
+
+    /// Find a file referenced from the config, trying the path relative to
+    /// the book's source directory, then relative to the book root and only
+    /// then relative to the working directory, which depends on where
+    /// `mdbook` was run from. Absolute paths are used as they are.
+    fn resolve_path(&self, path: &Path) -> Option<PathBuf> {
+        let candidates = [
+            self.ctx.root.join(&self.ctx.config.book.src).join(path),
+            self.ctx.root.join(path),
+            path.to_path_buf(),
+        ];
+
+        candidates
+            .iter()
+            .filter_map(|candidate| candidate.canonicalize().ok())
+            .find(|candidate| candidate.is_file())
+    }
+
+    /// The configured cover image, or one found in the source directory,
+    /// or else the generated text cover.
+    fn cover_image(&self) -> Option<PathBuf> {
+        // an explicitly configured cover always wins over auto-detection
+        self.config
+            .cover_image
+            .clone()
+            .or_else(|| self.found_cover.clone())
+            .or_else(|| self.is_text_cover().then(|| PathBuf::from(TEXT_COVER_PATH)))
+    }
+
+    /// Look through the source directory for a cover image, unless one is
+    /// configured, saying which is used.
+    fn find_cover(&self) -> Option<PathBuf> {
+        if self.config.cover_image.is_some() {
+            return None;
+        }
+        let src_dir = self.src_dir();
+        let found = find_cover_image(&src_dir);
+        if let Some(ref cover) = found {
+            info!("Using {} as the cover image", src_dir.join(cover).display());
+        }
+        found
+    }
+
+    /// Does the book get a generated text cover, having no image of its own?
+    fn is_text_cover(&self) -> bool {
+        self.config.generate_text_cover && self.config.cover_image.is_none() && self.found_cover.is_none()
+    }
+
+    /// The cover image, if it gets a cover page.
+    fn cover_page_image(&self) -> Option<PathBuf> {
+        self.cover_image()
+            .filter(|path| self.config.cover_page || is_svg(path))
+    }
+
+    fn add_cover_image(&mut self) -> Result<(), Error> {
+        debug!("Adding cover image...");
+
+        if self.is_text_cover() {
+            let title = self.ctx.config.book.title.as_deref().unwrap_or_default();
+            let svg = text_cover(
+                title,
+                &self.ctx.config.book.authors,
+                &self.config.text_cover_background,
+                &self.config.text_cover_foreground,
+            );
+            debug!("Adding a generated text cover as {}", TEXT_COVER_PATH);
+            self.builder.add_cover_image(TEXT_COVER_PATH, svg.as_bytes(), "image/svg+xml")?;
+            return Ok(());
+        }
+
+        if let Some(ref path) = self.cover_image() {
+            let full_path = self.resolve_path(path).ok_or_else(|| {
+                Error::AssetFileNotFound(format!("Failed to find cover image {}", path.display()))
+            })?;
+            let (path, full_path, mt) = match self.transcoded.get(&package_path(path)) {
+                Some((png, converted)) => (PathBuf::from(png), converted.clone(), mime_guess::mime::IMAGE_PNG),
+                None => (path.clone(), full_path.clone(), resources::mimetype(&full_path)),
+            };
+
+            let content = File::open(&full_path).map_err(|_| Error::AssetOpen)?;
+            debug!("Adding cover image: {:?} / {:?} ", path, mt.to_string());
+            self.builder.add_cover_image(path, content, mt.to_string())?;
+        }
+
+        Ok(())
+    }
+
+    /// Adds a cover page which shows the cover image full-bleed, so readers
+    /// don't open on a blank page. Readers can't show a vector cover image by
+    /// itself, so SVG covers always get one.
+    fn generate_cover_page(&mut self) -> Result<(), Error> {
+        let path = match self.cover_page_image() {
+            Some(path) => match self.transcoded.get(&package_path(&path)) {
+                Some((png, _)) => PathBuf::from(png),
+                None => path,
+            },
+            None => return Ok(()),
+        };
+        debug!("Rendering a cover page for {}", path.display());
+        let dimensions = self.config.cover_dimensions()?;
+        if dimensions.is_some() && self.config.version == EpubVersion::V2 {
+            warn!("The cover page is only fixed-layout in EPUB 3, `cover-width` and `cover-height` just size it");
+        }
+
+        let href = package::escape_xml(&path.display().to_string());
+        let mut body = String::from("<div class=\"cover\">\n");
+        if is_svg(&path) {
+            let view_box = self
+                .resolve_path(&path)
+                .and_then(|full_path| std::fs::read_to_string(full_path).ok())
+                .and_then(|svg| svg_view_box(&svg))
+                .or_else(|| dimensions.map(|(width, height)| format!("0 0 {} {}", width, height)))
+                .unwrap_or_else(|| String::from(DEFAULT_COVER_VIEW_BOX));
+            body.push_str(&format!(
+                "<svg xmlns=\"http://www.w3.org/2000/svg\" xmlns:xlink=\"http://www.w3.org/1999/xlink\" \
+                 version=\"1.1\" width=\"100%\" height=\"100%\" viewBox=\"{0}\" preserveAspectRatio=\"xMidYMid meet\">\n\
+                 <image width=\"100%\" height=\"100%\" xlink:href=\"{1}\"/>\n\
+                 </svg>\n",
+                package::escape_xml(&view_box),
+                href
+            ));
+        } else if let Some((width, height)) = dimensions {
+            body.push_str(&format!(
+                "<img src=\"{}\" width=\"{}\" height=\"{}\" alt=\"Cover\"/>\n",
+                href, width, height
+            ));
+        } else {
+            body.push_str(&format!("<img src=\"{}\" alt=\"Cover\"/>\n", href));
+        }
+        if let Some(ref title) = self.config.cover_title {
+            body.push_str(&format!("<p class=\"cover-title\">{}</p>\n", package::escape_xml(title)));
+        }
+        body.push_str("</div>\n");
+
+        let ctx = json!({
+            "title": "Cover",
+            "body": body,
+            "stylesheet": "stylesheet.css",
+            "lang": self.language(),
+            "viewport": dimensions.map(|(width, height)| format!("width={}, height={}", width, height)),
+        });
+        let rendered = self.render_template("index", "", ctx)?;
+
+        if self.config.version == EpubVersion::V3 && is_svg(&path) {
+            self.manifest_properties.push((String::from(COVER_PAGE_PATH), String::from("svg")));
+        }
+        let content = EpubContent::new(COVER_PAGE_PATH, rendered.as_bytes()).reftype(ReferenceType::Cover);
+        self.builder.add_content(content)?;
+
+        Ok(())
+    }
+
+    fn load_asset(&mut self, asset: &Asset) -> Result<(), Error> {
+        let mut asset = asset.clone();
+        if let Some((png, converted)) = self.transcoded.get(&package_path(&asset.filename)) {
+            asset.filename = PathBuf::from(png);
+            asset.location_on_disk = converted.clone();
+            asset.mimetype = mime_guess::mime::IMAGE_PNG;
+        }
+
+        let content = File::open(&asset.location_on_disk).map_err(|_| Error::AssetOpen)?;
+
+        if self.config.version == EpubVersion::V2 && resources::is_media(&asset.mimetype) {
+            warn!(
+                "Packaging {}, but audio and video need EPUB 3 and won't play with `version = \"2.0\"`",
+                package_path(&asset.filename)
+            );
+        }
+        let mt = asset.mimetype.to_string();
+
+        log!(
+            self.asset_log_level(),
+            "Packaging asset {} as {} ({})",
+            asset.location_on_disk.display(),
+            package_path(&asset.filename),
+            mt
+        );
+        self.builder.add_resource(&asset.filename, content, mt)?;
+        self.progress.asset_embedded(&package_path(&asset.filename));
+
+        Ok(())
+    }
+
+    /// Messages about the assets being packaged are only shown by default
+    /// with `log_assets`.
+    fn asset_log_level(&self) -> log::Level {
+        if self.config.log_assets {
+            log::Level::Info
+        } else {
+            log::Level::Debug
+        }
+    }
+
+    /// With `transcode_unsupported_images`, convert the images EPUB 2
+    /// readers don't understand to PNG before anything refers to them, so
+    /// they can be packaged with a `.png` name. Remote images are downloaded
+    /// for this, and used from the download cache afterwards.
+    fn transcode_images(&mut self) -> Result<(), Error> {
+        if !self.config.transcode_unsupported_images || self.config.version != EpubVersion::V2 {
+            return Ok(());
+        }
+        debug!("Converting images EPUB 2 readers don't understand");
+
+        let cache_dir = self.ctx.destination.join("cache");
+        let mut images = self.local_assets()?;
+        if self.config.download_remote_images {
+            let timeout = Duration::from_secs(self.config.download_timeout);
+            for url in resources::find_remote(&self.packaged_book()) {
+                images.push(resources::download(&url, &cache_dir, timeout)?);
+            }
+        }
+        if let Some(path) = self.cover_image().filter(|_| !self.is_text_cover()) {
+            if let Some(full_path) = self.resolve_path(&path) {
+                let mimetype = resources::mimetype(&full_path);
+                images.push(Asset {
+                    location_on_disk: full_path,
+                    filename: path,
+                    mimetype,
+                });
+            }
+        }
+
+        let mut taken: HashSet<String> = images.iter().map(|image| package_path(&image.filename)).collect();
+        for image in images {
+            let name = package_path(&image.filename);
+            if !resources::is_unsupported_in_epub2(&image.mimetype) || self.transcoded.contains_key(&name) {
+                continue;
+            }
+            if let Some(converted) = resources::transcode(&image.location_on_disk, &cache_dir) {
+                let png = flatten::untaken(package_path(&image.filename.with_extension("png")), &mut taken);
+                debug!("Converted {} to {}, packaged as {}", name, converted.display(), png);
+                self.transcoded.insert(name, (png, converted));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Concatenate all provided stylesheets into one long stylesheet.
+    fn generate_stylesheet(&mut self) -> Result<Vec<u8>, Error> {
+        let mut stylesheet = Vec::new();
+
+        if self.config.use_default_css {
+            stylesheet.extend(DEFAULT_CSS.as_bytes());
+
+            match self.config.css_theme {
+                CssTheme::Default => {}
+                CssTheme::Dark => stylesheet.extend(DARK_CSS.as_bytes()),
+                CssTheme::Sepia => stylesheet.extend(SEPIA_CSS.as_bytes()),
+            }
+        }
+
+        if let Some(ref highlighter) = self.highlighter {
+            stylesheet.extend(highlighter.stylesheet().as_bytes());
+        }
+
+        for href in font_hrefs(&self.config.embed_fonts) {
+            stylesheet.extend(font_face(&href).as_bytes());
+        }
+
+        let mut packaged = HashMap::new();
+        for additional_css in self.config.additional_css.clone() {
+            debug!("generating stylesheet: {:?}", &additional_css);
+            // relative to the book root, wherever `mdbook` was run from
+            let full_path = [self.ctx.root.join(&additional_css), additional_css.clone()]
+                .iter()
+                .find_map(|candidate| candidate.canonicalize().ok())
+                .ok_or_else(|| Error::CssOpen(self.ctx.root.join(&additional_css)))?;
+            debug!("Found stylesheet by a path = {:?}", full_path);
+            let mut f = File::open(&full_path).map_err(|_| Error::CssOpen(full_path.clone()))?;
+            let mut css = Vec::new();
+            f.read_to_end(&mut css).map_err(|_| Error::StylesheetRead)?;
+
+            // the stylesheet is packaged at the top of the book, so what it
+            // refers to comes along and is pointed at from there
+            let css_dir = full_path.parent().unwrap_or_else(|| Path::new(""));
+            let (css, assets) = stylesheet_assets(&String::from_utf8_lossy(&css), css_dir, &self.ctx.root, &mut packaged);
+            for (href, path) in assets {
+                let mt = font_mimetype(&path);
+                let content = File::open(&path).map_err(|e| Error::ResourceOpen(path.clone(), e))?;
+                log!(self.asset_log_level(), "Packaging {}'s {} as {} ({})", additional_css.display(), path.display(), href, mt);
+                self.builder.add_resource(&href, content, mt)?;
+            }
+            stylesheet.extend(css.as_bytes());
+        }
+
+        // last of all, so they win over the rules they're overriding
+        stylesheet.extend(css_overrides(&self.config.css_overrides).as_bytes());
+        if self.config.minify {
+            let css = String::from_utf8_lossy(&stylesheet);
+            stylesheet = minify::css(&css).into_bytes();
+        }
+        debug!("found style(s) = [{}]", stylesheet.len());
+        Ok(stylesheet)
+    }
+}
+
+impl<'a> Debug for Generator<'a> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("Generator")
+            .field("ctx", &self.ctx)
+            .field("builder", &self.builder)
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+/// The EPUB 3 refinement saying what kind of identifier the book's is, as
+/// an [ONIX product identifier type][codelist5]. UUIDs and URLs say so
+/// themselves, and have no code of their own.
+///
+/// [codelist5]: https://ns.editeur.org/onix/en/5
+fn identifier_type(scheme: IdentifierScheme, identifier: &str) -> Option<String> {
+    let code = match scheme {
+        IdentifierScheme::Isbn if identifier.len() == "urn:isbn:".len() + 10 => "02",
+        IdentifierScheme::Isbn => "15",
+        IdentifierScheme::Doi => "06",
+        IdentifierScheme::Uuid | IdentifierScheme::Url => return None,
+    };
+
+    Some(format!(
+        "<meta refines=\"#epub-id-1\" property=\"identifier-type\" scheme=\"onix:codelist5\">{}</meta>",
+        code
+    ))
+}
+
+#[test]
+fn tests_identifier_type() {
+    assert_eq!(
+        identifier_type(IdentifierScheme::Isbn, "urn:isbn:9780306406157").as_deref(),
+        Some("<meta refines=\"#epub-id-1\" property=\"identifier-type\" scheme=\"onix:codelist5\">15</meta>")
+    );
+    assert!(identifier_type(IdentifierScheme::Isbn, "urn:isbn:0306406152").unwrap().ends_with(">02</meta>"));
+    assert!(identifier_type(IdentifierScheme::Doi, "urn:doi:10.1000/182").unwrap().ends_with(">06</meta>"));
+    assert_eq!(identifier_type(IdentifierScheme::Url, "https://example.com/book"), None);
+}
+
+/// The `meta` elements describing how accessible the book is, filling in
+/// whatever `accessibility` leaves out from whether the book `has_images`.
+fn accessibility_metadata(version: EpubVersion, accessibility: &Accessibility, has_images: bool) -> Vec<String> {
+    let or_default = |values: &[String], defaults: &[&str]| -> Vec<String> {
+        if values.is_empty() {
+            defaults.iter().map(|value| value.to_string()).collect()
+        } else {
+            values.to_vec()
+        }
+    };
+    let (modes, sufficient): (&[&str], &[&str]) = if has_images {
+        (&["textual", "visual"], &["textual,visual"])
+    } else {
+        (&["textual"], &["textual"])
+    };
+
+    let properties = [
+        ("schema:accessMode", or_default(&accessibility.access_modes, modes)),
+        ("schema:accessModeSufficient", or_default(&accessibility.access_mode_sufficient, sufficient)),
+        (
+            "schema:accessibilityFeature",
+            or_default(&accessibility.features, &["readingOrder", "tableOfContents"]),
+        ),
+        ("schema:accessibilitySummary", accessibility.summary.iter().cloned().collect()),
+    ];
+    properties
+        .iter()
+        .flat_map(|(property, values)| values.iter().map(move |value| custom_metadata(version, property, value)))
+        .collect()
+}
+
+#[test]
+fn tests_accessibility_metadata() {
+    let defaults = accessibility_metadata(EpubVersion::V3, &Accessibility::default(), false);
+    assert_eq!(
+        defaults,
+        vec![
+            "<meta property=\"schema:accessMode\">textual</meta>",
+            "<meta property=\"schema:accessModeSufficient\">textual</meta>",
+            "<meta property=\"schema:accessibilityFeature\">readingOrder</meta>",
+            "<meta property=\"schema:accessibilityFeature\">tableOfContents</meta>",
+        ]
+    );
+
+    let accessibility = Accessibility {
+        features: vec![String::from("alternativeText")],
+        summary: Some(String::from("Every image has a description.")),
+        ..Default::default()
+    };
+    let illustrated = accessibility_metadata(EpubVersion::V2, &accessibility, true);
+    assert_eq!(
+        illustrated,
+        vec![
+            "<meta name=\"schema:accessMode\" content=\"textual\"/>",
+            "<meta name=\"schema:accessMode\" content=\"visual\"/>",
+            "<meta name=\"schema:accessModeSufficient\" content=\"textual,visual\"/>",
+            "<meta name=\"schema:accessibilityFeature\" content=\"alternativeText\"/>",
+            "<meta name=\"schema:accessibilitySummary\" content=\"Every image has a description.\"/>",
+        ]
+    );
+}
+
+/// The EPUB 3 link to the book's license. Unprefixed relationships have to
+/// be one of EPUB's own, which don't include licenses, so it's Dublin Core's.
+fn license_link(url: &str) -> String {
+    format!("<link rel=\"dcterms:license\" href=\"{}\"/>", package::escape_xml(url))
+}
+
+#[test]
+fn tests_license_link() {
+    assert_eq!(
+        license_link("https://creativecommons.org/licenses/by/4.0/?a=1&b=2"),
+        r#"<link rel="dcterms:license" href="https://creativecommons.org/licenses/by/4.0/?a=1&amp;b=2"/>"#
+    );
+}
+
+/// The `<guide>` entry for a cover image which has no cover page.
+fn cover_reference(href: &str) -> String {
+    format!("<reference type=\"cover\" title=\"Cover\" href=\"{}\"/>", package::escape_xml(href))
+}
+
+/// A `meta` element for an entry in the `metadata` table, with the key
+/// used as is.
+fn custom_metadata(version: EpubVersion, key: &str, value: &str) -> String {
+    let key = package::escape_xml(key);
+    let value = package::escape_xml(value);
+
+    match version {
+        EpubVersion::V2 => format!("<meta name=\"{}\" content=\"{}\"/>", key, value),
+        EpubVersion::V3 => format!("<meta property=\"{}\">{}</meta>", key, value),
+    }
+}
+
+#[test]
+fn tests_custom_metadata() {
+    assert_eq!(
+        custom_metadata(EpubVersion::V2, "ibooks:specified-fonts", "true"),
+        r#"<meta name="ibooks:specified-fonts" content="true"/>"#
+    );
+    assert_eq!(
+        custom_metadata(EpubVersion::V3, "ibooks:specified-fonts", "true"),
+        r#"<meta property="ibooks:specified-fonts">true</meta>"#
+    );
+}
+
+/// The package metadata for someone who worked on the book. Authors are
+/// creators, everyone else is a contributor.
+fn creator_metadata(version: EpubVersion, index: usize, name: &str, role: &str) -> Vec<String> {
+    let element = if role == "aut" { "dc:creator" } else { "dc:contributor" };
+    let name = package::escape_xml(name);
+    let role = package::escape_xml(role);
+
+    match version {
+        EpubVersion::V2 => vec![format!("<{0} opf:role=\"{1}\">{2}</{0}>", element, role, name)],
+        EpubVersion::V3 => {
+            let id = format!("epub-creator-{}", index);
+            vec![
+                format!("<{0} id=\"{1}\">{2}</{0}>", element, id, name),
+                format!("<meta refines=\"#{}\" property=\"role\" scheme=\"marc:relators\">{}</meta>", id, role),
+            ]
+        }
+    }
+}
+
+#[test]
+fn tests_creator_metadata() {
+    assert_eq!(
+        creator_metadata(EpubVersion::V2, 2, "Jane & John", "aut"),
+        vec![r#"<dc:creator opf:role="aut">Jane &amp; John</dc:creator>"#]
+    );
+    assert_eq!(
+        creator_metadata(EpubVersion::V3, 3, "Ann", "trl"),
+        vec![
+            r#"<dc:contributor id="epub-creator-3">Ann</dc:contributor>"#,
+            r##"<meta refines="#epub-creator-3" property="role" scheme="marc:relators">trl</meta>"##,
+        ]
+    );
+}
+
+/// Calibre's series metadata, plus the standard collection metadata in EPUB 3.
+fn series_metadata(version: EpubVersion, series: &str, index: f64) -> Vec<String> {
+    let series = package::escape_xml(series);
+    let mut metadata = vec![
+        format!("<meta name=\"calibre:series\" content=\"{}\"/>", series),
+        format!("<meta name=\"calibre:series_index\" content=\"{}\"/>", index),
+    ];
+
+    if version == EpubVersion::V3 {
+        metadata.extend(vec![
+            format!("<meta property=\"belongs-to-collection\" id=\"epub-collection-1\">{}</meta>", series),
+            String::from("<meta refines=\"#epub-collection-1\" property=\"collection-type\">series</meta>"),
+            format!("<meta refines=\"#epub-collection-1\" property=\"group-position\">{}</meta>", index),
+        ]);
+    }
+
+    metadata
+}
+
+#[test]
+fn tests_series_metadata() {
+    assert_eq!(
+        series_metadata(EpubVersion::V2, "Tom & Jerry", 1.0),
+        vec![
+            r#"<meta name="calibre:series" content="Tom &amp; Jerry"/>"#,
+            r#"<meta name="calibre:series_index" content="1"/>"#,
+        ]
+    );
+    assert_eq!(
+        series_metadata(EpubVersion::V3, "Saga", 2.5)[2..],
+        [
+            r#"<meta property="belongs-to-collection" id="epub-collection-1">Saga</meta>"#,
+            r##"<meta refines="#epub-collection-1" property="collection-type">series</meta>"##,
+            r##"<meta refines="#epub-collection-1" property="group-position">2.5</meta>"##,
+        ]
+    );
+}
+
+fn is_svg(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("svg"))
+}
+
+/// The `viewBox` of an SVG image, falling back to its `width` and `height`.
+fn svg_view_box(svg: &str) -> Option<String> {
+    let start = svg.find("<svg")?;
+    let tag = &svg[start..start + svg[start..].find('>')?];
+    let attribute = |name: &str| -> Option<&str> {
+        let needle = format!(" {}=\"", name);
+        let value = &tag[tag.find(&needle)? + needle.len()..];
+        Some(&value[..value.find('"')?])
+    };
+
+    if let Some(view_box) = attribute("viewBox") {
+        return Some(view_box.to_string());
+    }
+
+    // only unitless or pixel sizes can be used as coordinates
+    let size = |name: &str| -> Option<f64> { attribute(name)?.trim_end_matches("px").parse().ok() };
+    Some(format!("0 0 {} {}", size("width")?, size("height")?))
+}
+
+#[test]
+fn tests_svg_view_box() {
+    let inputs = [
+        (r#"<?xml version="1.0"?><svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 10 20">"#, Some("0 0 10 20")),
+        (r#"<svg width="300px" height="400">"#, Some("0 0 300 400")),
+        (r#"<svg width="10cm" height="20cm">"#, None),
+        ("not an svg", None),
+    ];
+
+    for (svg, should_be) in inputs {
+        assert_eq!(svg_view_box(svg).as_deref(), should_be, "{}", svg);
+    }
+}
+
+/// A plain SVG cover with the book's title and authors in `foreground` on a
+/// `background`, sized like `DEFAULT_COVER_VIEW_BOX`. Long titles are
+/// wrapped at spaces.
+fn text_cover(title: &str, authors: &[String], background: &str, foreground: &str) -> String {
+    const TITLE_LINE_LENGTH: usize = 18;
+    let mut lines: Vec<String> = Vec::new();
+    for word in title.split_whitespace() {
+        match lines.last_mut() {
+            Some(line) if line.chars().count() + 1 + word.chars().count() <= TITLE_LINE_LENGTH => {
+                line.push(' ');
+                line.push_str(word);
+            }
+            _ => lines.push(word.to_string()),
+        }
+    }
+
+    let mut svg = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <svg xmlns=\"http://www.w3.org/2000/svg\" version=\"1.1\" width=\"600\" height=\"800\" viewBox=\"{}\">\n\
+         <rect width=\"600\" height=\"800\" fill=\"{}\"/>\n\
+         <g fill=\"{}\" font-family=\"serif\" text-anchor=\"middle\">\n",
+        DEFAULT_COVER_VIEW_BOX,
+        package::escape_xml(background),
+        package::escape_xml(foreground)
+    );
+    // the title sits a little above the middle, however many lines it has
+    let first_line = 320 - 30 * (lines.len() as i32 - 1);
+    for (ix, line) in lines.iter().enumerate() {
+        svg.push_str(&format!(
+            "<text x=\"300\" y=\"{}\" font-size=\"48\">{}</text>\n",
+            first_line + 60 * ix as i32,
+            package::escape_xml(line)
+        ));
+    }
+    if !authors.is_empty() {
+        svg.push_str(&format!(
+            "<text x=\"300\" y=\"680\" font-size=\"28\">{}</text>\n",
+            package::escape_xml(&authors.join(", "))
+        ));
+    }
+    svg.push_str("</g>\n</svg>\n");
+    svg
+}
+
+#[test]
+fn tests_text_cover() {
+    let svg = text_cover("The Rust Programming Language", &[String::from("Steve & Carol")], "navy", "#fff");
+
+    assert!(svg.contains("viewBox=\"0 0 600 800\""), "{}", svg);
+    assert!(svg.contains("<rect width=\"600\" height=\"800\" fill=\"navy\"/>"), "{}", svg);
+    assert!(svg.contains("<g fill=\"#fff\""), "{}", svg);
+    assert!(svg.contains("<text x=\"300\" y=\"260\" font-size=\"48\">The Rust</text>"), "{}", svg);
+    assert!(svg.contains("<text x=\"300\" y=\"320\" font-size=\"48\">Programming</text>"), "{}", svg);
+    assert!(svg.contains("<text x=\"300\" y=\"380\" font-size=\"48\">Language</text>"), "{}", svg);
+    assert!(svg.contains(">Steve &amp; Carol</text>"), "{}", svg);
+    assert_eq!(svg_view_box(&svg).as_deref(), Some(DEFAULT_COVER_VIEW_BOX));
+}
+
+/// Look for an image called `cover.*` in the book's source directory,
+/// returning its path relative to that directory.
+fn find_cover_image(src_dir: &Path) -> Option<PathBuf> {
+    let entries = std::fs::read_dir(src_dir).ok()?;
+    let mut candidates: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .filter(|path| path.file_stem().is_some_and(|stem| stem == "cover"))
+        .filter(|path| {
+            mime_guess::from_path(path)
+                .first()
+                .is_some_and(|mt| mt.type_() == mime_guess::mime::IMAGE)
+        })
+        .collect();
+
+    // sort so the choice doesn't depend on the order of directory entries
+    candidates.sort();
+    candidates
+        .into_iter()
+        .next()
+        .and_then(|path| path.file_name().map(PathBuf::from))
+}
+
+#[test]
+fn tests_find_cover_image() {
+    let dir = tempdir::TempDir::new("mdbook-epub-cover").unwrap();
+    assert_eq!(find_cover_image(dir.path()), None);
+
+    for name in &["cover.txt", "cover.png", "cover.jpg", "not-a-cover.png"] {
+        File::create(dir.path().join(name)).unwrap();
+    }
+
+    assert_eq!(find_cover_image(dir.path()), Some(PathBuf::from("cover.jpg")));
+}
+
+/// Where each of the embedded `fonts` is packaged, relative to the
+/// stylesheet: by name, with a number added when another font already has
+/// it.
+fn font_hrefs(fonts: &[PathBuf]) -> Vec<String> {
+    let mut taken = HashSet::new();
+    fonts
+        .iter()
+        .map(|font| {
+            let name = font.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+            flatten::untaken(format!("fonts/{}", name), &mut taken)
+        })
+        .collect()
+}
+
+fn font_mimetype(font: &Path) -> String {
+    resources::mimetype(font).to_string()
+}
+
+/// Point a stylesheet's `url()`s at where the files they refer to are
+/// packaged, along with the files which haven't been `packaged` yet and
+/// their packaged paths. Paths are relative to `css_dir`, and are packaged
+/// by where they are in the book's `root` (or by name, for files outside
+/// it, with a number added when that's taken). Data URIs, remote URLs and
+/// files which don't exist are left alone.
+fn stylesheet_assets(
+    css: &str,
+    css_dir: &Path,
+    root: &Path,
+    packaged: &mut HashMap<PathBuf, String>,
+) -> (String, Vec<(String, PathBuf)>) {
+    let root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+    let mut taken: HashSet<String> = packaged.values().cloned().collect();
+    let mut assets = Vec::new();
+
+    let css = flatten::rewrite_css_urls(css, |link| {
+        if !validate::is_internal(link) || link.starts_with('/') {
+            return None;
+        }
+        let split = link.find(['#', '?']).unwrap_or(link.len());
+        let (path, suffix) = link.split_at(split);
+        let full_path = match css_dir.join(validate::percent_decode(path)).canonicalize() {
+            Ok(full_path) if full_path.is_file() => full_path,
+            _ => {
+                warn!("Unable to find {}, which a stylesheet refers to", link);
+                return None;
+            }
+        };
+
+        let href = match packaged.get(&full_path) {
+            Some(href) => href.clone(),
+            None => {
+                let within_book = match full_path.strip_prefix(&root) {
+                    Ok(relative) => relative.to_path_buf(),
+                    Err(_) => PathBuf::from(full_path.file_name().unwrap_or_default()),
+                };
+                let href = flatten::untaken(package_path(&Path::new(STYLESHEET_ASSETS_DIR).join(within_book)), &mut taken);
+                packaged.insert(full_path.clone(), href.clone());
+                assets.push((href.clone(), full_path));
+                href
+            }
+        };
+        Some(format!("{}{}", validate::percent_encode(&href), suffix))
+    });
+
+    (css, assets)
+}
+
+#[test]
+fn tests_stylesheet_assets() {
+    let temp = tempdir::TempDir::new("mdbook-epub-css").unwrap();
+    let theme = temp.path().join("theme");
+    std::fs::create_dir_all(theme.join("fonts")).unwrap();
+    std::fs::write(theme.join("fonts").join("serif.woff2"), b"font").unwrap();
+    std::fs::write(temp.path().join("paper.png"), b"image").unwrap();
+
+    let css = "@font-face { src: url(\"fonts/serif.woff2?v=2#serif\"); }\n\
+               body { background: url(../paper.png); }\n\
+               h1 { background: url('data:image/png;base64,AAAA'); }\n\
+               h2 { background: url(https://example.com/a.png) , url(missing.png); }\n";
+    let (css, assets) = stylesheet_assets(css, &theme, temp.path(), &mut HashMap::new());
+
+    assert_eq!(
+        css,
+        "@font-face { src: url(\"css/theme/fonts/serif.woff2?v=2#serif\"); }\n\
+         body { background: url(css/paper.png); }\n\
+         h1 { background: url('data:image/png;base64,AAAA'); }\n\
+         h2 { background: url(https://example.com/a.png) , url(missing.png); }\n"
+    );
+    let hrefs: Vec<_> = assets.iter().map(|(href, _)| href.as_str()).collect();
+    assert_eq!(hrefs, ["css/theme/fonts/serif.woff2", "css/paper.png"]);
+
+    // files outside the book with the same name don't clash, and are only
+    // packaged once however many stylesheets use them
+    let elsewhere = tempdir::TempDir::new("mdbook-epub-css").unwrap();
+    for dir in ["a", "b"] {
+        std::fs::create_dir_all(elsewhere.path().join(dir)).unwrap();
+        std::fs::write(elsewhere.path().join(dir).join("paper.png"), dir).unwrap();
+    }
+    let mut packaged = HashMap::new();
+    let (a, assets) = stylesheet_assets("body { background: url(a/paper.png); }", elsewhere.path(), temp.path(), &mut packaged);
+    assert_eq!(a, "body { background: url(css/paper.png); }");
+    assert_eq!(assets.len(), 1);
+    let css = "body { background: url(b/paper.png); } h1 { background: url(a/paper.png); }";
+    let (b, assets) = stylesheet_assets(css, elsewhere.path(), temp.path(), &mut packaged);
+    assert_eq!(b, "body { background: url(css/paper-2.png); } h1 { background: url(css/paper.png); }");
+    let hrefs: Vec<_> = assets.iter().map(|(href, _)| href.as_str()).collect();
+    assert_eq!(hrefs, ["css/paper-2.png"]);
+}
+
+/// The `@font-face` rule for an embedded font packaged at `href`, using
+/// the file stem as the font family.
+fn font_face(href: &str) -> String {
+    let family = Path::new(href).file_stem().map(|stem| stem.to_string_lossy()).unwrap_or_default();
+    format!(
+        "\n@font-face {{\n    font-family: \"{}\";\n    src: url(\"{}\");\n}}\n",
+        family,
+        validate::percent_encode(href)
+    )
+}
+
+#[test]
+fn tests_font_face() {
+    let font = Path::new("assets/Open-Sans-Regular.woff2");
+
+    assert_eq!(font_mimetype(font), "font/woff2");
+    let hrefs = font_hrefs(&[font.to_path_buf(), PathBuf::from("a/Regular.woff2"), PathBuf::from("b/Regular.woff2")]);
+    assert_eq!(hrefs, ["fonts/Open-Sans-Regular.woff2", "fonts/Regular.woff2", "fonts/Regular-2.woff2"]);
+    assert_eq!(
+        font_face(&hrefs[0]),
+        "\n@font-face {\n    font-family: \"Open-Sans-Regular\";\n    src: url(\"fonts/Open-Sans-Regular.woff2\");\n}\n"
+    );
+    assert!(font_face(&hrefs[2]).contains("font-family: \"Regular-2\";\n    src: url(\"fonts/Regular-2.woff2\")"));
+}
+
+/// Where the placeholder page for a draft chapter is packaged.
+fn draft_href(ch: &Chapter) -> String {
+    let slug: String = ch
+        .name
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '-' })
+        .collect();
+    format!("draft-{}.html", slug)
+}
+
+/// The relative path from a chapter back to the root of the book, e.g.
+/// `../` for `foo/bar.md`, or nothing for a chapter at the top of the book.
+/// Only directories count, so a leading `/` or `./` doesn't add a level.
+fn root_prefix(chapter_path: &Path) -> String {
+    let dir = chapter_path.parent().unwrap_or_else(|| Path::new(""));
+    dir.components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .map(|_| "../")
+        .collect()
+}
+
+#[test]
+fn tests_root_prefix() {
+    assert_eq!(root_prefix(Path::new("foo.md")), "");
+    assert_eq!(root_prefix(Path::new("/foo.md")), "");
+    assert_eq!(root_prefix(Path::new("./foo.md")), "");
+    assert_eq!(root_prefix(Path::new("")), "");
+    assert_eq!(root_prefix(Path::new("/")), "");
+    assert_eq!(root_prefix(Path::new("foo/bar.md")), "../");
+    assert_eq!(root_prefix(Path::new("a/b/c/d/e.md")), "../../../../");
+}
+
+/// Point remote images at the copies packaged by `additional_assets()`.
+fn localize_remote_images<'a>(event: Event<'a>, root: &str) -> Event<'a> {
+    let localize = |dest: CowStr<'a>| -> CowStr<'a> {
+        if resources::is_remote(&dest) {
+            CowStr::from(format!("{}{}", root, package_path(&resources::remote_filename(&dest))))
+        } else {
+            dest
+        }
+    };
+
+    match event {
+        Event::Start(Tag::Image(kind, dest, title)) => Event::Start(Tag::Image(kind, localize(dest), title)),
+        Event::End(Tag::Image(kind, dest, title)) => Event::End(Tag::Image(kind, localize(dest), title)),
+        Event::Html(html) if html.contains('=') => {
+            let rewritten = resources::rewrite_html_links(&html, |_, src| {
+                if resources::is_remote(src) {
+                    Some(format!("{}{}", root, package_path(&resources::remote_filename(src))))
+                } else {
+                    None
+                }
+            });
+            Event::Html(CowStr::from(rewritten))
+        }
+        _ => event,
+    }
+}
+
+/// Put each table in a `<div class="table-wrapper">`, which the default
+/// stylesheet lets scroll sideways, when `enabled`.
+fn wrap_tables(event: Event<'_>, enabled: bool) -> Vec<Event<'_>> {
+    match event {
+        Event::Start(Tag::Table(_)) if enabled => vec![Event::Html(CowStr::from("<div class=\"table-wrapper\">\n")), event],
+        Event::End(Tag::Table(_)) if enabled => vec![event, Event::Html(CowStr::from("</div>\n"))],
+        _ => vec![event],
+    }
+}
+
+/// Move a heading `offset` levels down, or up when it's negative, keeping it
+/// between `<h1>` and `<h6>`.
+fn offset_heading(event: Event<'_>, offset: i32) -> Event<'_> {
+    let offset_level = |level: HeadingLevel| {
+        let shifted = (level as i64 + offset as i64).clamp(1, 6) as usize;
+        HeadingLevel::try_from(shifted).unwrap_or(level)
+    };
+    match event {
+        Event::Start(Tag::Heading(level, id, classes)) if offset != 0 => {
+            Event::Start(Tag::Heading(offset_level(level), id, classes))
+        }
+        Event::End(Tag::Heading(level, id, classes)) if offset != 0 => {
+            Event::End(Tag::Heading(offset_level(level), id, classes))
+        }
+        _ => event,
+    }
+}
+
+#[test]
+fn tests_offset_heading() {
+    let input = "# One
+
+## Two
+
+###### Six
+";
+    let render = |offset: i32| {
+        let mut body = String::new();
+        html::push_html(&mut body, Generator::new_cmark_parser(input).map(|event| offset_heading(event, offset)));
+        body
+    };
+
+    assert_eq!(render(0), "<h1>One</h1>
+<h2>Two</h2>
+<h6>Six</h6>
+");
+    assert_eq!(render(1), "<h2>One</h2>
+<h3>Two</h3>
+<h6>Six</h6>
+");
+    assert_eq!(render(-1), "<h1>One</h1>
+<h1>Two</h1>
+<h5>Six</h5>
+");
+    assert_eq!(render(10), "<h6>One</h6>
+<h6>Two</h6>
+<h6>Six</h6>
+");
+    assert_eq!(render(-10), "<h1>One</h1>
+<h1>Two</h1>
+<h1>Six</h1>
+");
+}
+
+#[test]
+fn tests_wrap_tables() {
+    let input = "| a | b |\n|---|---|\n| 1 | 2 |\n";
+    let render = |enabled: bool| {
+        let mut body = String::new();
+        html::push_html(&mut body, Generator::new_cmark_parser(input).flat_map(|event| wrap_tables(event, enabled)));
+        body
+    };
+
+    let wrapped = render(true);
+    assert!(wrapped.starts_with("<div class=\"table-wrapper\">\n<table>"), "{}", wrapped);
+    assert!(wrapped.ends_with("</table>\n</div>\n"), "{}", wrapped);
+    assert!(render(false).starts_with("<table>"));
+}
+
+/// Point images whose path starts at the `src/` directory, like
+/// `/images/logo.png`, at where they're packaged relative to the chapter,
+/// which is `root` away from the top of the book.
+fn localize_root_relative_images<'a>(event: Event<'a>, root: &str) -> Event<'a> {
+    let localize = |dest: CowStr<'a>| -> CowStr<'a> {
+        if resources::is_root_relative(&dest) {
+            CowStr::from(format!("{}{}", root, dest.trim_start_matches('/')))
+        } else {
+            dest
+        }
+    };
+
+    match event {
+        Event::Start(Tag::Image(kind, dest, title)) => Event::Start(Tag::Image(kind, localize(dest), title)),
+        Event::End(Tag::Image(kind, dest, title)) => Event::End(Tag::Image(kind, localize(dest), title)),
+        Event::Html(html) if html.contains('=') => {
+            let rewritten = resources::rewrite_html_links(&html, |_, src| {
+                if resources::is_root_relative(src) {
+                    Some(format!("{}{}", root, src.trim_start_matches('/')))
+                } else {
+                    None
+                }
+            });
+            Event::Html(CowStr::from(rewritten))
+        }
+        _ => event,
+    }
+}
+
+#[test]
+fn tests_root_relative_images_are_localized() {
+    let input = "![a](/images/a.png) ![b](//example.com/b.png) ![c](c.png)\n\n<img src=\"/images/d.svg\"/>\n";
+    let expected = "<p><img src=\"../images/a.png\" alt=\"a\" /> <img src=\"//example.com/b.png\" alt=\"b\" /> \
+                    <img src=\"c.png\" alt=\"c\" /></p>\n<img src=\"../images/d.svg\"/>\n";
+
+    let events = Generator::new_cmark_parser(input).map(|event| localize_root_relative_images(event, "../"));
+    let mut got = String::new();
+    html::push_html(&mut got, events);
+
+    assert_eq!(got, expected);
+}
+
+/// Point images packaged more than once at the first copy in `duplicates`.
+/// Relative images are resolved against `dir`, the chapter's directory
+/// inside the book, which is `root` away from the top.
+fn point_at_first_copies<'a>(event: Event<'a>, dir: &Path, root: &str, duplicates: &BTreeMap<String, String>) -> Event<'a> {
+    if duplicates.is_empty() {
+        return event;
+    }
+    let first_copy = |dest: &str| -> Option<String> {
+        if is_external_link(dest) || dest.starts_with('/') {
+            return None;
+        }
+        let target = package_path(&validate::normalize(&dir.join(dest)));
+        duplicates.get(&target).map(|first| format!("{}{}", root, first))
+    };
+    let first_copy_cow = |dest: CowStr<'a>| -> CowStr<'a> {
+        match first_copy(&dest) {
+            Some(first) => CowStr::from(first),
+            None => dest,
+        }
+    };
+
+    match event {
+        Event::Start(Tag::Image(kind, dest, title)) => Event::Start(Tag::Image(kind, first_copy_cow(dest), title)),
+        Event::End(Tag::Image(kind, dest, title)) => Event::End(Tag::Image(kind, first_copy_cow(dest), title)),
+        Event::Html(html) if html.contains('=') => {
+            Event::Html(CowStr::from(resources::rewrite_html_links(&html, |_, src| first_copy(src))))
+        }
+        _ => event,
+    }
+}
+
+#[test]
+fn tests_duplicate_images_point_at_the_first_copy() {
+    let duplicates: BTreeMap<String, String> = vec![("guide/logo.png".to_string(), "images/logo.png".to_string())]
+        .into_iter()
+        .collect();
+    let input = "![a](logo.png) ![b](../guide/./logo.png) ![c](other.png) ![d](https://example.com/logo.png)\n\n\
+                 <img src=\"logo.png\"/>\n";
+    let expected = "<p><img src=\"../images/logo.png\" alt=\"a\" /> <img src=\"../images/logo.png\" alt=\"b\" /> \
+                    <img src=\"other.png\" alt=\"c\" /> <img src=\"https://example.com/logo.png\" alt=\"d\" /></p>\n\
+                    <img src=\"../images/logo.png\"/>\n";
+
+    let events = Generator::new_cmark_parser(input)
+        .map(|event| point_at_first_copies(event, Path::new("guide"), "../", &duplicates));
+    let mut got = String::new();
+    html::push_html(&mut got, events);
+
+    assert_eq!(got, expected);
+}
+
+/// The rules for `css_overrides`, one per selector.
+fn css_overrides(overrides: &BTreeMap<String, BTreeMap<String, String>>) -> String {
+    overrides
+        .iter()
+        .filter(|(_, declarations)| !declarations.is_empty())
+        .map(|(selector, declarations)| {
+            let declarations: String = declarations
+                .iter()
+                .map(|(property, value)| format!("  {}: {};\n", property, value.trim_end_matches(';')))
+                .collect();
+            format!("\n{} {{\n{}}}\n", selector, declarations)
+        })
+        .collect()
+}
+
+#[test]
+fn tests_css_overrides() {
+    let mut code = BTreeMap::new();
+    code.insert(String::from("font-family"), String::from("\"Fira Code\", monospace"));
+    code.insert(String::from("font-size"), String::from("0.9em;"));
+    let mut overrides = BTreeMap::new();
+    overrides.insert(String::from("code, pre"), code);
+    overrides.insert(String::from("p"), BTreeMap::new());
+
+    assert_eq!(
+        css_overrides(&overrides),
+        "\ncode, pre {\n  font-family: \"Fira Code\", monospace;\n  font-size: 0.9em;\n}\n"
+    );
+}
+
+/// Where a chapter's own stylesheet is packaged: at the path it was
+/// configured with, inside the book.
+fn stylesheet_href(path: &Path) -> String {
+    package_path(&validate::normalize(path))
+}
+
+/// Format a packaged path with forward slashes, as expected in hrefs.
+pub(crate) fn package_path(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[test]
+fn tests_remote_images_are_localized() {
+    let input = "![logo](https://example.com/logo.png)\n\n<img src=\"https://example.com/a.svg\" alt=\"a\" />\n\n![local](local.png)\n";
+
+    let mut body = String::new();
+    let events = Generator::new_cmark_parser(input).map(|event| localize_remote_images(event, "../"));
+    html::push_html(&mut body, events);
+
+    let logo = package_path(&resources::remote_filename("https://example.com/logo.png"));
+    let svg = package_path(&resources::remote_filename("https://example.com/a.svg"));
+    assert!(body.contains(&format!(r#"<img src="../{}" alt="logo" />"#, logo)), "{}", body);
+    assert!(body.contains(&format!(r#"<img src="../{}" alt="a" />"#, svg)), "{}", body);
+    assert!(body.contains(r#"<img src="local.png" alt="local" />"#), "{}", body);
+}
+
+/// One of `SUMMARY.md`'s parts, from its title up to the next part or
+/// separator.
+#[derive(Debug, Clone, PartialEq)]
+struct Part<'b> {
+    /// Counting from 1.
+    number: usize,
+    title: &'b str,
+    items: &'b [BookItem],
+}
+
+/// A part along with where its chapters are in the list of chapters being
+/// rendered.
+#[derive(Debug)]
+struct PartChapters<'b> {
+    part: Part<'b>,
+    chapters: Range<usize>,
+}
+
+/// An item at the top of the book, where everything in a part is grouped
+/// under it.
+#[derive(Debug, Clone, PartialEq)]
+enum TopLevelItem<'b> {
+    Item(&'b BookItem),
+    Part(Part<'b>),
+}
+
+fn top_level_items(items: &[BookItem]) -> Vec<TopLevelItem<'_>> {
+    let mut grouped = Vec::new();
+    let mut number = 0;
+    let mut ix = 0;
+
+    while ix < items.len() {
+        match items[ix] {
+            BookItem::PartTitle(ref title) => {
+                number += 1;
+                let rest = &items[ix + 1..];
+                let len = rest
+                    .iter()
+                    .position(|item| !matches!(*item, BookItem::Chapter(_)))
+                    .unwrap_or(rest.len());
+                grouped.push(TopLevelItem::Part(Part {
+                    number,
+                    title,
+                    items: &rest[..len],
+                }));
+                ix += len + 1;
+            }
+            ref item => {
+                grouped.push(TopLevelItem::Item(item));
+                ix += 1;
+            }
+        }
+    }
+
+    grouped
+}
+
+#[test]
+fn tests_top_level_items() {
+    let chapter = |name: &str| BookItem::Chapter(Chapter::new(name, String::new(), format!("{}.md", name), Vec::new()));
+    let items = vec![
+        chapter("intro"),
+        BookItem::PartTitle(String::from("Basics")),
+        chapter("one"),
+        chapter("two"),
+        BookItem::PartTitle(String::from("Empty")),
+        BookItem::Separator,
+        chapter("appendix"),
+    ];
+
+    let grouped = top_level_items(&items);
+
+    assert_eq!(
+        grouped,
+        vec![
+            TopLevelItem::Item(&items[0]),
+            TopLevelItem::Part(Part {
+                number: 1,
+                title: "Basics",
+                items: &items[2..4],
+            }),
+            TopLevelItem::Part(Part {
+                number: 2,
+                title: "Empty",
+                items: &[],
+            }),
+            TopLevelItem::Item(&items[5]),
+            TopLevelItem::Item(&items[6]),
+        ]
+    );
+}
+
+/// Where a part's own page is packaged.
+fn part_href(number: usize) -> String {
+    format!("part_{}.html", number)
+}
+
+/// The `id` of a part's section in a single-file book.
+fn part_anchor(number: usize) -> String {
+    format!("part-{}", number)
+}
+
+fn part_heading(title: &str) -> String {
+    format!("<h1 class=\"part-title\">{}</h1>\n", package::escape_xml(title))
+}
+
+/// How deeply a chapter is nested, where top-level chapters are `0`.
+fn chapter_level(ch: &Chapter) -> i32 {
+    ch.number.as_ref().map(|n| n.len() as i32 - 1).unwrap_or(0)
+}
+
+/// Splice in the files included by each chapter, keyed by the chapters'
+/// source paths. Chapters which don't include anything are left out.
+fn resolve_includes(book: &Book, src_dir: &Path) -> Result<HashMap<String, String>, Error> {
+    let mut included = HashMap::new();
+
+    for item in book.iter() {
+        let (ch, path) = match *item {
+            BookItem::Chapter(ref ch) => match ch.path {
+                Some(ref path) => (ch, path),
+                None => continue,
+            },
+            _ => continue,
+        };
+        let source = src_dir.join(path);
+        let dir = source.parent().unwrap_or(src_dir);
+        let full_path = source.canonicalize().unwrap_or_else(|_| source.clone());
+
+        let resolved = includes::resolve(&ch.content, dir, &mut vec![full_path])?;
+        if resolved != ch.content {
+            debug!("Resolved the includes in {}", path.display());
+            included.insert(package_path(path), resolved);
+        }
+    }
+
+    Ok(included)
+}
+
+/// Take the content marked for other formats out of the chapters, on top of
+/// anything `included` into them already.
+fn tailor_chapters(book: &Book, included: &mut HashMap<String, String>) {
+    for item in book.iter() {
+        let (ch, path) = match *item {
+            BookItem::Chapter(ref ch) => match ch.path {
+                Some(ref path) => (ch, package_path(path)),
+                None => continue,
+            },
+            _ => continue,
+        };
+        let content = included.get(&path).unwrap_or(&ch.content);
+        if let Some(tailored) = conditional::tailor(content) {
+            debug!("Tailored {} to the EPUB", path);
+            included.insert(path, tailored);
+        }
+    }
+}
+
+/// Work out where the chapters left out by the `include_chapters` and
+/// `exclude_chapters` patterns would have been packaged.
+///
+/// A chapter matching `exclude_chapters` is always left out. Otherwise one
+/// matching `include_chapters` is kept, and anything else (including drafts,
+/// which have no source path) does whatever its parent chapter does. Top
+/// level chapters are only kept by default when `include_chapters` is empty.
+fn excluded_chapters(book: &Book, config: &Config) -> Result<HashSet<String>, Error> {
+    let include = chapter_patterns(&config.include_chapters)?;
+    let exclude = chapter_patterns(&config.exclude_chapters)?;
+
+    let mut excluded = HashSet::new();
+    collect_excluded(&book.sections, include.is_empty(), &include, &exclude, &mut excluded);
+
+    Ok(excluded)
+}
+
+/// Work out where the chapters kept out of the navigation by `toc_hidden`
+/// are packaged, including the sub-chapters of each one listed.
+fn toc_hidden_chapters(book: &Book, toc_hidden: &[String]) -> HashSet<String> {
+    let listed: HashSet<String> = toc_hidden
+        .iter()
+        .map(|path| chapter_href(&validate::normalize(Path::new(path))))
+        .collect();
+
+    let mut hidden = HashSet::new();
+    collect_toc_hidden(&book.sections, false, &listed, &mut hidden);
+    for path in listed.difference(&hidden) {
+        warn!("`toc-hidden` lists {}, which isn't a chapter of the book", path);
+    }
+
+    hidden
+}
+
+fn collect_toc_hidden(items: &[BookItem], parent_hidden: bool, listed: &HashSet<String>, hidden: &mut HashSet<String>) {
+    for item in items {
+        let ch = match *item {
+            BookItem::Chapter(ref ch) => ch,
+            _ => continue,
+        };
+        let href = ch.path.as_ref().map(|path| chapter_href(path));
+        let is_hidden = parent_hidden || href.as_ref().is_some_and(|href| listed.contains(href));
+
+        if let Some(href) = href.filter(|_| is_hidden) {
+            hidden.insert(href);
+        }
+        collect_toc_hidden(&ch.sub_items, is_hidden, listed, hidden);
+    }
+}
+
+#[test]
+fn tests_toc_hidden_chapters() {
+    let mut book = Book::new();
+    let mut guide = Chapter::new("Guide", String::new(), "guide/index.md", Vec::new());
+    guide.sub_items.push(BookItem::Chapter(Chapter::new("Setup", String::new(), "guide/setup.md", Vec::new())));
+    book.push_item(Chapter::new("How to read", String::new(), "reading.md", Vec::new()));
+    book.push_item(guide);
+    book.push_item(Chapter::new("Usage", String::new(), "usage.md", Vec::new()));
+
+    let hidden = |paths: &[&str]| {
+        let paths: Vec<String> = paths.iter().map(|s| s.to_string()).collect();
+        let mut hidden: Vec<String> = toc_hidden_chapters(&book, &paths).into_iter().collect();
+        hidden.sort();
+        hidden
+    };
+
+    assert!(hidden(&[]).is_empty());
+    assert_eq!(hidden(&["./reading.md"]), ["reading.html"]);
+    assert_eq!(hidden(&["guide/index.md", "missing.md"]), ["guide/index.html", "guide/setup.html"]);
+}
+
+fn chapter_patterns(patterns: &[String]) -> Result<Vec<glob::Pattern>, Error> {
+    patterns
+        .iter()
+        .map(|pattern| {
+            // chapter paths are relative to `src/`, like in `SUMMARY.md`
+            glob::Pattern::new(pattern.trim_start_matches("./"))
+                .map_err(|e| Error::ChapterPattern(pattern.clone(), e))
+        })
+        .collect()
+}
+
+fn collect_excluded(
+    items: &[BookItem],
+    parent_included: bool,
+    include: &[glob::Pattern],
+    exclude: &[glob::Pattern],
+    excluded: &mut HashSet<String>,
+) {
+    for item in items {
+        let ch = match *item {
+            BookItem::Chapter(ref ch) => ch,
+            _ => continue,
+        };
+        // `part-1/*` is the chapters in `part-1/`, not those further down
+        let options = glob::MatchOptions {
+            require_literal_separator: true,
+            ..glob::MatchOptions::new()
+        };
+        let matches = |patterns: &[glob::Pattern]| {
+            ch.path
+                .as_ref()
+                .is_some_and(|path| patterns.iter().any(|pattern| pattern.matches_path_with(path, options)))
+        };
+        let included = !matches(exclude) && (matches(include) || parent_included);
+
+        if !included {
+            excluded.insert(match ch.path {
+                Some(ref path) => chapter_href(path),
+                None => draft_href(ch),
+            });
+        }
+        collect_excluded(&ch.sub_items, included, include, exclude, excluded);
+    }
+}
+
+#[test]
+fn tests_excluded_chapters() {
+    let mut book = Book::new();
+    let mut part = Chapter::new("Part", String::new(), "part/index.md", Vec::new());
+    for name in ["one", "two"] {
+        let path = format!("part/{}.md", name);
+        part.sub_items.push(BookItem::Chapter(Chapter::new(name, String::new(), path, Vec::new())));
+    }
+    book.push_item(Chapter::new("Intro", String::new(), "intro.md", Vec::new()));
+    book.push_item(part);
+
+    let excluded = |include: &[&str], exclude: &[&str]| {
+        let config = Config {
+            include_chapters: include.iter().map(|s| s.to_string()).collect(),
+            exclude_chapters: exclude.iter().map(|s| s.to_string()).collect(),
+            ..Config::default()
+        };
+        let mut excluded: Vec<String> = excluded_chapters(&book, &config).unwrap().into_iter().collect();
+        excluded.sort();
+        excluded
+    };
+
+    assert!(excluded(&[], &[]).is_empty());
+    assert_eq!(excluded(&[], &["part/index.md"]), ["part/index.html", "part/one.html", "part/two.html"]);
+    assert_eq!(excluded(&[], &["part/*"]), ["part/index.html", "part/one.html", "part/two.html"]);
+    assert_eq!(excluded(&["part/two.md"], &["part/*"]), ["intro.html", "part/index.html", "part/one.html", "part/two.html"]);
+    assert_eq!(excluded(&["part/two.md"], &["part/index.md"]), ["intro.html", "part/index.html", "part/one.html"]);
+    assert_eq!(excluded(&["./intro.md"], &[]), ["part/index.html", "part/one.html", "part/two.html"]);
+    assert_eq!(excluded(&["part/index.md"], &["part/one.md"]), ["intro.html", "part/one.html"]);
+
+    // `*` stays in its directory, `**` doesn't
+    let mut deep_book = Book::new();
+    deep_book.push_item(Chapter::new("One", String::new(), "part/one.md", Vec::new()));
+    deep_book.push_item(Chapter::new("Deep", String::new(), "part/deep/two.md", Vec::new()));
+    for (pattern, count) in [("part/*.md", 1), ("part/**/*.md", 2)] {
+        let config = Config {
+            exclude_chapters: vec![String::from(pattern)],
+            ..Config::default()
+        };
+        assert_eq!(excluded_chapters(&deep_book, &config).unwrap().len(), count, "{}", pattern);
+    }
+
+    let config = Config {
+        include_chapters: vec![String::from("[")],
+        ..Config::default()
+    };
+    assert!(excluded_chapters(&book, &config).is_err());
+}
+
+/// The key a template asset is given to templates under (its file name
+/// without the extension) and where it's packaged, which keeps the
+/// directories it's in so `a/logo.png` and `b/logo.png` don't clash.
+fn template_asset(path: &Path) -> (String, String) {
+    let key = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    (key, format!("{}/{}", TEMPLATE_ASSETS_DIR, package_path(&validate::normalize(path))))
+}
+
+#[test]
+fn tests_template_asset() {
+    assert_eq!(
+        template_asset(Path::new("images/logo.png")),
+        (String::from("logo"), String::from("template/images/logo.png"))
+    );
+    assert_eq!(template_asset(Path::new("./logo.png")).1, "template/logo.png");
+    assert_ne!(template_asset(Path::new("a/logo.png")).1, template_asset(Path::new("b/logo.png")).1);
+}
+
+/// A chapter's markdown file, relative to `src/`. Drafts don't have one and
+/// are packaged at the top level.
+fn source_path(ch: &Chapter) -> PathBuf {
+    ch.path.clone().unwrap_or_else(|| PathBuf::from(draft_href(ch)))
+}
+
+/// The id of a chapter's section with `single_file`, from where the chapter
+/// would otherwise have been packaged.
+fn chapter_anchor(href: &str) -> String {
+    let slug: String = Path::new(href)
+        .with_extension("")
+        .to_string_lossy()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    format!("chapter-{}", slug)
+}
+
+/// The ids of the chapters' sections with `single_file`, keyed by where the
+/// chapters packaged at `hrefs` would otherwise have been. Chapters whose
+/// [`chapter_anchor()`]s are the same, like `a-b.md` and `a/b.md`, are told
+/// apart by a hash of where they'd have been.
+fn single_file_anchors(hrefs: &[String]) -> BTreeMap<String, String> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for href in hrefs {
+        *counts.entry(chapter_anchor(href)).or_insert(0) += 1;
+    }
+
+    hrefs
+        .iter()
+        .map(|href| {
+            let anchor = chapter_anchor(href);
+            let anchor = if counts[&anchor] > 1 {
+                format!("{}-{:08x}", anchor, resources::stable_hash(href.as_bytes()) as u32)
+            } else {
+                anchor
+            };
+            (href.clone(), anchor)
+        })
+        .collect()
+}
+
+#[test]
+fn tests_single_file_anchors() {
+    let hrefs: Vec<String> = ["a-b.html", "a/b.html", "intro.html"].iter().map(|s| s.to_string()).collect();
+    let anchors = single_file_anchors(&hrefs);
+
+    assert_eq!(anchors["intro.html"], "chapter-intro");
+    assert!(anchors["a-b.html"].starts_with("chapter-a-b-"), "{:?}", anchors);
+    assert!(anchors["a/b.html"].starts_with("chapter-a-b-"), "{:?}", anchors);
+    assert_ne!(anchors["a-b.html"], anchors["a/b.html"]);
+}
+
+/// Point a chapter's links and images at where they are from the single
+/// file at the root of the book: links to chapters go to their sections (or
+/// to the heading they name, whose id starts with the section's) and other
+/// relative paths are made relative to the root. `anchors` are the
+/// sections' ids, keyed by where each chapter would have been packaged.
+fn rebase_for_single_file<'a>(event: Event<'a>, chapter_path: &Path, anchors: &BTreeMap<String, String>) -> Event<'a> {
+    let chapter_dir = chapter_path.parent().unwrap_or_else(|| Path::new(""));
+    let rebase = |dest: &str| -> Option<String> {
+        if is_external_link(dest) || dest.is_empty() || dest.starts_with('/') {
+            return None;
+        }
+
+        let (path, fragment) = match dest.find('#') {
+            Some(ix) => dest.split_at(ix),
+            None => (dest, ""),
+        };
+        let target = if path.is_empty() {
+            chapter_href(chapter_path)
+        } else {
+            package_path(&validate::normalize(&chapter_dir.join(path)))
+        };
+
+        match anchors.get(&target) {
+            None if path.is_empty() => None,
+            None => Some(format!("{}{}", target, fragment)),
+            Some(anchor) if fragment.len() > 1 => Some(format!("#{}-{}", anchor, &fragment[1..])),
+            Some(anchor) => Some(format!("#{}", anchor)),
+        }
+    };
+    let rebase_cow = |dest: CowStr<'a>| -> CowStr<'a> {
+        match rebase(&dest) {
+            Some(rebased) => CowStr::from(rebased),
+            None => dest,
+        }
+    };
+
+    match event {
+        Event::Start(Tag::Link(kind, dest, title)) => Event::Start(Tag::Link(kind, rebase_cow(dest), title)),
+        Event::End(Tag::Link(kind, dest, title)) => Event::End(Tag::Link(kind, rebase_cow(dest), title)),
+        Event::Start(Tag::Image(kind, dest, title)) => Event::Start(Tag::Image(kind, rebase_cow(dest), title)),
+        Event::End(Tag::Image(kind, dest, title)) => Event::End(Tag::Image(kind, rebase_cow(dest), title)),
+        Event::Html(html) if html.contains('=') => {
+            Event::Html(CowStr::from(resources::rewrite_html_links(&html, |_, src| {
+                if resources::is_remote(src) { None } else { rebase(src) }
+            })))
+        }
+        _ => event,
+    }
+}
+
+#[test]
+fn tests_rebase_for_single_file() {
+    let hrefs: Vec<String> = ["intro.html", "part/one.html", "part/two.html"].iter().map(|s| s.to_string()).collect();
+    let anchors = single_file_anchors(&hrefs);
+    let rebase = |dest: &str| {
+        let event = Event::Start(Tag::Link(pulldown_cmark::LinkType::Inline, CowStr::from(dest.to_string()), CowStr::from("")));
+        match rebase_for_single_file(event, Path::new("part/two.md"), &anchors) {
+            Event::Start(Tag::Link(_, dest, _)) => dest.to_string(),
+            _ => unreachable!(),
+        }
+    };
+
+    assert_eq!(rebase("../intro.html"), "#chapter-intro");
+    assert_eq!(rebase("one.html#setup"), "#chapter-part-one-setup");
+    assert_eq!(rebase("#local"), "#chapter-part-two-local");
+    assert_eq!(rebase("images/logo.png"), "part/images/logo.png");
+    assert_eq!(rebase("missing.html"), "part/missing.html");
+    assert_eq!(rebase("https://example.com/"), "https://example.com/");
+}
+
+/// Where a chapter's rendered XHTML lives inside the EPUB.
+fn chapter_href(content_path: &Path) -> String {
+    content_path.with_extension("html").display().to_string()
+}
+
+/// Point links at other chapters to the rendered `.html` files instead of
+/// their markdown sources. Links from a chapter in `dir` which lead outside
+/// the book can't be to a chapter, so they're left as they are.
+fn convert_md_links<'a>(event: Event<'a>, dir: &Path) -> Event<'a> {
+    match event {
+        Event::Start(Tag::Link(kind, dest, title)) if !escapes_book(dir, &dest) => {
+            Event::Start(Tag::Link(kind, rewrite_md_link(dest), title))
+        }
+        Event::End(Tag::Link(kind, dest, title)) if !escapes_book(dir, &dest) => {
+            Event::End(Tag::Link(kind, rewrite_md_link(dest), title))
+        }
+        Event::Start(Tag::Image(kind, dest, title)) => {
+            Event::Start(Tag::Image(kind, rewrite_md_link(dest), title))
+        }
+        Event::End(Tag::Image(kind, dest, title)) => {
+            Event::End(Tag::Image(kind, rewrite_md_link(dest), title))
+        }
+        _ => event,
     }
+}
 
-    /// Generate the stylesheet and add it to the document.
-    fn embed_stylesheets(&mut self) -> Result<(), Error> {
-        debug!("Embedding stylesheets");
+fn rewrite_md_link(dest: CowStr<'_>) -> CowStr<'_> {
+    if is_external_link(&dest) {
+        return dest;
+    }
 
-        let stylesheet = self
-            .generate_stylesheet()?;
-        self.builder.stylesheet(stylesheet.as_slice())?;
+    let (path, fragment) = match dest.find('#') {
+        Some(ix) => dest.split_at(ix),
+        None => (&*dest, ""),
+    };
 
-        Ok(())
+    match path.strip_suffix(".md") {
+        Some(stem) if !stem.is_empty() => CowStr::from(format!("{}.html{}", stem, fragment)),
+        _ => dest,
     }
+}
 
-    fn additional_assets(&mut self) -> Result<(), Error> {
-        debug!("Embedding additional assets");
-
-        let error = String::from("Failed finding/fetch resource taken from content? Look up content for possible error...");
-        // resources::find can emit very unclear error based on internal MD content,
-        // so let's give a tip to user in error message
-        let assets = resources::find(self.ctx).expect(&error);
+/// Whether a relative link from a chapter in `dir` climbs out above the
+/// top of the book, like `../../README.md` from a top-level chapter.
+fn escapes_book(dir: &Path, dest: &str) -> bool {
+    if is_external_link(dest) {
+        return false;
+    }
 
-        for asset in assets {
-            debug!("Embedding asset : {}", asset.filename.display());
-            self.load_asset(&asset)?;
+    let path = dest.split(['#', '?']).next().unwrap_or("");
+    let mut depth = 0;
+    for component in dir.join(path).components() {
+        match component {
+            Component::ParentDir if depth == 0 => return true,
+            Component::ParentDir => depth -= 1,
+            Component::Normal(_) => depth += 1,
+            _ => {}
         }
-
-        Ok(())
     }
 
-    fn additional_resources(&mut self) -> Result<(), Error> {
-        debug!("Embedding additional resources");
+    false
+}
 
-        for path in self.config.additional_resources.iter() {
-            debug!("Embedding resource: {:?}", path);
+#[test]
+fn tests_escapes_book() {
+    assert!(escapes_book(Path::new(""), "../README.md"));
+    assert!(escapes_book(Path::new("guide"), "../../README.md#top"));
+    assert!(escapes_book(Path::new("guide"), "../other/../../notes.md"));
+    assert!(!escapes_book(Path::new("guide"), "../intro.md"));
+    assert!(!escapes_book(Path::new(""), "./guide/../intro.md"));
+    assert!(!escapes_book(Path::new(""), "https://example.com/../x.md"));
+    assert!(!escapes_book(Path::new(""), "#heading"));
+}
 
-            let full_path: PathBuf;
-            if let Ok(full_path_internal) = path.canonicalize() { // try process by 'path only' first
-                debug!("Found resource by a path = {:?}", full_path_internal);
-                full_path = full_path_internal; // OK
+/// Links from a chapter's markdown, parsed with `options`, to other
+/// chapters, along with the rendered `.html` file each one should lead to.
+fn chapter_links(content: &str, options: Options) -> Vec<(String, String)> {
+    Parser::new_ext(content, options)
+        .filter_map(|event| match event {
+            Event::Start(Tag::Link(_, dest, _)) if !is_external_link(&dest) => Some(dest.to_string()),
+            _ => None,
+        })
+        .filter_map(|dest| {
+            let rewritten = rewrite_md_link(CowStr::from(dest.as_str())).to_string();
+            let path = rewritten.split(['#', '?']).next().unwrap_or("").to_string();
+
+            if path.ends_with(".html") {
+                Some((dest, path))
             } else {
-                debug!("Failed to find resource by path, trying to compose 'root + src + path'...");
-                // try process by using 'root + src + path'
-                let full_path_composed = self.ctx.root.join(self.ctx.config.book.src.clone()).join(path);
-                debug!("Try embed resource by a path = {:?}", full_path_composed);
-                if let Ok(full_path_src) = full_path_composed.canonicalize() {
-                    full_path = full_path_src; // OK
-                } else {
-                    // try process by using 'root + path' finally
-                    let mut error = format!("Failed to find resource file by 'root + src + path' = {:?}", full_path_composed);
-                    warn!("{:?}", error);
-                    debug!("Failed to find resource, trying to compose by 'root + path' only...");
-                    let full_path_composed = self.ctx.root.join(path);
-                    error = format!("Failed to find resource file by a root + path = {:?}", full_path_composed);
-                    full_path = full_path_composed.canonicalize().expect(&error);
-                }
+                None
             }
-            let mt = mime_guess::from_path(&full_path).first_or_octet_stream();
+        })
+        .collect()
+}
 
-            let content = File::open(&full_path).map_err(|_| Error::AssetOpen)?;
-            debug!("Adding resource: {:?} / {:?} ", path, mt.to_string());
-            self.builder.add_resource(&path, content, mt.to_string())?;
-        }
+/// The sources of the images in some markdown, parsed with `options`, which
+/// have no alt text.
+fn images_missing_alt(content: &str, options: Options) -> Vec<String> {
+    let mut missing = Vec::new();
+    let mut image: Option<(String, bool)> = None;
 
-        Ok(())
+    for event in Parser::new_ext(content, options) {
+        match event {
+            Event::Start(Tag::Image(_, dest, _)) => image = Some((dest.to_string(), false)),
+            Event::Text(ref text) | Event::Code(ref text) if !text.trim().is_empty() => {
+                if let Some((_, ref mut has_alt)) = image {
+                    *has_alt = true;
+                }
+            }
+            Event::End(Tag::Image(..)) => {
+                if let Some((dest, false)) = image.take() {
+                    missing.push(dest);
+                }
+            }
+            _ => {}
+        }
     }
 
-    fn add_cover_image(&mut self) -> Result<(), Error> {
-        debug!("Adding cover image...");
+    missing
+}
 
-        if let Some(ref path) = self.config.cover_image {
-            let full_path: PathBuf;
-            if let Ok(full_path_internal) = path.canonicalize() {
-                debug!("Found resource by a path = {:?}", full_path_internal);
-                full_path = full_path_internal;
-            } else {
-                debug!("Failed to find resource, trying to compose path...");
-                let full_path_composed = self.ctx.root.join(self.ctx.config.book.src.clone()).join(path);
-                debug!("Try cover image by a path = {:?}", full_path_composed);
-                let error = format!("Failed to find cover image by full path-name = {:?}", full_path_composed);
-                full_path = full_path_composed.canonicalize().expect(&error);
-            }
-            let mt = mime_guess::from_path(&full_path).first_or_octet_stream();
+#[test]
+fn tests_images_missing_alt() {
+    let input = "![ok](a.png) ![](b.png) ![ ](c.png)\n\n[![](d.svg)](https://example.com) ![`code`](e.png)\n";
 
-            let content = File::open(&full_path).map_err(|_| Error::AssetOpen)?;
-            debug!("Adding cover image: {:?} / {:?} ", path, mt.to_string());
-            self.builder.add_cover_image(&path, content, mt.to_string())?;
-        }
+    assert_eq!(
+        images_missing_alt(input, MarkdownOption::options(MarkdownOption::DEFAULT)),
+        vec![String::from("b.png"), String::from("c.png"), String::from("d.svg")]
+    );
+}
 
-        Ok(())
-    }
+#[test]
+fn tests_chapter_links() {
+    let input = "[a](../foo.md#bar) [b](baz.html) [c](#top) [d](https://example.com/x.md) [e](image.png)\n";
 
-    fn load_asset(&mut self, asset: &Asset) -> Result<(), Error> {
-        let content = File::open(&asset.location_on_disk).map_err(|_| Error::AssetOpen)?;
+    assert_eq!(
+        chapter_links(input, MarkdownOption::options(MarkdownOption::DEFAULT)),
+        vec![
+            (String::from("../foo.md#bar"), String::from("../foo.html")),
+            (String::from("baz.html"), String::from("baz.html")),
+        ]
+    );
+}
 
-        let mt = asset.mimetype.to_string();
+/// Does this link point somewhere outside the book?
+fn is_external_link(dest: &str) -> bool {
+    dest.contains("://") || dest.starts_with("mailto:")
+}
 
-        self.builder.add_resource(&asset.filename, content, mt)?;
+#[test]
+fn tests_md_links_are_rewritten() {
+    let input = "[x](../foo.md#bar) [y](baz.md) [z](https://example.com/qux.md) [w](mailto:a@b.md)\n";
+    let expected = r#"<p><a href="../foo.html#bar">x</a> <a href="baz.html">y</a> <a href="https://example.com/qux.md">z</a> <a href="mailto:a@b.md">w</a></p>
+"#;
 
-        Ok(())
-    }
+    let mut body = String::new();
+    let events = Generator::new_cmark_parser(input).map(|event| convert_md_links(event, Path::new("guide")));
+    html::push_html(&mut body, events);
 
-    /// Concatenate all provided stylesheets into one long stylesheet.
-    fn generate_stylesheet(&self) -> Result<Vec<u8>, Error> {
-        let mut stylesheet = Vec::new();
+    assert_eq!(expected, body);
+}
 
-        if self.config.use_default_css {
-            stylesheet.extend(DEFAULT_CSS.as_bytes());
-        }
+/// Drop empty and (case-insensitively) repeated subjects, keeping the first
+/// spelling of each.
+fn unique_subjects(subjects: &[String]) -> Vec<&str> {
+    let mut seen = HashSet::new();
 
-        for additional_css in &self.config.additional_css {
-            debug!("generating stylesheet: {:?}", &additional_css);
-            let full_path: PathBuf;
-            if let Ok(full_path_internal) = additional_css.canonicalize() {
-                debug!("Found stylesheet by a path = {:?}", full_path_internal);
-                full_path = full_path_internal;
-            } else {
-                debug!("Failed to find stylesheet, trying to compose path...");
-                let full_path_composed = self.ctx.root.join(additional_css);
-                debug!("Try stylesheet by a path = {:?}", full_path_composed);
-                let error = format!("Failed to find stylesheet by full path-name = {:?}", full_path_composed);
-                full_path = full_path_composed.canonicalize().expect(&error);
-            }
-            let mut f = File::open(&full_path).map_err(|_| Error::CssOpen(full_path.clone()))?;
-            f.read_to_end(&mut stylesheet).map_err(|_| Error::StylesheetRead)?;
-        }
-        debug!("found style(s) = [{}]", stylesheet.len());
-        Ok(stylesheet)
-    }
+    subjects
+        .iter()
+        .map(|subject| subject.trim())
+        .filter(|subject| !subject.is_empty())
+        .filter(|subject| seen.insert(subject.to_lowercase()))
+        .collect()
 }
 
-impl<'a> Debug for Generator<'a> {
-    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        f.debug_struct("Generator")
-            .field("ctx", &self.ctx)
-            .field("builder", &self.builder)
-            .field("config", &self.config)
-            .finish()
-    }
+#[test]
+fn tests_unique_subjects() {
+    let subjects = vec![
+        String::from("Rust"),
+        String::new(),
+        String::from("  "),
+        String::from("Programming"),
+        String::from("rust"),
+    ];
+
+    assert_eq!(unique_subjects(&subjects), vec!["Rust", "Programming"]);
 }
 
-/// From `mdbook/src/utils/mod.rs`, where this is a private struct.
+/// Elements of raw HTML whose text is code, and keeps its straight quotes
+/// like inline code and code blocks do.
+const RAW_CODE_ELEMENTS: &[&str] = &["code", "kbd", "samp", "pre"];
+
+/// From `mdbook/src/utils/mod.rs`, where this is a private struct. Besides
+/// quotes, it converts dashes and ellipses with `smart_punctuation`.
+///
+/// Only text is converted: raw HTML, attributes and all, comes as `Html`
+/// events, which are left alone, as is the text inside raw HTML code
+/// elements like `<code>`.
 struct EventQuoteConverter {
-    enabled: bool,
+    curly_quotes: bool,
+    smart_punctuation: bool,
     convert_text: bool,
+    /// How many raw HTML code elements the text is inside.
+    raw_code_depth: usize,
+    /// Whether the last character of the previous text was whitespace, so
+    /// quotes at the start of a text event (e.g. after emphasis) get the
+    /// right direction.
+    preceded_by_whitespace: bool,
 }
 
 impl EventQuoteConverter {
-    fn new(enabled: bool) -> Self {
+    fn new(curly_quotes: bool, smart_punctuation: bool) -> Self {
         EventQuoteConverter {
-            enabled,
+            curly_quotes,
+            smart_punctuation,
             convert_text: true,
+            raw_code_depth: 0,
+            preceded_by_whitespace: true,
         }
     }
 
     fn convert<'a>(&mut self, event: Event<'a>) -> Event<'a> {
-        if !self.enabled {
+        if !self.curly_quotes && !self.smart_punctuation {
             return event;
         }
 
@@ -328,33 +3785,101 @@ impl EventQuoteConverter {
             }
             Event::End(Tag::CodeBlock(_)) => {
                 self.convert_text = true;
+                self.preceded_by_whitespace = true;
+                event
+            }
+            Event::Text(ref text) if self.convert_text && self.raw_code_depth == 0 => {
+                let mut text = text.to_string();
+                if self.smart_punctuation {
+                    text = convert_dashes_and_ellipses(&text);
+                }
+                if self.curly_quotes {
+                    text = convert_quotes_to_curly(&text, &mut self.preceded_by_whitespace);
+                }
+                Event::Text(CowStr::from(text))
+            }
+            Event::Html(ref html) => {
+                for (name, closing) in html_tags(html) {
+                    if RAW_CODE_ELEMENTS.iter().any(|element| element.eq_ignore_ascii_case(name)) {
+                        self.raw_code_depth = if closing {
+                            self.raw_code_depth.saturating_sub(1)
+                        } else {
+                            self.raw_code_depth + 1
+                        };
+                    }
+                }
+                // an HTML block ends its line, but inline tags are within a sentence
+                if html.ends_with(char::is_whitespace) {
+                    self.preceded_by_whitespace = true;
+                }
+                event
+            }
+            // inline code comes as a single event and is never converted
+            Event::Code(ref code) => {
+                self.preceded_by_whitespace = code.ends_with(char::is_whitespace);
+                event
+            }
+            Event::SoftBreak | Event::HardBreak => {
+                self.preceded_by_whitespace = true;
                 event
             }
-            Event::Text(ref text) if self.convert_text => {
-                Event::Text(CowStr::from(convert_quotes_to_curly(text)))
+            // inline markup like emphasis and links doesn't interrupt a
+            // sentence, but anything else (paragraphs, list items...) does
+            Event::Start(ref tag) | Event::End(ref tag) if !is_inline_tag(tag) => {
+                self.preceded_by_whitespace = true;
+                event
             }
             _ => event,
         }
     }
 }
 
-fn convert_quotes_to_curly(original_text: &str) -> String {
-    // We'll consider the start to be "whitespace".
-    let mut preceded_by_whitespace = true;
+/// The names of the elements some raw HTML opens and closes, in order, with
+/// whether each is a closing tag. Self-closing tags, comments and
+/// declarations are left out.
+fn html_tags(html: &str) -> Vec<(&str, bool)> {
+    let mut tags = Vec::new();
+    let mut rest = html;
+
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let (closing, tag) = match rest.strip_prefix('/') {
+            Some(tag) => (true, tag),
+            None => (false, rest),
+        };
+        let name_end = tag
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == ':'))
+            .unwrap_or(tag.len());
+        let end = tag.find('>').unwrap_or(tag.len());
+        if name_end > 0 && !tag[..end].ends_with('/') {
+            tags.push((&tag[..name_end], closing));
+        }
+    }
+
+    tags
+}
+
+fn is_inline_tag(tag: &Tag<'_>) -> bool {
+    matches!(
+        *tag,
+        Tag::Emphasis | Tag::Strong | Tag::Strikethrough | Tag::Link(..) | Tag::Image(..)
+    )
+}
 
+fn convert_quotes_to_curly(original_text: &str, preceded_by_whitespace: &mut bool) -> String {
     original_text
         .chars()
         .map(|original_char| {
             let converted_char = match original_char {
                 '\'' => {
-                    if preceded_by_whitespace {
+                    if *preceded_by_whitespace {
                         '‘'
                     } else {
                         '’'
                     }
                 }
                 '"' => {
-                    if preceded_by_whitespace {
+                    if *preceded_by_whitespace {
                         '“'
                     } else {
                         '”'
@@ -363,13 +3888,138 @@ fn convert_quotes_to_curly(original_text: &str) -> String {
                 _ => original_char,
             };
 
-            preceded_by_whitespace = original_char.is_whitespace();
+            *preceded_by_whitespace = original_char.is_whitespace();
 
             converted_char
         })
         .collect()
 }
 
+/// Turn runs of hyphens into dashes and `...` into an ellipsis, the way
+/// `pulldown-cmark`'s smart punctuation does: two hyphens are an en dash and
+/// three an em dash, and longer runs are split into as many em dashes as
+/// they can be, or else en dashes, without leaving a hyphen over.
+fn convert_dashes_and_ellipses(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '-' => {
+                let mut run = 1;
+                while chars.next_if_eq(&'-').is_some() {
+                    run += 1;
+                }
+                out.push_str(&dashes(run));
+            }
+            '.' if chars.peek() == Some(&'.') => {
+                let mut run = 1;
+                while chars.next_if_eq(&'.').is_some() {
+                    run += 1;
+                }
+                out.push_str(&"\u{2026}".repeat(run / 3));
+                out.push_str(&".".repeat(run % 3));
+            }
+            _ => out.push(c),
+        }
+    }
+
+    out
+}
+
+/// The dashes a run of `hyphens` hyphens becomes.
+fn dashes(hyphens: usize) -> String {
+    let (em, en) = match hyphens {
+        1 => return String::from("-"),
+        _ if hyphens.is_multiple_of(3) => (hyphens / 3, 0),
+        _ if hyphens.is_multiple_of(2) => (0, hyphens / 2),
+        _ if hyphens % 3 == 2 => (hyphens / 3, 1),
+        _ => (hyphens / 3 - 1, 2),
+    };
+    format!("{}{}", "\u{2014}".repeat(em), "\u{2013}".repeat(en))
+}
+
+#[test]
+fn tests_smart_punctuation() {
+    let input = "Pages 1--2 --- or more... Well.... a-b ----- x\n\n`a -- b...`\n\n```\nx --- y...\n```\n\n<code>--</code> 'x'\n";
+    let expected = "<p>Pages 1\u{2013}2 \u{2014} or more\u{2026} Well\u{2026}. a-b \u{2014}\u{2013} x</p>\n\
+                    <p><code>a -- b...</code></p>\n<pre><code>x --- y...\n</code></pre>\n<p><code>--</code> 'x'</p>\n";
+
+    let mut body = String::new();
+    let mut converter = EventQuoteConverter::new(false, true);
+    let events = Generator::new_cmark_parser(input).map(|event| converter.convert(event));
+    html::push_html(&mut body, events);
+
+    assert_eq!(expected, body);
+    assert_eq!(dashes(4), "\u{2013}\u{2013}");
+    assert_eq!(dashes(7), "\u{2014}\u{2013}\u{2013}");
+    assert_eq!(dashes(9), "\u{2014}\u{2014}\u{2014}");
+}
+
+#[test]
+fn tests_smart_punctuation_with_curly_quotes() {
+    let input = "\"Wait...\" --- 'no'\n";
+    let expected = "<p>\u{201c}Wait\u{2026}\u{201d} \u{2014} \u{2018}no\u{2019}</p>\n";
+
+    let mut body = String::new();
+    let mut converter = EventQuoteConverter::new(true, true);
+    let events = Generator::new_cmark_parser(input).map(|event| converter.convert(event));
+    html::push_html(&mut body, events);
+
+    assert_eq!(expected, body);
+}
+
+#[test]
+fn tests_curly_quotes_across_events() {
+    let input = "*It*'s \"*quoted*\" and `code`'s fine.\n\n'New paragraph'\n";
+    let expected = "<p><em>It</em>’s “<em>quoted</em>” and <code>code</code>’s fine.</p>\n<p>‘New paragraph’</p>\n";
+
+    let mut body = String::new();
+    let mut converter = EventQuoteConverter::new(true, false);
+    let events = Generator::new_cmark_parser(input).map(|event| converter.convert(event));
+    html::push_html(&mut body, events);
+
+    assert_eq!(expected, body);
+}
+
+#[test]
+fn tests_curly_quotes_skip_inline_code() {
+    let input = "Use `a'b` and `\"c\"`, or 'd'.\n\n```\nlet x = 'e';\n```\n";
+    let expected = "<p>Use <code>a'b</code> and <code>&quot;c&quot;</code>, or ‘d’.</p>\n<pre><code>let x = 'e';\n</code></pre>\n";
+
+    let mut body = String::new();
+    let mut converter = EventQuoteConverter::new(true, false);
+    let events = Generator::new_cmark_parser(input).map(|event| converter.convert(event));
+    html::push_html(&mut body, events);
+
+    assert_eq!(expected, body);
+}
+
+#[test]
+fn tests_curly_quotes_skip_html() {
+    let input = "An <abbr title=\"it's\">it's</abbr> and <span\nclass='x' title=\"a 'b'\">'q'</span>.\n\n\
+                 <div title=\"it's\">\n\n'Inside'\n\n</div>\n\n\
+                 Run <kbd>'x'</kbd> or <code class=\"a\">\"y\"</code>, then 'z'<br/>\n";
+    let expected = "<p>An <abbr title=\"it's\">it’s</abbr> and <span\nclass='x' title=\"a 'b'\">‘q’</span>.</p>\n\
+                    <div title=\"it's\">\n<p>‘Inside’</p>\n</div>\n\
+                    <p>Run <kbd>'x'</kbd> or <code class=\"a\">&quot;y&quot;</code>, then ‘z’<br/></p>\n";
+
+    let mut body = String::new();
+    let mut converter = EventQuoteConverter::new(true, false);
+    let events = Generator::new_cmark_parser(input).map(|event| converter.convert(event));
+    html::push_html(&mut body, events);
+
+    assert_eq!(expected, body);
+}
+
+#[test]
+fn tests_html_tags() {
+    assert_eq!(
+        html_tags("<pre><code class=\"a>b\">x</code><br/><!-- c --></pre>\n"),
+        vec![("pre", false), ("code", false), ("code", true), ("pre", true)]
+    );
+}
+
 struct EventHtmlConverter {
     enabled: bool,
     comment_lines: bool,
@@ -390,7 +4040,7 @@ impl EventHtmlConverter {
 
         match event {
             Event::Html(ref text) if self.comment_lines => {
-                if let Some(_) = text.find("-->") {
+                if text.contains("-->") {
                     self.comment_lines = false;
                     Event::Html(CowStr::from("-->\n"))
                 } else {
@@ -400,7 +4050,7 @@ impl EventHtmlConverter {
 
             Event::Html(ref text) => {
                 if text.starts_with("<!--") {
-                    if let None = text.find("-->") {
+                    if !text.contains("-->") {
                         self.comment_lines = true;
                         return Event::Html(CowStr::from("<!-- removed\n"));
                     } else {
@@ -408,7 +4058,7 @@ impl EventHtmlConverter {
                     }
                 }
 
-                return event
+                event
             }
 
             _ => event
@@ -482,6 +4132,265 @@ impl RustCodeBlockFilter {
     }
 }
 
+/// Replaces fenced code blocks in a language we know about with
+/// highlighted markup.
+struct EventCodeHighlighter<'h> {
+    highlighter: Option<&'h Highlighter>,
+    lang: Option<String>,
+    code: String,
+}
+
+impl<'h> EventCodeHighlighter<'h> {
+    fn new(highlighter: Option<&'h Highlighter>) -> Self {
+        EventCodeHighlighter {
+            highlighter,
+            lang: None,
+            code: String::new(),
+        }
+    }
+
+    /// Code blocks are buffered until they end, so this swallows their
+    /// events by returning `None`.
+    fn convert<'a>(&mut self, event: Event<'a>) -> Option<Event<'a>> {
+        let highlighter = match self.highlighter {
+            Some(highlighter) => highlighter,
+            None => return Some(event),
+        };
+
+        match event {
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(ref info))) => {
+                let lang = code_block_language(info);
+                if lang.is_empty() || !highlighter.supports(lang) {
+                    return Some(event);
+                }
+
+                self.lang = Some(lang.to_string());
+                self.code.clear();
+                None
+            }
+            Event::Text(ref text) if self.lang.is_some() => {
+                self.code.push_str(text);
+                None
+            }
+            Event::End(Tag::CodeBlock(_)) if self.lang.is_some() => {
+                let lang = self.lang.take().unwrap_or_default();
+                let code = std::mem::take(&mut self.code);
+                let html = highlighter.highlight(&lang, &code).unwrap_or_else(|| {
+                    format!(
+                        "<pre><code class=\"language-{}\">{}</code></pre>\n",
+                        lang,
+                        package::escape_xml(&code)
+                    )
+                });
+
+                Some(Event::Html(CowStr::from(html)))
+            }
+            _ => Some(event),
+        }
+    }
+}
+
+/// The language of a fenced code block, i.e. the first word of its info
+/// string (`rust` for `rust,ignore`).
+fn code_block_language(info: &str) -> &str {
+    info.split(|c: char| c == ',' || c.is_whitespace())
+        .next()
+        .unwrap_or("")
+}
+
+#[test]
+fn tests_code_highlighting() {
+    let input = "```rust,ignore\nfn main() {}\n```\n\n```no-such-language\nfn main() {}\n```\n";
+
+    let highlighter = Highlighter::new();
+    let mut body = String::new();
+    let mut code_highlighter = EventCodeHighlighter::new(Some(&highlighter));
+    let events = Generator::new_cmark_parser(input).filter_map(|event| code_highlighter.convert(event));
+    html::push_html(&mut body, events);
+
+    assert!(body.starts_with(r#"<pre class="highlight"><code class="language-rust"><span class="hl-"#), "{}", body);
+    assert!(body.ends_with("<pre><code class=\"language-no-such-language\">fn main() {}\n</code></pre>\n"), "{}", body);
+}
+
+/// Gives images which have no alt text the `default_alt`.
+struct EventDefaultAlt<'a> {
+    default_alt: Option<&'a str>,
+    /// Whether the image being converted has any alt text so far.
+    image_has_alt: Option<bool>,
+}
+
+impl<'a> EventDefaultAlt<'a> {
+    fn new(default_alt: Option<&'a str>) -> Self {
+        EventDefaultAlt {
+            default_alt,
+            image_has_alt: None,
+        }
+    }
+
+    fn convert<'e>(&mut self, event: Event<'e>) -> Vec<Event<'e>> {
+        let default_alt = match self.default_alt {
+            Some(default_alt) => default_alt,
+            None => return vec![event],
+        };
+
+        match event {
+            Event::Start(Tag::Image(..)) => self.image_has_alt = Some(false),
+            Event::Text(ref text) | Event::Code(ref text) if !text.trim().is_empty() => {
+                if let Some(ref mut has_alt) = self.image_has_alt {
+                    *has_alt = true;
+                }
+            }
+            Event::End(Tag::Image(..)) if self.image_has_alt.take() == Some(false) => {
+                return vec![Event::Text(CowStr::from(default_alt.to_string())), event];
+            }
+            _ => {}
+        }
+
+        vec![event]
+    }
+}
+
+#[test]
+fn tests_default_alt() {
+    let mut default_alt = EventDefaultAlt::new(Some("An illustration"));
+    let events = Generator::new_cmark_parser("![](a.png) ![Kept](b.png)\n").flat_map(|event| default_alt.convert(event));
+    let mut rendered = String::new();
+    html::push_html(&mut rendered, events);
+
+    assert_eq!(
+        rendered,
+        "<p><img src=\"a.png\" alt=\"An illustration\" /> <img src=\"b.png\" alt=\"Kept\" /></p>\n"
+    );
+}
+
+/// Gives every heading an `id` so links like `chapter.html#some-heading`
+/// resolve.
+///
+/// The heading's text is only known once it ends, so its events are buffered
+/// until then.
+struct EventHeadingIds<'a> {
+    heading: Option<Vec<Event<'a>>>,
+    seen: HashMap<String, usize>,
+    /// Put in front of every id, with `single_file`, so headings in
+    /// different chapters never share one.
+    prefix: Option<&'a str>,
+}
+
+impl<'a> EventHeadingIds<'a> {
+    fn new(prefix: Option<&'a str>) -> Self {
+        EventHeadingIds {
+            heading: None,
+            seen: HashMap::new(),
+            prefix,
+        }
+    }
+
+    fn convert(&mut self, event: Event<'a>) -> Vec<Event<'a>> {
+        match event {
+            Event::Start(Tag::Heading(..)) => {
+                self.heading = Some(vec![event]);
+                Vec::new()
+            }
+            Event::End(Tag::Heading(level, ref id, ref classes)) => {
+                let mut events = self.heading.take().unwrap_or_default();
+                let text: String = events
+                    .iter()
+                    .filter_map(|event| match *event {
+                        Event::Text(ref text) | Event::Code(ref text) => Some(text.as_ref()),
+                        _ => None,
+                    })
+                    .collect();
+                let id = match *id {
+                    Some(id) => id.to_string(),
+                    None => self.unique_slug(&text),
+                };
+                let id = match self.prefix {
+                    Some(prefix) => format!("{}-{}", prefix, id),
+                    None => id,
+                };
+
+                let mut open = format!("<{} id=\"{}\"", level, package::escape_xml(&id));
+                if !classes.is_empty() {
+                    open.push_str(&format!(" class=\"{}\"", package::escape_xml(&classes.join(" "))));
+                }
+                open.push('>');
+
+                // swap the start tag for one carrying the id
+                events[0] = Event::Html(CowStr::from(open));
+                events.push(event);
+                events
+            }
+            _ => match self.heading {
+                Some(ref mut heading) => {
+                    heading.push(event);
+                    Vec::new()
+                }
+                None => vec![event],
+            },
+        }
+    }
+
+    /// A slug for the heading, made unique within the chapter by appending
+    /// `-1`, `-2`, etc. to repeats.
+    fn unique_slug(&mut self, text: &str) -> String {
+        let slug = slugify(text);
+        let count = self.seen.entry(slug.clone()).or_insert(0);
+        let unique = if *count == 0 {
+            slug
+        } else {
+            format!("{}-{}", slug, count)
+        };
+        *count += 1;
+        unique
+    }
+}
+
+/// A GitHub-style slug: lowercase, spaces become hyphens and punctuation is
+/// dropped.
+fn slugify(text: &str) -> String {
+    text.trim()
+        .chars()
+        .filter_map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                Some(c.to_lowercase().collect::<String>())
+            } else if c.is_whitespace() {
+                Some(String::from("-"))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn tests_heading_ids() {
+    let input = "# Hello, World!\n\n## Usage\n\n## Usage\n\n## `Code` & Things\n\n## Usage\n";
+
+    let mut body = String::new();
+    let mut heading_ids = EventHeadingIds::new(None);
+    let events = Generator::new_cmark_parser(input).flat_map(|event| heading_ids.convert(event));
+    html::push_html(&mut body, events);
+
+    assert_eq!(
+        body,
+        "<h1 id=\"hello-world\">Hello, World!</h1>\n\
+         <h2 id=\"usage\">Usage</h2>\n\
+         <h2 id=\"usage-1\">Usage</h2>\n\
+         <h2 id=\"code--things\"><code>Code</code> &amp; Things</h2>\n\
+         <h2 id=\"usage-2\">Usage</h2>\n"
+    );
+
+    let mut body = String::new();
+    let mut heading_ids = EventHeadingIds::new(Some("chapter-intro"));
+    let events = Generator::new_cmark_parser("## Usage\n\n## Usage\n").flat_map(|event| heading_ids.convert(event));
+    html::push_html(&mut body, events);
+
+    assert_eq!(
+        body,
+        "<h2 id=\"chapter-intro-usage\">Usage</h2>\n<h2 id=\"chapter-intro-usage-1\">Usage</h2>\n"
+    );
+}
+
 fn filter_rust_code(code: &str) -> String {
     code.split("\n")
         .filter(|s| !s.starts_with("# "))
@@ -0,0 +1,67 @@
+use syntect::highlighting::{Theme, ThemeSet};
+use syntect::html::{self, ClassStyle, ClassedHTMLGenerator};
+use syntect::parsing::SyntaxSet;
+use syntect::util::LinesWithEndings;
+
+/// The theme used to colour highlighted code. A light theme reads best on
+/// e-ink displays.
+const THEME: &str = "InspiredGitHub";
+
+/// Prefix every generated class so highlighting never clashes with the rest
+/// of the stylesheet.
+const CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed { prefix: "hl-" };
+
+/// Server-side syntax highlighting for fenced code blocks.
+pub(crate) struct Highlighter {
+    syntaxes: SyntaxSet,
+    theme: Theme,
+}
+
+impl Highlighter {
+    pub(crate) fn new() -> Highlighter {
+        let mut themes = ThemeSet::load_defaults();
+        let theme = themes
+            .themes
+            .remove(THEME)
+            .expect("The highlighting theme is bundled with syntect");
+
+        Highlighter {
+            syntaxes: SyntaxSet::load_defaults_newlines(),
+            theme,
+        }
+    }
+
+    /// Can we highlight code written in this language?
+    pub(crate) fn supports(&self, lang: &str) -> bool {
+        self.syntaxes.find_syntax_by_token(lang).is_some()
+    }
+
+    /// Highlight `code` as `lang`, returning the markup for its `<pre>` block
+    /// or `None` when the language isn't known.
+    pub(crate) fn highlight(&self, lang: &str, code: &str) -> Option<String> {
+        let syntax = self.syntaxes.find_syntax_by_token(lang)?;
+        let mut generator =
+            ClassedHTMLGenerator::new_with_class_style(syntax, &self.syntaxes, CLASS_STYLE);
+
+        for line in LinesWithEndings::from(code) {
+            if let Err(e) = generator.parse_html_for_line_which_includes_newline(line) {
+                warn!("Unable to highlight a {} code block: {}", lang, e);
+                return None;
+            }
+        }
+
+        Some(format!(
+            "<pre class=\"highlight\"><code class=\"language-{}\">{}</code></pre>\n",
+            lang,
+            generator.finalize()
+        ))
+    }
+
+    /// The CSS which gives highlighted code its colours.
+    pub(crate) fn stylesheet(&self) -> String {
+        html::css_for_theme_with_class_style(&self.theme, CLASS_STYLE).unwrap_or_else(|e| {
+            warn!("Unable to generate the highlighting stylesheet: {}", e);
+            String::new()
+        })
+    }
+}
@@ -0,0 +1,176 @@
+use std::path::{Path, PathBuf};
+
+use crate::Error;
+
+const DIRECTIVE: &str = "{{#include";
+
+/// Splice the files named by `{{#include path}}` directives into some
+/// markdown, the way `mdbook`'s `links` preprocessor does, for chapters which
+/// reach the renderer without having been through it.
+///
+/// A path can be followed by `:anchor` for the lines between
+/// `ANCHOR: anchor` and `ANCHOR_END: anchor`, or by a line range such as
+/// `:10:20`, `:10:` or `:10`. Paths are relative to `dir`, and included
+/// files can include others relative to themselves. `including` is the files
+/// being included so far, starting with the chapter's own source, so
+/// circular includes are caught instead of recursing forever. A directive
+/// escaped as `\{{#include ...}}` is left in, without the backslash.
+pub(crate) fn resolve(content: &str, dir: &Path, including: &mut Vec<PathBuf>) -> Result<String, Error> {
+    let mut out = String::with_capacity(content.len());
+    let mut rest = content;
+
+    while let Some(start) = rest.find(DIRECTIVE) {
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end + 2,
+            None => break,
+        };
+
+        if rest[..start].ends_with('\\') {
+            out.push_str(&rest[..start - 1]);
+            out.push_str(&rest[start..end]);
+            rest = &rest[end..];
+            continue;
+        }
+
+        out.push_str(&rest[..start]);
+        let argument = rest[start + DIRECTIVE.len()..end - 2].trim();
+        out.push_str(&include(argument, dir, including)?);
+        rest = &rest[end..];
+    }
+
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// The contents of the file an include directive's `argument` names.
+fn include(argument: &str, dir: &Path, including: &mut Vec<PathBuf>) -> Result<String, Error> {
+    let (path, selection) = match argument.split_once(':') {
+        Some((path, selection)) => (path.trim(), Some(selection.trim())),
+        None => (argument, None),
+    };
+
+    let path = dir.join(path);
+    let full_path = path.canonicalize().map_err(|_| Error::IncludeNotFound(path.clone()))?;
+    if including.contains(&full_path) {
+        let chain: Vec<String> = including
+            .iter()
+            .chain(std::iter::once(&full_path))
+            .map(|path| path.display().to_string())
+            .collect();
+        return Err(Error::CircularInclude(chain.join(" -> ")));
+    }
+    let content = std::fs::read_to_string(&full_path).map_err(|_| Error::IncludeNotFound(path.clone()))?;
+
+    let selected = match selection {
+        None => content,
+        Some(selection) if selection.chars().all(|c| c.is_ascii_digit() || c == ':') => line_range(&content, selection),
+        Some(anchor) => anchored_lines(&content, anchor),
+    };
+
+    including.push(full_path.clone());
+    let dir = full_path.parent().unwrap_or_else(|| Path::new(""));
+    let resolved = resolve(&selected, dir, including);
+    including.pop();
+    resolved
+}
+
+/// The lines `range` picks out, counting from 1: `10` is just line 10,
+/// `10:20` lines 10 to 20, and either end can be left off.
+fn line_range(content: &str, range: &str) -> String {
+    let mut bounds = range.splitn(2, ':');
+    let start: usize = bounds.next().and_then(|start| start.parse().ok()).unwrap_or(1);
+    let end = match bounds.next() {
+        Some(end) => end.parse().unwrap_or(usize::MAX),
+        None => start,
+    };
+
+    let lines: Vec<&str> = content
+        .lines()
+        .enumerate()
+        .filter(|(ix, _)| (start.max(1)..=end).contains(&(ix + 1)))
+        .map(|(_, line)| line)
+        .collect();
+    lines.join("\n")
+}
+
+/// The lines between the `ANCHOR: anchor` and `ANCHOR_END: anchor` markers,
+/// leaving out the markers of any other anchors inside.
+fn anchored_lines(content: &str, anchor: &str) -> String {
+    let start_marker = format!("ANCHOR: {}", anchor);
+    let end_marker = format!("ANCHOR_END: {}", anchor);
+    let mut lines = Vec::new();
+    let mut inside = false;
+
+    for line in content.lines() {
+        if !inside {
+            inside = has_marker(line, &start_marker);
+        } else if has_marker(line, &end_marker) {
+            break;
+        } else if !line.contains("ANCHOR:") && !line.contains("ANCHOR_END:") {
+            lines.push(line);
+        }
+    }
+
+    lines.join("\n")
+}
+
+/// Does a line hold `marker`, and not just the start of a longer anchor name?
+fn has_marker(line: &str, marker: &str) -> bool {
+    line.match_indices(marker).any(|(ix, _)| {
+        !line[ix + marker.len()..]
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_alphanumeric() || c == '_' || c == '-')
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn files_are_spliced_in() {
+        let temp = TempDir::new("mdbook-epub-includes").unwrap();
+        std::fs::create_dir(temp.path().join("listings")).unwrap();
+        std::fs::write(temp.path().join("listings").join("main.rs"), "// ANCHOR: main\nfn main() {\n    // ANCHOR: body\n    println!(\"hi\");\n    // ANCHOR_END: body\n}\n// ANCHOR_END: main\n").unwrap();
+        std::fs::write(temp.path().join("notes.md"), "A note, then {{#include listings/main.rs:body}}").unwrap();
+        let chapter = temp.path().join("chapter.md");
+
+        let content = "# Chapter\n\n{{#include notes.md}}\n\n```rust\n{{#include listings/main.rs:main}}\n```\n\n\
+                       {{#include listings/main.rs:2:3}}\n\n\\{{#include notes.md}}\n";
+        let resolved = resolve(content, temp.path(), &mut vec![chapter]).unwrap();
+
+        assert_eq!(
+            resolved,
+            "# Chapter\n\nA note, then     println!(\"hi\");\n\n```rust\nfn main() {\n    println!(\"hi\");\n}\n```\n\n\
+             fn main() {\n    // ANCHOR: body\n\n{{#include notes.md}}\n"
+        );
+    }
+
+    #[test]
+    fn circular_includes_are_an_error() {
+        let temp = TempDir::new("mdbook-epub-includes").unwrap();
+        std::fs::write(temp.path().join("a.md"), "A {{#include b.md}}").unwrap();
+        std::fs::write(temp.path().join("b.md"), "B {{#include a.md}}").unwrap();
+        let chapter = temp.path().join("chapter.md");
+
+        let err = resolve("{{#include a.md}}", temp.path(), &mut vec![chapter]).unwrap_err();
+
+        let chain = match err {
+            Error::CircularInclude(chain) => chain,
+            err => panic!("{}", err),
+        };
+        let files: Vec<_> = chain.split(" -> ").map(|path| Path::new(path).file_name().unwrap().to_owned()).collect();
+        assert_eq!(files, ["chapter.md", "a.md", "b.md", "a.md"]);
+    }
+
+    #[test]
+    fn missing_files_are_an_error() {
+        let temp = TempDir::new("mdbook-epub-includes").unwrap();
+
+        let err = resolve("{{#include missing.md}}", temp.path(), &mut Vec::new()).unwrap_err();
+
+        assert!(matches!(err, Error::IncludeNotFound(ref path) if path.ends_with("missing.md")), "{}", err);
+    }
+}
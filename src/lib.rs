@@ -18,16 +18,47 @@ use semver::{Version, VersionReq};
 use std::fs::{create_dir_all, File};
 use std::path::{Path, PathBuf};
 
+mod admonition;
+mod book_index;
+mod cache;
+mod conditional;
 mod config;
+mod figures;
+mod flatten;
+mod footnotes;
+mod front_matter;
 mod generator;
+mod highlight;
+mod includes;
+mod math;
+mod mermaid;
+mod minify;
+mod package;
+mod progress;
+mod report;
 mod resources;
+mod sanitize;
+mod split;
+mod task_list;
+mod validate;
+mod wikilinks;
 
-pub use crate::config::Config;
-pub use crate::generator::Generator;
+pub use crate::config::{
+    Accessibility, Compression, Config, Contributor, CssTheme, Direction, DirectoryLayout, EpubVersion, FootnotePlacement,
+    IdentifierScheme, MarkdownOption, MissingAssetPolicy, PagePosition, RawPage, TaskListStyle,
+};
+pub use crate::generator::{ChapterPostProcessor, Generator};
+pub use crate::progress::{NoProgress, ProgressReporter, StderrProgress};
 
 /// The default stylesheet used to make the rendered document pretty.
 pub const DEFAULT_CSS: &str = include_str!("master.css");
 
+/// Added after the default stylesheet with `css-theme = "dark"`.
+pub const DARK_CSS: &str = include_str!("dark.css");
+
+/// Added after the default stylesheet with `css-theme = "sepia"`.
+pub const SEPIA_CSS: &str = include_str!("sepia.css");
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("Incompatible mdbook version got {0} expected {1}")]
@@ -45,15 +76,60 @@ pub enum Error {
     #[error("{0}")]
     AssetFileNotFound(String),
 
+    #[error("Unable to download {0}: {1}")]
+    AssetDownload(String, String),
+
     #[error("Asset was not a file {0}")]
     AssetFile(PathBuf),
 
+    #[error("Unable to find font file {0}")]
+    FontNotFound(PathBuf),
+
+    #[error("Unable to find template asset {0}")]
+    TemplateAssetNotFound(PathBuf),
+
+    #[error("Unable to find page {0}")]
+    PageNotFound(PathBuf),
+
+    #[error("Unable to find additional resource {0}")]
+    ResourceNotFound(PathBuf),
+
+    #[error("Unable to open additional resource {0}")]
+    ResourceOpen(PathBuf, #[source] std::io::Error),
+
     #[error("Could not open css file {0}")]
     CssOpen(PathBuf),
 
     #[error("Unable to open template {0}")]
     OpenTemplate(PathBuf),
 
+    #[error("Invalid chapter pattern \'{0}\'")]
+    ChapterPattern(String, #[source] glob::PatternError),
+
+    #[error("Invalid publication date \'{0}\', expected an RFC 3339 timestamp or a YYYY-MM-DD date")]
+    InvalidPubDate(String),
+
+    #[error("Invalid ISBN \'{0}\', it should be 10 or 13 digits long with a correct check digit")]
+    InvalidIsbn(String),
+
+    #[error("`identifier-scheme = \"{0}\"` needs an `identifier`")]
+    MissingIdentifier(String),
+
+    #[error("Every volume of a book split by part would have the identifier \'{0}\', leave `identifier` unset to give each its own")]
+    SharedIdentifier(String),
+
+    #[error("`cover-width` and `cover-height` have to be set together, to more than 0")]
+    InvalidCoverDimensions,
+
+    #[error("Unable to find included file {0}")]
+    IncludeNotFound(PathBuf),
+
+    #[error("Circular include: {0}")]
+    CircularInclude(String),
+
+    #[error("{0} isn't well-formed XML: {1}")]
+    MalformedPage(PathBuf, String),
+
     #[error("Unable to parse render context")]
     RenderContext,
 
@@ -75,12 +151,39 @@ pub enum Error {
     Semver(#[from] semver::SemVerError),
     #[error(transparent)]
     SemverReqParse(#[from] semver::ReqParseError),
-    #[error(transparent)]
-    EpubBuilder(#[from] epub_builder::Error),
+    /// epub-builder's own errors can't be shared between threads, so what
+    /// they say is kept instead.
+    #[error("{0}")]
+    EpubBuilder(String),
     #[error(transparent)]
     Render(#[from] handlebars::RenderError),
     #[error(transparent)]
     TomlDeser(#[from] toml::de::Error),
+    #[error(transparent)]
+    Zip(#[from] zip::result::ZipError),
+    #[error("Found links to chapters which aren't in the book: {0}")]
+    BrokenLinks(String),
+    #[error("Found images without alt text: {0}")]
+    MissingAltText(String),
+    #[error("The generated EPUB is invalid: {0}")]
+    Validation(String),
+    #[error("Unable to start the rendering threads: {0}")]
+    ThreadPool(#[from] rayon::ThreadPoolBuildError),
+}
+
+impl From<epub_builder::Error> for Error {
+    fn from(err: epub_builder::Error) -> Error {
+        let mut message = err.to_string();
+        let mut source = std::error::Error::source(&err);
+        while let Some(cause) = source {
+            let cause_message = cause.to_string();
+            if !message.ends_with(&cause_message) {
+                message = format!("{}: {}", message, cause_message);
+            }
+            source = cause.source();
+        }
+        Error::EpubBuilder(message)
+    }
 }
 
 /// The exact version of `mdbook` this crate is compiled against.
@@ -116,6 +219,31 @@ pub fn generate(ctx: &RenderContext) -> Result<(), Error> {
         create_dir_all(&ctx.destination)?;
     }
 
+    let config = Config::from_render_context(ctx)?;
+    if config.split_by_part {
+        let volumes = split::by_part(ctx)?;
+        if !volumes.is_empty() {
+            for mut volume in volumes {
+                let outfile = split::volume_filename(&outfile, &volume.part);
+                if config.dry_run {
+                    Generator::new(&volume.ctx)?.generate(std::io::stdout())?;
+                    continue;
+                }
+                if let Some(ref report_path) = config.report_path {
+                    let report_path = split::volume_path(report_path, &volume.part);
+                    volume.ctx.config.set("output.epub.report-path", report_path)?;
+                }
+                info!("Writing the {} part to {}", volume.part, outfile.display());
+                let f = File::create(&outfile)?;
+                Generator::new(&volume.ctx)?.generate(f)?;
+            }
+            return Ok(());
+        }
+    }
+
+    if config.dry_run {
+        return Generator::new(ctx)?.generate(std::io::stdout());
+    }
     let f = File::create(&outfile)?;
     Generator::new(ctx)?.generate(f)?;
 
@@ -123,7 +251,14 @@ pub fn generate(ctx: &RenderContext) -> Result<(), Error> {
 }
 
 /// Calculate the output filename using the `mdbook` config.
+///
+/// The `output.epub.output-filename` option wins, otherwise the file is named
+/// after the book's title.
 pub fn output_filename(dest: &Path, config: &MdConfig) -> PathBuf {
+    if let Some(filename) = config.get("output.epub.output-filename").and_then(|v| v.as_str()) {
+        return dest.join(filename);
+    }
+
     match config.book.title {
         Some(ref title) => dest.join(title).with_extension("epub"),
         None => dest.join("book.epub"),
@@ -0,0 +1,162 @@
+use latex2mathml::{latex_to_mathml, DisplayStyle};
+use pulldown_cmark::{CowStr, Event, Tag};
+
+/// Converts `$...$` (inline) and `$$...$$` (display) math in chapter text to
+/// MathML.
+///
+/// pulldown-cmark splits text into several events (e.g. around escapes), so
+/// consecutive text events are buffered and converted as one run.
+pub(crate) struct EventMathConverter {
+    enabled: bool,
+    in_code_block: bool,
+    pending: String,
+}
+
+impl EventMathConverter {
+    pub(crate) fn new(enabled: bool) -> Self {
+        EventMathConverter {
+            enabled,
+            in_code_block: false,
+            pending: String::new(),
+        }
+    }
+
+    pub(crate) fn convert<'a>(&mut self, event: Event<'a>) -> Vec<Event<'a>> {
+        if !self.enabled {
+            return vec![event];
+        }
+
+        match event {
+            Event::Text(ref text) if !self.in_code_block => {
+                self.pending.push_str(text);
+                Vec::new()
+            }
+            _ => {
+                match event {
+                    Event::Start(Tag::CodeBlock(_)) => self.in_code_block = true,
+                    Event::End(Tag::CodeBlock(_)) => self.in_code_block = false,
+                    _ => {}
+                }
+
+                let mut events = self.flush();
+                events.push(event);
+                events
+            }
+        }
+    }
+
+    fn flush<'a>(&mut self) -> Vec<Event<'a>> {
+        if self.pending.is_empty() {
+            return Vec::new();
+        }
+
+        let text = std::mem::take(&mut self.pending);
+        convert_math(&text)
+    }
+}
+
+/// Split some text into plain text and MathML.
+fn convert_math<'a>(text: &str) -> Vec<Event<'a>> {
+    let mut events = Vec::new();
+    let mut plain = String::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find('$') {
+        plain.push_str(&rest[..start]);
+        let after = &rest[start..];
+
+        let (delimiter, style) = if after.starts_with("$$") {
+            ("$$", DisplayStyle::Block)
+        } else {
+            ("$", DisplayStyle::Inline)
+        };
+
+        match find_math(after, delimiter) {
+            Some((latex, len)) => match latex_to_mathml(latex, style) {
+                Ok(mathml) => {
+                    if !plain.is_empty() {
+                        events.push(Event::Text(CowStr::from(std::mem::take(&mut plain))));
+                    }
+                    // display math is rendered as a block by the `<math>`
+                    // element itself
+                    events.push(Event::Html(CowStr::from(mathml)));
+                    rest = &after[len..];
+                }
+                Err(e) => {
+                    warn!("Unable to convert \"{}\" to MathML: {}", latex, e);
+                    plain.push_str(&after[..len]);
+                    rest = &after[len..];
+                }
+            },
+            None => {
+                plain.push_str(delimiter);
+                rest = &after[delimiter.len()..];
+            }
+        }
+    }
+
+    plain.push_str(rest);
+    if !plain.is_empty() {
+        events.push(Event::Text(CowStr::from(plain)));
+    }
+
+    events
+}
+
+/// Find the LaTeX between a pair of delimiters at the start of `text`,
+/// returning it along with the length of the whole math span.
+///
+/// Inline math must hug its delimiters (`$x$`, not `$ x $`) so prices like
+/// "$5 and $10" aren't mistaken for math.
+fn find_math<'t>(text: &'t str, delimiter: &str) -> Option<(&'t str, usize)> {
+    let body = &text[delimiter.len()..];
+    let end = body.find(delimiter)?;
+    let latex = &body[..end];
+
+    if latex.trim().is_empty() {
+        return None;
+    }
+    if delimiter == "$"
+        && (latex.starts_with(char::is_whitespace) || latex.ends_with(char::is_whitespace))
+    {
+        return None;
+    }
+
+    Some((latex, delimiter.len() * 2 + end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Generator;
+    use pulldown_cmark::html;
+
+    fn render(input: &str) -> String {
+        let mut body = String::new();
+        let mut converter = EventMathConverter::new(true);
+        let events = Generator::new_cmark_parser(input).flat_map(|event| converter.convert(event));
+        html::push_html(&mut body, events);
+        body
+    }
+
+    #[test]
+    fn inline_and_display_math() {
+        let body = render("Inline $x^2$ math.\n\n$$\\frac{1}{2}$$\n");
+
+        assert!(body.contains(r#"<p>Inline <math xmlns="http://www.w3.org/1998/Math/MathML" display="inline">"#), "{}", body);
+        assert!(body.contains(r#"<p><math xmlns="http://www.w3.org/1998/Math/MathML" display="block">"#), "{}", body);
+    }
+
+    #[test]
+    fn dollar_amounts_are_left_alone() {
+        assert_eq!(render("It costs $5 and $10.\n"), "<p>It costs $5 and $10.</p>\n");
+    }
+
+    #[test]
+    fn code_is_left_alone() {
+        assert_eq!(
+            render("```\n$x$\n```\n"),
+            "<pre><code>$x$\n</code></pre>\n"
+        );
+    }
+}
@@ -0,0 +1,252 @@
+use mdbook::book::{Book, BookItem};
+use pulldown_cmark::{CodeBlockKind, CowStr, Event, Parser, Tag};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+
+use crate::resources;
+
+/// The mermaid CLI (`npm install -g @mermaid-js/mermaid-cli`).
+const MERMAID_CLI: &str = "mmdc";
+
+/// Replaces ```` ```mermaid ```` code blocks with an image of the diagram.
+///
+/// Diagrams are rendered by `render`, which gives back where the image is
+/// packaged relative to the chapter. When it can't render a diagram, the
+/// code block is kept as is.
+pub(crate) struct EventMermaid<'a, F> {
+    enabled: bool,
+    render: F,
+    /// The events of the mermaid code block we're in.
+    pending: Option<Vec<Event<'a>>>,
+}
+
+impl<'a, F> EventMermaid<'a, F>
+where
+    F: FnMut(&str) -> Option<String>,
+{
+    pub(crate) fn new(enabled: bool, render: F) -> Self {
+        EventMermaid {
+            enabled,
+            render,
+            pending: None,
+        }
+    }
+
+    pub(crate) fn convert(&mut self, event: Event<'a>) -> Vec<Event<'a>> {
+        if !self.enabled {
+            return vec![event];
+        }
+
+        let mut pending = match self.pending.take() {
+            Some(pending) => pending,
+            None if is_mermaid(&event) => {
+                self.pending = Some(vec![event]);
+                return Vec::new();
+            }
+            None => return vec![event],
+        };
+
+        if let Event::End(Tag::CodeBlock(_)) = event {
+            let source: String = pending
+                .iter()
+                .filter_map(|event| match *event {
+                    Event::Text(ref text) => Some(text.as_ref()),
+                    _ => None,
+                })
+                .collect();
+
+            if let Some(href) = (self.render)(&source) {
+                let html = format!(
+                    "<div class=\"mermaid\"><img src=\"{}\" alt=\"Diagram\"/></div>\n",
+                    crate::package::escape_xml(&href)
+                );
+                return vec![Event::Html(CowStr::from(html))];
+            }
+
+            pending.push(event);
+            return pending;
+        }
+
+        pending.push(event);
+        self.pending = Some(pending);
+        Vec::new()
+    }
+}
+
+fn is_mermaid(event: &Event) -> bool {
+    match *event {
+        Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(ref info))) => {
+            info.split(|c: char| c == ',' || c.is_whitespace()).next() == Some("mermaid")
+        }
+        _ => false,
+    }
+}
+
+/// Every mermaid diagram in the book.
+pub(crate) fn find(book: &Book) -> Vec<String> {
+    let mut diagrams = Vec::new();
+
+    for section in book.iter() {
+        if let BookItem::Chapter(ref ch) = *section {
+            let mut collector = EventMermaid::new(true, |source: &str| {
+                diagrams.push(source.to_string());
+                None
+            });
+            for event in Parser::new(&ch.content) {
+                collector.convert(event);
+            }
+        }
+    }
+
+    diagrams.sort();
+    diagrams.dedup();
+    diagrams
+}
+
+/// Where a diagram is packaged, relative to the `src/` directory.
+///
+/// The name is derived from the diagram's source so chapters can refer to
+/// the image before it's been added to the book.
+pub(crate) fn diagram_filename(source: &str) -> PathBuf {
+    Path::new("mermaid")
+        .join(format!("{:016x}", resources::stable_hash(source.as_bytes())))
+        .with_extension("svg")
+}
+
+/// The diagrams rendered during one build, keyed by their source, along
+/// with those which couldn't be, so each is only given to the CLI (and
+/// warned about) once however many chapters it's in. Chapters are rendered
+/// on several threads, which take turns.
+#[derive(Debug, Default)]
+pub(crate) struct Diagrams {
+    rendered: Mutex<HashMap<String, Option<PathBuf>>>,
+}
+
+impl Diagrams {
+    /// Where the diagram was rendered to in `cache_dir`, rendering it the
+    /// first time it's asked for.
+    pub(crate) fn render(&self, source: &str, cache_dir: &Path) -> Option<PathBuf> {
+        self.remember(source, || render(source, cache_dir))
+    }
+
+    fn remember<F: FnOnce() -> Option<PathBuf>>(&self, source: &str, render: F) -> Option<PathBuf> {
+        let mut rendered = self.rendered.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        rendered.entry(source.to_string()).or_insert_with(render).clone()
+    }
+}
+
+/// Render a diagram to an SVG in `cache_dir`, returning where it was
+/// written. Diagrams which were rendered by an earlier build aren't
+/// rendered again.
+fn render(source: &str, cache_dir: &Path) -> Option<PathBuf> {
+    let location = cache_dir.join(diagram_filename(source));
+    if location.is_file() {
+        return Some(location);
+    }
+
+    let input = location.with_extension("mmd");
+    let rendered = fs::create_dir_all(cache_dir.join("mermaid"))
+        .and_then(|_| fs::write(&input, source))
+        .and_then(|_| Command::new(MERMAID_CLI).arg("-i").arg(&input).arg("-o").arg(&location).output());
+
+    match rendered {
+        Ok(output) if output.status.success() && location.is_file() => Some(location),
+        Ok(output) => {
+            warn!(
+                "Unable to render a mermaid diagram, leaving it as a code block: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            None
+        }
+        Err(e) => {
+            warn!(
+                "Unable to run `{}` to render a mermaid diagram, leaving it as a code block: {}",
+                MERMAID_CLI, e
+            );
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mdbook::book::Chapter;
+    use pulldown_cmark::html;
+
+    const DIAGRAM: &str = "# Flow\n\n```mermaid\ngraph TD\n  A --> B\n```\n";
+
+    fn render_with<F: FnMut(&str) -> Option<String>>(markdown: &str, render: F) -> String {
+        let mut mermaid = EventMermaid::new(true, render);
+        let events = Parser::new(markdown).flat_map(|event| mermaid.convert(event));
+        let mut rendered = String::new();
+        html::push_html(&mut rendered, events);
+        rendered
+    }
+
+    #[test]
+    fn diagrams_become_images() {
+        let rendered = render_with(DIAGRAM, |source| {
+            assert_eq!(source, "graph TD\n  A --> B\n");
+            Some(String::from("../mermaid/diagram.svg"))
+        });
+
+        assert!(
+            rendered.contains(r#"<div class="mermaid"><img src="../mermaid/diagram.svg" alt="Diagram"/></div>"#),
+            "{}",
+            rendered
+        );
+        assert!(!rendered.contains("<code"), "{}", rendered);
+    }
+
+    #[test]
+    fn diagrams_which_cant_be_rendered_stay_code_blocks() {
+        let rendered = render_with(DIAGRAM, |_| None);
+
+        assert!(rendered.contains("<pre><code class=\"language-mermaid\">graph TD\n  A --&gt; B\n</code></pre>"), "{}", rendered);
+    }
+
+    #[test]
+    fn other_code_blocks_are_left_alone() {
+        let rendered = render_with("```rust\nfn main() {}\n```\n", |_| panic!("not a diagram"));
+
+        assert!(rendered.contains("language-rust"), "{}", rendered);
+    }
+
+    #[test]
+    fn diagrams_are_rendered_once_per_build() {
+        let diagrams = Diagrams::default();
+        let mut attempts = 0;
+        for _ in 0..3 {
+            assert_eq!(
+                diagrams.remember("graph TD", || {
+                    attempts += 1;
+                    None
+                }),
+                None
+            );
+        }
+        assert_eq!(attempts, 1);
+
+        let rendered = diagrams.remember("graph LR", || Some(PathBuf::from("a.svg")));
+        assert_eq!(rendered, Some(PathBuf::from("a.svg")));
+        assert_eq!(diagrams.remember("graph LR", || panic!("rendered again")), rendered);
+    }
+
+    #[test]
+    fn diagram_names_stay_the_same_between_builds() {
+        assert_eq!(diagram_filename("graph TD\n"), PathBuf::from("mermaid/52fdda3df2858d3d.svg"));
+    }
+
+    #[test]
+    fn find_every_diagram_once() {
+        let mut book = Book::new();
+        book.push_item(Chapter::new("One", String::from(DIAGRAM), "one.md", Vec::new()));
+        book.push_item(Chapter::new("Two", String::from(DIAGRAM), "two.md", Vec::new()));
+
+        assert_eq!(find(&book), vec![String::from("graph TD\n  A --> B\n")]);
+    }
+}
@@ -0,0 +1,204 @@
+/// Elements whose text is kept exactly as it is.
+const PRESERVED_ELEMENTS: &[&str] = &["pre", "code", "textarea", "script", "style"];
+
+/// Collapse each run of whitespace in the text of some XHTML into a single
+/// character, a newline if the run had one and a space otherwise. Tags,
+/// comments and the text of `<pre>`, `<code>` and the like are left alone,
+/// so nothing but the size of the page changes.
+pub(crate) fn xhtml(xhtml: &str) -> String {
+    let mut out = String::with_capacity(xhtml.len());
+    // the preserved elements which are open, innermost last
+    let mut preserving: Vec<String> = Vec::new();
+    let mut rest = xhtml;
+
+    while !rest.is_empty() {
+        let start = rest.find('<').unwrap_or(rest.len());
+        let text = &rest[..start];
+        if preserving.is_empty() {
+            collapse_whitespace(text, &mut out);
+        } else {
+            out.push_str(text);
+        }
+        rest = &rest[start..];
+        if rest.is_empty() {
+            break;
+        }
+
+        let end = markup_end(rest);
+        let markup = &rest[..end];
+        out.push_str(markup);
+        rest = &rest[end..];
+
+        if let Some(name) = markup.strip_prefix("</").map(tag_name) {
+            if preserving.last().is_some_and(|open| open.eq_ignore_ascii_case(name)) {
+                preserving.pop();
+            }
+        } else if !markup.starts_with("<!") && !markup.starts_with("<?") && !markup.ends_with("/>") {
+            let name = tag_name(&markup[1..]);
+            if PRESERVED_ELEMENTS.iter().any(|element| element.eq_ignore_ascii_case(name)) {
+                preserving.push(name.to_string());
+            }
+        }
+    }
+
+    out
+}
+
+/// Remove the comments from a stylesheet, along with the whitespace which
+/// doesn't matter. Strings are left alone.
+pub(crate) fn css(css: &str) -> String {
+    let mut out = String::with_capacity(css.len());
+    let mut chars = css.chars().peekable();
+    let mut pending_space = false;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut previous = ' ';
+                for c in chars.by_ref() {
+                    if previous == '*' && c == '/' {
+                        break;
+                    }
+                    previous = c;
+                }
+                pending_space = true;
+            }
+            c if c.is_whitespace() => pending_space = true,
+            '{' | '}' | ';' | ',' => {
+                if c == '}' && out.ends_with(';') {
+                    out.pop();
+                }
+                out.push(c);
+                pending_space = false;
+            }
+            '"' | '\'' => {
+                push_pending_space(&mut out, &mut pending_space);
+                out.push(c);
+                let mut escaped = false;
+                for inner in chars.by_ref() {
+                    out.push(inner);
+                    match inner {
+                        _ if escaped => escaped = false,
+                        '\\' => escaped = true,
+                        _ if inner == c => break,
+                        _ => {}
+                    }
+                }
+            }
+            _ => {
+                push_pending_space(&mut out, &mut pending_space);
+                out.push(c);
+            }
+        }
+    }
+
+    out
+}
+
+/// Write a space for the whitespace skipped before something, unless it
+/// comes after punctuation which doesn't need one.
+fn push_pending_space(out: &mut String, pending_space: &mut bool) {
+    if std::mem::take(pending_space) && !out.is_empty() && !out.ends_with(['{', '}', ';', ',', ':']) {
+        out.push(' ');
+    }
+}
+
+fn collapse_whitespace(text: &str, out: &mut String) {
+    let mut run: Option<char> = None;
+
+    for c in text.chars() {
+        if c.is_whitespace() {
+            if c == '\n' || run.is_none() {
+                run = Some(if c == '\n' { '\n' } else { ' ' });
+            }
+            continue;
+        }
+        if let Some(space) = run.take() {
+            out.push(space);
+        }
+        out.push(c);
+    }
+
+    if let Some(space) = run {
+        out.push(space);
+    }
+}
+
+/// Where the tag, comment or declaration at the start of `xhtml` ends.
+fn markup_end(xhtml: &str) -> usize {
+    let terminator = if xhtml.starts_with("<!--") {
+        "-->"
+    } else if xhtml.starts_with("<![CDATA[") {
+        "]]>"
+    } else {
+        let mut quote = None;
+        for (ix, c) in xhtml.char_indices() {
+            match (quote, c) {
+                (Some(q), c) if c == q => quote = None,
+                (Some(_), _) => {}
+                (None, '"') | (None, '\'') => quote = Some(c),
+                (None, '>') => return ix + 1,
+                _ => {}
+            }
+        }
+        return xhtml.len();
+    };
+
+    xhtml.find(terminator).map_or(xhtml.len(), |end| end + terminator.len())
+}
+
+fn tag_name(tag: &str) -> &str {
+    let end = tag
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == ':'))
+        .unwrap_or(tag.len());
+    &tag[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn whitespace_between_text_is_collapsed() {
+        let minified = xhtml("<body>\n    <h1  class=\"a  b\">Some   title</h1>\n\n    <p>\n      Some <em>text</em>\t here.\n    </p>\n</body>\n");
+
+        assert_eq!(
+            minified,
+            "<body>\n<h1  class=\"a  b\">Some title</h1>\n<p>\nSome <em>text</em> here.\n</p>\n</body>\n"
+        );
+    }
+
+    #[test]
+    fn code_is_left_alone() {
+        let page = "<div>\n  <pre><code class=\"language-rust\">fn main() {\n    println!(\"hi\");\n}\n</code></pre>\n  <p>Use <code>a  b</code>   here</p>\n</div>\n";
+
+        let minified = xhtml(page);
+
+        assert_eq!(
+            minified,
+            "<div>\n<pre><code class=\"language-rust\">fn main() {\n    println!(\"hi\");\n}\n</code></pre>\n\
+             <p>Use <code>a  b</code> here</p>\n</div>\n"
+        );
+    }
+
+    #[test]
+    fn comments_and_self_closing_tags_are_left_alone() {
+        let page = "<!--   a <pre> comment -->\n  <br/>  <code/>  text   <![CDATA[ x   y ]]>\n";
+
+        assert_eq!(
+            xhtml(page),
+            "<!--   a <pre> comment -->\n<br/> <code/> text <![CDATA[ x   y ]]>\n"
+        );
+    }
+
+    #[test]
+    fn stylesheets_lose_comments_and_whitespace() {
+        let stylesheet = "/* The body */\nbody {\n    font-family: \"Open  Sans\", serif;\n    margin: 0 auto;\n}\n\na:hover, div  :first-child { content: '/* not a comment */'; }\n";
+
+        assert_eq!(
+            css(stylesheet),
+            "body{font-family:\"Open  Sans\",serif;margin:0 auto}a:hover,div :first-child{content:'/* not a comment */'}"
+        );
+    }
+}
@@ -0,0 +1,798 @@
+use super::Error;
+use crate::config::{Compression, DirectoryLayout};
+use crate::flatten;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{Cursor, Read, Seek, Write};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// The location of the package document written by `epub-builder`.
+pub(crate) const CONTENT_OPF: &str = "OEBPS/content.opf";
+
+/// The location of the EPUB 3 navigation document written by `epub-builder`.
+pub(crate) const NAV_XHTML: &str = "OEBPS/nav.xhtml";
+
+/// The location of the NCX, the navigation EPUB 2 readers use.
+const TOC_NCX: &str = "OEBPS/toc.ncx";
+
+/// The start of the element holding a package's last-modified timestamp.
+const MODIFIED_META: &str = "<meta property=\"dcterms:modified\"";
+
+/// Tweaks to the package document (`content.opf`) which `epub-builder` has
+/// no API for, applied after the archive has been generated.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(crate) struct PackagePatch {
+    /// Replaces the randomly generated `dc:identifier`.
+    pub(crate) identifier: Option<String>,
+    /// The `opf:scheme` of the `dc:identifier`, for EPUB 2.
+    pub(crate) identifier_scheme: Option<String>,
+    /// Replaces the `dc:date`, which otherwise defaults to the build time.
+    pub(crate) date: Option<String>,
+    /// The `dcterms:modified` timestamp EPUB 3 requires, which replaces the
+    /// existing one or is added when there isn't one.
+    pub(crate) modified: Option<String>,
+    /// Raw XML elements appended to the `<metadata>` section.
+    pub(crate) metadata: Vec<String>,
+    /// Extra `properties` for manifest items, keyed by their `href`.
+    pub(crate) properties: Vec<(String, String)>,
+    /// Extra `properties` for the spine's references to manifest items,
+    /// keyed by the items' `href`s.
+    pub(crate) spine_properties: Vec<(String, String)>,
+    /// The `href`s of the chapters in the order the spine should read them,
+    /// which the spine's references to them are rearranged into. Other pages
+    /// keep their places.
+    pub(crate) spine_order: Vec<String>,
+    /// The spine's `page-progression-direction`, `ltr` or `rtl`.
+    pub(crate) page_progression_direction: Option<String>,
+    /// Raw `<reference>` elements appended to the `<guide>`.
+    pub(crate) guide: Vec<String>,
+    /// Raw `<li>` elements added to the EPUB 3 navigation document's
+    /// landmarks.
+    pub(crate) landmarks: Vec<String>,
+    /// Remove the generator `<meta>` from the navigation document.
+    pub(crate) strip_generator: bool,
+    /// Set the NCX's `dtb:depth` to how deep its entries really nest, which
+    /// `epub-builder` always says is 1.
+    pub(crate) ncx_depth: bool,
+}
+
+impl PackagePatch {
+    /// Does this patch leave the package document untouched?
+    pub(crate) fn is_empty(&self) -> bool {
+        self.identifier.is_none()
+            && self.identifier_scheme.is_none()
+            && self.date.is_none()
+            && self.modified.is_none()
+            && self.metadata.is_empty()
+            && self.properties.is_empty()
+            && self.spine_properties.is_empty()
+            && self.spine_order.is_empty()
+            && self.page_progression_direction.is_none()
+            && self.guide.is_empty()
+            && self.landmarks.is_empty()
+            && !self.strip_generator
+            && !self.ncx_depth
+    }
+
+    /// Apply the patch to the text of an NCX.
+    pub(crate) fn apply_ncx(&self, ncx: &str) -> String {
+        if !self.ncx_depth {
+            return ncx.to_string();
+        }
+
+        let mut depth = 0;
+        let mut deepest = 1;
+        for (ix, _) in ncx.match_indices("navPoint") {
+            match &ncx[..ix] {
+                before if before.ends_with("</") => depth -= 1,
+                before if before.ends_with('<') => {
+                    depth += 1;
+                    deepest = deepest.max(depth);
+                }
+                _ => {}
+            }
+        }
+
+        let meta = "<meta name=\"dtb:depth\" content=\"";
+        match ncx.find(meta) {
+            Some(ix) => {
+                let value_start = ix + meta.len();
+                let value_end = value_start + ncx[value_start..].find('"').unwrap_or(0);
+                format!("{}{}{}", &ncx[..value_start], deepest, &ncx[value_end..])
+            }
+            None => ncx.to_string(),
+        }
+    }
+
+    /// Apply the patch to the text of a navigation document.
+    pub(crate) fn apply_nav(&self, nav: &str) -> String {
+        let nav = if self.strip_generator {
+            remove_generator_meta(nav)
+        } else {
+            nav.to_string()
+        };
+        if self.landmarks.is_empty() {
+            return nav;
+        }
+        let start = match nav.find("\"landmarks\"") {
+            Some(start) => start,
+            None => return nav,
+        };
+        let end = match nav[start..].find("</nav>") {
+            Some(end) => start + end,
+            None => return nav,
+        };
+
+        let mut nav = nav;
+        let entries: String = self
+            .landmarks
+            .iter()
+            .map(|entry| format!("      {}\n", entry))
+            .collect();
+        match nav[start..end].rfind("</ol>") {
+            Some(list_end) => {
+                let line_start = nav[..start + list_end].rfind('\n').map_or(start + list_end, |ix| ix + 1);
+                nav.insert_str(line_start, &entries);
+            }
+            None => {
+                let content_start = nav[start..end].find('>').map_or(end, |ix| start + ix + 1);
+                let list = format!("\n    <ol>\n{}    </ol>\n  ", entries);
+                nav.replace_range(content_start..end, &list);
+            }
+        }
+        nav
+    }
+
+    /// Apply the patch to the text of a package document.
+    pub(crate) fn apply(&self, opf: &str) -> String {
+        let mut opf = unique_ids(opf);
+
+        if let Some(ref identifier) = self.identifier {
+            opf = replace_element_text(&opf, "<dc:identifier", "</dc:identifier>", &escape_xml(identifier));
+        }
+        if let Some(ref scheme) = self.identifier_scheme {
+            let attribute = format!("<dc:identifier opf:scheme=\"{}\"", escape_xml(scheme));
+            opf = opf.replacen("<dc:identifier", &attribute, 1);
+        }
+
+        if let Some(ref date) = self.date {
+            opf = replace_element_text(&opf, "<dc:date", "</dc:date>", &escape_xml(date));
+        }
+
+        let mut metadata = self.metadata.clone();
+        if let Some(ref modified) = self.modified {
+            if opf.contains(MODIFIED_META) {
+                opf = replace_element_text(&opf, MODIFIED_META, "</meta>", &escape_xml(modified));
+            } else {
+                metadata.insert(0, format!("{}>{}</meta>", MODIFIED_META, escape_xml(modified)));
+            }
+        }
+
+        append_to_element(&mut opf, "</metadata>", &metadata);
+        append_to_element(&mut opf, "</guide>", &self.guide);
+
+        for (href, property) in &self.properties {
+            opf = add_item_property(&opf, href, property);
+        }
+        for (href, property) in &self.spine_properties {
+            opf = add_itemref_property(&opf, href, property);
+        }
+        if !self.spine_order.is_empty() {
+            opf = reorder_spine(&opf, &self.spine_order);
+        }
+
+        if let Some(ref direction) = self.page_progression_direction {
+            let attribute = format!("<spine page-progression-direction=\"{}\"", escape_xml(direction));
+            opf = opf.replacen("<spine", &attribute, 1);
+        }
+
+        opf
+    }
+}
+
+/// Add `elements` to the end of the element closed by `end`, a level deeper
+/// than its closing tag.
+fn append_to_element(opf: &mut String, end: &str, elements: &[String]) {
+    if elements.is_empty() {
+        return;
+    }
+
+    if let Some(end) = opf.find(end) {
+        // insert at the start of the closing tag's line to keep the
+        // indentation intact
+        let line_start = opf[..end].rfind('\n').map(|ix| ix + 1).unwrap_or(end);
+        let indent = &opf[line_start..end];
+        let indent = if indent.trim().is_empty() { indent } else { "" };
+        let extra: String = elements
+            .iter()
+            .map(|element| format!("{}  {}\n", indent, element))
+            .collect();
+        opf.insert_str(line_start, &extra);
+    }
+}
+
+/// Add a property to the manifest `<item>` with the given `href`.
+fn add_item_property(opf: &str, href: &str, property: &str) -> String {
+    let needle = format!("href=\"{}\"", escape_xml(href));
+    let href_ix = match opf.find(&needle) {
+        Some(ix) => ix,
+        None => {
+            warn!("No manifest item found for {}", href);
+            return opf.to_string();
+        }
+    };
+    let item_start = opf[..href_ix].rfind("<item").unwrap_or(href_ix);
+    let item_end = opf[href_ix..].find('>').map(|ix| href_ix + ix).unwrap_or(href_ix);
+    let item = &opf[item_start..item_end];
+
+    format!("{}{}{}", &opf[..item_start], with_property(item, property), &opf[item_end..])
+}
+
+/// An element's start tag, up to its closing `>`, with `property` added to
+/// its `properties`.
+fn with_property(tag: &str, property: &str) -> String {
+    match tag.find("properties=\"") {
+        Some(ix) => {
+            let value_start = ix + "properties=\"".len();
+            format!("{}{} {}", &tag[..value_start], property, &tag[value_start..])
+        }
+        None => format!("{} properties=\"{}\"", tag.trim_end_matches('/'), property)
+            + if tag.ends_with('/') { "/" } else { "" },
+    }
+}
+
+/// Add a property to the spine's `<itemref>` for the manifest item at `href`.
+fn add_itemref_property(opf: &str, href: &str, property: &str) -> String {
+    let needle = format!("href=\"{}\"", escape_xml(href));
+    let id = opf.find(&needle).and_then(|href_ix| {
+        let item_start = opf[..href_ix].rfind("<item")?;
+        let item_end = href_ix + opf[href_ix..].find('>')?;
+        attribute(&opf[item_start..item_end], "id")
+    });
+    let itemref_ix = id.and_then(|id| opf.find(&format!("<itemref idref=\"{}\"", id)));
+    let itemref_ix = match itemref_ix {
+        Some(ix) => ix,
+        None => {
+            warn!("No spine item found for {}", href);
+            return opf.to_string();
+        }
+    };
+    let itemref_end = itemref_ix + opf[itemref_ix..].find('>').unwrap_or(0);
+    let itemref = &opf[itemref_ix..itemref_end];
+
+    format!("{}{}{}", &opf[..itemref_ix], with_property(itemref, property), &opf[itemref_end..])
+}
+
+/// Rearrange the spine's references to the manifest items at `hrefs` into
+/// that order, in the places those references were. The rest of the spine
+/// is left where it is.
+fn reorder_spine(opf: &str, hrefs: &[String]) -> String {
+    let mut ids = HashMap::new();
+    let mut rest = opf;
+    while let Some(start) = rest.find("<item ") {
+        let end = rest[start..].find('>').map_or(rest.len(), |end| start + end);
+        let item = &rest[start..end];
+        if let (Some(id), Some(href)) = (attribute(item, "id"), attribute(item, "href")) {
+            ids.insert(href, id);
+        }
+        rest = &rest[end..];
+    }
+    let order: Vec<&str> = hrefs
+        .iter()
+        .filter_map(|href| ids.get(escape_xml(href).as_str()).copied())
+        .collect();
+
+    // where each reference being moved is, and its whole element
+    let spine_start = opf.find("<spine").unwrap_or(opf.len());
+    let mut itemrefs = Vec::new();
+    let mut ix = spine_start;
+    while let Some(start) = opf[ix..].find("<itemref ").map(|start| ix + start) {
+        let end = opf[start..].find('>').map_or(opf.len(), |end| start + end + 1);
+        let itemref = &opf[start..end];
+        if attribute(itemref, "idref").is_some_and(|idref| order.contains(&idref)) {
+            itemrefs.push((start..end, itemref));
+        }
+        ix = end;
+    }
+    let mut sorted: Vec<&str> = itemrefs.iter().map(|&(_, itemref)| itemref).collect();
+    sorted.sort_by_key(|itemref| attribute(itemref, "idref").and_then(|idref| order.iter().position(|id| *id == idref)));
+
+    let mut out = String::with_capacity(opf.len());
+    let mut last = 0;
+    for ((range, _), itemref) in itemrefs.iter().zip(sorted) {
+        out.push_str(&opf[last..range.start]);
+        out.push_str(itemref);
+        last = range.end;
+    }
+    out.push_str(&opf[last..]);
+    out
+}
+
+/// Give every manifest item an id of its own which is a valid XML name.
+/// `epub-builder` makes ids from paths by replacing `.` and `/` with `_`, so
+/// `guide/index.html` and `guide_index.html` get the same one, and paths
+/// starting with a digit or holding spaces give invalid ones. Clashing ids
+/// get a number added, and the spine is pointed at the renamed items, which
+/// it lists in the same order as the manifest.
+pub(crate) fn unique_ids(opf: &str) -> String {
+    let mut out = String::with_capacity(opf.len());
+    let mut taken = HashSet::new();
+    // the new ids of the chapters given each original id, in order
+    let mut spine_ids: HashMap<String, VecDeque<String>> = HashMap::new();
+    let mut rest = opf;
+
+    while let Some(start) = rest.find("<item ") {
+        let end = match rest[start..].find('>') {
+            Some(end) => start + end + 1,
+            None => break,
+        };
+        let item = &rest[start..end];
+        out.push_str(&rest[..start]);
+        rest = &rest[end..];
+
+        let id = match attribute(item, "id") {
+            Some(id) => id,
+            None => {
+                out.push_str(item);
+                continue;
+            }
+        };
+        let base = valid_id(id);
+        let mut unique = base.clone();
+        let mut count = 2;
+        while !taken.insert(unique.clone()) {
+            unique = format!("{}-{}", base, count);
+            count += 1;
+        }
+
+        if attribute(item, "media-type") == Some("application/xhtml+xml") {
+            spine_ids.entry(id.to_string()).or_default().push_back(unique.clone());
+        }
+        if unique == id {
+            out.push_str(item);
+        } else {
+            debug!("Renaming the manifest item {} to {}", id, unique);
+            out.push_str(&item.replacen(&format!(" id=\"{}\"", id), &format!(" id=\"{}\"", unique), 1));
+        }
+    }
+    out.push_str(rest);
+
+    let mut opf = String::with_capacity(out.len());
+    let mut rest = out.as_str();
+    while let Some(start) = rest.find("<itemref idref=\"") {
+        let value_start = start + "<itemref idref=\"".len();
+        let value_end = match rest[value_start..].find('"') {
+            Some(end) => value_start + end,
+            None => break,
+        };
+        let idref = &rest[value_start..value_end];
+        let renamed = spine_ids
+            .get_mut(idref)
+            .and_then(|ids| ids.pop_front())
+            .unwrap_or_else(|| valid_id(idref));
+        opf.push_str(&rest[..value_start]);
+        opf.push_str(&renamed);
+        rest = &rest[value_end..];
+    }
+    opf.push_str(rest);
+    opf
+}
+
+/// The value of the `name` attribute of an element's start tag.
+fn attribute<'t>(tag: &'t str, name: &str) -> Option<&'t str> {
+    let needle = format!(" {}=\"", name);
+    let value = &tag[tag.find(&needle)? + needle.len()..];
+    Some(&value[..value.find('"')?])
+}
+
+/// An XML name made from an id, with anything which can't be in one replaced.
+fn valid_id(id: &str) -> String {
+    let id: String = id
+        .chars()
+        .map(|c| if c.is_alphanumeric() || c == '_' || c == '-' { c } else { '_' })
+        .collect();
+    match id.chars().next() {
+        Some(c) if c.is_alphabetic() || c == '_' => id,
+        _ => format!("id-{}", id),
+    }
+}
+
+/// Does the package document of a generated EPUB need its ids fixing?
+fn has_clashing_ids(epub: &[u8]) -> Result<bool, Error> {
+    let mut archive = ZipArchive::new(Cursor::new(epub))?;
+    let mut opf = String::new();
+    archive.by_name(CONTENT_OPF)?.read_to_string(&mut opf)?;
+    Ok(unique_ids(&opf) != opf)
+}
+
+/// Copy a generated EPUB archive into `writer`, patching its package
+/// document and recompressing its files along the way. With `flatten`, every
+/// file is also moved to the top of `OEBPS`, and with the conventional
+/// `layout` into the directory for its kind, and what refers to it updated.
+pub(crate) fn rewrite<R, W>(
+    epub: R,
+    writer: W,
+    patch: &PackagePatch,
+    compression: Compression,
+    flatten: bool,
+    layout: DirectoryLayout,
+) -> Result<(), Error>
+where
+    R: Read + Seek,
+    W: Write + Seek,
+{
+    let mut archive = ZipArchive::new(epub)?;
+    let mut out = ZipWriter::new(writer);
+
+    let moving = flatten || layout != DirectoryLayout::Flat;
+    let renamed = if moving {
+        let names = (0..archive.len())
+            .map(|i| archive.by_index(i).map(|file| file.name().to_string()))
+            .collect::<Result<Vec<_>, _>>()?;
+        flatten::new_names(&names, flatten, layout)
+    } else {
+        HashMap::new()
+    };
+
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        let mut name = file.name().to_string();
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+
+        if name == CONTENT_OPF {
+            debug!("Patching the package document");
+            let opf = String::from_utf8_lossy(&content);
+            content = patch.apply(&opf).into_bytes();
+        }
+        if name == NAV_XHTML {
+            let nav = String::from_utf8_lossy(&content);
+            content = patch.apply_nav(&nav).into_bytes();
+        }
+        if name == TOC_NCX {
+            let ncx = String::from_utf8_lossy(&content);
+            content = patch.apply_ncx(&ncx).into_bytes();
+        }
+        if moving && flatten::has_references(&name) {
+            let text = String::from_utf8_lossy(&content);
+            content = flatten::rewrite_references(&text, &name, &renamed).into_bytes();
+        }
+        if let Some(new_name) = renamed.get(&name) {
+            trace!("Moving {} to {}", name, new_name);
+            name = new_name.clone();
+        }
+
+        // the "mimetype" entry must be stored uncompressed
+        let options = if name == "mimetype" {
+            FileOptions::default().compression_method(CompressionMethod::Stored)
+        } else {
+            file_options(compression)
+        };
+        out.start_file(name, options)?;
+        out.write_all(&content)?;
+    }
+
+    out.finish()?;
+    Ok(())
+}
+
+fn file_options(compression: Compression) -> FileOptions {
+    let (method, level) = match compression {
+        Compression::Stored => (CompressionMethod::Stored, None),
+        Compression::DeflateFast => (CompressionMethod::Deflated, Some(1)),
+        Compression::Deflate => (CompressionMethod::Deflated, None),
+        Compression::DeflateBest => (CompressionMethod::Deflated, Some(9)),
+    };
+
+    FileOptions::default()
+        .compression_method(method)
+        .compression_level(level)
+}
+
+/// Generate the final archive into `writer`, only going through the slower
+/// repacking step when there's actually something to patch (including ids
+/// which clash), the files need compressing differently to how
+/// `epub-builder` does it, or they're being moved out of their directories.
+pub(crate) fn write<W: Write>(
+    epub: Vec<u8>,
+    mut writer: W,
+    patch: &PackagePatch,
+    compression: Compression,
+    flatten: bool,
+    layout: DirectoryLayout,
+) -> Result<(), Error> {
+    if patch.is_empty()
+        && compression == Compression::Deflate
+        && !flatten
+        && layout == DirectoryLayout::Flat
+        && !has_clashing_ids(&epub)?
+    {
+        writer.write_all(&epub)?;
+        return Ok(());
+    }
+
+    let mut buffer = Cursor::new(Vec::new());
+    rewrite(Cursor::new(epub), &mut buffer, patch, compression, flatten, layout)?;
+    writer.write_all(buffer.get_ref())?;
+    Ok(())
+}
+
+/// Replace the text content of the first element opened by `start` and
+/// closed by `end`.
+fn replace_element_text(xml: &str, start: &str, end: &str, text: &str) -> String {
+    let open = match xml.find(start) {
+        Some(ix) => ix,
+        None => return xml.to_string(),
+    };
+    let content_start = match xml[open..].find('>') {
+        Some(ix) => open + ix + 1,
+        None => return xml.to_string(),
+    };
+    let content_end = match xml[content_start..].find(end) {
+        Some(ix) => content_start + ix,
+        None => return xml.to_string(),
+    };
+
+    format!("{}{}{}", &xml[..content_start], text, &xml[content_end..])
+}
+
+/// Escape text so it can be embedded in an XML document.
+pub(crate) fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Remove the `<meta name="generator" ... />` line from a navigation
+/// document.
+fn remove_generator_meta(nav: &str) -> String {
+    let start = match nav.find("<meta name=\"generator\"") {
+        Some(start) => start,
+        None => return nav.to_string(),
+    };
+    let end = match nav[start..].find("/>") {
+        Some(end) => start + end + 2,
+        None => return nav.to_string(),
+    };
+    let line_start = nav[..start].rfind('\n').filter(|&ix| nav[ix + 1..start].trim().is_empty());
+    let line_end = nav[end..].find('\n').filter(|&ix| nav[end..end + ix].trim().is_empty());
+    match (line_start, line_end) {
+        (Some(line_start), Some(line_end)) => format!("{}{}", &nav[..line_start], &nav[end + line_end..]),
+        _ => format!("{}{}", &nav[..start], &nav[end..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPF: &str = r#"<package>
+  <metadata>
+    <dc:identifier id="epub-id-1">urn:uuid:1234</dc:identifier>
+    <dc:title>Title</dc:title>
+  </metadata>
+</package>"#;
+
+    #[test]
+    fn patch_landmarks() {
+        let patch = PackagePatch {
+            landmarks: vec![String::from("<li><a epub:type=\"cover\" href=\"cover.html\">Cover</a></li>")],
+            ..Default::default()
+        };
+        let nav = "<nav epub:type = \"toc\">\n  </nav>\n  <nav epub:type = \"landmarks\">\n\n  </nav>\n";
+
+        let got = patch.apply_nav(nav);
+        assert_eq!(
+            got,
+            "<nav epub:type = \"toc\">\n  </nav>\n  <nav epub:type = \"landmarks\">\n    <ol>\n      \
+             <li><a epub:type=\"cover\" href=\"cover.html\">Cover</a></li>\n    </ol>\n  </nav>\n"
+        );
+
+        let got = patch.apply_nav(&got);
+        assert_eq!(got.matches("<li>").count(), 2, "{}", got);
+        assert_eq!(got.matches("<ol>").count(), 1, "{}", got);
+    }
+
+    #[test]
+    fn patch_spine_properties() {
+        let patch = PackagePatch {
+            spine_properties: vec![
+                (String::from("cover.xhtml"), String::from("rendition:layout-pre-paginated")),
+                (String::from("missing.xhtml"), String::from("page-spread-left")),
+            ],
+            ..Default::default()
+        };
+        let opf = "<manifest>\n<item media-type=\"application/xhtml+xml\" id=\"cover_xhtml\" href=\"cover.xhtml\"/>\n\
+                   <item media-type=\"application/xhtml+xml\" id=\"chapter_html\" href=\"chapter.html\"/>\n</manifest>\n\
+                   <spine toc=\"ncx\">\n<itemref idref=\"cover_xhtml\"/>\n<itemref idref=\"chapter_html\"/>\n</spine>";
+
+        let got = patch.apply(opf);
+
+        assert!(got.contains("<itemref idref=\"cover_xhtml\" properties=\"rendition:layout-pre-paginated\"/>"), "{}", got);
+        assert!(got.contains("<itemref idref=\"chapter_html\"/>"), "{}", got);
+        assert!(got.contains("<item media-type=\"application/xhtml+xml\" id=\"cover_xhtml\" href=\"cover.xhtml\"/>"), "{}", got);
+    }
+
+    #[test]
+    fn patch_spine_order() {
+        let opf = "<manifest>\n  <item media-type=\"application/xhtml+xml\" id=\"toc_html\" href=\"toc.html\"/>\n  \
+                   <item media-type=\"application/xhtml+xml\" id=\"a_html\" href=\"a.html\"/>\n  \
+                   <item media-type=\"application/xhtml+xml\" id=\"b_html\" href=\"b.html\"/>\n  \
+                   <item media-type=\"application/xhtml+xml\" id=\"c_html\" href=\"c.html\"/>\n</manifest>\n\
+                   <spine toc=\"ncx\">\n  <itemref idref=\"toc_html\"/>\n  <itemref idref=\"a_html\"/>\n  \
+                   <itemref idref=\"b_html\"/>\n  <itemref idref=\"c_html\" linear=\"yes\"/>\n</spine>";
+        let patch = PackagePatch {
+            spine_order: vec![String::from("c.html"), String::from("a.html"), String::from("b.html")],
+            ..Default::default()
+        };
+
+        let patched = patch.apply(opf);
+        let spine = &patched[patched.find("<spine").unwrap()..];
+        assert_eq!(
+            spine,
+            "<spine toc=\"ncx\">\n  <itemref idref=\"toc_html\"/>\n  <itemref idref=\"c_html\" linear=\"yes\"/>\n  \
+             <itemref idref=\"a_html\"/>\n  <itemref idref=\"b_html\"/>\n</spine>"
+        );
+        assert!(patched.starts_with(&opf[..opf.find("<spine").unwrap()]));
+    }
+
+    #[test]
+    fn patch_ncx_depth() {
+        let patch = PackagePatch {
+            ncx_depth: true,
+            ..Default::default()
+        };
+        let ncx = "<head>\n    <meta name=\"dtb:depth\" content=\"1\" />\n</head>\n<navMap>\n\
+                   <navPoint id=\"navPoint-1\">\n<navPoint id=\"navPoint-2\">\n<navPoint id=\"navPoint-3\">\n\
+                   </navPoint>\n</navPoint>\n<navPoint id=\"navPoint-4\">\n</navPoint>\n</navPoint>\n\
+                   <navPoint id=\"navPoint-5\">\n</navPoint>\n</navMap>\n";
+
+        let got = patch.apply_ncx(ncx);
+
+        assert!(got.contains("<meta name=\"dtb:depth\" content=\"3\" />"), "{}", got);
+        assert_eq!(got.replace("content=\"3\"", "content=\"1\""), ncx);
+        assert_eq!(PackagePatch::default().apply_ncx(ncx), ncx);
+    }
+
+    #[test]
+    fn patch_generator() {
+        let patch = PackagePatch {
+            strip_generator: true,
+            ..Default::default()
+        };
+        let nav = "<head>\n  <meta name=\"generator\" content=\"Rust EPUB library\" />\n  <title>Toc</title>\n</head>\n";
+
+        assert_eq!(patch.apply_nav(nav), "<head>\n  <title>Toc</title>\n</head>\n");
+    }
+
+    #[test]
+    fn clashing_ids_are_made_unique() {
+        let opf = "<manifest>\n\
+                   <item id=\"nav\" href=\"nav.xhtml\" media-type=\"application/xhtml+xml\" properties=\"nav\"/>\n\
+                   <item media-type=\"application/xhtml+xml\" id=\"guide_index_html\" href=\"guide/index.html\"/>\n\
+                   <item media-type=\"image/png\" id=\"guide_index_html\" href=\"guide.index.html\"/>\n\
+                   <item media-type=\"application/xhtml+xml\" id=\"guide_index_html\" href=\"guide_index.html\"/>\n\
+                   <item media-type=\"application/xhtml+xml\" id=\"1 intro_html\" href=\"1 intro.html\"/>\n\
+                   </manifest>\n<spine toc=\"ncx\">\n\
+                   <itemref idref=\"guide_index_html\"/>\n<itemref idref=\"guide_index_html\"/>\n<itemref idref=\"1 intro_html\"/>\n\
+                   </spine>";
+
+        let got = unique_ids(opf);
+
+        assert!(got.contains("id=\"guide_index_html\" href=\"guide/index.html\""), "{}", got);
+        assert!(got.contains("id=\"guide_index_html-2\" href=\"guide.index.html\""), "{}", got);
+        assert!(got.contains("id=\"guide_index_html-3\" href=\"guide_index.html\""), "{}", got);
+        assert!(got.contains("id=\"id-1_intro_html\" href=\"1 intro.html\""), "{}", got);
+        assert!(
+            got.ends_with(
+                "<itemref idref=\"guide_index_html\"/>\n<itemref idref=\"guide_index_html-3\"/>\n\
+                 <itemref idref=\"id-1_intro_html\"/>\n</spine>"
+            ),
+            "{}",
+            got
+        );
+        assert_eq!(unique_ids(&got), got);
+    }
+
+    #[test]
+    fn patch_identifier_scheme() {
+        let patch = PackagePatch {
+            identifier_scheme: Some(String::from("ISBN")),
+            ..Default::default()
+        };
+
+        let got = patch.apply(OPF);
+
+        assert!(got.contains(r#"<dc:identifier opf:scheme="ISBN" id="epub-id-1">urn:uuid:1234</dc:identifier>"#));
+    }
+
+    #[test]
+    fn patch_identifier_and_metadata() {
+        let patch = PackagePatch {
+            identifier: Some(String::from("urn:isbn:9780000000002")),
+            metadata: vec![String::from("<dc:publisher>A &amp; B</dc:publisher>")],
+            ..Default::default()
+        };
+
+        let got = patch.apply(OPF);
+
+        assert!(got.contains(r#"<dc:identifier id="epub-id-1">urn:isbn:9780000000002</dc:identifier>"#));
+        assert!(got.contains("    <dc:publisher>A &amp; B</dc:publisher>\n  </metadata>"));
+        assert!(!got.contains("urn:uuid:1234"));
+    }
+
+    #[test]
+    fn patch_modified_timestamp() {
+        let patch = PackagePatch {
+            modified: Some(String::from("2023-02-08T10:20:30Z")),
+            ..Default::default()
+        };
+
+        let got = patch.apply(OPF);
+        assert!(got.contains("    <meta property=\"dcterms:modified\">2023-02-08T10:20:30Z</meta>\n  </metadata>"), "{}", got);
+
+        // an existing timestamp is replaced rather than duplicated
+        let got = patch.apply(&got.replace("2023-02-08T10:20:30Z", "2020-01-01T00:00:00Z"));
+        assert_eq!(got.matches("dcterms:modified").count(), 1, "{}", got);
+        assert!(got.contains("2023-02-08T10:20:30Z"), "{}", got);
+    }
+
+    #[test]
+    fn patch_page_progression_direction() {
+        let patch = PackagePatch {
+            page_progression_direction: Some(String::from("rtl")),
+            ..Default::default()
+        };
+
+        let got = patch.apply("<package>\n  <spine toc=\"ncx\">\n  </spine>\n</package>");
+
+        assert!(got.contains(r#"<spine page-progression-direction="rtl" toc="ncx">"#), "{}", got);
+    }
+
+    #[test]
+    fn patch_item_properties() {
+        let opf = r#"<manifest>
+    <item media-type="application/xhtml+xml" id="a_html" href="a.html"/>
+    <item media-type="image/png" properties="cover-image" id="cover-image" href="cover.png"/>
+</manifest>"#;
+        let patch = PackagePatch {
+            properties: vec![
+                (String::from("a.html"), String::from("mathml")),
+                (String::from("cover.png"), String::from("svg")),
+            ],
+            ..Default::default()
+        };
+
+        let got = patch.apply(opf);
+
+        assert!(got.contains(r#"<item media-type="application/xhtml+xml" id="a_html" href="a.html" properties="mathml"/>"#), "{}", got);
+        assert!(got.contains(r#"properties="svg cover-image""#), "{}", got);
+    }
+
+    #[test]
+    fn stored_files_are_not_compressed() {
+        let mut epub = ZipWriter::new(Cursor::new(Vec::new()));
+        for name in ["mimetype", "OEBPS/a.html"] {
+            epub.start_file(name, FileOptions::default()).unwrap();
+            epub.write_all("application/epub+zip ".repeat(50).as_bytes()).unwrap();
+        }
+        let epub = epub.finish().unwrap().into_inner();
+
+        let mut out = Vec::new();
+        write(epub, &mut out, &PackagePatch::default(), Compression::Stored, false, DirectoryLayout::Flat).unwrap();
+
+        let mut archive = ZipArchive::new(Cursor::new(out)).unwrap();
+        for i in 0..archive.len() {
+            assert_eq!(archive.by_index(i).unwrap().compression(), CompressionMethod::Stored);
+        }
+    }
+
+    #[test]
+    fn empty_patch_is_a_noop() {
+        let patch = PackagePatch::default();
+
+        assert!(patch.is_empty());
+        assert_eq!(patch.apply(OPF), OPF);
+    }
+}
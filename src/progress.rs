@@ -0,0 +1,37 @@
+/// Told how far a build has got, e.g. to drive a progress bar.
+///
+/// Every method does nothing by default, so implementations only need the
+/// ones they care about. `chapter_started` can come from any of the rendering
+/// threads, in whatever order they get to the chapters, so a reporter
+/// counting how many are done should go by the calls it has had rather than
+/// by `index`.
+pub trait ProgressReporter: Send + Sync {
+    /// A chapter has started rendering. `index` counts from 0 in book order,
+    /// out of `total` chapters.
+    fn chapter_started(&self, _name: &str, _index: usize, _total: usize) {}
+
+    /// An image or other asset used by the chapters has been added to the
+    /// book, under `name`.
+    fn asset_embedded(&self, _name: &str) {}
+}
+
+/// Reports nothing. This is what a [`crate::Generator`] uses unless it's
+/// given another reporter.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct NoProgress;
+
+impl ProgressReporter for NoProgress {}
+
+/// Writes a line to stderr for every chapter and asset.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct StderrProgress;
+
+impl ProgressReporter for StderrProgress {
+    fn chapter_started(&self, name: &str, index: usize, total: usize) {
+        eprintln!("[{}/{}] Rendering {}", index + 1, total, name);
+    }
+
+    fn asset_embedded(&self, name: &str) {
+        eprintln!("Embedded {}", name);
+    }
+}
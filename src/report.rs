@@ -0,0 +1,175 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::io::Cursor;
+
+use zip::ZipArchive;
+
+use crate::Error;
+
+/// How many of the largest assets a report lists.
+const LARGEST_ASSETS: usize = 10;
+
+/// What went into a generated book, to help find what makes it big.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Report {
+    title: String,
+    /// The size of the whole archive, compressed.
+    total_size: u64,
+    chapters: usize,
+    /// The number of XHTML pages and their size, chapters and generated
+    /// pages alike.
+    pages: (usize, u64),
+    /// Every file which isn't a page or part of the package's own
+    /// bookkeeping, largest first.
+    assets: Vec<Asset>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Asset {
+    name: String,
+    media_type: String,
+    size: u64,
+}
+
+impl Report {
+    /// Summarize a generated EPUB with `chapters` chapters in it. Sizes are
+    /// of the files as they are once they've been unpacked.
+    pub(crate) fn new(title: &str, epub: &[u8], chapters: usize) -> Result<Report, Error> {
+        let mut archive = ZipArchive::new(Cursor::new(epub))?;
+        let mut pages = (0, 0);
+        let mut assets = Vec::new();
+
+        for i in 0..archive.len() {
+            let file = archive.by_index(i)?;
+            let name = file.name().to_string();
+            if file.is_dir() || is_bookkeeping(&name) {
+                continue;
+            }
+            if name.ends_with(".xhtml") || name.ends_with(".html") {
+                pages.0 += 1;
+                pages.1 += file.size();
+                continue;
+            }
+            let media_type = mime_guess::from_path(&name).first_or_octet_stream().to_string();
+            assets.push(Asset { size: file.size(), name, media_type });
+        }
+        assets.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.name.cmp(&b.name)));
+
+        Ok(Report {
+            title: title.to_string(),
+            total_size: epub.len() as u64,
+            chapters,
+            pages,
+            assets,
+        })
+    }
+
+    /// The number of assets of each media type and their total size.
+    fn by_media_type(&self) -> BTreeMap<&str, (usize, u64)> {
+        let mut types = BTreeMap::new();
+        for asset in &self.assets {
+            let (count, size) = types.entry(asset.media_type.as_str()).or_insert((0, 0));
+            *count += 1;
+            *size += asset.size;
+        }
+        types
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Report for {}", self.title)?;
+        writeln!(f, "Total size: {}", human_size(self.total_size))?;
+        writeln!(f, "Chapters: {}", self.chapters)?;
+        writeln!(f, "Pages: {} ({})", self.pages.0, human_size(self.pages.1))?;
+
+        let assets_size: u64 = self.assets.iter().map(|asset| asset.size).sum();
+        writeln!(f, "Assets: {} ({})", self.assets.len(), human_size(assets_size))?;
+        let mut types: Vec<_> = self.by_media_type().into_iter().collect();
+        types.sort_by_key(|&(_, (_, size))| std::cmp::Reverse(size));
+        for (media_type, (count, size)) in types {
+            writeln!(f, "  {}: {} ({})", media_type, count, human_size(size))?;
+        }
+
+        if !self.assets.is_empty() {
+            writeln!(f, "Largest assets:")?;
+            for asset in self.assets.iter().take(LARGEST_ASSETS) {
+                writeln!(f, "  {:>9}  {}", human_size(asset.size), asset.name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Is the file one every EPUB has, rather than part of the book?
+fn is_bookkeeping(name: &str) -> bool {
+    name == "mimetype" || name.starts_with("META-INF/") || name.ends_with(".opf") || name.ends_with(".ncx")
+}
+
+/// A size in bytes the way people read them, e.g. `1.5 MB`.
+fn human_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KB", "MB", "GB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    fn epub(files: &[(&str, usize)]) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for &(name, size) in files {
+            zip.start_file(name, FileOptions::default()).unwrap();
+            zip.write_all(&vec![b'a'; size]).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn assets_are_totalled_by_type() {
+        let epub = epub(&[
+            ("mimetype", 20),
+            ("META-INF/container.xml", 200),
+            ("OEBPS/content.opf", 2000),
+            ("OEBPS/toc.ncx", 500),
+            ("OEBPS/nav.xhtml", 1000),
+            ("OEBPS/chapter_1.html", 3000),
+            ("OEBPS/stylesheet.css", 800),
+            ("OEBPS/images/big.png", 3 * 1024 * 1024),
+            ("OEBPS/images/small.png", 100),
+            ("OEBPS/logo.svg", 2048),
+        ]);
+
+        let report = Report::new("My Book", &epub, 1).unwrap();
+
+        assert_eq!(report.pages, (2, 4000));
+        let names: Vec<_> = report.assets.iter().map(|asset| asset.name.as_str()).collect();
+        assert_eq!(names, ["OEBPS/images/big.png", "OEBPS/logo.svg", "OEBPS/stylesheet.css", "OEBPS/images/small.png"]);
+        let report = report.to_string();
+        assert!(report.starts_with("Report for My Book\nTotal size: "), "{}", report);
+        assert!(report.contains("\nChapters: 1\nPages: 2 (3.9 KB)\nAssets: 4 (3.0 MB)\n"), "{}", report);
+        assert!(report.contains("\n  image/png: 2 (3.0 MB)\n  image/svg+xml: 1 (2.0 KB)\n  text/css: 1 (800 B)\n"), "{}", report);
+        assert!(report.ends_with("Largest assets:\n     3.0 MB  OEBPS/images/big.png\n     2.0 KB  OEBPS/logo.svg\n      800 B  OEBPS/stylesheet.css\n      100 B  OEBPS/images/small.png\n"), "{}", report);
+    }
+
+    #[test]
+    fn human_sizes() {
+        assert_eq!(human_size(0), "0 B");
+        assert_eq!(human_size(1023), "1023 B");
+        assert_eq!(human_size(1536), "1.5 KB");
+        assert_eq!(human_size(5 * 1024 * 1024 * 1024 * 1024), "5120.0 GB");
+    }
+}
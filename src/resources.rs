@@ -1,44 +1,232 @@
 use super::Error;
-use html_parser::{Dom, Node};
-use mdbook::book::BookItem;
-use mdbook::renderer::RenderContext;
+use crate::config::MissingAssetPolicy;
+use mdbook::book::{Book, BookItem};
 use mime_guess::{self, Mime};
 use pulldown_cmark::{Event, Parser, Options, Tag};
-use std::path::{Path, PathBuf};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
 
-pub(crate) fn find(ctx: &RenderContext) -> Result<Vec<Asset>, Error> {
+/// ImageMagick, which converts images EPUB 2 readers don't understand.
+const IMAGE_CONVERTER: &str = "convert";
+/// The raw HTML elements whose images, audio and video are packaged.
+const LINKED_ELEMENTS: &[&str] = &["img", "audio", "video", "source", "image"];
+
+/// The mimetype chapters' markdown sources are packaged with.
+pub(crate) const SOURCE_MEDIA_TYPE: &str = "text/markdown";
+
+/// Where the image standing in for missing ones is packaged.
+pub(crate) const PLACEHOLDER_IMAGE_PATH: &str = "missing-image.png";
+
+/// A transparent 1×1 PNG.
+pub(crate) const PLACEHOLDER_IMAGE: &[u8] = &[
+    0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44, 0x52, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x06, 0x00, 0x00, 0x00, 0x1f, 0x15, 0xc4, 0x89, 0x00, 0x00, 0x00, 0x0b, 0x49,
+    0x44, 0x41, 0x54, 0x78, 0xda, 0x63, 0x60, 0x00, 0x02, 0x00, 0x00, 0x05, 0x00, 0x01, 0xe9, 0xfa, 0xdc, 0xd8, 0x00,
+    0x00, 0x00, 0x00, 0x49, 0x45, 0x4e, 0x44, 0xae, 0x42, 0x60, 0x82,
+];
+
+/// A file a chapter uses which doesn't exist, and the chapter's path.
+pub(crate) type MissingAsset = (Asset, PathBuf);
+
+/// The local assets the book's chapters use. Files which don't exist fail
+/// with the `Fail` policy, and are left out otherwise.
+pub(crate) fn find(book: &Book, src_dir: &Path, policy: MissingAssetPolicy) -> Result<Vec<Asset>, Error> {
+    let (assets, missing) = scan(book, src_dir)?;
+    match missing.first() {
+        Some((asset, chapter)) if policy == MissingAssetPolicy::Fail => Err(Error::AssetFileNotFound(format!(
+            "{} (used by {}) doesn't exist",
+            asset.location_on_disk.display(),
+            chapter.display()
+        ))),
+        _ => Ok(assets),
+    }
+}
+
+/// The files the book's chapters use which don't exist, each where it would
+/// have been packaged, along with the chapter using it.
+pub(crate) fn find_missing(book: &Book, src_dir: &Path) -> Result<Vec<MissingAsset>, Error> {
+    Ok(scan(book, src_dir)?.1)
+}
+
+/// The local assets the book's chapters use, split into those which exist
+/// and those which don't (along with the chapter using them).
+fn scan(book: &Book, src_dir: &Path) -> Result<(Vec<Asset>, Vec<MissingAsset>), Error> {
     let mut assets = Vec::new();
-    debug!("Finding resources by:\n{:?}", ctx.config);
-    let src_dir = ctx
-        .root
-        .join(&ctx.config.book.src)
-        .canonicalize()?;
-
-    debug!("Start iteration over a [{:?}] sections in src_dir = {:?}", ctx.book.sections.len(), src_dir);
-    for section in ctx.book.iter() {
+    let mut missing = Vec::new();
+    let mut seen = HashSet::new();
+    let src_dir = src_dir.canonicalize()?;
+
+    debug!("Start iteration over a [{:?}] sections in src_dir = {:?}", book.sections.len(), src_dir);
+    for section in book.iter() {
         if let BookItem::Chapter(ref ch) = *section {
             debug!("Searching links and assets for: {}", ch);
 
-            let asset_path = ch.path.as_ref()
-                .ok_or_else(|| Error::AssetFileNotFound(format!("Asset was not found for Chapter {}", ch.name) ))?;
+            let asset_path = match ch.path {
+                Some(ref path) => path,
+                None => {
+                    // draft chapters have no content to search for assets
+                    debug!("Skipping draft chapter {}", ch.name);
+                    continue;
+                }
+            };
             let full_path = src_dir.join(asset_path);
             debug!("Asset full path = {:?}", full_path);
             let parent = full_path
                 .parent()
                 .expect("All book chapters have a parent directory");
-            let found = assets_in_markdown(&ch.content, parent)?;
+            let found = assets_in_markdown(&ch.content, parent, &src_dir)?;
 
             for full_filename in found {
-                let relative = full_filename.strip_prefix(&src_dir).unwrap();
-                debug!("An relative path to asset: {:?}", full_path);
-                assets.push(Asset::new(relative, &full_filename));
+                // assets are packaged at the same place relative to `src/`
+                // so the chapters' relative links keep working
+                let relative = full_filename.strip_prefix(&src_dir).map_err(|_| {
+                    Error::AssetFileNotFound(format!(
+                        "{} (used by {}) is outside the book's source directory",
+                        full_filename.display(),
+                        asset_path.display()
+                    ))
+                })?;
+                debug!("An relative path to asset: {:?}", relative);
+
+                // the same image is often used by several chapters
+                if !seen.insert(relative.to_path_buf()) {
+                    continue;
+                }
+                if full_filename.exists() {
+                    assets.push(Asset::new(relative, &full_filename));
+                } else {
+                    missing.push((Asset::new(relative, &full_filename), asset_path.clone()));
+                }
             }
         } else {
             debug!("That's odd! Section is not found !");
         }
     }
 
-    Ok(assets)
+    Ok((assets, missing))
+}
+
+/// Assets with the same contents as an earlier one, mapped to where that
+/// first copy is packaged.
+pub(crate) fn duplicates(assets: &[Asset]) -> Result<HashMap<PathBuf, PathBuf>, Error> {
+    let mut first_copies: HashMap<u64, Vec<(&Asset, Vec<u8>)>> = HashMap::new();
+    let mut duplicates = HashMap::new();
+
+    for asset in assets {
+        let content = fs::read(&asset.location_on_disk)?;
+        let mut hasher = DefaultHasher::new();
+        content.hash(&mut hasher);
+
+        // the contents are compared too, in case two files' hashes collide
+        let same_hash = first_copies.entry(hasher.finish()).or_default();
+        match same_hash.iter().find(|(_, first_content)| *first_content == content) {
+            Some((first, _)) => {
+                duplicates.insert(asset.filename.clone(), first.filename.clone());
+            }
+            None => same_hash.push((asset, content)),
+        }
+    }
+
+    Ok(duplicates)
+}
+
+/// Find every remote (`http://` or `https://`) image referenced by the book.
+pub(crate) fn find_remote(book: &Book) -> Vec<String> {
+    find_links(book, is_remote)
+}
+
+/// Find every protocol-relative (`//example.com/logo.png`) image referenced
+/// by the book, which are left for the reader to load.
+pub(crate) fn find_protocol_relative(book: &Book) -> Vec<String> {
+    find_links(book, |link| link.starts_with("//"))
+}
+
+fn find_links<F: Fn(&str) -> bool>(book: &Book, wanted: F) -> Vec<String> {
+    let mut links = Vec::new();
+
+    for section in book.iter() {
+        if let BookItem::Chapter(ref ch) = *section {
+            links.extend(asset_links(&ch.content).into_iter().filter(|link| wanted(link)));
+        }
+    }
+
+    links.sort();
+    links.dedup();
+    links
+}
+
+/// Is this a link to an image on the web?
+pub(crate) fn is_remote(link: &str) -> bool {
+    link.starts_with("http://") || link.starts_with("https://")
+}
+
+/// Where a remote image is packaged, relative to the `src/` directory.
+///
+/// The name is derived from the URL so chapters can refer to the image
+/// without knowing anything about the download.
+pub(crate) fn remote_filename(url: &str) -> PathBuf {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    let name = path.rsplit('/').next().unwrap_or("");
+    let stem = format!("{:016x}", stable_hash(url.as_bytes()));
+
+    match Path::new(name).extension() {
+        Some(ext) => Path::new("remote").join(stem).with_extension(ext),
+        None => Path::new("remote").join(stem),
+    }
+}
+
+/// A 64-bit FNV-1a hash, for names of files kept between builds, which
+/// `DefaultHasher` doesn't promise to give the same way across Rust releases.
+pub(crate) fn stable_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Download a remote image into `cache_dir`, giving up after `timeout`.
+/// Images downloaded by an earlier build are used again, unless their URL
+/// has no extension, in which case only the server can say what they are.
+pub(crate) fn download(url: &str, cache_dir: &Path, timeout: Duration) -> Result<Asset, Error> {
+    let filename = remote_filename(url);
+    let location = cache_dir.join(&filename);
+    if filename.extension().is_some() && location.is_file() {
+        debug!("Using the earlier download of {} from {}", url, location.display());
+        return Ok(Asset::new(filename, location));
+    }
+    debug!("Downloading {} to {}", url, location.display());
+
+    let agent = ureq::AgentBuilder::new().timeout(timeout).build();
+    let response = agent
+        .get(url)
+        .call()
+        .map_err(|e| Error::AssetDownload(url.to_string(), e.to_string()))?;
+    let mimetype = response
+        .header("Content-Type")
+        .and_then(|mt| mt.parse::<Mime>().ok());
+
+    let mut content = Vec::new();
+    response
+        .into_reader()
+        .read_to_end(&mut content)
+        .map_err(|e| Error::AssetDownload(url.to_string(), e.to_string()))?;
+
+    if let Some(parent) = location.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    io::copy(&mut content.as_slice(), &mut File::create(&location)?)?;
+
+    let mut asset = Asset::new(filename, location);
+    if let Some(mimetype) = mimetype {
+        asset.mimetype = mimetype;
+    }
+
+    Ok(asset)
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -57,7 +245,7 @@ impl Asset {
         Q: Into<PathBuf>,
     {
         let location_on_disk = absolute_location.into();
-        let mt = mime_guess::from_path(&location_on_disk).first_or_octet_stream();
+        let mt = mimetype(&location_on_disk);
 
         Asset {
             location_on_disk,
@@ -67,7 +255,90 @@ impl Asset {
     }
 }
 
-fn assets_in_markdown(src: &str, parent_dir: &Path) -> Result<Vec<PathBuf>, Error> {
+/// Guess a file's mimetype from its extension.
+pub(crate) fn mimetype(path: &Path) -> Mime {
+    let extension = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+
+    // readers only render images and fonts with exactly these types, so
+    // they're not left up to the guesswork
+    let known = match extension.as_str() {
+        "svg" => Some("image/svg+xml"),
+        "webp" => Some("image/webp"),
+        "avif" => Some("image/avif"),
+        "mp3" => Some("audio/mpeg"),
+        "m4a" => Some("audio/mp4"),
+        "mp4" | "m4v" => Some("video/mp4"),
+        "ttf" => Some("font/ttf"),
+        "otf" => Some("font/otf"),
+        "woff" => Some("font/woff"),
+        "woff2" => Some("font/woff2"),
+        _ => None,
+    };
+    if let Some(known) = known {
+        return known.parse().expect("A valid mimetype");
+    }
+
+    mime_guess::from_path(path).first_or_octet_stream()
+}
+
+/// Image formats which EPUB 2 readers can't be expected to show.
+pub(crate) fn is_unsupported_in_epub2(mimetype: &Mime) -> bool {
+    mimetype.essence_str() == "image/webp" || mimetype.essence_str() == "image/avif"
+}
+
+/// Is this audio or video, which only EPUB 3 readers play?
+pub(crate) fn is_media(mimetype: &Mime) -> bool {
+    mimetype.type_() == mime_guess::mime::AUDIO || mimetype.type_() == mime_guess::mime::VIDEO
+}
+
+/// Convert an image to a PNG in `cache_dir`, returning where it was written.
+/// Images which were converted before aren't converted again.
+pub(crate) fn transcode(location: &Path, cache_dir: &Path) -> Option<PathBuf> {
+    let content = match fs::read(location) {
+        Ok(content) => content,
+        Err(e) => {
+            warn!("Unable to read {} to convert it: {}", location.display(), e);
+            return None;
+        }
+    };
+    let dir = cache_dir.join("transcoded");
+    let output = dir.join(format!("{:016x}", stable_hash(&content))).with_extension("png");
+    if output.is_file() {
+        return Some(output);
+    }
+
+    let converted = fs::create_dir_all(&dir)
+        .and_then(|_| Command::new(IMAGE_CONVERTER).arg(location).arg(&output).output());
+
+    match converted {
+        Ok(result) if result.status.success() && output.is_file() => Some(output),
+        Ok(result) => {
+            warn!(
+                "Unable to convert {} to a PNG, packaging it as is: {}",
+                location.display(),
+                String::from_utf8_lossy(&result.stderr).trim()
+            );
+            None
+        }
+        Err(e) => {
+            warn!(
+                "Unable to run `{}` to convert {} to a PNG, packaging it as is: {}",
+                IMAGE_CONVERTER,
+                location.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Every image, audio and video file referenced by a chapter, either with
+/// markdown syntax or by raw `<img>`, `<audio>`, `<video>`, `<source>` and
+/// SVG `<image>` elements.
+fn asset_links(src: &str) -> Vec<String> {
     let mut found = Vec::new();
 
     let mut options = Options::empty();
@@ -84,22 +355,12 @@ fn assets_in_markdown(src: &str, parent_dir: &Path) -> Result<Vec<PathBuf>, Erro
                 found.push(dest.to_string());
             }
             Event::Html(html) => {
-                let content = html.into_string();
-
-                if let Ok(dom) = Dom::parse(&content) {
-                    for item in dom.children {
-                        match item {
-                            Node::Element(ref element) if element.name == "img" => {
-                                if let Some(dest) = &element.attributes["src"] {
-                                    if !dest.starts_with("http") {
-                                        found.push(dest.clone());
-                                    }
-                                }
-                            }
-                            _ => {}
-                        }
+                rewrite_html_links(&html, |element, link| {
+                    if LINKED_ELEMENTS.contains(&element) && !link.is_empty() {
+                        found.push(link.to_string());
                     }
-                }
+                    None
+                });
             }
             _ => {}
         }
@@ -107,21 +368,176 @@ fn assets_in_markdown(src: &str, parent_dir: &Path) -> Result<Vec<PathBuf>, Erro
 
     found.sort();
     found.dedup();
+    found
+}
+
+/// Rewrite the links to files in some raw HTML: every `src` and `poster`,
+/// each of the candidates in a `srcset`, and the `href` of SVG `<image>`s.
+/// `rewrite` is given the element and the link, and leaves the link as it
+/// was by returning `None`.
+pub(crate) fn rewrite_html_links<F>(html: &str, mut rewrite: F) -> String
+where
+    F: FnMut(&str, &str) -> Option<String>,
+{
+    rewrite_attribute_values(html, |element, attribute, value| match attribute {
+        "src" | "poster" => rewrite(element, value),
+        "href" | "xlink:href" if element == "image" => rewrite(element, value),
+        "srcset" => Some(rewrite_srcset(value, |link| rewrite(element, link))),
+        _ => None,
+    })
+}
 
-    // TODO: Allow linked images to be either a URL or path on disk
+/// Rewrite the values of the attributes in some markup. `rewrite` is given
+/// the element and the attribute, both in lowercase, and the value, and
+/// leaves the value as it was by returning `None`.
+///
+/// The tags are read by hand, which copes with attributes without a value,
+/// like `<audio controls src="clip.mp3">`, and leaves everything else as it
+/// was.
+pub(crate) fn rewrite_attribute_values<F>(markup: &str, mut rewrite: F) -> String
+where
+    F: FnMut(&str, &str, &str) -> Option<String>,
+{
+    let mut out = String::with_capacity(markup.len());
+    let mut rest = markup;
 
-    // I'm assuming you'd just determine if each link is a URL or filename so
-    // the `find()` function can put together a deduplicated list of URLs and
-    // try to download all of them (in parallel?) to a temporary location. It'd
-    // be nice if we could have some sort of caching mechanism by using the
-    // destination directory (hash the URL and store it as
-    // `book/epub/cache/$hash.$ext`?).
+    while let Some(start) = rest.find('<') {
+        let (before, tag) = rest.split_at(start + 1);
+        out.push_str(before);
+        let end = tag.find('>').unwrap_or(tag.len());
+        let name_end = tag[..end].find(|c: char| c.is_whitespace() || c == '/').unwrap_or(end);
+        let element = tag[..name_end].to_ascii_lowercase();
+
+        out.push_str(&tag[..name_end]);
+        out.push_str(&rewrite_attributes(&tag[name_end..end], &element, &mut rewrite));
+        rest = &tag[end..];
+    }
+
+    out.push_str(rest);
+    out
+}
+
+/// Rewrite the values in the `attributes` of one tag, which follow the name
+/// of its `element`.
+fn rewrite_attributes<F>(attributes: &str, element: &str, rewrite: &mut F) -> String
+where
+    F: FnMut(&str, &str, &str) -> Option<String>,
+{
+    let mut out = String::with_capacity(attributes.len());
+    let mut ix = 0;
+
+    while let Some(c) = attributes[ix..].chars().next() {
+        let name_len = attributes[ix..]
+            .find(|c: char| c.is_whitespace() || c == '=' || c == '/')
+            .unwrap_or(attributes.len() - ix);
+        if name_len == 0 {
+            out.push(c);
+            ix += c.len_utf8();
+            continue;
+        }
+        let name = &attributes[ix..ix + name_len];
+        out.push_str(name);
+        ix += name_len;
+        if !attributes[ix..].starts_with('=') {
+            continue;
+        }
+        out.push('=');
+        ix += 1;
+
+        let quote = attributes[ix..].chars().next().filter(|&c| c == '"' || c == '\'');
+        let value_start = ix + quote.map_or(0, char::len_utf8);
+        let mut value_end = match quote {
+            Some(quote) => attributes[value_start..].find(quote),
+            None => attributes[value_start..].find(char::is_whitespace),
+        }
+        .map_or(attributes.len(), |end| value_start + end);
+        // the `/` closing `<img src=logo.png/>` isn't part of the link
+        if quote.is_none() && value_end == attributes.len() && value_end > value_start + 1 && attributes.ends_with('/') {
+            value_end -= 1;
+        }
+
+        let value = &attributes[value_start..value_end];
+        let rewritten = rewrite(element, &name.to_ascii_lowercase(), value);
+        out.push_str(&attributes[ix..value_start]);
+        out.push_str(rewritten.as_deref().unwrap_or(value));
+        ix = value_end;
+        if let Some(quote) = quote.filter(|_| ix < attributes.len()) {
+            out.push(quote);
+            ix += quote.len_utf8();
+        }
+    }
+
+    out
+}
+
+/// Rewrite the link of each candidate in a `srcset`, like
+/// `small.png 1x, large.png 2x`.
+pub(crate) fn rewrite_srcset<F>(srcset: &str, mut rewrite: F) -> String
+where
+    F: FnMut(&str) -> Option<String>,
+{
+    srcset
+        .split(',')
+        .map(|candidate| {
+            let start = candidate.len() - candidate.trim_start().len();
+            let end = candidate[start..].find(char::is_whitespace).map_or(candidate.len(), |end| start + end);
+            match rewrite(&candidate[start..end]) {
+                Some(link) => format!("{}{}{}", &candidate[..start], link, &candidate[end..]),
+                None => candidate.to_string(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Is this a link to an image relative to the `src/` directory, like
+/// `/images/logo.png`? Protocol-relative URLs (`//example.com/logo.png`)
+/// aren't.
+pub(crate) fn is_root_relative(link: &str) -> bool {
+    link.starts_with('/') && !link.starts_with("//")
+}
+
+/// A path with its `.` and `..` components resolved, without looking at
+/// the file system.
+fn without_dots(path: &Path) -> PathBuf {
+    let mut resolved = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            component => resolved.push(component),
+        }
+    }
+    resolved
+}
+
+/// The local images, audio and video a chapter uses, including any which
+/// don't exist. Relative links are relative to the chapter's `parent_dir`,
+/// and root-relative ones to `src_dir`.
+fn assets_in_markdown(src: &str, parent_dir: &Path, src_dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    // remote images are handled separately by `find_remote()`, and
+    // protocol-relative ones are left to the reader
+    let found = asset_links(src)
+        .into_iter()
+        .filter(|link| !is_remote(link) && !link.starts_with("//") && !link.starts_with("data:"));
     let mut assets = Vec::new();
 
     for link in found {
-        let link = PathBuf::from(link);
-        let filename = parent_dir.join(link);
-        let filename = filename.canonicalize()?;
+        let filename = if is_root_relative(&link) {
+            src_dir.join(link.trim_start_matches('/'))
+        } else {
+            parent_dir.join(link)
+        };
+        let filename = match filename.canonicalize() {
+            Ok(filename) => filename,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                assets.push(without_dots(&filename));
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
 
         if !filename.is_file() {
             return Err(Error::AssetFile(filename));
@@ -136,6 +552,33 @@ fn assets_in_markdown(src: &str, parent_dir: &Path) -> Result<Vec<PathBuf>, Erro
 #[cfg(test)]
 mod tests {
     use super::*;
+    use mdbook::book::Chapter;
+
+    #[test]
+    fn identical_assets_are_duplicates() {
+        let temp = tempdir::TempDir::new("mdbook-epub").unwrap();
+        let asset = |name: &str, content: &str| {
+            let location = temp.path().join(name);
+            fs::write(&location, content).unwrap();
+            Asset::new(name, location)
+        };
+        let assets = vec![
+            asset("a.svg", "<svg/>"),
+            asset("b.png", "png"),
+            asset("c.svg", "<svg/>"),
+            asset("d.svg", "<svg/>"),
+        ];
+
+        let duplicates = duplicates(&assets).unwrap();
+
+        let expected: HashMap<_, _> = vec![
+            (PathBuf::from("c.svg"), PathBuf::from("a.svg")),
+            (PathBuf::from("d.svg"), PathBuf::from("a.svg")),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(duplicates, expected);
+    }
 
     #[test]
     fn find_images() {
@@ -150,8 +593,192 @@ mod tests {
             parent_dir.join("rust-logo.svg").canonicalize().unwrap(),
         ];
 
-        let got = assets_in_markdown(src, &parent_dir).unwrap();
+        let got = assets_in_markdown(src, &parent_dir, &parent_dir).unwrap();
 
         assert_eq!(got, should_be);
     }
+
+    #[test]
+    fn nested_assets_keep_their_directories() {
+        let temp = tempdir::TempDir::new("mdbook-epub").unwrap();
+        let src_dir = temp.path().join("src");
+        for dir in ["images", "a/images", "b/c/images"] {
+            fs::create_dir_all(src_dir.join(dir)).unwrap();
+            fs::write(src_dir.join(dir).join("logo.png"), dir).unwrap();
+        }
+
+        let mut book = Book::new();
+        let chapters = [
+            ("a/chapter.md", "![](images/logo.png)"),
+            ("b/c/chapter.md", "![](images/logo.png) ![](../../images/logo.png)"),
+            ("top.md", "![](./images/logo.png) ![](a/./images/logo.png)"),
+        ];
+        for (path, content) in chapters {
+            book.push_item(Chapter::new(path, content.to_string(), path, vec![]));
+        }
+
+        let mut got: Vec<PathBuf> = find(&book, &src_dir, MissingAssetPolicy::Fail)
+            .unwrap()
+            .into_iter()
+            .map(|asset| asset.filename)
+            .collect();
+        got.sort();
+
+        let should_be: Vec<PathBuf> = ["a/images/logo.png", "b/c/images/logo.png", "images/logo.png"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        assert_eq!(got, should_be);
+    }
+
+    #[test]
+    fn assets_outside_the_source_directory_are_an_error() {
+        let temp = tempdir::TempDir::new("mdbook-epub").unwrap();
+        let src_dir = temp.path().join("src");
+        fs::create_dir_all(&src_dir).unwrap();
+        fs::write(temp.path().join("outside.png"), "").unwrap();
+
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Chapter", String::from("![](../outside.png)"), "chapter.md", vec![]));
+
+        let err = find(&book, &src_dir, MissingAssetPolicy::Fail).unwrap_err().to_string();
+        assert!(err.contains("outside the book's source directory"), "{}", err);
+    }
+
+    #[test]
+    fn root_relative_images_are_found_in_the_source_directory() {
+        let src_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/dummy/src");
+        let src = "![Logo](/rust-logo.png)\n\n<img src=\"/reddit.svg\" />\n\n![Remote](//example.com/logo.png)\n";
+
+        let got = assets_in_markdown(src, &src_dir.join("nested/chapter"), &src_dir).unwrap();
+
+        assert_eq!(
+            got,
+            vec![
+                src_dir.join("reddit.svg").canonicalize().unwrap(),
+                src_dir.join("rust-logo.png").canonicalize().unwrap(),
+            ]
+        );
+        assert!(is_root_relative("/images/logo.png"));
+        assert!(!is_root_relative("//example.com/logo.png"));
+        assert!(!is_root_relative("images/logo.png"));
+    }
+
+    #[test]
+    fn unpackaged_images_are_found() {
+        let mut book = Book::new();
+        let content = "![Remote](https://example.com/a.png) ![Relative](//example.com/b.png) ![Local](c.png)";
+        book.push_item(Chapter::new("Chapter", String::from(content), "chapter.md", vec![]));
+
+        assert_eq!(find_remote(&book), vec![String::from("https://example.com/a.png")]);
+        assert_eq!(find_protocol_relative(&book), vec![String::from("//example.com/b.png")]);
+    }
+
+    #[test]
+    fn remote_images_are_not_local_assets() {
+        let parent_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/dummy/src");
+        let src = "![Remote](https://example.com/logo.png)\n\n<img src=\"http://example.com/a.svg\" />\n";
+
+        assert!(assets_in_markdown(src, &parent_dir, &parent_dir).unwrap().is_empty());
+        assert_eq!(
+            asset_links(src).into_iter().filter(|link| is_remote(link)).count(),
+            2
+        );
+    }
+
+    #[test]
+    fn media_is_found() {
+        let temp = tempdir::TempDir::new("mdbook-epub").unwrap();
+        for name in ["clip.mp3", "demo.mp4", "demo.webm", "poster.png"] {
+            fs::write(temp.path().join(name), name).unwrap();
+        }
+        let src = "![Poster](poster.png)\n\n<audio controls src=\"clip.mp3\"></audio>\n\n\
+                   <video controls>\n  <source src=\"demo.webm\" type=\"video/webm\"/>\n  \
+                   <source src=\"demo.mp4\" type=\"video/mp4\"/>\n</video>\n\n\
+                   <audio src=\"https://example.com/remote.mp3\"></audio>\n";
+
+        let got = assets_in_markdown(src, temp.path(), temp.path()).unwrap();
+
+        let names: Vec<_> = got.iter().map(|path| path.file_name().unwrap().to_str().unwrap()).collect();
+        assert_eq!(names, ["clip.mp3", "demo.mp4", "demo.webm", "poster.png"]);
+        assert!(is_media(&mimetype(Path::new("clip.mp3"))));
+        assert!(is_media(&mimetype(Path::new("demo.webm"))));
+        assert!(!is_media(&mimetype(Path::new("poster.png"))));
+    }
+
+    #[test]
+    fn nested_and_inline_html_is_searched() {
+        let links = asset_links(
+            "Inline <img src=\"inline.png\"> image.\n\n\
+             <figure>\n<img alt=\"\" src=small.png srcset=\"small.png 1x, large.png 2x\"/>\n</figure>\n\n\
+             <video controls poster=\"poster.svg\"></video>\n\n\
+             <svg><image xlink:href=\"drawing.png\"/></svg>\n\n<a href=\"chapter.md\">Not an asset</a>\n",
+        );
+        assert_eq!(links, ["drawing.png", "inline.png", "large.png", "poster.svg", "small.png"]);
+    }
+
+    #[test]
+    fn html_links_are_rewritten_in_place() {
+        let html = "<img alt='a=b' src=\"a.png\" srcset=\"a.png 1x,b.png 2x\"> <video controls poster=p.png/>\n\
+                    <a href=\"a.png\">a</a> <img src=\"\">";
+        let rewritten = rewrite_html_links(html, |_, link| Some(format!("../{}", link)).filter(|_| !link.is_empty()));
+        assert_eq!(
+            rewritten,
+            "<img alt='a=b' src=\"../a.png\" srcset=\"../a.png 1x,../b.png 2x\"> <video controls poster=../p.png/>\n\
+             <a href=\"a.png\">a</a> <img src=\"\">"
+        );
+    }
+
+    #[test]
+    fn media_mimetypes() {
+        assert_eq!(mimetype(Path::new("clip.MP3")).to_string(), "audio/mpeg");
+        assert_eq!(mimetype(Path::new("clip.m4a")).to_string(), "audio/mp4");
+        assert_eq!(mimetype(Path::new("demo.mp4")).to_string(), "video/mp4");
+    }
+
+    #[test]
+    fn svg_mimetype() {
+        assert_eq!(mimetype(Path::new("images/logo.SVG")).to_string(), "image/svg+xml");
+        assert_eq!(mimetype(Path::new("images/logo.png")).to_string(), "image/png");
+    }
+
+    #[test]
+    fn modern_image_mimetypes() {
+        let webp = mimetype(Path::new("images/photo.WebP"));
+        let avif = mimetype(Path::new("images/photo.avif"));
+
+        assert_eq!(webp.to_string(), "image/webp");
+        assert_eq!(avif.to_string(), "image/avif");
+        assert!(is_unsupported_in_epub2(&webp));
+        assert!(is_unsupported_in_epub2(&avif));
+        assert!(!is_unsupported_in_epub2(&mimetype(Path::new("images/logo.png"))));
+    }
+
+    #[test]
+    fn remote_filenames_are_stable() {
+        let url = "https://example.com/images/logo.png?size=large";
+        let got = remote_filename(url);
+
+        assert_eq!(got, remote_filename(url));
+        assert!(got.starts_with("remote"));
+        assert_eq!(got.extension().unwrap(), "png");
+        assert_ne!(got, remote_filename("https://example.com/other/logo.png"));
+        assert_eq!(stable_hash(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(stable_hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn earlier_downloads_are_used_again() {
+        let temp = tempdir::TempDir::new("mdbook-epub").unwrap();
+        // nothing listens on port 9, so anything but the cached copy fails
+        let url = "http://127.0.0.1:9/logo.png";
+        let location = temp.path().join(remote_filename(url));
+        fs::create_dir_all(location.parent().unwrap()).unwrap();
+        fs::write(&location, "logo").unwrap();
+
+        let asset = download(url, temp.path(), Duration::from_secs(1)).unwrap();
+        assert_eq!(asset.location_on_disk, location);
+        assert_eq!(asset.mimetype.to_string(), "image/png");
+        assert!(download("http://127.0.0.1:9/logo", temp.path(), Duration::from_secs(1)).is_err());
+    }
 }
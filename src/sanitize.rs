@@ -0,0 +1,333 @@
+use pulldown_cmark::{CowStr, Event, Tag};
+
+/// Elements which are removed along with everything inside them.
+const DROPPED_ELEMENTS: &[&str] = &["script", "iframe"];
+/// Attributes holding a URL, which mustn't be a `javascript:` one.
+const URL_ATTRIBUTES: &[&str] = &["href", "src", "xlink:href", "action", "formaction"];
+
+/// Removes scripting from the raw HTML in a chapter, which readers and
+/// stores refuse: `<script>` and `<iframe>` elements, `on*` event handler
+/// attributes and `javascript:` URLs. Everything else is left as it was
+/// written.
+///
+/// An HTML block comes one line at a time and inline HTML comes one tag at
+/// a time, so a removed element's contents may span several events, and a
+/// tag split over several lines is held back until it's complete.
+pub(crate) struct EventSanitizer {
+    enabled: bool,
+    /// The element whose contents are being removed.
+    dropping: Option<String>,
+    /// The start of a tag which continues in the next event.
+    partial_tag: String,
+    in_comment: bool,
+}
+
+impl EventSanitizer {
+    pub(crate) fn new(enabled: bool) -> Self {
+        EventSanitizer {
+            enabled,
+            dropping: None,
+            partial_tag: String::new(),
+            in_comment: false,
+        }
+    }
+
+    pub(crate) fn convert<'a>(&mut self, event: Event<'a>) -> Vec<Event<'a>> {
+        if !self.enabled {
+            return vec![event];
+        }
+
+        if let Event::Html(ref html) = event {
+            let sanitized = self.sanitize(html);
+            return if sanitized.is_empty() {
+                Vec::new()
+            } else {
+                vec![Event::Html(CowStr::from(sanitized))]
+            };
+        }
+
+        let mut events = Vec::new();
+        if !self.partial_tag.is_empty() {
+            // the tag was never finished, so make the most of what there is
+            let partial = std::mem::take(&mut self.partial_tag);
+            let sanitized = self.sanitize_tag(&partial);
+            if !sanitized.is_empty() {
+                events.push(Event::Html(CowStr::from(sanitized)));
+            }
+        }
+
+        if self.dropping.is_some() {
+            // an inline element which is never closed only swallows the
+            // rest of its block
+            match event {
+                Event::End(Tag::Paragraph)
+                | Event::End(Tag::Heading(..))
+                | Event::End(Tag::Item)
+                | Event::End(Tag::TableCell) => self.dropping = None,
+                _ => return events,
+            }
+        }
+
+        events.push(event);
+        events
+    }
+
+    fn sanitize(&mut self, html: &str) -> String {
+        let input = std::mem::take(&mut self.partial_tag) + html;
+        let mut out = String::with_capacity(input.len());
+        let mut rest = input.as_str();
+
+        loop {
+            if self.in_comment {
+                match rest.find("-->") {
+                    Some(end) => {
+                        out.push_str(&rest[..end + 3]);
+                        rest = &rest[end + 3..];
+                        self.in_comment = false;
+                    }
+                    None => {
+                        out.push_str(rest);
+                        break;
+                    }
+                }
+            }
+
+            if let Some(ref element) = self.dropping {
+                match closing_tag_end(rest, element) {
+                    Some(end) => {
+                        rest = &rest[end..];
+                        self.dropping = None;
+                    }
+                    None => break,
+                }
+            }
+
+            let start = match rest.find('<') {
+                Some(start) => start,
+                None => {
+                    out.push_str(rest);
+                    break;
+                }
+            };
+            out.push_str(&rest[..start]);
+            rest = &rest[start..];
+
+            if rest.starts_with("<!--") {
+                out.push_str("<!--");
+                rest = &rest[4..];
+                self.in_comment = true;
+                continue;
+            }
+
+            let is_tag = rest[1..]
+                .chars()
+                .next()
+                .is_some_and(|c| c.is_ascii_alphabetic() || c == '/');
+            if !is_tag {
+                out.push('<');
+                rest = &rest[1..];
+                continue;
+            }
+
+            match tag_end(rest) {
+                Some(end) => {
+                    out.push_str(&self.sanitize_tag(&rest[..end]));
+                    rest = &rest[end..];
+                }
+                None => {
+                    self.partial_tag = rest.to_string();
+                    break;
+                }
+            }
+        }
+
+        out
+    }
+
+    /// Sanitize a single tag, from its `<` to its `>`. Tags of dropped
+    /// elements are removed, which also starts removing what follows an
+    /// opening tag.
+    fn sanitize_tag(&mut self, tag: &str) -> String {
+        let closing = tag.starts_with("</");
+        let name_start = if closing { 2 } else { 1 };
+        let name_len = tag[name_start..]
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == ':'))
+            .unwrap_or(tag.len() - name_start);
+        let name = &tag[name_start..name_start + name_len];
+
+        let lowercase_name = name.to_ascii_lowercase();
+        if DROPPED_ELEMENTS.contains(&lowercase_name.as_str()) {
+            if !closing && !tag.ends_with("/>") {
+                self.dropping = Some(lowercase_name);
+            }
+            return String::new();
+        }
+        if closing {
+            return tag.to_string();
+        }
+
+        let body = &tag[name_start + name_len..];
+        let body = body.strip_suffix('>').unwrap_or(body);
+        let mut out = format!("<{}", name);
+        let mut rest = body;
+
+        while !rest.is_empty() {
+            let space_len = rest.len() - rest.trim_start().len();
+            let space = &rest[..space_len];
+            rest = &rest[space_len..];
+
+            let attr_name_len = rest
+                .find(|c: char| c.is_whitespace() || c == '=' || c == '/' || c == '>')
+                .unwrap_or(rest.len());
+            if attr_name_len == 0 {
+                // a self-closing `/`, or something we don't understand
+                out.push_str(space);
+                out.push_str(rest);
+                break;
+            }
+
+            let (attr_len, value) = attribute(rest, attr_name_len);
+            let attr_name = &rest[..attr_name_len];
+            if !is_dangerous(attr_name, value) {
+                out.push_str(space);
+                out.push_str(&rest[..attr_len]);
+            }
+            rest = &rest[attr_len..];
+        }
+
+        if tag.ends_with('>') {
+            out.push('>');
+        }
+        out
+    }
+}
+
+/// The length of the attribute at the start of `rest`, whose name is
+/// `name_len` long, and its value if it has one.
+fn attribute(rest: &str, name_len: usize) -> (usize, Option<&str>) {
+    let after_name = &rest[name_len..];
+    let after_equals = match after_name.trim_start().strip_prefix('=') {
+        Some(after_equals) => after_equals.trim_start(),
+        None => return (name_len, None),
+    };
+    let value_start = rest.len() - after_equals.len();
+
+    match after_equals.chars().next() {
+        Some(quote) if quote == '"' || quote == '\'' => match after_equals[1..].find(quote) {
+            Some(end) => (value_start + end + 2, Some(&after_equals[1..end + 1])),
+            None => (rest.len(), Some(&after_equals[1..])),
+        },
+        _ => {
+            let end = after_equals
+                .find(|c: char| c.is_whitespace())
+                .unwrap_or(after_equals.len());
+            (value_start + end, Some(&after_equals[..end]))
+        }
+    }
+}
+
+fn is_dangerous(name: &str, value: Option<&str>) -> bool {
+    let name = name.to_ascii_lowercase();
+    if name.starts_with("on") {
+        return true;
+    }
+
+    match value {
+        Some(value) if URL_ATTRIBUTES.contains(&name.as_str()) => {
+            // browsers ignore whitespace and control characters in the scheme
+            let scheme: String = value
+                .chars()
+                .filter(|c| !c.is_whitespace() && !c.is_control())
+                .take("javascript:".len())
+                .collect();
+            scheme.eq_ignore_ascii_case("javascript:")
+        }
+        _ => false,
+    }
+}
+
+/// Where the tag at the start of `html` ends, just after its `>`, skipping
+/// over quoted attribute values.
+fn tag_end(html: &str) -> Option<usize> {
+    let mut quote = None;
+
+    for (ix, c) in html.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, '>') => return Some(ix + 1),
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// Where the closing tag of `element` ends in `html`, just after its `>`.
+fn closing_tag_end(html: &str, element: &str) -> Option<usize> {
+    let lowercase = html.to_ascii_lowercase();
+    let start = lowercase.find(&format!("</{}", element))?;
+
+    html[start..].find('>').map(|end| start + end + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Generator;
+    use pulldown_cmark::html;
+
+    fn render(markdown: &str) -> String {
+        let mut sanitizer = EventSanitizer::new(true);
+        let events = Generator::new_cmark_parser(markdown).flat_map(|event| sanitizer.convert(event));
+        let mut rendered = String::new();
+        html::push_html(&mut rendered, events);
+        rendered
+    }
+
+    #[test]
+    fn scripts_are_removed() {
+        let rendered = render("# Title\n\n<script>\nalert(\"hi\");\n</script>\n\nAfter\n");
+
+        assert_eq!(rendered, "<h1>Title</h1>\n\n<p>After</p>\n");
+    }
+
+    #[test]
+    fn inline_scripts_and_iframes_are_removed() {
+        let rendered = render("Before <script>alert(1)</script> after\n\n<IFRAME src=\"https://example.com\"></IFRAME>\n");
+
+        assert_eq!(rendered, "<p>Before  after</p>\n\n");
+    }
+
+    #[test]
+    fn event_handlers_and_javascript_urls_are_removed() {
+        let rendered = render(
+            "<div class=\"box\" onclick=\"steal()\">\n<a href=\" JavaScript:steal()\" title='x'>link</a>\n\
+             <img src=\"logo.png\" ONERROR=steal() alt=\"\"/>\n</div>\n",
+        );
+
+        assert_eq!(
+            rendered,
+            "<div class=\"box\">\n<a title='x'>link</a>\n<img src=\"logo.png\" alt=\"\"/>\n</div>\n"
+        );
+    }
+
+    #[test]
+    fn tags_split_over_lines_are_sanitized() {
+        let rendered = render("<div\n  onclick=\"steal()\"\n  class=\"box\">\ntext\n</div>\n");
+
+        assert!(!rendered.contains("onclick"), "{}", rendered);
+        assert!(rendered.contains("class=\"box\""), "{}", rendered);
+        assert!(rendered.contains("text"), "{}", rendered);
+    }
+
+    #[test]
+    fn safe_html_is_left_alone() {
+        let markdown = "<table class=\"wide\">\n<tr><td title=\"a > b\">1 < 2</td></tr>\n</table>\n\n<!-- <script> -->\n";
+        let mut sanitizer = EventSanitizer::new(true);
+        let sanitized: Vec<_> = Generator::new_cmark_parser(markdown).flat_map(|event| sanitizer.convert(event)).collect();
+
+        assert_eq!(sanitized, Generator::new_cmark_parser(markdown).collect::<Vec<_>>());
+    }
+}
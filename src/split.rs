@@ -0,0 +1,197 @@
+use std::path::{Path, PathBuf};
+
+use mdbook::book::BookItem;
+use mdbook::renderer::RenderContext;
+
+use super::Error;
+
+/// One of the books a `split_by_part` book is written out as.
+#[derive(Debug)]
+pub(crate) struct Volume {
+    /// The part's title.
+    pub(crate) part: String,
+    /// The book with only the part's chapters, with its title and series set
+    /// for the volume.
+    pub(crate) ctx: RenderContext,
+}
+
+/// Split a book into a volume for each of its parts. Chapters before the
+/// first part go in the first volume. Books with fewer than two parts aren't
+/// split, and have no volumes.
+///
+/// Each volume caches its chapters in a directory of its own, as one
+/// volume's configuration would clear another's cache. An `identifier` set
+/// for the whole book can't tell the volumes apart, so is an error.
+pub(crate) fn by_part(ctx: &RenderContext) -> Result<Vec<Volume>, Error> {
+    let mut parts: Vec<(String, Vec<BookItem>)> = Vec::new();
+    let mut leading = Vec::new();
+
+    for item in &ctx.book.sections {
+        match (item, parts.last_mut()) {
+            (BookItem::PartTitle(title), _) => parts.push((title.clone(), std::mem::take(&mut leading))),
+            (_, Some((_, items))) => items.push(item.clone()),
+            (_, None) => leading.push(item.clone()),
+        }
+    }
+    if parts.len() < 2 {
+        debug!("Not splitting a book with {} parts", parts.len());
+        return Ok(Vec::new());
+    }
+
+    let title = ctx.config.book.title.clone();
+    let series_set = ctx.config.get("output.epub.series").is_some();
+    if let Some(identifier) = ctx.config.get("output.epub.identifier") {
+        return Err(Error::SharedIdentifier(identifier.as_str().map_or_else(|| identifier.to_string(), String::from)));
+    }
+    let cache_dir = ctx.config.get("output.epub.cache-dir").and_then(|v| v.as_str()).map(PathBuf::from);
+
+    parts
+        .into_iter()
+        .enumerate()
+        .map(|(ix, (part, items))| {
+            let mut volume = ctx.clone();
+            volume.book.sections = items;
+            volume.config.book.title = Some(match title {
+                Some(ref title) => format!("{}: {}", title, part),
+                None => part.clone(),
+            });
+            // the volumes are a series of their own, unless the book is
+            // already part of one
+            if !series_set {
+                if let Some(ref title) = title {
+                    volume.config.set("output.epub.series", title)?;
+                    volume.config.set("output.epub.series-index", ix as f64 + 1.0)?;
+                }
+            }
+            if let Some(ref cache_dir) = cache_dir {
+                volume.config.set("output.epub.cache-dir", volume_path(cache_dir, &part))?;
+            }
+            Ok(Volume { part, ctx: volume })
+        })
+        .collect()
+}
+
+/// Where a volume is written: beside the whole book's `outfile`, with the
+/// part's title added to the name.
+pub(crate) fn volume_filename(outfile: &Path, part: &str) -> PathBuf {
+    volume_name(outfile, part, ".epub")
+}
+
+/// Where a volume's copy of one of the whole book's other files, such as its
+/// report, goes.
+pub(crate) fn volume_path(path: &Path, part: &str) -> PathBuf {
+    let extension = path.extension().map(|ext| format!(".{}", ext.to_string_lossy())).unwrap_or_default();
+    volume_name(path, part, &extension)
+}
+
+fn volume_name(path: &Path, part: &str, extension: &str) -> PathBuf {
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy()).unwrap_or_default();
+    let part: String = part
+        .chars()
+        .map(|c| if matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|') { '_' } else { c })
+        .collect();
+    path.with_file_name(format!("{} - {}{}", stem, part.trim(), extension))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mdbook::book::{Book, Chapter};
+    use mdbook::Config as MdConfig;
+
+    fn chapter(name: &str) -> BookItem {
+        BookItem::Chapter(Chapter::new(name, format!("# {}\n", name), format!("{}.md", name), vec![]))
+    }
+
+    fn names(volume: &Volume) -> Vec<String> {
+        volume
+            .ctx
+            .book
+            .sections
+            .iter()
+            .filter_map(|item| match item {
+                BookItem::Chapter(ch) => Some(ch.name.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn each_part_is_a_volume() {
+        let mut book = Book::new();
+        for item in [
+            chapter("intro"),
+            BookItem::PartTitle(String::from("Basics")),
+            chapter("setup"),
+            BookItem::Separator,
+            chapter("usage"),
+            BookItem::PartTitle(String::from("Advanced")),
+            chapter("tuning"),
+        ] {
+            book.push_item(item);
+        }
+        let mut config = MdConfig::default();
+        config.book.title = Some(String::from("Handbook"));
+        let ctx = RenderContext::new("root", book, config, "book");
+
+        let volumes = by_part(&ctx).unwrap();
+
+        let parts: Vec<_> = volumes.iter().map(|volume| volume.part.as_str()).collect();
+        assert_eq!(parts, ["Basics", "Advanced"]);
+        assert_eq!(names(&volumes[0]), ["intro", "setup", "usage"]);
+        assert_eq!(names(&volumes[1]), ["tuning"]);
+        let second = &volumes[1].ctx.config;
+        assert_eq!(second.book.title.as_deref(), Some("Handbook: Advanced"));
+        assert_eq!(second.get("output.epub.series").and_then(|v| v.as_str()), Some("Handbook"));
+        assert_eq!(second.get("output.epub.series-index").and_then(|v| v.as_float()), Some(2.0));
+    }
+
+    #[test]
+    fn books_with_one_part_are_not_split() {
+        let mut book = Book::new();
+        book.push_item(BookItem::PartTitle(String::from("Only")));
+        book.push_item(chapter("intro"));
+        let ctx = RenderContext::new("root", book, MdConfig::default(), "book");
+
+        assert!(by_part(&ctx).unwrap().is_empty());
+    }
+
+    #[test]
+    fn volumes_cache_apart_and_cant_share_an_identifier() {
+        let mut book = Book::new();
+        for item in [
+            BookItem::PartTitle(String::from("Basics")),
+            chapter("setup"),
+            BookItem::PartTitle(String::from("Advanced")),
+            chapter("tuning"),
+        ] {
+            book.push_item(item);
+        }
+        let mut config = MdConfig::default();
+        config.set("output.epub.cache-dir", "cache").unwrap();
+        let ctx = RenderContext::new("root", book, config, "book");
+
+        let cache_dirs: Vec<_> = by_part(&ctx)
+            .unwrap()
+            .iter()
+            .map(|volume| volume.ctx.config.get("output.epub.cache-dir").and_then(|v| v.as_str()).map(String::from))
+            .collect();
+        assert_eq!(cache_dirs, [Some(String::from("cache - Basics")), Some(String::from("cache - Advanced"))]);
+
+        let mut ctx = ctx;
+        ctx.config.set("output.epub.identifier", "urn:isbn:9780306406157").unwrap();
+        assert!(matches!(by_part(&ctx), Err(Error::SharedIdentifier(id)) if id == "urn:isbn:9780306406157"));
+    }
+
+    #[test]
+    fn volumes_are_named_after_their_parts() {
+        assert_eq!(
+            volume_filename(Path::new("book/Handbook.epub"), "Tips / Tricks"),
+            PathBuf::from("book/Handbook - Tips _ Tricks.epub")
+        );
+        assert_eq!(
+            volume_path(Path::new("reports/size.txt"), "Part 1"),
+            PathBuf::from("reports/size - Part 1.txt")
+        );
+    }
+}
@@ -0,0 +1,103 @@
+use crate::config::TaskListStyle;
+use pulldown_cmark::{CowStr, Event, Tag};
+
+/// Replaces task list checkboxes (`- [x] done`) with ☐/☑ characters, which
+/// readers show reliably, and marks their list items with a
+/// `task-list-item` class.
+///
+/// The marker comes after the item has been opened, so the item's opening
+/// tag is held back until we know whether it's a task. In a loose list the
+/// marker also comes before the item's paragraph, so the character is held
+/// back until the paragraph has been opened.
+pub(crate) struct EventTaskList<'a> {
+    style: TaskListStyle,
+    /// The start of a list item which might still turn out to be a task.
+    pending_item: Option<Event<'a>>,
+    /// The character for a task whose text hasn't started yet.
+    pending_glyph: Option<&'static str>,
+}
+
+impl<'a> EventTaskList<'a> {
+    pub(crate) fn new(style: TaskListStyle) -> Self {
+        EventTaskList {
+            style,
+            pending_item: None,
+            pending_glyph: None,
+        }
+    }
+
+    pub(crate) fn convert(&mut self, event: Event<'a>) -> Vec<Event<'a>> {
+        if self.style == TaskListStyle::Checkbox {
+            return vec![event];
+        }
+
+        if let Event::TaskListMarker(checked) = event {
+            self.pending_glyph = Some(if checked { "\u{2611} " } else { "\u{2610} " });
+            return match self.pending_item.take() {
+                // swap the item's opening tag for one with a class
+                Some(_) => vec![Event::Html(CowStr::from("<li class=\"task-list-item\">"))],
+                None => Vec::new(),
+            };
+        }
+
+        let mut events: Vec<Event<'a>> = self.pending_item.take().into_iter().collect();
+        if let Some(glyph) = self.pending_glyph.take() {
+            let glyph = Event::Text(CowStr::from(glyph));
+            if let Event::Start(Tag::Paragraph) = event {
+                events.push(event);
+                events.push(glyph);
+                return events;
+            }
+            events.push(glyph);
+        }
+
+        match event {
+            Event::Start(Tag::Item) => self.pending_item = Some(event),
+            _ => events.push(event),
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Generator;
+    use pulldown_cmark::html;
+
+    fn render(markdown: &str, style: TaskListStyle) -> String {
+        let mut task_list = EventTaskList::new(style);
+        let events = Generator::new_cmark_parser(markdown).flat_map(|event| task_list.convert(event));
+        let mut rendered = String::new();
+        html::push_html(&mut rendered, events);
+        rendered
+    }
+
+    #[test]
+    fn checkboxes_are_kept_by_default() {
+        let rendered = render("- [x] done\n", TaskListStyle::Checkbox);
+
+        assert!(rendered.contains(r#"<input disabled="" type="checkbox" checked=""/>"#), "{}", rendered);
+    }
+
+    #[test]
+    fn checkboxes_become_characters() {
+        let rendered = render("- [x] done\n- [ ] to do\n- plain\n", TaskListStyle::Unicode);
+
+        assert_eq!(
+            rendered,
+            "<ul>\n<li class=\"task-list-item\">\u{2611} done</li>\n\
+             <li class=\"task-list-item\">\u{2610} to do</li>\n\
+             <li>plain</li>\n</ul>\n"
+        );
+    }
+
+    #[test]
+    fn loose_task_lists_get_the_class_too() {
+        let rendered = render("- [x] done\n\n- [ ] to do\n", TaskListStyle::Unicode);
+
+        assert!(rendered.contains("<li class=\"task-list-item\">\n<p>\u{2611} done</p>"), "{}", rendered);
+        assert!(rendered.contains("<li class=\"task-list-item\">\n<p>\u{2610} to do</p>"), "{}", rendered);
+        assert!(!rendered.contains("<input"), "{}", rendered);
+    }
+}
@@ -0,0 +1,361 @@
+use super::Error;
+use crate::package::CONTENT_OPF;
+use crate::resources::SOURCE_MEDIA_TYPE;
+use std::collections::HashSet;
+use std::io::{Cursor, Read};
+use std::path::{Component, Path, PathBuf};
+use xml::reader::{EventReader, XmlEvent};
+use zip::ZipArchive;
+
+/// How many problems are listed in the error before giving up.
+const MAX_REPORTED: usize = 5;
+
+/// A manifest `<item>` from the package document.
+#[derive(Debug, Clone, PartialEq)]
+struct Item {
+    id: String,
+    /// The item's location within the archive.
+    path: PathBuf,
+    media_type: String,
+    properties: String,
+}
+
+/// Check a generated EPUB for dangling references: every `src`/`href` in
+/// its content documents (and `url()` in stylesheets) must point at a
+/// manifest item, and every manifest item must be referenced by something.
+///
+/// This is a long way from a full `epubcheck`, but catches the mistakes
+/// which most often make readers reject a book.
+pub(crate) fn validate(epub: &[u8]) -> Result<(), Error> {
+    let mut archive = ZipArchive::new(Cursor::new(epub))?;
+    let opf = read_to_string(&mut archive, CONTENT_OPF)?;
+    let opf_dir = Path::new(CONTENT_OPF).parent().unwrap_or_else(|| Path::new(""));
+
+    let items: Vec<Item> = tags(&opf, "item")
+        .into_iter()
+        .filter_map(|tag| {
+            Some(Item {
+                id: attribute(tag, "id")?,
+                path: normalize(&opf_dir.join(attribute(tag, "href")?)),
+                media_type: attribute(tag, "media-type").unwrap_or_default(),
+                properties: attribute(tag, "properties").unwrap_or_default(),
+            })
+        })
+        .collect();
+    let manifest: HashSet<&Path> = items.iter().map(|item| item.path.as_path()).collect();
+
+    let mut problems = Vec::new();
+    let mut referenced_ids = HashSet::new();
+    let mut referenced_paths = HashSet::new();
+
+    // references from the package document itself
+    for tag in tags(&opf, "itemref") {
+        referenced_ids.extend(attribute(tag, "idref"));
+    }
+    for tag in tags(&opf, "spine") {
+        referenced_ids.extend(attribute(tag, "toc"));
+    }
+    for tag in tags(&opf, "meta") {
+        if attribute(tag, "name").as_deref() == Some("cover") {
+            referenced_ids.extend(attribute(tag, "content"));
+        }
+    }
+    for tag in tags(&opf, "reference") {
+        if let Some(href) = attribute(tag, "href") {
+            referenced_paths.insert(resolve(opf_dir, &href));
+        }
+    }
+
+    for item in &items {
+        let links = match item.media_type.as_str() {
+            "application/xhtml+xml" => {
+                let content = read_to_string(&mut archive, &item.path.to_string_lossy())?;
+                xhtml_links(&content)
+            }
+            "text/css" => {
+                let content = read_to_string(&mut archive, &item.path.to_string_lossy())?;
+                css_links(&content)
+            }
+            _ => continue,
+        };
+
+        let dir = item.path.parent().unwrap_or_else(|| Path::new(""));
+        for link in links.into_iter().filter(|link| is_internal(link)) {
+            let target = resolve(dir, &link);
+            if target != item.path && !manifest.contains(target.as_path()) {
+                problems.push(format!(
+                    "{} refers to {} which isn't in the manifest",
+                    item.path.display(),
+                    link
+                ));
+            }
+            referenced_paths.insert(target);
+        }
+    }
+
+    for item in &items {
+        // the chapters' sources from `embed_source` are there to be
+        // extracted rather than read
+        let referenced = referenced_ids.contains(&item.id)
+            || referenced_paths.contains(&item.path)
+            || item.properties.split_whitespace().any(|p| p == "nav" || p == "cover-image")
+            || item.media_type == SOURCE_MEDIA_TYPE;
+        if !referenced {
+            problems.push(format!("{} is never referenced", item.path.display()));
+        }
+    }
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    let count = problems.len();
+    problems.truncate(MAX_REPORTED);
+    if count > MAX_REPORTED {
+        problems.push(format!("and {} more", count - MAX_REPORTED));
+    }
+    Err(Error::Validation(problems.join("; ")))
+}
+
+fn read_to_string<R>(archive: &mut ZipArchive<R>, name: &str) -> Result<String, Error>
+where
+    R: Read + std::io::Seek,
+{
+    let mut file = archive.by_name(name)?;
+    let mut content = String::new();
+    file.read_to_string(&mut content)?;
+    Ok(content)
+}
+
+/// The opening tags (without the `<`) of every `name` element.
+fn tags<'x>(xml: &'x str, name: &str) -> Vec<&'x str> {
+    let open = format!("<{}", name);
+    let mut found = Vec::new();
+    let mut rest = xml;
+
+    while let Some(start) = rest.find(&open) {
+        let tag = &rest[start + 1..];
+        let end = tag.find('>').unwrap_or(tag.len());
+        // make sure `<item` didn't match `<itemref`
+        if tag[name.len()..].starts_with(|c: char| c.is_whitespace() || c == '/' || c == '>') {
+            found.push(&tag[..end]);
+        }
+        rest = &tag[end..];
+    }
+
+    found
+}
+
+/// The value of the `name="..."` attribute in a tag.
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    attribute_values(tag, name).into_iter().next()
+}
+
+/// Every value of every `name="..."` attribute in some markup.
+fn attribute_values(markup: &str, name: &str) -> Vec<String> {
+    let mut values = Vec::new();
+
+    for quote in ['"', '\''] {
+        let needle = format!("{}={}", name, quote);
+        let mut rest = markup;
+
+        while let Some(ix) = rest.find(&needle) {
+            let preceded_by_space = rest[..ix].ends_with(char::is_whitespace);
+            let value = &rest[ix + needle.len()..];
+            let end = value.find(quote).unwrap_or(value.len());
+            if preceded_by_space {
+                values.push(unescape(&value[..end]));
+            }
+            rest = &value[end..];
+        }
+    }
+
+    values
+}
+
+fn xhtml_links(content: &str) -> Vec<String> {
+    let mut links = attribute_values(content, "href");
+    links.extend(attribute_values(content, "src"));
+    links.extend(attribute_values(content, "xlink:href"));
+    links
+}
+
+fn css_links(content: &str) -> Vec<String> {
+    let mut links = Vec::new();
+    let mut rest = content;
+
+    while let Some(ix) = rest.find("url(") {
+        let value = &rest[ix + 4..];
+        let end = value.find(')').unwrap_or(value.len());
+        links.push(value[..end].trim().trim_matches(|c| c == '"' || c == '\'').to_string());
+        rest = &value[end..];
+    }
+
+    links
+}
+
+/// Does this link point at something inside the book?
+pub(crate) fn is_internal(link: &str) -> bool {
+    let link = link.trim();
+    !(link.is_empty() || link.starts_with('#') || link.contains("://") || link.starts_with("mailto:") || link.starts_with("data:"))
+}
+
+/// Where a link from a file in `dir` leads to within the archive.
+fn resolve(dir: &Path, link: &str) -> PathBuf {
+    let path = link.split(['#', '?']).next().unwrap_or(link);
+    normalize(&dir.join(percent_decode(path)))
+}
+
+/// The path a link spells out, with its percent-escapes (`%20`, `%C3%A9`)
+/// decoded. A `%` which doesn't start an escape is kept as it is.
+pub(crate) fn percent_decode(link: &str) -> String {
+    let bytes = link.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut ix = 0;
+
+    while ix < bytes.len() {
+        let escaped = match bytes.get(ix + 1..ix + 3) {
+            Some(hex) if bytes[ix] == b'%' => std::str::from_utf8(hex)
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                ix += 3;
+            }
+            None => {
+                decoded.push(bytes[ix]);
+                ix += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// A path as a link: the characters which would end it or change what it
+/// means, like spaces, `#` and `%`, are percent-escaped.
+pub(crate) fn percent_encode(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for c in path.chars() {
+        if c.is_ascii_control() || " \"#%'<>?\\".contains(c) {
+            encoded.push_str(&format!("%{:02X}", c as u32));
+        } else {
+            encoded.push(c);
+        }
+    }
+    encoded
+}
+
+/// Check that a document is well-formed XML, describing the first problem
+/// and where it is if it isn't.
+pub(crate) fn well_formed(xml: &str) -> Result<(), String> {
+    for event in EventReader::from_str(xml) {
+        match event {
+            Ok(XmlEvent::EndDocument) => break,
+            Ok(_) => {}
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolve `..` components without touching the filesystem.
+pub(crate) fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::Normal(part) => normalized.push(part),
+            _ => {}
+        }
+    }
+
+    normalized
+}
+
+fn unescape(value: &str) -> String {
+    value
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    const OPF: &str = r#"<package>
+  <manifest>
+    <item id="ncx" href="toc.ncx" media-type="application/x-dtbncx+xml"/>
+    <item media-type="application/xhtml+xml" id="a_html" href="a.html"/>
+    <item media-type="text/css" id="stylesheet_css" href="stylesheet.css"/>
+    <item media-type="image/png" id="logo_png" href="img/logo.png"/>
+    EXTRA
+  </manifest>
+  <spine toc="ncx">
+    <itemref idref="a_html"/>
+  </spine>
+</package>"#;
+
+    fn epub(extra_item: &str, chapter: &str) -> Vec<u8> {
+        let mut out = ZipWriter::new(Cursor::new(Vec::new()));
+        let files = [
+            ("OEBPS/content.opf", OPF.replace("EXTRA", extra_item)),
+            ("OEBPS/a.html", chapter.to_string()),
+            ("OEBPS/stylesheet.css", String::from("body { margin: 0; }")),
+        ];
+        for (name, content) in files.iter() {
+            out.start_file(*name, FileOptions::default()).unwrap();
+            out.write_all(content.as_bytes()).unwrap();
+        }
+        out.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn malformed_xml_is_described() {
+        well_formed("<?xml version=\"1.0\"?>\n<html><body><p>Fine &amp; dandy</p></body></html>").unwrap();
+
+        let err = well_formed("<html>\n<body><p>Unclosed</body>\n</html>").unwrap_err();
+        assert!(err.starts_with("2:"), "{}", err);
+        assert!(well_formed("<html><body>&nbsp;</body></html>").is_err());
+    }
+
+    #[test]
+    fn percent_escapes() {
+        assert_eq!(percent_decode("caf%C3%A9%20menu.png"), "café menu.png");
+        assert_eq!(percent_decode("100%.png"), "100%.png");
+        assert_eq!(percent_encode("café menu #1?.png"), "café%20menu%20%231%3F.png");
+        assert_eq!(percent_decode(&percent_encode("50% off.png")), "50% off.png");
+    }
+
+    #[test]
+    fn valid_book_passes() {
+        let chapter = r#"<link rel="stylesheet" href="stylesheet.css" /><img src="./img/logo.png" /><a href="a.html#top">x</a><a href="https://example.com">y</a>"#;
+
+        validate(&epub("", chapter)).unwrap();
+    }
+
+    #[test]
+    fn dangling_and_unused_items_are_reported() {
+        let chapter = r#"<link rel="stylesheet" href="stylesheet.css" /><img src="img/missing.png" />"#;
+        let extra = r#"<item media-type="image/png" id="unused_png" href="unused.png"/>"#;
+
+        let err = validate(&epub(extra, chapter)).unwrap_err().to_string();
+
+        assert!(err.contains("OEBPS/a.html refers to img/missing.png which isn't in the manifest"), "{}", err);
+        assert!(err.contains("OEBPS/img/logo.png is never referenced"), "{}", err);
+        assert!(err.contains("OEBPS/unused.png is never referenced"), "{}", err);
+    }
+}
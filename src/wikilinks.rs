@@ -0,0 +1,205 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use mdbook::book::{Book, BookItem, Chapter};
+use pulldown_cmark::{CowStr, Event, LinkType, Tag};
+
+use crate::flatten;
+use crate::validate;
+
+/// The chapters wikilinks can lead to, keyed by their titles and the names
+/// of their source files, the way [`key()`] writes them. Only the chapters
+/// which are `packaged` can be led to. When two chapters share a name the
+/// first in the book wins.
+pub(crate) fn targets<F>(book: &Book, packaged: F) -> BTreeMap<String, PathBuf>
+where
+    F: Fn(&Chapter) -> bool,
+{
+    let mut targets = BTreeMap::new();
+    for item in book.iter() {
+        let (ch, path) = match *item {
+            BookItem::Chapter(ref ch) if packaged(ch) => match ch.path {
+                Some(ref path) => (ch, path),
+                None => continue,
+            },
+            _ => continue,
+        };
+        let stem = path.file_stem().map(|stem| stem.to_string_lossy()).unwrap_or_default();
+        for name in [ch.name.as_str(), &stem] {
+            targets.entry(key(name)).or_insert_with(|| path.clone());
+        }
+    }
+    targets
+}
+
+/// How a wikilink's target is matched: without case, and with spaces,
+/// hyphens and underscores all the same, so `[[Getting Started]]` finds
+/// `getting-started.md` as well as the chapter titled "Getting Started".
+fn key(name: &str) -> String {
+    name.trim()
+        .to_lowercase()
+        .split(|c: char| c.is_whitespace() || c == '-' || c == '_')
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Turns `[[Target]]` and `[[Target|Label]]` into links to the chapter
+/// called `Target`, pointing at its markdown source so the links are
+/// rewritten like any other link to a chapter.
+///
+/// The brackets can reach us spread over several text events, so text is
+/// held back until whatever follows it. Text in code and in links is left
+/// alone, and wikilinks to chapters which aren't in the book are written out
+/// as their labels.
+pub(crate) struct EventWikilinks<'t> {
+    enabled: bool,
+    targets: &'t BTreeMap<String, PathBuf>,
+    /// The directory of the chapter's source, which links are relative to.
+    dir: &'t Path,
+    /// The text which hasn't been looked through yet.
+    text: String,
+    /// How many code blocks, links and images we're in.
+    depth: usize,
+}
+
+impl<'t> EventWikilinks<'t> {
+    pub(crate) fn new(enabled: bool, targets: &'t BTreeMap<String, PathBuf>, chapter_path: &'t Path) -> Self {
+        EventWikilinks {
+            enabled,
+            targets,
+            dir: chapter_path.parent().unwrap_or_else(|| Path::new("")),
+            text: String::new(),
+            depth: 0,
+        }
+    }
+
+    pub(crate) fn convert<'a>(&mut self, event: Event<'a>) -> Vec<Event<'a>> {
+        if !self.enabled {
+            return vec![event];
+        }
+
+        match event {
+            Event::Text(ref text) if self.depth == 0 => {
+                self.text.push_str(text);
+                return Vec::new();
+            }
+            Event::Start(Tag::CodeBlock(_)) | Event::Start(Tag::Link(..)) | Event::Start(Tag::Image(..)) => {
+                self.depth += 1
+            }
+            Event::End(Tag::CodeBlock(_)) | Event::End(Tag::Link(..)) | Event::End(Tag::Image(..)) => {
+                self.depth = self.depth.saturating_sub(1)
+            }
+            _ => {}
+        }
+
+        let mut events = self.flush();
+        events.push(event);
+        events
+    }
+
+    /// The events for the text held back so far, with its wikilinks made
+    /// into links.
+    fn flush<'a>(&mut self) -> Vec<Event<'a>> {
+        let text = std::mem::take(&mut self.text);
+        let mut events = Vec::new();
+        let mut rest = text.as_str();
+
+        while let Some(start) = rest.find("[[") {
+            let end = match rest[start..].find("]]") {
+                Some(end) => start + end,
+                None => break,
+            };
+            let (target, label) = match rest[start + 2..end].split_once('|') {
+                Some((target, label)) => (target.trim(), label.trim()),
+                None => (rest[start + 2..end].trim(), rest[start + 2..end].trim()),
+            };
+            if target.is_empty() || target.contains('[') {
+                push_text(&mut events, &rest[..start + 2]);
+                rest = &rest[start + 2..];
+                continue;
+            }
+
+            push_text(&mut events, &rest[..start]);
+            match self.targets.get(&key(target)) {
+                Some(path) => {
+                    let dest = CowStr::from(validate::percent_encode(&flatten::relative_path(self.dir, path)));
+                    let link = Tag::Link(LinkType::Inline, dest, CowStr::from(""));
+                    events.push(Event::Start(link.clone()));
+                    push_text(&mut events, label);
+                    events.push(Event::End(link));
+                }
+                None => {
+                    warn!("Unable to find the chapter [[{}]] links to", target);
+                    push_text(&mut events, label);
+                }
+            }
+            rest = &rest[end + 2..];
+        }
+
+        push_text(&mut events, rest);
+        events
+    }
+}
+
+fn push_text<'a>(events: &mut Vec<Event<'a>>, text: &str) {
+    if !text.is_empty() {
+        events.push(Event::Text(CowStr::from(text.to_string())));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pulldown_cmark::{html, Parser};
+
+    fn book() -> Book {
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Getting Started", String::new(), "getting_started.md", Vec::new()));
+        book.push_item(Chapter::new("Setup", String::new(), "guide/setup.md", Vec::new()));
+        book
+    }
+
+    fn render(markdown: &str, chapter_path: &str) -> String {
+        let targets = targets(&book(), |_| true);
+        let mut wikilinks = EventWikilinks::new(true, &targets, Path::new(chapter_path));
+        let mut out = String::new();
+        html::push_html(&mut out, Parser::new(markdown).flat_map(|event| wikilinks.convert(event)));
+        out
+    }
+
+    #[test]
+    fn wikilinks_lead_to_chapters() {
+        assert_eq!(
+            render("See [[Getting Started]] and [[setup|setting up]].", "guide/index.md"),
+            "<p>See <a href=\"../getting_started.md\">Getting Started</a> and <a href=\"setup.md\">setting up</a>.</p>\n"
+        );
+    }
+
+    #[test]
+    fn unresolved_wikilinks_are_text() {
+        assert_eq!(render("A [[Missing Page|missing page]] and [[]].", "intro.md"), "<p>A missing page and [[]].</p>\n");
+    }
+
+    #[test]
+    fn code_and_links_are_left_alone() {
+        assert_eq!(
+            render("`[[Setup]]` [see [[Setup]]](x.md)\n\n```\n[[Setup]]\n```\n", "intro.md"),
+            "<p><code>[[Setup]]</code> <a href=\"x.md\">see [[Setup]]</a></p>\n<pre><code>[[Setup]]\n</code></pre>\n"
+        );
+    }
+
+    #[test]
+    fn targets_ignore_case_and_separators() {
+        assert_eq!(key(" Getting  Started "), "getting-started");
+        assert_eq!(key("getting_started"), "getting-started");
+        assert_eq!(targets(&book(), |_| true).get("setup"), Some(&PathBuf::from("guide/setup.md")));
+    }
+
+    #[test]
+    fn only_packaged_chapters_are_targets() {
+        let targets = targets(&book(), |ch| ch.name != "Setup");
+        assert!(targets.contains_key("getting-started"));
+        assert!(!targets.contains_key("setup"));
+    }
+}
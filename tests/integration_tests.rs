@@ -9,13 +9,15 @@ extern crate log;
 extern crate serial_test;
 
 use epub::doc::EpubDoc;
+use std::fs::File;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use tempdir::TempDir;
 use std::sync::Once;
+use mdbook::book::{BookItem, Chapter};
 use mdbook::renderer::RenderContext;
 use mdbook::MDBook;
-use mdbook_epub::Error;
+use mdbook_epub::{Error, Generator, ProgressReporter};
 
 static INIT: Once = Once::new();
 
@@ -37,11 +39,12 @@ fn generate_epub() -> Result< (EpubDoc, PathBuf), Error> {
     match EpubDoc::new(&output_file) {
         Ok(epub) => {
             let result: (EpubDoc, PathBuf) = (epub, output_file);
-            return Ok( result )},
+            Ok(result)
+        }
         Err(err) => {
             error!("dummy book creation error = {}", err);
-            return Err(Error::EpubDocCreate(output_file.display().to_string()))?
-        },
+            Err(Error::EpubDocCreate(output_file.display().to_string()))
+        }
     }
 }
 
@@ -102,19 +105,18 @@ fn look_for_chapter_1_heading() {
     let mut doc = generate_epub().unwrap();
     debug!("doc current path = {:?}", doc.1);
 
-    let path;
-    if cfg!(target_os = "linux") {
-        path = Path::new("OEBPS").join("chapter_1.html"); // linux
+    let path = if cfg!(target_os = "linux") {
+        Path::new("OEBPS").join("chapter_1.html") // linux
     } else {
-        path = Path::new("OEBPS/chapter_1.html").to_path_buf(); // windows with 'forward slash' /
-    }
+        Path::new("OEBPS/chapter_1.html").to_path_buf() // windows with 'forward slash' /
+    };
     debug!("short path = {:?}", path.display().to_string());
     debug!("full path = {:?}", &doc.1);
     let file = doc.0.get_resource_str_by_path(path);
     debug!("file = {:?}", &file);
     let content = file.unwrap();
     debug!("content = {:?}", content.len());
-    assert!(content.contains("<h1>Chapter 1</h1>"));
+    assert!(content.contains(r#"<h1 id="chapter-1">Chapter 1</h1>"#));
     // assert!(!content.contains("{{#rustdoc_include")); // prepare fix link error
     // assert!(content.contains("fn main() {")); // prepare fix link error
 }
@@ -129,12 +131,11 @@ fn rendered_document_contains_all_chapter_files_and_assets() {
     debug!("doc current path = {:?} / {:?}", doc.0.get_current_path(), doc.1);
 
     for chapter in chapters {
-        let path;
-        if cfg!(target_os = "windows") {
-            path = Path::new("OEBPS/").join(chapter); // windows with 'forward slash' /
+        let path = if cfg!(target_os = "windows") {
+            Path::new("OEBPS/").join(chapter) // windows with 'forward slash' /
         } else {
-            path = Path::new("OEBPS").join(chapter); // linux
-        }
+            Path::new("OEBPS").join(chapter) // linux
+        };
         // let path = path.display().to_string();
         debug!("path = {}", &path.display().to_string());
         let got = doc.0.get_resource_by_path(&path);
@@ -151,38 +152,2262 @@ fn straight_quotes_transformed_into_curly_quotes() {
     let mut doc = generate_epub().unwrap();
     debug!("doc current path = {:?}", doc.1);
 
-    let path;
-    if cfg!(target_os = "linux") {
-        path = Path::new("OEBPS").join("chapter_1.html"); // linux
+    let path = if cfg!(target_os = "linux") {
+        Path::new("OEBPS").join("chapter_1.html") // linux
     } else {
-        path = Path::new("OEBPS/chapter_1.html").to_path_buf(); // windows with 'forward slash' /
-    }
+        Path::new("OEBPS/chapter_1.html").to_path_buf() // windows with 'forward slash' /
+    };
     let file = doc.0.get_resource_str_by_path(path);
     let content = file.unwrap();
     debug!("content = {:?}", content);
     assert!(content.contains("<p>“One morning, when Gregor Samsa woke from troubled dreams, he found himself ‘transformed’ in his bed into a horrible vermin.”</p>"));
 }
 
+#[test]
+#[serial]
+fn book_language_is_used_for_package_and_chapters() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.book.language = Some(String::from("fr"));
+    mdbook_epub::generate(&ctx).unwrap();
 
-/// Use `MDBook::load()` to load the dummy book into memory, then set up the
-/// `RenderContext` for use the EPUB generator.
-fn create_dummy_book() -> Result<(RenderContext, MDBook, TempDir), Error> {
-    let temp = TempDir::new("mdbook-epub")?;
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    assert_eq!(doc.mdata("language"), Some(String::from("fr")));
 
-    let dummy_book = Path::new(env!("CARGO_MANIFEST_DIR"))
-        .join("tests")
-        .join("dummy");
-    debug!("dummy_book = {:?}", &dummy_book.display().to_string());
+    let content = doc.get_resource_str_by_path("OEBPS/chapter_1.html").unwrap();
+    assert!(content.contains(r#"xml:lang="fr" lang="fr""#));
+}
 
-    let md = MDBook::load(dummy_book);
+#[test]
+#[serial]
+fn publisher_and_identifier_are_emitted() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.publisher", "Dummy Press").unwrap();
+    ctx.config.set("output.epub.identifier", "urn:isbn:9780000000002").unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
 
-    let book = md.expect("dummy MDBook is not loaded");
-    let ctx = RenderContext::new(
-        book.root.clone(),
-        book.book.clone(),
-        book.config.clone(),
-        temp.path().to_path_buf(),
-    );
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let doc = EpubDoc::new(&output_file).unwrap();
+    assert_eq!(doc.mdata("publisher"), Some(String::from("Dummy Press")));
+    assert_eq!(doc.mdata("identifier"), Some(String::from("urn:isbn:9780000000002")));
+}
 
-    Ok((ctx, book, temp))
+#[test]
+#[serial]
+fn epub3_output_can_be_selected() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.version", "3.0").unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let opf = doc.get_resource_str_by_path("OEBPS/content.opf").unwrap();
+    assert!(opf.contains(r#"<package version="3.0""#));
+
+    epub_check(&output_file).unwrap();
+}
+
+#[test]
+#[serial]
+fn toc_page_links_to_chapters() {
+    init_logging();
+    let mut doc = generate_epub().unwrap();
+
+    let toc = doc.0.get_resource_str_by_path("OEBPS/toc.html").unwrap();
+    assert!(toc.contains(r#"<li><a href="chapter_1.html">1. Chapter 1</a>"#), "{}", toc);
+}
+
+#[test]
+#[serial]
+fn toc_page_can_be_disabled() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.generate-toc", false).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    assert!(doc.get_resource_by_path("OEBPS/toc.html").is_err());
+}
+
+#[test]
+#[serial]
+fn page_break_before_top_level_chapters() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.page-break-on-chapter", true).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let content = doc.get_resource_str_by_path("OEBPS/chapter_1.html").unwrap();
+    assert!(content.contains("page-break-before: always"));
+}
+
+#[test]
+#[serial]
+fn missing_font_is_reported_by_path() {
+    init_logging();
+    let (mut ctx, _md, _temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.embed-fonts", vec!["no-such-font.ttf"]).unwrap();
+
+    let err = mdbook_epub::generate(&ctx).unwrap_err();
+    assert!(err.to_string().contains("no-such-font.ttf"), "{}", err);
+}
+
+#[test]
+#[serial]
+fn draft_chapters_are_skipped() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.book.push_item(Chapter::new_draft("Draft", vec![]));
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let doc = EpubDoc::new(&output_file).unwrap();
+    assert!(!doc.spine.iter().any(|id| id.contains("draft")));
+}
+
+#[test]
+#[serial]
+fn draft_chapters_can_get_a_placeholder() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.book.push_item(Chapter::new_draft("Draft", vec![]));
+    ctx.config.set("output.epub.skip-drafts", false).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let content = doc.get_resource_str_by_path("OEBPS/draft-draft.html").unwrap();
+    assert!(content.contains("<h1>Draft</h1>"));
+}
+
+#[test]
+#[serial]
+fn math_is_rendered_as_mathml() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.book.push_item(Chapter::new("Math", String::from("# Math\n\nInline $x^2$.\n"), "math.md", vec![]));
+    ctx.config.set("output.epub.math", true).unwrap();
+    ctx.config.set("output.epub.version", "3.0").unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let content = doc.get_resource_str_by_path("OEBPS/math.html").unwrap();
+    assert!(content.contains("<math xmlns=\"http://www.w3.org/1998/Math/MathML\""));
+    let opf = doc.get_resource_str_by_path("OEBPS/content.opf").unwrap();
+    assert!(opf.contains(r#"href="math.html" properties="mathml""#), "{}", opf);
+}
+
+#[test]
+#[serial]
+fn title_page_comes_first() {
+    init_logging();
+    let (ctx, _md, temp) = create_dummy_book().unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    assert_eq!(doc.spine[0], "title_page_html");
+    let content = doc.get_resource_str_by_path("OEBPS/title_page.html").unwrap();
+    assert!(content.contains("<h1 class=\"title\">DummyBook</h1>"), "{}", content);
+}
+
+#[test]
+#[serial]
+fn title_page_can_be_disabled() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.title-page", false).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let doc = EpubDoc::new(&output_file).unwrap();
+    assert!(!doc.resources.contains_key("title_page_html"));
+}
+
+#[test]
+#[serial]
+fn generator_can_be_used_without_a_render_context() {
+    init_logging();
+    let (ctx, _md, temp) = create_dummy_book().unwrap();
+    let output_file = temp.path().join("library.epub");
+
+    let config = mdbook_epub::Config {
+        generate_toc: false,
+        ..Default::default()
+    };
+    Generator::from_book(&ctx.book, config, &ctx.root)
+        .unwrap()
+        .with_book_config(ctx.config.book.clone())
+        .with_destination(temp.path())
+        .generate(File::create(&output_file).unwrap())
+        .unwrap();
+
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    assert_eq!(doc.mdata("title").unwrap(), "DummyBook");
+    assert!(doc.get_resource_str_by_path("OEBPS/chapter_1.html").is_ok());
+    assert!(!doc.resources.contains_key("toc_html"));
+}
+
+#[test]
+#[serial]
+fn parallel_rendering_keeps_chapter_order() {
+    init_logging();
+    let spine = |threads: usize| {
+        let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+        for i in 0..20 {
+            let name = format!("Extra {}", i);
+            let sub = Chapter::new("Sub", String::from("sub"), format!("extra_{}/sub.md", i), vec![name.clone()]);
+            let mut ch = Chapter::new(&name, String::from("extra"), format!("extra_{}.md", i), vec![]);
+            ch.sub_items.push(mdbook::BookItem::Chapter(sub));
+            ctx.book.push_item(ch);
+        }
+        ctx.config.set("output.epub.render-threads", threads).unwrap();
+        mdbook_epub::generate(&ctx).unwrap();
+
+        let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+        EpubDoc::new(&output_file).unwrap().spine
+    };
+
+    let serial = spine(1);
+    assert_eq!(serial, spine(4));
+    let first = serial.iter().position(|id| id == "extra_0_html").unwrap();
+    assert_eq!(serial[first + 1], "extra_0_sub_html");
+    assert_eq!(serial[first + 2], "extra_1_html");
+}
+
+#[test]
+#[serial]
+fn missing_resource_is_an_error() {
+    init_logging();
+    for resource in ["no-such-file.txt", "assets/.."] {
+        let (mut ctx, _md, _temp) = create_dummy_book().unwrap();
+        ctx.config.set("output.epub.additional-resources", vec![resource]).unwrap();
+
+        let err = mdbook_epub::generate(&ctx).unwrap_err();
+        assert!(err.to_string().contains(resource), "{}", err);
+    }
+}
+
+#[test]
+fn errors_are_std_errors() {
+    fn boxed(result: Result<(), Error>) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+        Ok(result?)
+    }
+
+    let io = std::io::Error::new(std::io::ErrorKind::PermissionDenied, "denied");
+    let err = boxed(Err(Error::ResourceOpen(PathBuf::from("font.ttf"), io))).unwrap_err();
+    assert_eq!(err.to_string(), "Unable to open additional resource font.ttf");
+    assert_eq!(err.source().unwrap().to_string(), "denied");
+
+    let io = std::io::Error::new(std::io::ErrorKind::NotFound, "gone");
+    let err = boxed(Err(Error::from(io))).unwrap_err();
+    assert_eq!(err.to_string(), "gone");
+}
+
+/// The dummy book with a chapter using an image and a clip which don't
+/// exist, under `missing_asset_policy`.
+fn book_with_missing_assets(policy: &str) -> (RenderContext, TempDir) {
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.version", "3.0").unwrap();
+    ctx.config.set("output.epub.missing-asset-policy", policy).unwrap();
+    let content = "# Missing\n\n![Gone](images/gone.png)\n\n<audio controls src=\"gone.mp3\"></audio>\n";
+    ctx.book.push_item(Chapter::new("Missing", content.to_string(), "guide/missing.md", vec![]));
+    (ctx, temp)
+}
+
+#[test]
+#[serial]
+fn missing_assets_fail_the_build_by_default() {
+    init_logging();
+    let (ctx, _temp) = book_with_missing_assets("fail");
+
+    let err = mdbook_epub::generate(&ctx).unwrap_err().to_string();
+    assert!(err.contains("gone.mp3 (used by guide/missing.md) doesn't exist"), "{}", err);
+}
+
+#[test]
+#[serial]
+fn missing_assets_can_be_left_out() {
+    init_logging();
+    let (ctx, temp) = book_with_missing_assets("warn");
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let chapter = doc.get_resource_str_by_path("OEBPS/guide/missing.html").unwrap();
+    assert!(chapter.contains(r#"src="images/gone.png""#), "{}", chapter);
+    assert!(doc.get_resource_by_path("OEBPS/guide/images/gone.png").is_err());
+    assert!(doc.get_resource_by_path("OEBPS/missing-image.png").is_err());
+}
+
+#[test]
+#[serial]
+fn missing_images_can_be_replaced_by_a_placeholder() {
+    init_logging();
+    let (ctx, temp) = book_with_missing_assets("placeholder");
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let chapter = doc.get_resource_str_by_path("OEBPS/guide/missing.html").unwrap();
+    assert!(chapter.contains(r#"<img src="../missing-image.png" alt="Gone" />"#), "{}", chapter);
+    // there's no stand-in for audio, which is left out
+    assert!(chapter.contains(r#"<audio controls src="gone.mp3"></audio>"#), "{}", chapter);
+    assert!(doc.get_resource_by_path("OEBPS/guide/gone.mp3").is_err());
+    let placeholder = doc.get_resource_by_path("OEBPS/missing-image.png").unwrap();
+    assert!(placeholder.starts_with(b"\x89PNG"));
+}
+
+#[test]
+#[serial]
+fn front_matter_class_is_applied_to_body() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    let content = String::from("---\nclass: code-heavy\n---\n# Styled\n");
+    ctx.book.push_item(Chapter::new("Styled", content, "styled.md", vec![]));
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let content = doc.get_resource_str_by_path("OEBPS/styled.html").unwrap();
+    assert!(content.contains(r#"<body class="code-heavy">"#), "{}", content);
+    assert!(!content.contains("class: code-heavy"), "{}", content);
+}
+
+#[test]
+#[serial]
+fn front_matter_lang_overrides_book_language() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.book.language = Some(String::from("en"));
+    let content = String::from("---\nlang: fr\n---\n# Bonjour\n");
+    ctx.book.push_item(Chapter::new("Bonjour", content, "bonjour.md", vec![]));
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let french = doc.get_resource_str_by_path("OEBPS/bonjour.html").unwrap();
+    assert!(french.contains(r#"xml:lang="fr" lang="fr""#), "{}", french);
+    let english = doc.get_resource_str_by_path("OEBPS/chapter_1.html").unwrap();
+    assert!(english.contains(r#"xml:lang="en" lang="en""#), "{}", english);
+
+    ctx.config.set("output.epub.single-file", true).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let book = doc.get_resource_str_by_path("OEBPS/book.html").unwrap();
+    assert!(book.contains(r#"id="chapter-bonjour" xml:lang="fr" lang="fr""#), "{}", book);
+    assert_eq!(book.matches(r#"xml:lang="fr""#).count(), 1, "{}", book);
+}
+
+#[test]
+#[serial]
+fn validation_passes_for_a_consistent_book() {
+    init_logging();
+    let (mut ctx, _md, _temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.validate", true).unwrap();
+
+    mdbook_epub::generate(&ctx).unwrap();
+}
+
+#[test]
+#[serial]
+fn validation_reports_unreferenced_resources() {
+    init_logging();
+    let (mut ctx, _md, _temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.validate", true).unwrap();
+    ctx.config.set("output.epub.additional-resources", vec!["reddit.svg"]).unwrap();
+
+    let err = mdbook_epub::generate(&ctx).unwrap_err();
+    assert!(err.to_string().contains("OEBPS/reddit.svg is never referenced"), "{}", err);
+}
+
+#[test]
+#[serial]
+fn output_filename_can_be_configured() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.output-filename", "mybook-v2.epub").unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    assert_eq!(output_file, temp.path().join("mybook-v2.epub"));
+    assert!(output_file.is_file());
+}
+
+#[test]
+#[serial]
+fn every_author_and_contributor_is_listed() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.book.authors = vec![String::from("Alice"), String::from("Bob")];
+    let contributors: toml::Value = toml::from_str(r#"list = [{ name = "Carol", role = "trl" }]"#).unwrap();
+    ctx.config.set("output.epub.contributors", &contributors["list"]).unwrap();
+    ctx.config.set("output.epub.version", "3.0").unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let opf = doc.get_resource_str_by_path("OEBPS/content.opf").unwrap();
+    assert!(opf.contains(r#"<dc:creator id="epub-creator-1">Alice</dc:creator>"#), "{}", opf);
+    assert!(opf.contains(r#"<dc:creator id="epub-creator-2">Bob</dc:creator>"#), "{}", opf);
+    assert!(opf.contains(r#"<dc:contributor id="epub-creator-3">Carol</dc:contributor>"#), "{}", opf);
+    assert!(opf.contains(r##"<meta refines="#epub-creator-3" property="role" scheme="marc:relators">trl</meta>"##), "{}", opf);
+}
+
+#[test]
+#[serial]
+fn front_matter_is_given_to_the_template() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    let template = temp.path().join("template.hbs");
+    std::fs::write(&template, "<html><body><p class=\"by\">{{ front_matter.author }}</p>{{{ body }}}</body></html>").unwrap();
+    ctx.config.set("output.epub.index-template", template).unwrap();
+    let content = String::from("+++\nauthor = \"Jane\"\n+++\n# With Front Matter\n");
+    ctx.book.push_item(Chapter::new("With Front Matter", content, "front.md", vec![]));
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let content = doc.get_resource_str_by_path("OEBPS/front.html").unwrap();
+    assert!(content.contains(r#"<p class="by">Jane</p>"#), "{}", content);
+    assert!(!content.contains("+++"), "{}", content);
+}
+
+#[test]
+#[serial]
+fn alerts_are_rendered_as_admonitions() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    let content = String::from("# Alerts\n\n> [!WARNING]\n> Hot!\n");
+    ctx.book.push_item(Chapter::new("Alerts", content, "alerts.md", vec![]));
+    ctx.config.set("output.epub.admonitions", true).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let content = doc.get_resource_str_by_path("OEBPS/alerts.html").unwrap();
+    // EPUB 2 has no <aside>
+    assert!(content.contains(r#"<div class="admonition warning">"#), "{}", content);
+}
+
+#[test]
+#[serial]
+fn broken_links_can_fail_the_build() {
+    init_logging();
+    let (mut ctx, _md, _temp) = create_dummy_book().unwrap();
+    let content = String::from("# Links\n\n[ok](chapter_1.md) [broken](no-such-chapter.md#intro)\n");
+    ctx.book.push_item(Chapter::new("Links", content, "links.md", vec![]));
+
+    // only a warning by default
+    mdbook_epub::generate(&ctx).unwrap();
+
+    ctx.config.set("output.epub.fail-on-broken-links", true).unwrap();
+    let err = mdbook_epub::generate(&ctx).unwrap_err().to_string();
+    assert!(err.contains("links.md links to no-such-chapter.md#intro"), "{}", err);
+    assert!(!err.contains("chapter_1.md"), "{}", err);
+}
+
+#[test]
+#[serial]
+fn inline_svg_survives_rendering() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    let svg = r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 10 10"><circle cx="5" cy="5" r="4" fill="red"/></svg>"#;
+    let content = format!("# Shapes\n\n{}\n", svg);
+    ctx.book.push_item(Chapter::new("Shapes", content, "shapes.md", vec![]));
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let content = doc.get_resource_str_by_path("OEBPS/shapes.html").unwrap();
+    assert!(content.contains(svg), "{}", content);
+}
+
+#[test]
+#[serial]
+fn svg_cover_gets_a_cover_page() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.cover-image", "reddit.svg").unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    assert_eq!(doc.spine[0], "cover_html");
+    assert_eq!(doc.resources["cover-image"].1, "image/svg+xml");
+    let content = doc.get_resource_str_by_path("OEBPS/cover.html").unwrap();
+    assert!(content.contains(r#"xlink:href="reddit.svg""#), "{}", content);
+}
+
+#[test]
+#[serial]
+fn nested_images_are_packaged_separately() {
+    init_logging();
+    let temp = TempDir::new("mdbook-epub").unwrap();
+    let src = temp.path().join("src");
+    for dir in ["a/images", "b/c/images"] {
+        std::fs::create_dir_all(src.join(dir)).unwrap();
+        std::fs::write(src.join(dir).join("logo.png"), dir).unwrap();
+    }
+    let mut book = mdbook::book::Book::new();
+    book.push_item(Chapter::new("A", String::from("![](images/logo.png)"), "a/chapter.md", vec![]));
+    book.push_item(Chapter::new("C", String::from("![](images/logo.png)"), "b/c/chapter.md", vec![]));
+
+    let output_file = temp.path().join("nested.epub");
+    Generator::from_book(&book, mdbook_epub::Config::default(), temp.path())
+        .unwrap()
+        .generate(File::create(&output_file).unwrap())
+        .unwrap();
+
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    assert_eq!(doc.get_resource_by_path("OEBPS/a/images/logo.png").unwrap(), b"a/images");
+    assert_eq!(doc.get_resource_by_path("OEBPS/b/c/images/logo.png").unwrap(), b"b/c/images");
+    let content = doc.get_resource_str_by_path("OEBPS/b/c/chapter.html").unwrap();
+    assert!(content.contains(r#"<img src="images/logo.png""#), "{}", content);
+}
+
+#[test]
+#[serial]
+fn copyright_page_follows_the_title_page() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    let copyright = temp.path().join("copyright.md");
+    std::fs::write(&copyright, "Copyright © 2023 Someone\n").unwrap();
+    ctx.config.set("output.epub.copyright-page", &copyright).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    assert_eq!(&doc.spine[..2], ["title_page_html", "copyright_html"]);
+    let content = doc.get_resource_str_by_path("OEBPS/copyright.html").unwrap();
+    assert!(content.contains("<p>Copyright © 2023 Someone</p>"), "{}", content);
+    let nav = doc.get_resource_str_by_path("OEBPS/toc.ncx").unwrap();
+    assert!(nav.contains("copyright.html"), "{}", nav);
+}
+
+#[test]
+#[serial]
+fn copyright_page_can_go_at_the_back_without_a_toc_entry() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    let copyright = temp.path().join("copyright.md");
+    std::fs::write(&copyright, "Copyright\n").unwrap();
+    ctx.config.set("output.epub.copyright-page", &copyright).unwrap();
+    ctx.config.set("output.epub.copyright-position", "back").unwrap();
+    ctx.config.set("output.epub.copyright-in-toc", false).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    assert_eq!(doc.spine.last().unwrap(), "copyright_html");
+    let nav = doc.get_resource_str_by_path("OEBPS/toc.ncx").unwrap();
+    assert!(!nav.contains("copyright.html"), "{}", nav);
+}
+
+#[test]
+#[serial]
+fn copyright_page_with_math_has_the_mathml_property() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    let copyright = temp.path().join("copyright.md");
+    std::fs::write(&copyright, "Copyright $x$ Someone\n").unwrap();
+    ctx.config.set("output.epub.copyright-page", &copyright).unwrap();
+    ctx.config.set("output.epub.math", true).unwrap();
+    ctx.config.set("output.epub.version", "3.0").unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let opf = doc.get_resource_str_by_path("OEBPS/content.opf").unwrap();
+    assert!(opf.contains(r#"href="copyright.html" properties="mathml""#), "{}", opf);
+}
+
+#[test]
+#[serial]
+fn missing_copyright_page_is_reported_by_path() {
+    init_logging();
+    let (mut ctx, _md, _temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.copyright-page", "no-such-page.md").unwrap();
+
+    let err = mdbook_epub::generate(&ctx).unwrap_err();
+    assert!(err.to_string().contains("no-such-page.md"), "{}", err);
+}
+
+#[test]
+#[serial]
+fn footnotes_can_go_at_the_chapter_end() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    let content = String::from("# Notes\n\nText[^1].\n\n[^1]: The note.\n\nAfter.\n");
+    ctx.book.push_item(Chapter::new("Notes", content, "notes.md", vec![]));
+    ctx.config.set("output.epub.footnotes", "chapter-end").unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let content = doc.get_resource_str_by_path("OEBPS/notes.html").unwrap();
+    let after = content.find("<p>After.</p>").unwrap();
+    let note = content.find(r#"<li id="notes-fn-1">"#).unwrap();
+    assert!(after < note, "{}", content);
+}
+
+#[test]
+#[serial]
+fn post_processors_see_the_rendered_chapters() {
+    init_logging();
+    let (ctx, _md, temp) = create_dummy_book().unwrap();
+    let output_file = temp.path().join("processed.epub");
+
+    Generator::new(&ctx)
+        .unwrap()
+        .with_post_processor(|ch: &Chapter, html: String| html.replace("</body>", &format!("<p>{} done</p></body>", ch.name)))
+        .with_post_processor(|_: &Chapter, html: String| html.replace("done", "processed"))
+        .generate(File::create(&output_file).unwrap())
+        .unwrap();
+
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let content = doc.get_resource_str_by_path("OEBPS/chapter_1.html").unwrap();
+    assert!(content.contains("<p>Chapter 1 processed</p></body>"), "{}", content);
+}
+
+
+/// Use `MDBook::load()` to load the dummy book into memory, then set up the
+/// `RenderContext` for use the EPUB generator.
+fn create_dummy_book() -> Result<(RenderContext, MDBook, TempDir), Error> {
+    let temp = TempDir::new("mdbook-epub")?;
+
+    let dummy_book = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("dummy");
+    debug!("dummy_book = {:?}", &dummy_book.display().to_string());
+
+    let md = MDBook::load(dummy_book);
+
+    let book = md.expect("dummy MDBook is not loaded");
+    let ctx = RenderContext::new(
+        book.root.clone(),
+        book.book.clone(),
+        book.config.clone(),
+        temp.path().to_path_buf(),
+    );
+
+    Ok((ctx, book, temp))
+}
+
+#[test]
+#[serial]
+fn series_defaults_to_the_first_book() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.series", "The Saga").unwrap();
+    ctx.config.set("output.epub.version", "3.0").unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let opf = doc.get_resource_str_by_path("OEBPS/content.opf").unwrap();
+    assert!(opf.contains(r#"<meta name="calibre:series" content="The Saga"/>"#), "{}", opf);
+    assert!(opf.contains(r#"<meta name="calibre:series_index" content="1"/>"#), "{}", opf);
+    assert!(opf.contains(r#"<meta property="belongs-to-collection" id="epub-collection-1">The Saga</meta>"#), "{}", opf);
+    assert!(opf.contains(r##"<meta refines="#epub-collection-1" property="group-position">1</meta>"##), "{}", opf);
+}
+
+#[test]
+#[serial]
+fn raster_cover_gets_a_cover_page_with_a_title() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.cover-image", "rust-logo.png").unwrap();
+    ctx.config.set("output.epub.cover-title", "Rust & Friends").unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    assert_eq!(doc.spine[0], "cover_html");
+    let content = doc.get_resource_str_by_path("OEBPS/cover.html").unwrap();
+    assert!(content.contains(r#"<img src="rust-logo.png" alt="Cover"/>"#), "{}", content);
+    assert!(content.contains(r#"<p class="cover-title">Rust &amp; Friends</p>"#), "{}", content);
+}
+
+#[test]
+#[serial]
+fn sized_cover_page_is_fixed_layout() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.version", "3.0").unwrap();
+    ctx.config.set("output.epub.cover-image", "rust-logo.png").unwrap();
+    ctx.config.set("output.epub.cover-width", 1600).unwrap();
+    ctx.config.set("output.epub.cover-height", 2400).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let content = doc.get_resource_str_by_path("OEBPS/cover.html").unwrap();
+    assert!(content.contains(r#"<meta name="viewport" content="width=1600, height=2400"/>"#), "{}", content);
+    assert!(content.contains(r#"<img src="rust-logo.png" width="1600" height="2400" alt="Cover"/>"#), "{}", content);
+    let opf = doc.get_resource_str_by_path("OEBPS/content.opf").unwrap();
+    assert!(opf.contains(r#"<itemref idref="cover_html" properties="rendition:layout-pre-paginated"/>"#), "{}", opf);
+    assert_eq!(opf.matches("rendition:").count(), 1, "{}", opf);
+    let chapter = doc.get_resource_str_by_path("OEBPS/chapter_1.html").unwrap();
+    assert!(!chapter.contains("viewport"), "{}", chapter);
+
+    epub_check(&output_file).unwrap();
+}
+
+#[test]
+#[serial]
+fn cover_dimensions_are_set_together() {
+    init_logging();
+    let (mut ctx, _md, _temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.cover-width", 1600).unwrap();
+
+    let err = mdbook_epub::generate(&ctx).unwrap_err();
+
+    assert!(matches!(err, Error::InvalidCoverDimensions), "{}", err);
+}
+
+#[test]
+#[serial]
+fn raster_cover_page_can_be_disabled() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.cover-image", "rust-logo.png").unwrap();
+    ctx.config.set("output.epub.cover-page", false).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    assert!(!doc.resources.contains_key("cover_html"));
+    assert_eq!(doc.resources["cover-image"].1, "image/png");
+    let opf = doc.get_resource_str_by_path("OEBPS/content.opf").unwrap();
+    assert!(opf.contains(r#"<reference type="cover" title="Cover" href="rust-logo.png"/>"#), "{}", opf);
+}
+
+#[test]
+#[serial]
+fn cover_page_is_a_landmark() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.version", "3.0").unwrap();
+    ctx.config.set("output.epub.cover-image", "rust-logo.png").unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let opf = doc.get_resource_str_by_path("OEBPS/content.opf").unwrap();
+    assert!(opf.contains(r#"<reference type="cover" title="" href="cover.html"/>"#), "{}", opf);
+    assert_eq!(opf.matches("<reference type=\"cover\"").count(), 1, "{}", opf);
+    let nav = doc.get_resource_str_by_path("OEBPS/nav.xhtml").unwrap();
+    assert!(nav.contains(r#"<li><a epub:type="cover" href="cover.html">Cover</a></li>"#), "{}", nav);
+}
+
+#[test]
+#[serial]
+fn unchanged_chapters_are_taken_from_the_cache() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    let cache_dir = temp.path().join("render-cache");
+    ctx.config.set("output.epub.cache-dir", &cache_dir).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    // swap the cached chapter out so we can tell whether it's reused
+    let entry = std::fs::read_dir(&cache_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().is_some_and(|ext| ext == "xhtml"))
+        .unwrap();
+    std::fs::write(&entry, "<p>from the cache</p>").unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let content = doc.get_resource_str_by_path("OEBPS/chapter_1.html").unwrap();
+    assert_eq!(content, "<p>from the cache</p>");
+
+    // changing the config throws the whole cache away
+    ctx.config.set("output.epub.curly-quotes", false).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let content = doc.get_resource_str_by_path("OEBPS/chapter_1.html").unwrap();
+    assert!(content.contains("<h1 id=\"chapter-1\">"), "{}", content);
+}
+
+#[test]
+#[serial]
+fn css_theme_is_added_to_the_default_stylesheet() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.css-theme", "dark").unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let stylesheet = doc.get_resource_str_by_path("OEBPS/stylesheet.css").unwrap();
+    assert!(stylesheet.starts_with(mdbook_epub::DEFAULT_CSS));
+    assert!(stylesheet.contains(mdbook_epub::DARK_CSS));
+    assert!(!stylesheet.contains(mdbook_epub::SEPIA_CSS));
+
+    ctx.config.set("output.epub.use-default-css", false).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let stylesheet = doc.get_resource_str_by_path("OEBPS/stylesheet.css").unwrap_or_default();
+    assert!(!stylesheet.contains(mdbook_epub::DARK_CSS));
+}
+
+#[test]
+#[serial]
+fn excluded_chapters_are_left_out() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    let mut part = Chapter::new("Part", String::from("# Part\n"), "part/index.md", vec![]);
+    part.sub_items.push(BookItem::Chapter(Chapter::new(
+        "Sample",
+        String::from("# Sample\n"),
+        "part/sample.md",
+        vec![String::from("Part")],
+    )));
+    ctx.book.push_item(part);
+    let excluded: toml::Value = toml::from_str(r#"list = ["part/*"]"#).unwrap();
+    ctx.config.set("output.epub.exclude-chapters", &excluded["list"]).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    assert!(doc.get_resource_by_path("OEBPS/chapter_1.html").is_ok());
+    assert!(doc.get_resource_by_path("OEBPS/part/sample.html").is_err());
+    assert!(doc.get_resource_by_path("OEBPS/part/index.html").is_err());
+    let toc = doc.get_resource_str_by_path("OEBPS/toc.html").unwrap();
+    assert!(!toc.contains("Part"), "{}", toc);
+}
+
+#[test]
+#[serial]
+fn included_sub_chapters_survive_an_excluded_parent() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    let mut part = Chapter::new("Part", String::from("# Part\n"), "part/index.md", vec![]);
+    part.sub_items.push(BookItem::Chapter(Chapter::new(
+        "Sample",
+        String::from("# Sample\n"),
+        "part/sample.md",
+        vec![String::from("Part")],
+    )));
+    ctx.book.push_item(part);
+    let excluded: toml::Value = toml::from_str(r#"list = ["part/index.md"]"#).unwrap();
+    let included: toml::Value = toml::from_str(r#"list = ["chapter_1.md", "part/sample.md"]"#).unwrap();
+    ctx.config.set("output.epub.exclude-chapters", &excluded["list"]).unwrap();
+    ctx.config.set("output.epub.include-chapters", &included["list"]).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    assert!(doc.get_resource_by_path("OEBPS/chapter_1.html").is_ok());
+    assert!(doc.get_resource_by_path("OEBPS/part/sample.html").is_ok());
+    assert!(doc.get_resource_by_path("OEBPS/part/index.html").is_err());
+    let toc = doc.get_resource_str_by_path("OEBPS/toc.html").unwrap();
+    assert!(toc.contains(r#"<a href="part/sample.html">"#), "{}", toc);
+    assert!(!toc.contains("part/index.html"), "{}", toc);
+}
+
+#[test]
+#[serial]
+fn publication_and_modification_dates_are_separate() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.version", "3.0").unwrap();
+    ctx.config.set("output.epub.pub-date", "2023-02-08").unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let opf = doc.get_resource_str_by_path("OEBPS/content.opf").unwrap();
+    assert!(opf.contains("<dc:date>2023-02-08</dc:date>"), "{}", opf);
+    assert_eq!(opf.matches("dcterms:modified").count(), 1, "{}", opf);
+
+    let needle = r#"<meta property="dcterms:modified">"#;
+    let start = opf.find(needle).unwrap() + needle.len();
+    let modified = &opf[start..start + opf[start..].find('<').unwrap()];
+    assert_eq!(modified.len(), "2023-02-08T10:20:30Z".len(), "{}", modified);
+    assert!(modified.ends_with('Z'), "{}", modified);
+    assert!(!modified.starts_with("2023-02-08"), "{}", modified);
+}
+
+#[test]
+#[serial]
+fn single_file_puts_every_chapter_in_one_document() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    let content = String::from("# Part One\n\n[back](../chapter_1.md) [their usage](two.md#usage)\n\n## Usage\n");
+    ctx.book.push_item(Chapter::new("Part One", content, "part/one.md", vec![]));
+    ctx.book.push_item(Chapter::new("Part Two", String::from("# Part Two\n\n## Usage\n"), "part/two.md", vec![]));
+    ctx.config.set("output.epub.single-file", true).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    assert!(doc.spine.contains(&String::from("book_html")), "{:?}", doc.spine);
+    assert!(!doc.resources.contains_key("chapter_1_html"));
+
+    let content = doc.get_resource_str_by_path("OEBPS/book.html").unwrap();
+    assert!(content.contains(r#"<div class="chapter" id="chapter-chapter-1""#), "{}", content);
+    assert!(content.contains(r#"<div class="chapter" id="chapter-part-one""#), "{}", content);
+    assert!(content.contains(r##"<a href="#chapter-chapter-1">back</a>"##), "{}", content);
+    // headings' ids start with their section's, so each is only used once
+    assert!(content.contains(r#"<h2 id="chapter-part-one-usage">"#), "{}", content);
+    assert!(content.contains(r#"<h2 id="chapter-part-two-usage">"#), "{}", content);
+    assert!(content.contains(r##"<a href="#chapter-part-two-usage">their usage</a>"##), "{}", content);
+
+    let ncx = doc.get_resource_str_by_path("OEBPS/toc.ncx").unwrap();
+    assert!(ncx.contains(r#"src="book.html#chapter-part-one""#), "{}", ncx);
+    let toc = doc.get_resource_str_by_path("OEBPS/toc.html").unwrap();
+    assert!(toc.contains(r##"href="book.html#chapter-part-one""##), "{}", toc);
+}
+
+#[test]
+#[serial]
+fn template_is_told_where_the_chapter_is() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    let template = temp.path().join("template.hbs");
+    std::fs::write(
+        &template,
+        "<html><body><p class=\"nav\">{{ chapter_number }}|{{ chapter_path }}|{{ prev_title }}|{{ next_title }}</p>{{{ body }}}</body></html>",
+    )
+    .unwrap();
+    ctx.config.set("output.epub.index-template", template).unwrap();
+    let mut ch = Chapter::new("Second", String::from("# Second\n"), "part/second.md", vec![]);
+    ch.number = Some(mdbook::book::SectionNumber(vec![2, 1]));
+    ctx.book.push_item(ch);
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let first = doc.get_resource_str_by_path("OEBPS/chapter_1.html").unwrap();
+    assert!(first.contains(r#"<p class="nav">1|chapter_1.md||Second</p>"#), "{}", first);
+    let second = doc.get_resource_str_by_path("OEBPS/part/second.html").unwrap();
+    assert!(second.contains(r#"<p class="nav">2.1|part/second.md|Chapter 1|</p>"#), "{}", second);
+}
+
+#[test]
+#[serial]
+fn template_assets_are_packaged_and_given_to_the_template() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    let template_dir = temp.path().join("epub");
+    std::fs::create_dir_all(&template_dir).unwrap();
+    std::fs::copy(ctx.root.join("src").join("rust-logo.png"), template_dir.join("logo.png")).unwrap();
+    std::fs::create_dir_all(template_dir.join("dark")).unwrap();
+    std::fs::write(template_dir.join("dark").join("logo.png"), "dark").unwrap();
+    let template = template_dir.join("template.hbs");
+    std::fs::write(&template, "<html><body><img src=\"{{ logo }}\" alt=\"\"/>{{{ body }}}</body></html>").unwrap();
+    ctx.config.set("output.epub.index-template", template).unwrap();
+    ctx.config.set("output.epub.template-assets", vec!["logo.png", "dark/logo.png"]).unwrap();
+    let mut ch = Chapter::new("Second", String::from("# Second\n"), "part/second.md", vec![]);
+    ch.number = Some(mdbook::book::SectionNumber(vec![2]));
+    ctx.book.push_item(ch);
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    assert!(doc.get_resource_by_path("OEBPS/template/logo.png").is_ok());
+    // assets with the same name are kept apart, and templates get the first
+    assert_eq!(doc.get_resource_by_path("OEBPS/template/dark/logo.png").unwrap(), b"dark");
+    let first = doc.get_resource_str_by_path("OEBPS/chapter_1.html").unwrap();
+    assert!(first.contains(r#"<img src="template/logo.png" alt=""/>"#), "{}", first);
+    let second = doc.get_resource_str_by_path("OEBPS/part/second.html").unwrap();
+    assert!(second.contains(r#"<img src="../template/logo.png" alt=""/>"#), "{}", second);
+}
+
+#[test]
+#[serial]
+fn missing_template_asset_is_reported_by_path() {
+    init_logging();
+    let (mut ctx, _md, _temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.template-assets", vec!["logo.png"]).unwrap();
+
+    let error = mdbook_epub::generate(&ctx).unwrap_err().to_string();
+    assert!(error.contains("Unable to find template asset"), "{}", error);
+    assert!(error.contains("logo.png"), "{}", error);
+}
+
+#[test]
+#[serial]
+fn webp_images_are_packaged_with_their_mimetype() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.book.push_item(Chapter::new("Photos", String::from("# Photos\n\n![Pixel](pixel.webp)\n"), "photos.md", vec![]));
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    assert_eq!(doc.get_resource_mime_by_path("OEBPS/pixel.webp").unwrap(), "image/webp");
+    let opf = doc.get_resource_str_by_path("OEBPS/content.opf").unwrap();
+    assert!(opf.contains(r#"<item media-type="image/webp" id="pixel_webp" href="pixel.webp"/>"#), "{}", opf);
+}
+
+#[cfg(unix)]
+#[test]
+#[serial]
+fn transcoded_images_are_packaged_as_png() {
+    use std::os::unix::fs::PermissionsExt;
+
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    // stands in for ImageMagick, "converting" by copying
+    let bin = temp.path().join("bin");
+    std::fs::create_dir_all(&bin).unwrap();
+    std::fs::write(bin.join("convert"), "#!/bin/sh\ncp \"$1\" \"$2\"\n").unwrap();
+    std::fs::set_permissions(bin.join("convert"), std::fs::Permissions::from_mode(0o755)).unwrap();
+    let path = env::var_os("PATH").unwrap_or_default();
+    let mut paths = vec![bin];
+    paths.extend(env::split_paths(&path));
+    env::set_var("PATH", env::join_paths(paths).unwrap());
+
+    ctx.config.set("output.epub.version", "2.0").unwrap();
+    ctx.config.set("output.epub.transcode-unsupported-images", true).unwrap();
+    let content = String::from("# Photos\n\n![Pixel](../pixel.webp)\n\n<img alt=\"pixel\" src=\"/pixel.webp\"/>\n");
+    ctx.book.push_item(Chapter::new("Photos", content, "guide/photos.md", vec![]));
+    let generated = mdbook_epub::generate(&ctx);
+    env::set_var("PATH", path);
+    generated.unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    assert_eq!(doc.get_resource_mime_by_path("OEBPS/pixel.png").unwrap(), "image/png");
+    assert!(doc.get_resource_by_path("OEBPS/pixel.webp").is_err());
+    let chapter = doc.get_resource_str_by_path("OEBPS/guide/photos.html").unwrap();
+    assert!(chapter.contains(r#"src="../pixel.png" alt="Pixel""#), "{}", chapter);
+    assert!(chapter.contains(r#"<img alt="pixel" src="../pixel.png"/>"#), "{}", chapter);
+}
+
+#[test]
+#[serial]
+fn progress_is_reported_for_chapters_and_assets() {
+    #[derive(Default)]
+    struct Recorder {
+        chapters: std::sync::Mutex<Vec<(String, usize, usize)>>,
+        assets: std::sync::Mutex<Vec<String>>,
+    }
+
+    impl ProgressReporter for &Recorder {
+        fn chapter_started(&self, name: &str, index: usize, total: usize) {
+            self.chapters.lock().unwrap().push((name.to_string(), index, total));
+        }
+
+        fn asset_embedded(&self, name: &str) {
+            self.assets.lock().unwrap().push(name.to_string());
+        }
+    }
+
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.book.push_item(Chapter::new("Second", String::from("# Second\n"), "second.md", vec![]));
+    let recorder = Recorder::default();
+    Generator::new(&ctx)
+        .unwrap()
+        .with_progress_reporter(&recorder)
+        .generate(File::create(temp.path().join("progress.epub")).unwrap())
+        .unwrap();
+
+    let mut chapters = recorder.chapters.into_inner().unwrap();
+    chapters.sort_by_key(|&(_, index, _)| index);
+    assert_eq!(
+        chapters,
+        vec![(String::from("Chapter 1"), 0, 2), (String::from("Second"), 1, 2)]
+    );
+    let assets = recorder.assets.into_inner().unwrap();
+    assert!(assets.contains(&String::from("rust-logo.png")), "{:?}", assets);
+}
+
+#[test]
+#[serial]
+fn custom_metadata_is_passed_through() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.metadata.ibooks:specified-fonts", true).unwrap();
+    ctx.config.set("output.epub.metadata.title", "Another Title").unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let opf = doc.get_resource_str_by_path("OEBPS/content.opf").unwrap();
+    assert!(opf.contains(r#"<meta name="ibooks:specified-fonts" content="true"/>"#), "{}", opf);
+    assert_eq!(doc.mdata("title").unwrap(), "Another Title");
+}
+
+#[test]
+#[serial]
+fn scripts_are_stripped_from_chapters() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    let content = "# Scripted\n\n<script>\ndocument.write(\"hi\");\n</script>\n\n<p class=\"note\" onclick=\"steal()\">Kept</p>\n";
+    ctx.book.push_item(Chapter::new("Scripted", String::from(content), "scripted.md", vec![]));
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let chapter = doc.get_resource_str_by_path("OEBPS/scripted.html").unwrap();
+    assert!(!chapter.contains("<script"), "{}", chapter);
+    assert!(!chapter.contains("document.write"), "{}", chapter);
+    assert!(chapter.contains(r#"<p class="note">Kept</p>"#), "{}", chapter);
+}
+
+fn book_with_a_part() -> (RenderContext, MDBook, TempDir) {
+    let (mut ctx, md, temp) = create_dummy_book().unwrap();
+    ctx.book.push_item(BookItem::PartTitle(String::from("Advanced Topics")));
+    let mut ch = Chapter::new("Lifetimes", String::from("# Lifetimes\n"), "lifetimes.md", vec![]);
+    ch.number = Some(mdbook::book::SectionNumber(vec![2]));
+    ctx.book.push_item(ch);
+    (ctx, md, temp)
+}
+
+#[test]
+#[serial]
+fn epub2_ncx_follows_the_chapter_hierarchy() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.version", "2.0").unwrap();
+    let mut guide = Chapter::new("Guide", String::from("# Guide\n"), "guide.md", vec![]);
+    guide.number = Some(mdbook::book::SectionNumber(vec![2]));
+    let mut setup = Chapter::new("Setup", String::from("# Setup\n"), "setup.md", vec![]);
+    setup.number = Some(mdbook::book::SectionNumber(vec![2, 1]));
+    guide.sub_items.push(BookItem::Chapter(setup));
+    ctx.book.push_item(guide);
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let opf = doc.get_resource_str_by_path("OEBPS/content.opf").unwrap();
+    assert!(opf.contains(r#"<spine toc="ncx">"#), "{}", opf);
+    let ncx = doc.get_resource_str_by_path("OEBPS/toc.ncx").unwrap();
+    assert!(ncx.contains(r#"<meta name="dtb:depth" content="2" />"#), "{}", ncx);
+    let chapter_1 = ncx.find("<content src=\"chapter_1.html\"/>").expect("chapter 1 is in the NCX");
+    let guide = ncx.find("<content src=\"guide.html\"/>").expect("the guide is in the NCX");
+    let setup = ncx.find("<content src=\"setup.html\"/>").expect("setup is in the NCX");
+    assert!(chapter_1 < guide && guide < setup, "{}", ncx);
+    // chapter 1 is closed before the guide, and setup is nested in the guide
+    assert!(ncx[chapter_1..guide].contains("</navPoint>"), "{}", ncx);
+    assert!(!ncx[guide..setup].contains("</navPoint>"), "{}", ncx);
+    // the contents page, chapter 1, the guide and setup
+    assert_eq!(ncx.matches("<navPoint ").count(), 4, "{}", ncx);
+    assert!(ncx.contains("<text>2.1. Setup</text>"), "{}", ncx);
+
+    epub_check(&output_file).unwrap();
+}
+
+#[test]
+#[serial]
+fn spine_order_moves_chapters_without_changing_the_navigation() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.book.push_item(Chapter::new("Appendix", String::from("# Appendix\n"), "appendix.md", vec![]));
+    ctx.book.push_item(Chapter::new("Preface", String::from("# Preface\n"), "preface.md", vec![]));
+    ctx.config
+        .set("output.epub.spine-order", vec!["preface.md", "missing.md"])
+        .unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let opf = doc.get_resource_str_by_path("OEBPS/content.opf").unwrap();
+    let spine = |id: &str| opf.find(&format!("<itemref idref=\"{}\"", id)).expect(id);
+    assert!(spine("toc_html") < spine("preface_html"), "{}", opf);
+    assert!(spine("preface_html") < spine("chapter_1_html"), "{}", opf);
+    assert!(spine("chapter_1_html") < spine("appendix_html"), "{}", opf);
+
+    let ncx = doc.get_resource_str_by_path("OEBPS/toc.ncx").unwrap();
+    let nav = |href: &str| ncx.find(&format!("<content src=\"{}\"/>", href)).expect(href);
+    assert!(nav("chapter_1.html") < nav("appendix.html"), "{}", ncx);
+    assert!(nav("appendix.html") < nav("preface.html"), "{}", ncx);
+}
+
+#[test]
+#[serial]
+fn chapter_titles_follow_the_template() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config
+        .set("output.epub.chapter-title-template", "Ch. {{number}} — {{name}}")
+        .unwrap();
+    let mut setup = Chapter::new("Setup & Use", String::from("# Setup\n"), "setup.md", vec![]);
+    setup.number = Some(mdbook::book::SectionNumber(vec![2, 1]));
+    ctx.book.push_item(setup);
+    ctx.book.push_item(Chapter::new("Unnumbered", String::from("# Unnumbered\n"), "unnumbered.md", vec![]));
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let ncx = doc.get_resource_str_by_path("OEBPS/toc.ncx").unwrap();
+    assert!(ncx.contains("<text>Ch. 1 — Chapter 1</text>"), "{}", ncx);
+    assert!(ncx.contains("<text>Ch. 2.1 — Setup &amp; Use</text>"), "{}", ncx);
+    assert!(ncx.contains("<text>Unnumbered</text>"), "{}", ncx);
+    let toc = doc.get_resource_str_by_path("OEBPS/toc.html").unwrap();
+    assert!(toc.contains("Ch. 2.1 — Setup &amp; Use</a>"), "{}", toc);
+}
+
+#[test]
+#[serial]
+fn part_titles_become_navigation_headings() {
+    init_logging();
+    let (ctx, _md, temp) = book_with_a_part();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    assert!(!doc.resources.contains_key("part_1_html"));
+    let ncx = doc.get_resource_str_by_path("OEBPS/toc.ncx").unwrap();
+    let part = ncx.find("Advanced Topics").expect("the part is in the navigation");
+    let chapter = ncx.find("Lifetimes").unwrap();
+    assert!(part < chapter, "{}", ncx);
+    // the part's chapter is nested in the part's entry
+    assert!(!ncx[part..chapter].contains("</navPoint>"), "{}", ncx);
+    let toc = doc.get_resource_str_by_path("OEBPS/toc.html").unwrap();
+    assert!(toc.contains("<li class=\"part\">Advanced Topics\n<ol class=\"toc\">"), "{}", toc);
+}
+
+#[test]
+#[serial]
+fn part_titles_can_get_their_own_pages() {
+    init_logging();
+    let (mut ctx, _md, temp) = book_with_a_part();
+    ctx.config.set("output.epub.emit-part-pages", true).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let page = doc.get_resource_str_by_path("OEBPS/part_1.html").unwrap();
+    assert!(page.contains(r#"<h1 class="part-title">Advanced Topics</h1>"#), "{}", page);
+    let part = doc.spine.iter().position(|id| id == "part_1_html").unwrap();
+    assert_eq!(doc.spine[part + 1], "lifetimes_html");
+    let toc = doc.get_resource_str_by_path("OEBPS/toc.html").unwrap();
+    assert!(toc.contains(r#"<li class="part"><a href="part_1.html">Advanced Topics</a>"#), "{}", toc);
+}
+
+#[test]
+#[serial]
+fn figures_are_numbered_across_chapters() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.number-figures", true).unwrap();
+    ctx.config.set("output.epub.version", "3.0").unwrap();
+    let content = "# Logos\n\n![The Rust logo](rust-logo.png)\n\nText\n\n![The logo again](rust-logo.svg)\n";
+    ctx.book.push_item(Chapter::new("Logos", String::from(content), "logos.md", vec![]));
+    let content = "# More\n\n![One more](rust-logo.png)\n";
+    ctx.book.push_item(Chapter::new("More", String::from(content), "more.md", vec![]));
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let intro = doc.get_resource_str_by_path("OEBPS/chapter_1.html").unwrap();
+    assert!(intro.contains(r#"<figcaption class="figcaption">Figure 1. Rust Logo</figcaption>"#), "{}", intro);
+    let first = doc.get_resource_str_by_path("OEBPS/logos.html").unwrap();
+    assert!(first.contains(r#"<figcaption class="figcaption">Figure 2. The Rust logo</figcaption>"#), "{}", first);
+    assert!(first.contains(r#"<figcaption class="figcaption">Figure 3. The logo again</figcaption>"#), "{}", first);
+    let second = doc.get_resource_str_by_path("OEBPS/more.html").unwrap();
+    assert!(second.contains(r#"<figure class="figure" id="figure-4">"#), "{}", second);
+    assert!(second.contains(r#"<figcaption class="figcaption">Figure 4. One more</figcaption>"#), "{}", second);
+}
+
+#[test]
+#[serial]
+fn root_relative_images_are_packaged_for_nested_chapters() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    let content = "# Nested\n\n![Reddit](/reddit.svg)\n\n![Remote](//example.com/logo.png)\n";
+    ctx.book.push_item(Chapter::new("Nested", String::from(content), "guide/nested.md", vec![]));
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    assert!(doc.get_resource_by_path("OEBPS/reddit.svg").is_ok());
+    let chapter = doc.get_resource_str_by_path("OEBPS/guide/nested.html").unwrap();
+    assert!(chapter.contains(r#"<img src="../reddit.svg" alt="Reddit" />"#), "{}", chapter);
+    assert!(chapter.contains(r#"<img src="//example.com/logo.png" alt="Remote" />"#), "{}", chapter);
+}
+
+#[test]
+#[serial]
+fn rtl_books_turn_pages_right_to_left() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.version", "3.0").unwrap();
+    ctx.config.book.language = Some(String::from("ar"));
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let opf = doc.get_resource_str_by_path("OEBPS/content.opf").unwrap();
+    assert!(opf.contains(r#"<spine page-progression-direction="rtl""#), "{}", opf);
+    let chapter = doc.get_resource_str_by_path("OEBPS/chapter_1.html").unwrap();
+    assert!(chapter.contains(r#"lang="ar" dir="rtl">"#), "{}", chapter);
+}
+
+#[test]
+#[serial]
+fn ltr_books_leave_the_direction_to_the_reader() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.version", "3.0").unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let opf = doc.get_resource_str_by_path("OEBPS/content.opf").unwrap();
+    assert!(!opf.contains("page-progression-direction"), "{}", opf);
+    let chapter = doc.get_resource_str_by_path("OEBPS/chapter_1.html").unwrap();
+    assert!(!chapter.contains("dir="), "{}", chapter);
+}
+
+#[test]
+#[serial]
+fn identical_images_are_packaged_once() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    let content = "# Copies\n\n![Reddit](reddit.svg)\n\n![Copy](reddit_copy.svg)\n\n<img src=\"reddit_copy.svg\"/>\n";
+    ctx.book.push_item(Chapter::new("Copies", String::from(content), "copies.md", vec![]));
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    assert!(doc.get_resource_by_path("OEBPS/reddit.svg").is_ok());
+    assert!(doc.get_resource_by_path("OEBPS/reddit_copy.svg").is_err());
+    let chapter = doc.get_resource_str_by_path("OEBPS/copies.html").unwrap();
+    assert!(chapter.contains(r#"<img src="reddit.svg" alt="Copy" />"#), "{}", chapter);
+    assert!(chapter.contains(r#"<img src="reddit.svg"/>"#), "{}", chapter);
+}
+
+#[test]
+#[serial]
+fn identical_images_can_be_kept_apart() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.dedup-assets", false).unwrap();
+    let content = "# Copies\n\n![Copy](reddit_copy.svg)\n";
+    ctx.book.push_item(Chapter::new("Copies", String::from(content), "copies.md", vec![]));
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    assert!(doc.get_resource_by_path("OEBPS/reddit_copy.svg").is_ok());
+    let chapter = doc.get_resource_str_by_path("OEBPS/copies.html").unwrap();
+    assert!(chapter.contains(r#"<img src="reddit_copy.svg" alt="Copy" />"#), "{}", chapter);
+}
+
+#[test]
+#[serial]
+fn isbn_identifiers_are_marked_with_their_scheme() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.identifier-scheme", "isbn").unwrap();
+    ctx.config.set("output.epub.identifier", "978-0-306-40615-7").unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let opf = doc.get_resource_str_by_path("OEBPS/content.opf").unwrap();
+    assert!(
+        opf.contains(r#"<dc:identifier opf:scheme="ISBN" id="epub-id-1">urn:isbn:9780306406157</dc:identifier>"#),
+        "{}",
+        opf
+    );
+
+    ctx.config.set("output.epub.version", "3.0").unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let opf = doc.get_resource_str_by_path("OEBPS/content.opf").unwrap();
+    assert!(opf.contains(r#"<dc:identifier id="epub-id-1">urn:isbn:9780306406157</dc:identifier>"#), "{}", opf);
+    assert!(
+        opf.contains(r##"<meta refines="#epub-id-1" property="identifier-type" scheme="onix:codelist5">15</meta>"##),
+        "{}",
+        opf
+    );
+}
+
+#[test]
+#[serial]
+fn malformed_isbns_are_rejected() {
+    init_logging();
+    let (mut ctx, _md, _temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.identifier-scheme", "isbn").unwrap();
+    ctx.config.set("output.epub.identifier", "978-0-306-40615-8").unwrap();
+
+    let err = mdbook_epub::generate(&ctx).unwrap_err();
+
+    assert!(matches!(err, Error::InvalidIsbn(_)), "{}", err);
+}
+
+#[test]
+#[serial]
+fn index_lists_marked_terms() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.generate-index", true).unwrap();
+    let content = "# Ownership\n\n<!-- index: ownership -->\nSee the borrow checker<!-- index: Borrow checker -->.\n";
+    ctx.book.push_item(Chapter::new("Ownership", String::from(content), "ownership.md", vec![]));
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let chapter = doc.get_resource_str_by_path("OEBPS/ownership.html").unwrap();
+    assert!(chapter.contains(r#"<div class="index-marker" id="index-ownership-1"></div>"#), "{}", chapter);
+    assert!(chapter.contains(r#"<span class="index-marker" id="index-ownership-2"></span>"#), "{}", chapter);
+    let index = doc.get_resource_str_by_path("OEBPS/book_index.html").unwrap();
+    assert!(index.contains(r#"<h2 class="index-letter">B</h2>"#), "{}", index);
+    assert!(
+        index.contains(r#"<li>Borrow checker, <a href="ownership.html#index-ownership-2">Ownership</a></li>"#),
+        "{}",
+        index
+    );
+    let b = index.find("Borrow checker").unwrap();
+    let o = index.find("<li>ownership").unwrap();
+    assert!(b < o, "{}", index);
+    assert_eq!(doc.spine.last().map(String::as_str), Some("book_index_html"));
+}
+
+#[test]
+#[serial]
+fn markdown_options_choose_the_extensions() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config
+        .set("output.epub.markdown-options", vec!["tables", "heading-attributes"])
+        .unwrap();
+    let content = "# Setup {#getting-started .appendix}\n\nSee the note[^1].\n\n[^1]: A note.\n";
+    ctx.book.push_item(Chapter::new("Setup", String::from(content), "setup.md", vec![]));
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let chapter = doc.get_resource_str_by_path("OEBPS/setup.html").unwrap();
+    assert!(chapter.contains(r#"<h1 id="getting-started" class="appendix">Setup</h1>"#), "{}", chapter);
+    assert!(!chapter.contains("footnote"), "{}", chapter);
+}
+
+#[test]
+#[serial]
+fn accessibility_metadata_is_emitted() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.version", "3.0").unwrap();
+    ctx.config
+        .set("output.epub.accessibility.summary", "Images have alt text.")
+        .unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let opf = doc.get_resource_str_by_path("OEBPS/content.opf").unwrap();
+    // the dummy book has images
+    for meta in &[
+        r#"<meta property="schema:accessMode">textual</meta>"#,
+        r#"<meta property="schema:accessMode">visual</meta>"#,
+        r#"<meta property="schema:accessModeSufficient">textual,visual</meta>"#,
+        r#"<meta property="schema:accessibilityFeature">tableOfContents</meta>"#,
+        r#"<meta property="schema:accessibilitySummary">Images have alt text.</meta>"#,
+    ] {
+        assert!(opf.contains(meta), "{} not in {}", meta, opf);
+    }
+}
+
+#[test]
+#[serial]
+fn chapters_can_have_their_own_stylesheet() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    let stylesheets: toml::Value = toml::from_str(r#""chapter_1.md" = "appendix.css""#).unwrap();
+    ctx.config.set("output.epub.chapter-stylesheets", stylesheets).unwrap();
+    let content = "---\nstylesheet: appendix.css\n---\n# Appendix\n";
+    ctx.book.push_item(Chapter::new("Appendix", String::from(content), "appendix.md", vec![]));
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    assert_eq!(doc.get_resource_mime_by_path("OEBPS/appendix.css").unwrap(), "text/css");
+    for chapter in &["OEBPS/chapter_1.html", "OEBPS/appendix.html"] {
+        let chapter = doc.get_resource_str_by_path(chapter).unwrap();
+        let base = chapter.find(r#"<link rel="stylesheet" href="stylesheet.css" />"#);
+        let own = chapter.find(r#"<link rel="stylesheet" href="appendix.css" />"#);
+        assert!(base.is_some() && own.is_some() && base < own, "{}", chapter);
+    }
+}
+
+#[test]
+#[serial]
+fn raw_pages_are_added_verbatim() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    let pages: toml::Value = toml::from_str(
+        r#"list = [{ path = "colophon.xhtml", position = "back", title = "Colophon" }]"#,
+    )
+    .unwrap();
+    ctx.config.set("output.epub.raw-pages", pages["list"].clone()).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let page = doc.get_resource_str_by_path("OEBPS/colophon.xhtml").unwrap();
+    let original = std::fs::read_to_string(ctx.root.join("src").join("colophon.xhtml")).unwrap();
+    assert_eq!(page, original);
+    assert_eq!(doc.spine.last().map(String::as_str), Some("colophon_xhtml"));
+    let nav = doc.get_resource_str_by_path("OEBPS/toc.ncx").unwrap();
+    assert!(nav.contains("<text>Colophon</text>"), "{}", nav);
+}
+
+#[test]
+#[serial]
+fn malformed_raw_pages_are_rejected() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    let broken = temp.path().join("broken.xhtml");
+    std::fs::write(&broken, "<html><body><p>Unclosed</body></html>").unwrap();
+    let pages: toml::Value = toml::from_str(&format!("list = [{{ path = {:?} }}]", broken.display().to_string())).unwrap();
+    ctx.config.set("output.epub.raw-pages", pages["list"].clone()).unwrap();
+
+    let err = mdbook_epub::generate(&ctx).unwrap_err();
+
+    assert!(matches!(err, Error::MalformedPage(..)), "{}", err);
+}
+
+#[test]
+#[serial]
+fn footnote_ids_are_unique_across_the_book() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.footnotes", "chapter-end").unwrap();
+    for (name, path) in &[("One", "1-one.md"), ("Two", "part/two.md")] {
+        let content = format!("# {}\n\nText[^1] and again[^1].\n\n[^1]: The note.\n", name);
+        ctx.book.push_item(Chapter::new(name, content, *path, vec![]));
+    }
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let mut ids = Vec::new();
+    for chapter in &["OEBPS/1-one.html", "OEBPS/part/two.html"] {
+        let content = doc.get_resource_str_by_path(chapter).unwrap();
+        let chapter_ids: Vec<String> = content
+            .split(" id=\"")
+            .skip(1)
+            .filter_map(|rest| rest.split('"').next())
+            .filter(|id| id.contains("-fn"))
+            .map(String::from)
+            .collect();
+        assert_eq!(chapter_ids.len(), 3, "{}", content);
+        // every reference and back-link goes to an id in the same chapter
+        for href in content.split(" href=\"#").skip(1).filter_map(|rest| rest.split('"').next()) {
+            assert!(chapter_ids.iter().any(|id| id == href), "#{} not in {}", href, content);
+        }
+        ids.extend(chapter_ids);
+    }
+
+    let unique: std::collections::HashSet<_> = ids.iter().collect();
+    assert_eq!(unique.len(), ids.len(), "{:?}", ids);
+    assert!(ids.contains(&String::from("ch-1-one-fn-1")), "{:?}", ids);
+    assert!(ids.contains(&String::from("part-two-fn-1")), "{:?}", ids);
+}
+
+#[test]
+#[serial]
+fn minify_collapses_whitespace_but_not_code() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    let content = "# Minified\n\nSome    text.\n\n```text\nkeep    this\n    indented\n```\n";
+    ctx.book.push_item(Chapter::new("Minified", content.to_string(), "minified.md", vec![]));
+    ctx.config.set("output.epub.minify", true).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let chapter = doc.get_resource_str_by_path("OEBPS/minified.html").unwrap();
+    assert!(chapter.contains("keep    this\n    indented"), "{}", chapter);
+    assert!(chapter.contains("<p>Some text.</p>"), "{}", chapter);
+    assert!(!chapter.contains("\n <"), "{}", chapter);
+    let stylesheet = doc.get_resource_str_by_path("OEBPS/stylesheet.css").unwrap();
+    assert!(!stylesheet.contains("/*"), "{}", stylesheet);
+    assert!(!stylesheet.contains('\n'), "{}", stylesheet);
+}
+
+#[test]
+#[serial]
+fn images_without_alt_text_get_the_default() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.book.push_item(Chapter::new("No Alt", "# No Alt\n\n![](reddit.svg)\n".to_string(), "no_alt.md", vec![]));
+    ctx.config.set("output.epub.default-alt", "An illustration").unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let chapter = doc.get_resource_str_by_path("OEBPS/no_alt.html").unwrap();
+    assert!(chapter.contains("<img src=\"reddit.svg\" alt=\"An illustration\""), "{}", chapter);
+}
+
+#[test]
+#[serial]
+fn images_without_alt_text_can_fail_the_build() {
+    init_logging();
+    let (mut ctx, _md, _temp) = create_dummy_book().unwrap();
+    ctx.book.push_item(Chapter::new("No Alt", "# No Alt\n\n![](reddit.svg)\n".to_string(), "no_alt.md", vec![]));
+    ctx.config.set("output.epub.fail-on-missing-alt", true).unwrap();
+
+    let err = mdbook_epub::generate(&ctx).unwrap_err();
+
+    assert!(matches!(err, Error::MissingAltText(ref images) if images == "reddit.svg in no_alt.md"), "{}", err);
+
+    // chapters which are left out don't count
+    ctx.config.set("output.epub.exclude-chapters", vec!["no_alt.md"]).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+}
+
+#[test]
+#[serial]
+fn rights_and_license_are_in_the_metadata() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.version", "3.0").unwrap();
+    ctx.config.set("output.epub.rights", "© 2023 Jane Doe. Licensed under CC BY-SA 4.0.").unwrap();
+    ctx.config
+        .set("output.epub.license-url", "https://creativecommons.org/licenses/by-sa/4.0/")
+        .unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let opf = doc.get_resource_str_by_path("OEBPS/content.opf").unwrap();
+    assert!(opf.contains("<dc:rights>© 2023 Jane Doe. Licensed under CC BY-SA 4.0.</dc:rights>"), "{}", opf);
+    assert!(
+        opf.contains(r#"<link rel="dcterms:license" href="https://creativecommons.org/licenses/by-sa/4.0/"/>"#),
+        "{}",
+        opf
+    );
+}
+
+#[test]
+#[serial]
+fn stylesheet_is_linked_relative_to_the_chapter() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.book.push_item(Chapter::new("Top", "# Top\n".to_string(), "top.md", vec![]));
+    ctx.book.push_item(Chapter::new("Deep", "# Deep\n".to_string(), "a/b/c/deep.md", vec![]));
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let top = doc.get_resource_str_by_path("OEBPS/top.html").unwrap();
+    assert!(top.contains(r#"href="stylesheet.css""#), "{}", top);
+    let deep = doc.get_resource_str_by_path("OEBPS/a/b/c/deep.html").unwrap();
+    assert!(deep.contains(r#"href="../../../stylesheet.css""#), "{}", deep);
+}
+
+#[test]
+#[serial]
+fn toc_depth_leaves_deep_chapters_out_of_the_navigation() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    let mut sub = Chapter::new("Shallow Sub", String::from("# Shallow Sub\n"), "deep/sub.md", vec![]);
+    sub.number = Some(mdbook::book::SectionNumber(vec![2, 1]));
+    let mut ch = Chapter::new("Deep", String::from("# Deep\n"), "deep.md", vec![]);
+    ch.number = Some(mdbook::book::SectionNumber(vec![2]));
+    ch.sub_items.push(mdbook::BookItem::Chapter(sub));
+    ctx.book.push_item(ch);
+    ctx.config.set("output.epub.toc-depth", 1).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    assert!(doc.spine.contains(&String::from("deep_sub_html")), "{:?}", doc.spine);
+    let nav = doc.get_resource_str_by_path("OEBPS/toc.ncx").unwrap();
+    assert!(nav.contains("<text>2. Deep</text>"), "{}", nav);
+    assert!(!nav.contains("Shallow Sub"), "{}", nav);
+    let toc_page = doc.get_resource_str_by_path("OEBPS/toc.html").unwrap();
+    assert!(toc_page.contains(">2. Deep</a>"), "{}", toc_page);
+    assert!(!toc_page.contains("Shallow Sub"), "{}", toc_page);
+}
+
+#[test]
+#[serial]
+fn links_out_of_the_book_are_left_alone() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    let content = "# Escaping\n\nSee the [readme](../../chapter_1.md).\n";
+    ctx.book.push_item(Chapter::new("Escaping", content.to_string(), "guide/escaping.md", vec![]));
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let chapter = doc.get_resource_str_by_path("OEBPS/guide/escaping.html").unwrap();
+    assert!(chapter.contains(r#"<a href="../../chapter_1.md">readme</a>"#), "{}", chapter);
+
+    ctx.config.set("output.epub.fail-on-broken-links", true).unwrap();
+    let err = mdbook_epub::generate(&ctx).unwrap_err();
+    assert!(
+        matches!(err, Error::BrokenLinks(ref links) if links == "guide/escaping.md links to ../../chapter_1.md"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+#[serial]
+fn flattened_paths_still_resolve() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    let content = "# Setup\n\n![Logo](images/logo.png)\n\nBack to [chapter 1](../chapter_1.md#top).\n";
+    ctx.book.push_item(Chapter::new("Setup", content.to_string(), "guide/setup.md", vec![]));
+    ctx.config.set("output.epub.flatten-paths", true).unwrap();
+    ctx.config.set("output.epub.validate", true).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let nested: Vec<_> = doc
+        .resources
+        .values()
+        .map(|(path, _)| path.display().to_string())
+        .filter(|path| path.trim_start_matches("OEBPS/").contains('/'))
+        .collect();
+    assert!(nested.is_empty(), "{:?}", nested);
+    let chapter = doc.get_resource_str_by_path("OEBPS/guide_setup.html").unwrap();
+    assert!(chapter.contains(r#"href="stylesheet.css""#), "{}", chapter);
+    assert!(chapter.contains(r#"src="guide_images_logo.png""#), "{}", chapter);
+    assert!(chapter.contains(r#"href="chapter_1.html#top""#), "{}", chapter);
+    assert!(doc.get_resource_by_path("OEBPS/guide_images_logo.png").is_ok());
+}
+
+#[test]
+#[serial]
+fn conventional_layout_still_resolves() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    let content = "# Setup\n\n![Logo](images/logo.png)\n\nBack to [chapter 1](../chapter_1.md#top).\n";
+    ctx.book.push_item(Chapter::new("Setup", content.to_string(), "guide/setup.md", vec![]));
+    ctx.config.set("output.epub.directory-layout", "conventional").unwrap();
+    ctx.config.set("output.epub.validate", true).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let chapter = doc.get_resource_str_by_path("OEBPS/text/guide/setup.html").unwrap();
+    assert!(chapter.contains(r#"href="../../styles/stylesheet.css""#), "{}", chapter);
+    assert!(chapter.contains(r#"src="../../images/guide/images/logo.png""#), "{}", chapter);
+    assert!(chapter.contains(r#"href="../chapter_1.html#top""#), "{}", chapter);
+    let chapter = doc.get_resource_str_by_path("OEBPS/text/chapter_1.html").unwrap();
+    assert!(chapter.contains(r#"src="../images/rust-logo.png""#), "{}", chapter);
+    assert!(doc.get_resource_by_path("OEBPS/images/guide/images/logo.png").is_ok());
+    assert!(doc.get_resource_by_path("OEBPS/styles/stylesheet.css").is_ok());
+    assert!(doc.get_resource_by_path("OEBPS/stylesheet.css").is_err());
+    epub_check(&output_file).unwrap();
+}
+
+#[test]
+#[serial]
+fn content_can_be_kept_to_or_out_of_the_epub() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    let content = "# Formats\n\n<!-- epub:only -->\nYou're reading the *EPUB*.\n<!-- /epub:only -->\n\n\
+                   <!-- epub:skip -->\n![Widget](images/no-such-widget.png)\n<!-- /epub:skip -->\n\n\
+                   `<!-- epub:skip -->` hides web-only content.\n";
+    ctx.book.push_item(Chapter::new("Formats", content.to_string(), "formats.md", vec![]));
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let chapter = doc.get_resource_str_by_path("OEBPS/formats.html").unwrap();
+    assert!(chapter.contains("<p>You’re reading the <em>EPUB</em>.</p>"), "{}", chapter);
+    assert!(!chapter.contains("epub:only"), "{}", chapter);
+    // the skipped image isn't looked for, so its absence doesn't matter
+    assert!(!chapter.contains("no-such-widget"), "{}", chapter);
+    assert!(chapter.contains("<code>&lt;!-- epub:skip --&gt;</code> hides"), "{}", chapter);
+}
+
+#[test]
+#[serial]
+fn includes_are_spliced_into_chapters() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    let snippet = temp.path().join("snippet.md");
+    std::fs::write(&snippet, "// ANCHOR: greeting\nIncluded *greeting*\n// ANCHOR_END: greeting\n").unwrap();
+    let content = format!("# Including\n\n{{{{#include {}:greeting}}}}\n", snippet.display());
+    ctx.book.push_item(Chapter::new("Including", content, "including.md", vec![]));
+    ctx.config.set("output.epub.resolve-includes", true).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let chapter = doc.get_resource_str_by_path("OEBPS/including.html").unwrap();
+    assert!(chapter.contains("<p>Included <em>greeting</em></p>"), "{}", chapter);
+    assert!(!chapter.contains("#include"), "{}", chapter);
+
+    let looping = temp.path().join("looping.md");
+    std::fs::write(&looping, format!("{{{{#include {}}}}}", looping.display())).unwrap();
+    let content = format!("{{{{#include {}}}}}\n", looping.display());
+    ctx.book.push_item(Chapter::new("Looping", content, "looping.md", vec![]));
+    let err = mdbook_epub::generate(&ctx).unwrap_err();
+    assert!(matches!(err, Error::CircularInclude(_)), "{}", err);
+}
+
+#[test]
+#[serial]
+fn generator_metadata_can_be_left_out() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.version", "3.0").unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let nav = doc.get_resource_str_by_path("OEBPS/nav.xhtml").unwrap();
+    let generator = format!(r#"<meta name="generator" content="mdbook-epub {}" />"#, env!("CARGO_PKG_VERSION"));
+    assert_eq!(nav.matches(r#"name="generator""#).count(), 1, "{}", nav);
+    assert!(nav.contains(&generator), "{}", nav);
+
+    ctx.config.set("output.epub.emit-generator-metadata", false).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let nav = doc.get_resource_str_by_path("OEBPS/nav.xhtml").unwrap();
+    assert!(!nav.contains("generator"), "{}", nav);
+    let opf = doc.get_resource_str_by_path("OEBPS/content.opf").unwrap();
+    assert!(!opf.contains("mdbook-epub"), "{}", opf);
+}
+
+#[test]
+#[serial]
+fn stylesheet_urls_are_packaged() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    let theme = temp.path().join("theme");
+    std::fs::create_dir_all(theme.join("fonts")).unwrap();
+    std::fs::write(theme.join("fonts").join("serif.woff2"), b"not really a font").unwrap();
+    let css = "@font-face { font-family: Serif; src: url('fonts/serif.woff2'); }\n\
+               body { background: url(https://example.com/paper.png); }\n";
+    std::fs::write(theme.join("theme.css"), css).unwrap();
+    ctx.config.set("output.epub.additional-css", vec![theme.join("theme.css")]).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let stylesheet = doc.get_resource_str_by_path("OEBPS/stylesheet.css").unwrap();
+    assert!(stylesheet.contains("url('css/serif.woff2')"), "{}", stylesheet);
+    assert!(stylesheet.contains("url(https://example.com/paper.png)"), "{}", stylesheet);
+    let font = doc.get_resource_by_path("OEBPS/css/serif.woff2").unwrap();
+    assert_eq!(font, b"not really a font");
+}
+
+#[test]
+#[serial]
+fn stylesheets_load_in_order_with_overrides_last() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    let css = temp.path().join("extra.css");
+    std::fs::write(&css, "code { font-family: \"Extra Mono\"; }\n").unwrap();
+    ctx.config.set("output.epub.additional-css", vec![&css]).unwrap();
+    let code: std::collections::BTreeMap<_, _> = [("font-family", "\"Override Mono\", monospace")].into_iter().collect();
+    ctx.config.set("output.epub.css-overrides.code", code).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let stylesheet = doc.get_resource_str_by_path("OEBPS/stylesheet.css").unwrap();
+    assert!(stylesheet.starts_with(mdbook_epub::DEFAULT_CSS), "{}", stylesheet);
+    let additional = stylesheet.find("\"Extra Mono\"").expect("the additional stylesheet is included");
+    let overrides = stylesheet
+        .find("code {\n  font-family: \"Override Mono\", monospace;\n}")
+        .expect("the overrides are included");
+    assert!(mdbook_epub::DEFAULT_CSS.len() <= additional && additional < overrides, "{}", stylesheet);
+}
+
+#[test]
+#[serial]
+fn each_part_can_be_its_own_book() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.book.push_item(BookItem::PartTitle(String::from("Basics")));
+    ctx.book.push_item(Chapter::new("Setup", String::from("# Setup\n\nSee [tuning](tuning.md).\n"), "setup.md", vec![]));
+    ctx.book.push_item(BookItem::PartTitle(String::from("Advanced")));
+    ctx.book.push_item(Chapter::new("Tuning", String::from("# Tuning\n"), "tuning.md", vec![]));
+    ctx.config.set("output.epub.split-by-part", true).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    assert!(!output_file.exists());
+    let stem = output_file.file_stem().unwrap().to_string_lossy().to_string();
+
+    let basics = EpubDoc::new(temp.path().join(format!("{} - Basics.epub", stem))).unwrap();
+    assert!(basics.spine.contains(&String::from("chapter_1_html")), "{:?}", basics.spine);
+    assert!(basics.spine.contains(&String::from("setup_html")), "{:?}", basics.spine);
+    assert!(!basics.spine.contains(&String::from("tuning_html")), "{:?}", basics.spine);
+    let title = basics.mdata("title").unwrap();
+    assert!(title.ends_with(": Basics"), "{}", title);
+
+    let advanced = EpubDoc::new(temp.path().join(format!("{} - Advanced.epub", stem))).unwrap();
+    assert!(advanced.spine.contains(&String::from("tuning_html")), "{:?}", advanced.spine);
+    assert!(!advanced.spine.contains(&String::from("chapter_1_html")), "{:?}", advanced.spine);
+
+    ctx.config.set("output.epub.fail-on-broken-links", true).unwrap();
+    let err = mdbook_epub::generate(&ctx).unwrap_err();
+    assert!(matches!(err, Error::BrokenLinks(ref links) if links == "setup.md links to tuning.md"), "{}", err);
+}
+
+#[test]
+#[serial]
+fn text_cover_is_generated_without_a_cover_image() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.version", "3.0").unwrap();
+    ctx.config.set("output.epub.generate-text-cover", true).unwrap();
+    ctx.config.set("output.epub.text-cover-background", "navy").unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let cover = doc.get_resource_str_by_path("OEBPS/cover.svg").unwrap();
+    assert!(cover.contains(r#"fill="navy""#), "{}", cover);
+    let title = ctx.config.book.title.clone().unwrap();
+    assert!(cover.contains(title.split_whitespace().next().unwrap()), "{}", cover);
+    assert_eq!(doc.get_cover().unwrap(), cover.as_bytes());
+    let cover_page = doc.get_resource_str_by_path("OEBPS/cover.html").unwrap();
+    assert!(cover_page.contains(r#"xlink:href="cover.svg""#), "{}", cover_page);
+
+    epub_check(&output_file).unwrap();
+}
+
+#[test]
+#[serial]
+fn hidden_chapters_are_in_the_spine_but_not_the_navigation() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    let content = String::from("# How to Read\n");
+    ctx.book.push_item(Chapter::new("How to Read", content, "how-to-read.md", vec![]));
+    ctx.config.set("output.epub.version", "3.0").unwrap();
+    ctx.config.set("output.epub.toc-hidden", vec!["how-to-read.md"]).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    assert!(doc.spine.contains(&String::from("how-to-read_html")), "{:?}", doc.spine);
+    let nav = doc.get_resource_str_by_path("OEBPS/nav.xhtml").unwrap();
+    assert!(!nav.contains("how-to-read.html"), "{}", nav);
+    assert!(nav.contains("chapter_1.html"), "{}", nav);
+    let ncx = doc.get_resource_str_by_path("OEBPS/toc.ncx").unwrap();
+    assert!(!ncx.contains("How to Read"), "{}", ncx);
+    let toc_page = doc.get_resource_str_by_path("OEBPS/toc.html").unwrap();
+    assert!(!toc_page.contains("How to Read"), "{}", toc_page);
+}
+
+#[test]
+#[serial]
+fn the_options_in_effect_can_be_inspected() {
+    init_logging();
+    let (mut ctx, _md, _temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.toc-depth", 2).unwrap();
+    ctx.config.set("output.epub.cache-dir", "render-cache").unwrap();
+
+    let generator = Generator::new(&ctx).unwrap();
+    let config = generator.config();
+
+    assert_eq!(config.toc_depth, Some(2));
+    assert_eq!(config.cache_dir, Some(ctx.root.join("render-cache")));
+    // options which weren't set keep their defaults
+    assert!(config.use_default_css);
+    assert!(config.emit_generator_metadata);
+    assert_eq!(config.index_template, None);
+}
+
+#[test]
+#[serial]
+fn relative_paths_are_found_from_any_working_directory() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    // a decoy with the same name where the generator happens to be run from
+    std::fs::create_dir_all(temp.path().join("guide").join("images")).unwrap();
+    std::fs::write(temp.path().join("guide").join("images").join("logo.png"), b"not the cover").unwrap();
+    std::fs::write(temp.path().join("reddit.svg"), b"not the resource").unwrap();
+    ctx.config.set("output.epub.cover-image", "guide/images/logo.png").unwrap();
+    ctx.config.set("output.epub.additional-resources", vec!["reddit.svg"]).unwrap();
+    ctx.config.set("output.epub.additional-css", vec!["src/appendix.css"]).unwrap();
+
+    let cwd = env::current_dir().unwrap();
+    env::set_current_dir(temp.path()).unwrap();
+    let result = mdbook_epub::generate(&ctx);
+    env::set_current_dir(cwd).unwrap();
+    result.unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let cover = std::fs::read(ctx.root.join("src").join("guide").join("images").join("logo.png")).unwrap();
+    assert_eq!(doc.get_cover().unwrap(), cover);
+    let resource = std::fs::read(ctx.root.join("src").join("reddit.svg")).unwrap();
+    assert_eq!(doc.get_resource_by_path("OEBPS/reddit.svg").unwrap(), resource);
+    let stylesheet = doc.get_resource_str_by_path("OEBPS/stylesheet.css").unwrap();
+    let appendix = std::fs::read_to_string(ctx.root.join("src").join("appendix.css")).unwrap();
+    assert!(stylesheet.contains(appendix.trim()), "{}", stylesheet);
+}
+
+#[test]
+#[serial]
+fn tables_can_scroll_sideways() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    let content = String::from("# Wide\n\n| a | b |\n|---|---|\n| 1 | 2 |\n");
+    ctx.book.push_item(Chapter::new("Wide", content, "wide.md", vec![]));
+    ctx.config.set("output.epub.responsive-tables", true).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let chapter = doc.get_resource_str_by_path("OEBPS/wide.html").unwrap();
+    assert!(chapter.contains("<div class=\"table-wrapper\">\n<table>"), "{}", chapter);
+    assert!(chapter.contains("</table>\n</div>"), "{}", chapter);
+    let stylesheet = doc.get_resource_str_by_path("OEBPS/stylesheet.css").unwrap();
+    assert!(stylesheet.contains(".table-wrapper {\n    overflow-x: auto;"), "{}", stylesheet);
+}
+
+#[test]
+#[serial]
+fn chapters_whose_ids_clash_are_all_in_the_spine() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    for path in ["a/index.md", "b/index.md", "a_index.md"] {
+        let content = format!("# {}\n", path);
+        ctx.book.push_item(Chapter::new(path, content, path, vec![]));
+    }
+    ctx.config.set("output.epub.flatten-paths", true).unwrap();
+    ctx.config.set("output.epub.validate", true).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    for id in ["a_index_html", "b_index_html", "a_index_html-2"] {
+        assert_eq!(doc.spine.iter().filter(|entry| *entry == id).count(), 1, "{:?}", doc.spine);
+    }
+    let opf = doc.get_resource_str_by_path("OEBPS/content.opf").unwrap();
+    // the top-level chapter keeps its name, so the flattened one moves aside
+    assert!(opf.contains(r#"id="a_index_html-2" href="a_index.html""#), "{}", opf);
+    assert!(doc.get_resource_str_by_path("OEBPS/a_index.html").unwrap().contains("a_index.md"));
+    assert!(doc.get_resource_str_by_path("OEBPS/a_index-2.html").unwrap().contains("a/index.md"));
+
+    epub_check(&output_file).unwrap();
+}
+
+#[test]
+#[serial]
+fn description_can_be_a_page() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.book.description = Some(String::from("A book about *crabs* & friends."));
+    ctx.config.set("output.epub.description-as-page", true).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let page = doc.get_resource_str_by_path("OEBPS/about_this_book.html").unwrap();
+    assert!(page.contains("About this book</h1>"), "{}", page);
+    assert!(page.contains("<p>A book about <em>crabs</em> &amp; friends.</p>"), "{}", page);
+    let chapter_1 = doc.spine.iter().position(|id| id == "chapter_1_html").unwrap();
+    let about = doc.spine.iter().position(|id| id == "about_this_book_html").unwrap();
+    assert!(about < chapter_1, "{:?}", doc.spine);
+    assert_eq!(doc.mdata("description").as_deref(), Some("A book about *crabs* & friends."));
+
+    ctx.config.book.description = Some(String::from("  "));
+    mdbook_epub::generate(&ctx).unwrap();
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    assert!(doc.get_resource_by_path("OEBPS/about_this_book.html").is_err());
+
+    // a chapter of the book keeps its name, and the page can be retitled
+    ctx.config.book.description = Some(String::from("A book about crabs."));
+    ctx.config.set("output.epub.description-page-title", "Blurb").unwrap();
+    let about = String::from("# About\n\nThe chapter.\n");
+    ctx.book.push_item(Chapter::new("About", about, "about_this_book.md", vec![]));
+    mdbook_epub::generate(&ctx).unwrap();
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let chapter = doc.get_resource_str_by_path("OEBPS/about_this_book.html").unwrap();
+    assert!(chapter.contains("The chapter."), "{}", chapter);
+    let page = doc.get_resource_str_by_path("OEBPS/about_this_book-2.html").unwrap();
+    assert!(page.contains("Blurb</h1>"), "{}", page);
+    assert!(page.contains("A book about crabs."), "{}", page);
+}
+
+#[test]
+#[serial]
+fn size_report_is_written() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    let report_path = temp.path().join("report.txt");
+    ctx.config.set("output.epub.report-path", &report_path).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let report = std::fs::read_to_string(&report_path).unwrap();
+    assert!(report.starts_with("Report for DummyBook\nTotal size: "), "{}", report);
+    assert!(report.contains("KB\nChapters: "), "{}", report);
+    assert!(report.contains("\n  image/png: "), "{}", report);
+    assert!(report.contains("\nLargest assets:\n"), "{}", report);
+    assert!(report.contains("OEBPS/rust-logo.png"), "{}", report);
+}
+
+#[test]
+#[serial]
+fn wikilinks_lead_to_chapters() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.wikilinks", true).unwrap();
+    let content = String::from("# Wiki\n\nSee [[Chapter 1]], [[chapter_1|the first chapter]] and [[Nowhere]].\n");
+    ctx.book.push_item(Chapter::new("Wiki", content, "wiki/index.md", vec![]));
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let content = doc.get_resource_str_by_path("OEBPS/wiki/index.html").unwrap();
+    assert!(content.contains(r#"See <a href="../chapter_1.html">Chapter 1</a>, <a href="../chapter_1.html">the first chapter</a> and Nowhere."#), "{}", content);
+}
+
+#[test]
+#[serial]
+fn headings_can_be_offset() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.heading-offset", 1).unwrap();
+    let content = String::from("---\nheading_offset: -1\n---\n## Raised\n\n# Still first\n");
+    ctx.book.push_item(Chapter::new("Raised", content, "raised.md", vec![]));
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let chapter_1 = doc.get_resource_str_by_path("OEBPS/chapter_1.html").unwrap();
+    assert!(chapter_1.contains("<h2 id=\"chapter-1\">"), "{}", chapter_1);
+    let raised = doc.get_resource_str_by_path("OEBPS/raised.html").unwrap();
+    assert!(raised.contains("<h1 id=\"raised\">Raised</h1>"), "{}", raised);
+    assert!(raised.contains("<h1 id=\"still-first\">Still first</h1>"), "{}", raised);
+}
+
+#[test]
+#[serial]
+fn audio_is_packaged() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.version", "3.0").unwrap();
+    ctx.config.set("output.epub.validate", true).unwrap();
+    let content = String::from("# Listen\n\n<audio controls src=\"/clip.mp3\"></audio>\n");
+    ctx.book.push_item(Chapter::new("Listen", content, "guide/listen.md", vec![]));
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let opf = doc.get_resource_str_by_path("OEBPS/content.opf").unwrap();
+    assert!(opf.contains(r#"<item media-type="audio/mpeg" id="clip_mp3" href="clip.mp3"/>"#), "{}", opf);
+    let chapter = doc.get_resource_str_by_path("OEBPS/guide/listen.html").unwrap();
+    assert!(chapter.contains(r#"<audio controls src="../clip.mp3"></audio>"#), "{}", chapter);
+}
+
+#[test]
+#[serial]
+fn images_in_raw_html_are_packaged() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.validate", true).unwrap();
+    let content = "# Pictures\n\nThe <img alt=\"logo\" src=\"images/logo.png\"> logo.\n\n\
+                   <figure>\n<img alt=\"\" srcset=\"../reddit.svg 2x\" src=\"/reddit_copy.svg\"/>\n</figure>\n";
+    ctx.book.push_item(Chapter::new("Pictures", content.to_string(), "guide/pictures.md", vec![]));
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    assert!(doc.get_resource_by_path("OEBPS/guide/images/logo.png").is_ok());
+    assert!(doc.get_resource_by_path("OEBPS/reddit.svg").is_ok());
+    assert!(doc.get_resource_by_path("OEBPS/reddit_copy.svg").is_err());
+    let chapter = doc.get_resource_str_by_path("OEBPS/guide/pictures.html").unwrap();
+    assert!(chapter.contains(r#"<img alt="logo" src="images/logo.png">"#), "{}", chapter);
+    // the copy is packaged once, under the name of the file it copies
+    assert!(chapter.contains(r#"srcset="../reddit.svg 2x" src="../reddit.svg""#), "{}", chapter);
+}
+
+#[test]
+#[serial]
+fn dry_run_lists_the_plan_without_writing() {
+    init_logging();
+    let temp = TempDir::new("mdbook-epub").unwrap();
+    let src = temp.path().join("src");
+    std::fs::create_dir_all(src.join("images")).unwrap();
+    std::fs::write(src.join("images").join("logo.png"), "logo").unwrap();
+    std::fs::write(src.join("images").join("copy.png"), "logo").unwrap();
+    std::fs::create_dir_all(src.join("intro")).unwrap();
+    let mut book = mdbook::book::Book::new();
+    let mut intro = Chapter::new("Intro", String::from("![Logo](images/logo.png)"), "intro.md", vec![]);
+    intro.number = Some(mdbook::book::SectionNumber(vec![1]));
+    let mut details = Chapter::new("Details", String::from("![Copy](../images/copy.png)"), "intro/details.md", vec![]);
+    details.number = Some(mdbook::book::SectionNumber(vec![1, 1]));
+    intro.sub_items.push(BookItem::Chapter(details));
+    book.push_item(intro);
+    book.push_item(BookItem::PartTitle(String::from("Reference")));
+    book.push_item(Chapter::new("Glossary", String::from("[Intro](intro.md)"), "glossary.md", vec![]));
+
+    let config = mdbook_epub::Config {
+        dry_run: true,
+        dedup_assets: true,
+        ..Default::default()
+    };
+    let generator = Generator::from_book(&book, config, temp.path()).unwrap();
+    let plan = generator.plan().unwrap();
+    assert_eq!(
+        plan,
+        "Dry run of the book, nothing is written\n\
+         Chapters, in reading order:\n  1. intro.html (Intro)\n  2. intro/details.html (Details)\n  3. glossary.html (Glossary)\n\
+         Table of contents:\n  1. Intro\n    1.1. Details\n  Reference\n    Glossary\n\
+         Assets:\n  images/logo.png (image/png)\n  images/copy.png (the same as images/logo.png, not packaged)\n"
+    );
+
+    let mut out = Vec::new();
+    generator.generate(&mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), plan);
+
+    // the contents only go as deep as the navigation will, counting parts
+    let config = mdbook_epub::Config {
+        dry_run: true,
+        toc_depth: Some(1),
+        ..Default::default()
+    };
+    let generator = Generator::from_book(&book, config, temp.path()).unwrap();
+    let plan = generator.plan().unwrap();
+    assert!(plan.contains("Table of contents:\n  1. Intro\n  Reference\nAssets:"), "{}", plan);
+
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.dry-run", true).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+    assert!(!mdbook_epub::output_filename(temp.path(), &ctx.config).exists());
+}
+
+#[test]
+#[serial]
+fn chapter_sources_can_be_embedded() {
+    init_logging();
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.embed-source", true).unwrap();
+    ctx.config.set("output.epub.validate", true).unwrap();
+    let content = String::from("# Written in memory\n");
+    ctx.book.push_item(Chapter::new("In Memory", content.clone(), "guide/in_memory.md", vec![]));
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file).unwrap();
+    let opf = doc.get_resource_str_by_path("OEBPS/content.opf").unwrap();
+    assert!(opf.contains(r#"media-type="text/markdown" id="chapter_1_md" href="chapter_1.md"/>"#), "{}", opf);
+    assert!(!opf.contains(r#"idref="chapter_1_md""#), "{}", opf);
+    let ncx = doc.get_resource_str_by_path("OEBPS/toc.ncx").unwrap();
+    assert!(!ncx.contains(".md"), "{}", ncx);
+
+    let source = doc.get_resource_str_by_path("OEBPS/chapter_1.md").unwrap();
+    let expected = std::fs::read_to_string(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/dummy/src/chapter_1.md")).unwrap();
+    assert_eq!(source, expected);
+    assert_eq!(doc.get_resource_str_by_path("OEBPS/guide/in_memory.md").unwrap(), content);
 }